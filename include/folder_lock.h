@@ -0,0 +1,90 @@
+/* folder_lock C bindings, generated by cbindgen from src/ffi.rs; do not edit. */
+
+#ifndef FOLDER_LOCK_H
+#define FOLDER_LOCK_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Takes bytes of the archive being written: consumes up to `len` bytes at `buf` and
+// returns how many it took, or -1 to abort with an error
+typedef ptrdiff_t (*FolderLockWriteFn)(void *ctx, const uint8_t *buf, size_t len);
+
+// Supplies bytes of the archive being read: fills up to `len` bytes at `buf` and
+// returns how many it stored, 0 at the end of the archive, or -1 to abort with an error
+typedef ptrdiff_t (*FolderLockReadFn)(void *ctx, uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Encrypt `folder` into a new archive at `out_path`, which must not exist yet
+//
+// The archive is encrypted to `passphrase`, to the `n_recipients` age recipient strings
+// at `recipients`, or to both; pass NULL for what isn't used. `out_path` may also be an
+// `s3://` or `sftp://` URL when the library is built with those features.
+//
+// # Safety
+//
+// Every non-NULL pointer must be valid: strings NUL-terminated, and `recipients` an
+// array of `n_recipients` strings.
+int folder_lock_encrypt_folder_to_path(const char *folder,
+                                       const char *out_path,
+                                       const char *passphrase,
+                                       const char *const *recipients,
+                                       size_t n_recipients);
+
+// Like `folder_lock_encrypt_folder_to_path`, handing the archive to `write` as it is
+// produced, with `ctx` as its first argument
+//
+// # Safety
+//
+// As for `folder_lock_encrypt_folder_to_path`; `write` must be safe to call with `ctx`
+// from this thread until this function returns.
+int folder_lock_encrypt_folder_to_callback(const char *folder,
+                                           const char *passphrase,
+                                           const char *const *recipients,
+                                           size_t n_recipients,
+                                           FolderLockWriteFn write,
+                                           void *ctx);
+
+// Decrypt the archive at `archive` into the existing folder `out_folder`, never
+// replacing files already there
+//
+// The archive is opened with `passphrase` or with the age identities in the file at
+// `identity_file`; pass NULL for the one that isn't used.
+//
+// # Safety
+//
+// Every non-NULL pointer must be a valid NUL-terminated string.
+int folder_lock_decrypt_path_to_folder(const char *archive,
+                                       const char *out_folder,
+                                       const char *passphrase,
+                                       const char *identity_file);
+
+// Like `folder_lock_decrypt_path_to_folder`, reading the archive from `read`, with `ctx`
+// as its first argument
+//
+// # Safety
+//
+// As for `folder_lock_decrypt_path_to_folder`; `read` must be safe to call with `ctx`
+// from this thread until this function returns.
+int folder_lock_decrypt_callback_to_folder(FolderLockReadFn read,
+                                           void *ctx,
+                                           const char *out_folder,
+                                           const char *passphrase,
+                                           const char *identity_file);
+
+// What went wrong in this thread's last failed call, or NULL if it succeeded
+//
+// The string stays valid until the next call into the library on the same thread.
+const char *folder_lock_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FOLDER_LOCK_H */
@@ -0,0 +1,224 @@
+//! A passphrase and identity cache for a sequence of commands, like `ssh-agent`
+//!
+//! `agent` keeps what was last typed at a prompt in locked memory (never swapped out, and
+//! out of core dumps) for `--ttl` after it was stored, serving processes of the same user
+//! over a Unix socket. Commands ask it before prompting, and hand it a passphrase once it
+//! opened an archive (or was confirmed for a new one), so `encrypt`, `list` and `verify`
+//! in a row prompt only once. A cached passphrase that fails is dropped again.
+//!
+//! Commands only use an agent when `$FOLDER_LOCK_AGENT_SOCK` names its socket, as
+//! `folder_lock agent` prints it for `eval`, and only one running as the same user (see
+//! `socket`); the agent answers nobody else either. Each request and response is one
+//! JSON line.
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::time::Duration;
+
+use age::secrecy::SecretString;
+#[cfg(unix)]
+use anyhow::Result;
+
+/// Environment variable naming the agent's socket
+pub const SOCKET_ENV: &str = "FOLDER_LOCK_AGENT_SOCK";
+
+/// Slot of the passphrase typed at a prompt
+pub const PASSPHRASE: &str = "passphrase";
+/// Slot of the `AGE-SECRET-KEY-1…` identity typed at a prompt
+pub const IDENTITY: &str = "identity";
+
+/// Where a new agent listens: `$FOLDER_LOCK_AGENT_SOCK`, else `folder_lock-agent.sock` in
+/// this user's `socket::runtime_dir`
+#[cfg(unix)]
+pub fn default_socket() -> Result<PathBuf> {
+    match std::env::var_os(SOCKET_ENV).filter(|p| !p.is_empty()) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(crate::socket::runtime_dir()?.join("folder_lock-agent.sock")),
+    }
+}
+
+/// The secret cached in `slot`, if an agent is running and holds one
+pub fn get(slot: &str) -> Option<SecretString> {
+    let response = request(serde_json::json!({ "op": "get", "slot": slot }))?;
+    let secret = response.get("secret")?.as_str()?.to_owned();
+    Some(SecretString::new(secret))
+}
+
+/// Cache `secret` in `slot`, if an agent is running
+pub fn put(slot: &str, secret: &SecretString) {
+    use age::secrecy::ExposeSecret;
+    request(serde_json::json!({ "op": "put", "slot": slot, "secret": secret.expose_secret() }));
+}
+
+/// Drop whatever `slot` holds, if an agent is running
+pub fn forget(slot: &str) {
+    request(serde_json::json!({ "op": "forget", "slot": slot }));
+}
+
+/// Send one request to the agent `$FOLDER_LOCK_AGENT_SOCK` names; any failure means there
+/// is no usable agent
+#[cfg(unix)]
+fn request(request: serde_json::Value) -> Option<serde_json::Value> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let path = PathBuf::from(std::env::var_os(SOCKET_ENV).filter(|p| !p.is_empty())?);
+    let mut stream = UnixStream::connect(&path).ok()?;
+    if !crate::socket::peer_is_self(&stream) {
+        log::warn!(
+            "ignoring the agent at {}, which runs as another user",
+            path.display()
+        );
+        return None;
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(2))).ok()?;
+    writeln!(stream, "{}", request).ok()?;
+    let mut line = age::secrecy::zeroize::Zeroizing::new(String::new());
+    BufReader::new(stream).read_line(&mut line).ok()?;
+    match serde_json::from_str(&line) {
+        Ok(response) => Some(response),
+        Err(e) => {
+            log::debug!(
+                "unexpected answer from the agent at {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn request(_request: serde_json::Value) -> Option<serde_json::Value> {
+    None
+}
+
+/// Serve the cache on `socket` until killed, expiring entries `ttl` after they are stored
+#[cfg(unix)]
+pub fn serve(socket: &std::path::Path, ttl: Duration) -> Result<()> {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    type Cache = Arc<Mutex<HashMap<String, (Locked, Instant)>>>;
+
+    fn handle(stream: UnixStream, cache: &Cache, ttl: Duration) -> std::io::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = age::secrecy::zeroize::Zeroizing::new(line?);
+            let request: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(_) => {
+                    writeln!(
+                        out,
+                        "{}",
+                        serde_json::json!({ "error": "malformed request" })
+                    )?;
+                    continue;
+                }
+            };
+            let field = |name: &str| request.get(name).and_then(|v| v.as_str()).unwrap_or("");
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            let response = match field("op") {
+                "get" => match cache.get(field("slot")) {
+                    Some((secret, expires)) if *expires > Instant::now() => {
+                        serde_json::json!({ "secret": secret.as_str() })
+                    }
+                    _ => serde_json::json!({ "secret": null }),
+                },
+                "put" => {
+                    let entry = (Locked::new(field("secret")), Instant::now() + ttl);
+                    cache.insert(field("slot").to_owned(), entry);
+                    serde_json::json!({})
+                }
+                "forget" => {
+                    cache.remove(field("slot"));
+                    serde_json::json!({})
+                }
+                _ => serde_json::json!({ "error": "unknown op" }),
+            };
+            drop(cache);
+            let response = age::secrecy::zeroize::Zeroizing::new(response.to_string());
+            writeln!(out, "{}", response.as_str())?;
+        }
+        Ok(())
+    }
+
+    // Other processes of this user can't read the cache through ptrace or a core dump
+    #[cfg(target_os = "linux")]
+    // SAFETY: PR_SET_DUMPABLE takes a plain integer and touches no memory
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+
+    let listener = crate::socket::listen(socket, "an agent")?;
+
+    let cache: Cache = Arc::default();
+    // Wipe expired secrets promptly instead of on the next request
+    let sweeper = cache.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let now = Instant::now();
+        sweeper
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, (_, expires)| *expires > now);
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        if !crate::socket::peer_is_self(&stream) {
+            log::warn!("refused a connection from another user");
+            continue;
+        }
+        let cache = cache.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream, &cache, ttl) {
+                log::debug!("agent connection closed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// A secret in memory that is kept out of swap and wiped when dropped
+#[cfg(unix)]
+struct Locked(Vec<u8>);
+
+#[cfg(unix)]
+impl Locked {
+    fn new(secret: &str) -> Self {
+        let mut buf = Vec::with_capacity(secret.len().max(1));
+        // SAFETY: the range is the vector's own allocation, which never grows or moves
+        if unsafe { libc::mlock(buf.as_ptr().cast(), buf.capacity()) } != 0 {
+            log::warn!("failed to lock cached secret in memory; it may be swapped out");
+        }
+        buf.extend_from_slice(secret.as_bytes());
+        Self(buf)
+    }
+
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Locked {
+    fn drop(&mut self) {
+        use age::secrecy::zeroize::Zeroize;
+        self.0.zeroize();
+        // SAFETY: unlocks exactly the range `new` locked
+        unsafe {
+            libc::munlock(self.0.as_ptr().cast(), self.0.capacity());
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Async adapters for `Locker` and `Unlocker` (feature `async`)
+//!
+//! Walking, tar, compression and age are all blocking, so each pipeline runs on tokio's
+//! blocking pool and talks to the async side through `SyncIoBridge`. A runtime thread is
+//! never blocked on file system or CPU work.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_util::io::SyncIoBridge;
+
+use crate::report::Stats;
+use crate::{Locked, Locker, Unlocker};
+
+/// Buffer between the encrypting thread and the reader returned by `encrypt_stream`
+const STREAM_BUFFER: usize = 256 * 1024;
+
+impl Locker {
+    /// Like `encrypt_to`, writing to an async destination; `w` is flushed and handed back
+    pub async fn encrypt_to_async<W>(self, w: W) -> Result<(Locked, W)>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut bridge = SyncIoBridge::new(w);
+        tokio::task::spawn_blocking(move || {
+            let locked = self.encrypt_to(&mut bridge)?;
+            bridge.flush().context("failed to flush output")?;
+            Ok((locked, bridge.into_inner()))
+        })
+        .await
+        .context("encryption task failed")?
+    }
+
+    /// Encrypt in the background, returning the ciphertext as an `AsyncRead`
+    ///
+    /// Useful as a request body for network uploads. The handle resolves once the last
+    /// byte has been produced; if the reader is dropped early, encryption fails with a
+    /// broken pipe.
+    pub fn encrypt_stream(self) -> (DuplexStream, JoinHandle<Result<Locked>>) {
+        let (reader, writer) = tokio::io::duplex(STREAM_BUFFER);
+        let mut bridge = SyncIoBridge::new(writer);
+        let handle = tokio::task::spawn_blocking(move || {
+            let locked = self.encrypt_to(&mut bridge)?;
+            bridge.shutdown().context("failed to close the stream")?;
+            Ok(locked)
+        });
+        (reader, handle)
+    }
+}
+
+impl<R> Unlocker<SyncIoBridge<R>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    /// An `Unlocker` over an async source; must be called from within a tokio runtime
+    pub fn new_async(reader: R) -> Self {
+        Unlocker::new(SyncIoBridge::new(reader))
+    }
+
+    /// Like `extract_to`, off the runtime's worker threads
+    pub async fn extract_to_async(self, out_folder: impl Into<PathBuf>) -> Result<Stats> {
+        let out_folder = out_folder.into();
+        tokio::task::spawn_blocking(move || self.extract_to(out_folder))
+            .await
+            .context("decryption task failed")?
+    }
+}
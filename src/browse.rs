@@ -0,0 +1,371 @@
+//! Terminal browser for an archive's contents (feature `tui`)
+//!
+//! The archive is read once to build a tree of its entries, keeping small files in memory
+//! for the preview pane. Entries marked in the browser are returned as `PathFilter`
+//! patterns, so the caller can extract just those in a second pass.
+
+use std::collections::HashSet;
+use std::io::{self, Read};
+
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::snapshot;
+
+/// Files up to this size are kept for previewing
+const PREVIEW_LIMIT: u64 = 64 * 1024;
+
+/// Total size of kept previews; files past it show no preview
+const PREVIEW_BUDGET: u64 = 64 * 1024 * 1024;
+
+const ROOT: usize = 0;
+
+#[derive(Debug)]
+pub enum Kind {
+    Dir,
+    File,
+    Symlink(String),
+    HardLink(String),
+    /// Devices, FIFOs and other special files
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub name: String,
+    /// Archive path (`docs/a.txt`)
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+    parent: usize,
+    /// Folders first, then by name
+    children: Vec<usize>,
+    preview: Option<Vec<u8>>,
+}
+
+/// Every entry of an archive, as a tree rooted at the archive's top level
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Read all entries of `archive`, leaving out folder_lock's own metadata
+    pub fn read<R: Read>(archive: &mut tar::Archive<R>) -> Result<Self> {
+        let mut tree = Tree {
+            nodes: vec![Node {
+                name: String::new(),
+                path: String::new(),
+                kind: Kind::Dir,
+                size: 0,
+                parent: ROOT,
+                children: Vec::new(),
+                preview: None,
+            }],
+        };
+        let mut kept = 0;
+        for entry in archive
+            .entries()
+            .context("failed to read archive entries")?
+        {
+            let mut entry = entry.context("failed to read archive entry")?;
+            let path = snapshot::key(&entry.path().context("invalid path in archive")?);
+            if path.is_empty() || snapshot::is_metadata(&path) {
+                continue;
+            }
+            let header = entry.header();
+            let size = header.size().unwrap_or(0);
+            let link = || -> Result<String> {
+                let target = header
+                    .link_name()
+                    .context("invalid link target in archive")?;
+                Ok(target.map_or_else(String::new, |t| t.display().to_string()))
+            };
+            let kind = match header.entry_type() {
+                tar::EntryType::Directory => Kind::Dir,
+                tar::EntryType::Symlink => Kind::Symlink(link()?),
+                tar::EntryType::Link => Kind::HardLink(link()?),
+                kind if kind.is_file() || kind.is_gnu_sparse() => Kind::File,
+                _ => Kind::Other,
+            };
+            let preview = if matches!(kind, Kind::File)
+                && size <= PREVIEW_LIMIT
+                && kept + size <= PREVIEW_BUDGET
+            {
+                let mut data = Vec::with_capacity(size as usize);
+                entry
+                    .read_to_end(&mut data)
+                    .with_context(|| format!("failed to read '{}'", path))?;
+                kept += size;
+                Some(data)
+            } else {
+                None
+            };
+            let node = tree.insert(&path);
+            let node = &mut tree.nodes[node];
+            node.kind = kind;
+            node.size = size;
+            node.preview = preview;
+        }
+        tree.sort(ROOT);
+        Ok(tree)
+    }
+
+    /// The node at `path`, created with any missing parent folders
+    fn insert(&mut self, path: &str) -> usize {
+        let mut current = ROOT;
+        for name in path.split('/') {
+            let found = self.nodes[current]
+                .children
+                .iter()
+                .copied()
+                .find(|&child| self.nodes[child].name == name);
+            current = match found {
+                Some(child) => child,
+                None => {
+                    let parent = &self.nodes[current];
+                    let path = if parent.path.is_empty() {
+                        name.to_string()
+                    } else {
+                        format!("{}/{}", parent.path, name)
+                    };
+                    self.nodes.push(Node {
+                        name: name.to_string(),
+                        path,
+                        kind: Kind::Dir,
+                        size: 0,
+                        parent: current,
+                        children: Vec::new(),
+                        preview: None,
+                    });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[current].children.push(child);
+                    child
+                }
+            };
+        }
+        current
+    }
+
+    fn sort(&mut self, node: usize) {
+        let mut children = std::mem::take(&mut self.nodes[node].children);
+        children.sort_by(|&a, &b| {
+            let (a, b) = (&self.nodes[a], &self.nodes[b]);
+            let (a_dir, b_dir) = (matches!(a.kind, Kind::Dir), matches!(b.kind, Kind::Dir));
+            b_dir.cmp(&a_dir).then_with(|| a.name.cmp(&b.name))
+        });
+        for &child in &children {
+            self.sort(child);
+        }
+        self.nodes[node].children = children;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes[ROOT].children.is_empty()
+    }
+}
+
+/// Browser state
+struct Browser<'a> {
+    tree: &'a Tree,
+    title: String,
+    /// Folder being listed
+    cwd: usize,
+    list: ListState,
+    marked: HashSet<usize>,
+}
+
+/// Run the browser until the user quits; returns the `PathFilter` patterns of the entries
+/// marked for extraction, or `None` to extract nothing
+///
+/// Keys: arrows (or h/j/k/l) to move and enter or leave folders, space to mark, `x` to
+/// extract what is marked (or the entry under the cursor), `q` to quit.
+pub fn run(tree: &Tree, title: &str) -> Result<Option<Vec<String>>> {
+    let mut browser = Browser {
+        tree,
+        title: title.to_string(),
+        cwd: ROOT,
+        list: ListState::default().with_selected(Some(0)),
+        marked: HashSet::new(),
+    };
+    let mut terminal = ratatui::try_init().context("failed to set up the terminal")?;
+    // Restore the terminal whatever happened, so an error is readable
+    let result = browser.event_loop(&mut terminal);
+    ratatui::restore();
+    let chosen = result?;
+    Ok(chosen.map(|nodes| {
+        let mut paths: Vec<_> = nodes
+            .iter()
+            .map(|&n| escape_glob(&tree.nodes[n].path))
+            .collect();
+        paths.sort();
+        paths
+    }))
+}
+
+impl Browser<'_> {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<Option<Vec<usize>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let children = &self.tree.nodes[self.cwd].children;
+            let selected = self.list.selected().and_then(|i| children.get(i).copied());
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Home => self.list.select_first(),
+                KeyCode::End => self.list.select_last(),
+                KeyCode::Right | KeyCode::Enter | KeyCode::Char('l') => {
+                    if let Some(node) =
+                        selected.filter(|&n| !self.tree.nodes[n].children.is_empty())
+                    {
+                        self.cwd = node;
+                        self.list.select(Some(0));
+                    }
+                }
+                KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h') if self.cwd != ROOT => {
+                    let left = self.cwd;
+                    self.cwd = self.tree.nodes[left].parent;
+                    let position = self.tree.nodes[self.cwd]
+                        .children
+                        .iter()
+                        .position(|&c| c == left);
+                    self.list.select(position);
+                }
+                KeyCode::Char(' ') => {
+                    if let Some(node) = selected {
+                        if !self.marked.remove(&node) {
+                            self.marked.insert(node);
+                        }
+                        self.list.select_next();
+                    }
+                }
+                KeyCode::Char('x') => {
+                    if self.marked.is_empty() {
+                        self.marked.extend(selected);
+                    }
+                    if !self.marked.is_empty() {
+                        return Ok(Some(self.marked.iter().copied().collect()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(main);
+
+        let tree = self.tree;
+        let cwd = &tree.nodes[self.cwd];
+        let items: Vec<ListItem> = cwd
+            .children
+            .iter()
+            .map(|&child| {
+                let node = &tree.nodes[child];
+                let mark = if self.marked.contains(&child) {
+                    "[x]"
+                } else if self.inside_marked(child) {
+                    "[~]"
+                } else {
+                    "[ ]"
+                };
+                let line = match &node.kind {
+                    Kind::Dir => format!("{} {}/", mark, node.name),
+                    Kind::Symlink(target) => format!("{} {} -> {}", mark, node.name, target),
+                    _ => format!("{} {}  {}", mark, node.name, HumanBytes(node.size)),
+                };
+                ListItem::new(line)
+            })
+            .collect();
+        let location = format!(" {}/{} ", self.title, cwd.path);
+        let list = List::new(items)
+            .block(Block::bordered().title(location))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, left, &mut self.list);
+
+        let selected = self
+            .list
+            .selected()
+            .and_then(|i| cwd.children.get(i).copied());
+        let preview = selected
+            .map(|node| preview(&tree.nodes[node]))
+            .unwrap_or_default();
+        let title =
+            selected.map_or_else(String::new, |node| format!(" {} ", tree.nodes[node].name));
+        frame.render_widget(
+            Paragraph::new(preview)
+                .block(Block::bordered().title(title))
+                .wrap(Wrap { trim: false }),
+            right,
+        );
+
+        let status = format!(
+            "↑↓ move  ←→ folders  space mark  x extract  q quit   {} marked",
+            self.marked.len()
+        );
+        frame.render_widget(Line::from(status).dim(), help);
+    }
+
+    /// Whether a folder above `node` is marked
+    fn inside_marked(&self, mut node: usize) -> bool {
+        while node != ROOT {
+            node = self.tree.nodes[node].parent;
+            if self.marked.contains(&node) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Text for the preview pane
+fn preview(node: &Node) -> String {
+    match (&node.kind, &node.preview) {
+        (Kind::Dir, _) => format!("{} entries", node.children.len()),
+        (Kind::Symlink(target), _) => format!("symbolic link to {}", target),
+        (Kind::HardLink(target), _) => format!("hard link to {}", target),
+        (Kind::Other, _) => "special file".to_string(),
+        (Kind::File, Some(data)) => match std::str::from_utf8(data) {
+            Ok(text) if !text.contains('\0') => text.to_string(),
+            _ => format!("binary file, {}", HumanBytes(node.size)),
+        },
+        (Kind::File, None) if node.size > PREVIEW_LIMIT => {
+            format!("{}: too large to preview", HumanBytes(node.size))
+        }
+        (Kind::File, None) => format!("{}: no preview kept", HumanBytes(node.size)),
+    }
+}
+
+/// `path` as a glob matching only itself
+fn escape_glob(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '{' | '}' => {
+                out.push('[');
+                out.push(c);
+                out.push(']');
+            }
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
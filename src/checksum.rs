@@ -0,0 +1,313 @@
+//! SHA-256 manifest of file contents, embedded in every archive
+//!
+//! The age MAC only proves the ciphertext wasn't altered; the manifest also catches
+//! damage that happened before encryption or while unpacking (a buggy compressor, a bad
+//! disk under the output folder). It is stored at `MANIFEST_PATH` in `sha256sum` format,
+//! so an extracted copy can be checked with `sha256sum -c` too.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+
+use crate::failure::{Classify, Failure};
+use crate::header::{self, Header};
+use crate::{progress, snapshot};
+
+/// Archive path of the manifest, written after all file entries
+pub const MANIFEST_PATH: &str = ".folder-lock/manifest.sha256";
+
+pub type Hash = [u8; 32];
+
+/// Content hashes keyed by archive path (`docs/a.txt`)
+#[derive(Debug, Default)]
+pub struct Manifest {
+    hashes: BTreeMap<String, Hash>,
+}
+
+impl Manifest {
+    pub fn insert(&mut self, key: String, hash: Hash) {
+        self.hashes.insert(key, hash);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Hash> {
+        self.hashes.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// `sha256sum` lines, sorted by path
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.hashes
+            .iter()
+            .map(|(key, hash)| line(key, hash))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Read `sha256sum` lines in any order
+    pub fn parse(r: &mut impl Read) -> Result<Self> {
+        let mut text = String::new();
+        r.read_to_string(&mut text)?;
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (digest, name) = line
+                .split_once("  ")
+                .ok_or_else(|| anyhow::anyhow!("malformed manifest line '{}'", line))?;
+            let hash = unhex(digest)
+                .ok_or_else(|| anyhow::anyhow!("malformed hash in manifest line '{}'", line))?;
+            let name = if escaped {
+                unescape(name)
+            } else {
+                name.to_string()
+            };
+            manifest.insert(name, hash);
+        }
+        Ok(manifest)
+    }
+}
+
+/// One `sha256sum` line for `key`, newline included; names with `\` or newlines use its
+/// escaped form
+pub fn line(key: &str, hash: &Hash) -> String {
+    if key.contains(['\\', '\n']) {
+        let escaped = key.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}\n", hex(hash), escaped)
+    } else {
+        format!("{}  {}\n", hex(hash), key)
+    }
+}
+
+/// Hashes everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> Hash {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Hashes everything written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> (W, Hash) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hash the remaining contents of `r`
+pub fn hash_reader(r: impl Read) -> io::Result<Hash> {
+    let mut reader = HashingReader::new(r);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+pub fn hash_file(path: &Path) -> io::Result<Hash> {
+    hash_reader(File::open(path)?)
+}
+
+pub fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Hash> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Compare the hashes computed while reading an archive against its manifest
+///
+/// Every mismatch is an error. When `complete` is set, `actual` covers the whole archive
+/// and manifest entries missing from it are errors too.
+pub fn compare(manifest: &Manifest, actual: &BTreeMap<String, Hash>, complete: bool) -> Result<()> {
+    let mut problems = Vec::new();
+    for (key, hash) in actual {
+        match manifest.get(key) {
+            Some(expected) if expected == hash => {}
+            Some(expected) => problems.push(format!(
+                "'{}': expected sha256 {}, got {}",
+                key,
+                hex(expected),
+                hex(hash)
+            )),
+            None => log::warn!("'{}' is not listed in the checksum manifest", key),
+        }
+    }
+    for key in manifest.hashes.keys() {
+        if complete && !actual.contains_key(key) {
+            problems.push(format!("'{}' is listed in the manifest but missing", key));
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "checksum verification failed for {} file(s):\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Whether entries of this type carry file contents covered by the manifest
+pub fn has_contents(kind: tar::EntryType) -> bool {
+    kind.is_file() || kind.is_gnu_sparse()
+}
+
+/// Counts from a successful `check_archive`
+pub struct Checked {
+    /// Entries other than the manifest and header
+    pub entries: u64,
+    /// Non-directory entries
+    pub files: u64,
+    /// File content bytes
+    pub bytes: u64,
+}
+
+/// Read every entry to the end and compare file contents against the manifest, as `verify`
+/// does
+pub fn check_archive(
+    mut archive: tar::Archive<Box<dyn Read>>,
+    bar: &ProgressBar,
+) -> Result<Checked> {
+    let mut entries = 0u64;
+    let mut files = 0u64;
+    let mut bytes = 0u64;
+    let mut manifest = None;
+    let mut hashes = BTreeMap::new();
+    for entry in archive
+        .entries()
+        .classify(Failure::Corrupted, "archive is corrupted")?
+    {
+        let mut entry = entry.classify(
+            Failure::Corrupted,
+            "archive is corrupted: unreadable entry header",
+        )?;
+        let path = entry
+            .path()
+            .context("invalid path in archive")?
+            .into_owned();
+        let key = snapshot::key(&path);
+        if key == MANIFEST_PATH {
+            manifest = Some(Manifest::parse(&mut entry).classify(
+                Failure::Corrupted,
+                "archive is corrupted: unreadable checksum manifest",
+            )?);
+            continue;
+        }
+        if key == header::HEADER_PATH {
+            Header::read(&mut entry)?;
+            continue;
+        }
+        // Reading every byte forces gzip CRC and age MAC checks on the whole stream
+        let mut reader = HashingReader::new(&mut entry);
+        bytes += io::copy(&mut reader, &mut io::sink()).classify(
+            Failure::Corrupted,
+            format!("archive is corrupted at '{}'", path.display()),
+        )?;
+        let hash = reader.finish();
+        if has_contents(entry.header().entry_type()) {
+            hashes.insert(key, hash);
+        }
+        entries += 1;
+        if entry.header().entry_type() != tar::EntryType::Directory {
+            files += 1;
+        }
+        progress::set_files(bar, entries, "checked", &path);
+    }
+
+    // Drain anything after the tar end marker so truncation past it is caught too
+    io::copy(&mut archive.into_inner(), &mut io::sink()).classify(
+        Failure::Corrupted,
+        "archive is corrupted: trailing data failed to decrypt",
+    )?;
+
+    match &manifest {
+        Some(manifest) => {
+            compare(manifest, &hashes, true)
+                .classify(Failure::Corrupted, "archive is corrupted")?;
+            log::debug!("{} files match the checksum manifest", manifest.len());
+        }
+        None => log::warn!("archive has no checksum manifest; only the encryption was verified"),
+    }
+    Ok(Checked {
+        entries,
+        files,
+        bytes,
+    })
+}
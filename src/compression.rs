@@ -0,0 +1,302 @@
+//! Compression of the tar stream inside the age envelope
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+
+use crate::streams::CountingWriter;
+
+/// Inner stream compression; the algorithm is detected from magic bytes on decrypt
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    /// gzip (the original format, readable by any `tar`)
+    #[default]
+    Gzip,
+    /// Zstandard, faster and smaller than gzip for most backups
+    Zstd,
+    /// xz/LZMA2, slowest but smallest; suited to cold storage
+    Xz,
+    /// No compression: tar is written straight into the age envelope
+    #[value(name = "none", alias = "store")]
+    Store,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+/// Default gzip level (same as `gzip` and `Compression::default()`)
+const GZIP_DEFAULT_LEVEL: i32 = 6;
+/// Default zstd level when none is given (zstd's own default)
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+/// Default xz preset (same as the `xz` command)
+const XZ_DEFAULT_LEVEL: i32 = 6;
+
+impl Algorithm {
+    /// Name as given to `--compression`
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Zstd => "zstd",
+            Algorithm::Xz => "xz",
+            Algorithm::Store => "none",
+        }
+    }
+
+    /// Valid `--level` values for this algorithm
+    pub fn levels(self) -> RangeInclusive<i32> {
+        match self {
+            Algorithm::Gzip => 0..=9,
+            Algorithm::Zstd => 1..=22,
+            Algorithm::Xz => 0..=9,
+            Algorithm::Store => 0..=0,
+        }
+    }
+
+    /// Reject levels outside the algorithm's range before any work starts
+    pub fn check_level(self, level: Option<i32>) -> anyhow::Result<()> {
+        match level {
+            Some(_) if self == Algorithm::Store => {
+                anyhow::bail!("--level has no effect without compression")
+            }
+            Some(level) if !self.levels().contains(&level) => anyhow::bail!(
+                "compression level {} is out of range for {:?} ({}-{})",
+                level,
+                self,
+                self.levels().start(),
+                self.levels().end()
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Everything needed to build an encoder for a new archive
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub algorithm: Algorithm,
+    /// Must be within `algorithm.levels()`; `None` picks the algorithm's default
+    pub level: Option<i32>,
+    /// Worker threads for zstd and xz; gzip and store are always single-threaded
+    pub threads: u32,
+}
+
+impl Settings {
+    /// `threads` of `None` uses one worker per available core
+    pub fn new(
+        algorithm: Algorithm,
+        level: Option<i32>,
+        threads: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        algorithm.check_level(level)?;
+        let threads = match threads {
+            Some(0) => anyhow::bail!("--threads must be at least 1"),
+            Some(n) => n,
+            None => default_threads(),
+        };
+        Ok(Self {
+            algorithm,
+            level,
+            threads,
+        })
+    }
+
+    /// Rough upper bound on what one archive's encoder and pipeline buffers take, for
+    /// sizing parallel `batch` jobs
+    ///
+    /// The xz figures are the compressor's from `xz --help`; zstd's grow with its window.
+    pub fn memory(&self) -> u64 {
+        const MIB: u64 = 1 << 20;
+        let per_worker = match self.algorithm {
+            Algorithm::Gzip | Algorithm::Store => 0,
+            Algorithm::Zstd => match self.level.unwrap_or(ZSTD_DEFAULT_LEVEL) {
+                ..=3 => 16 * MIB,
+                4..=9 => 64 * MIB,
+                10..=19 => 256 * MIB,
+                _ => 1024 * MIB,
+            },
+            Algorithm::Xz => match self.level.unwrap_or(XZ_DEFAULT_LEVEL) {
+                ..=0 => 3 * MIB,
+                1 => 9 * MIB,
+                2 => 17 * MIB,
+                3 => 32 * MIB,
+                4 => 48 * MIB,
+                5 | 6 => 94 * MIB,
+                7 => 186 * MIB,
+                8 => 370 * MIB,
+                _ => 674 * MIB,
+            },
+        };
+        // Buffers between the walker, tar, the encoder and age
+        8 * MIB + per_worker * u64::from(self.threads.max(1))
+    }
+}
+
+/// gzip at its default level, with one worker per core for when the algorithm changes
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::default(),
+            level: None,
+            threads: default_threads(),
+        }
+    }
+}
+
+fn default_threads() -> u32 {
+    std::thread::available_parallelism().map_or(1, |n| n.get() as u32)
+}
+
+/// A compressing writer for any supported algorithm
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Xz(xz2::write::XzEncoder<W>),
+    Store(W),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(settings: &Settings, w: W) -> io::Result<Self> {
+        let level = settings.level;
+        Ok(match settings.algorithm {
+            Algorithm::Gzip => Encoder::Gzip(GzEncoder::new(
+                w,
+                flate2::Compression::new(level.unwrap_or(GZIP_DEFAULT_LEVEL) as u32),
+            )),
+            Algorithm::Zstd => {
+                let mut e =
+                    zstd::stream::write::Encoder::new(w, level.unwrap_or(ZSTD_DEFAULT_LEVEL))?;
+                #[cfg(not(target_arch = "wasm32"))]
+                if settings.threads > 1 {
+                    e.multithread(settings.threads)?;
+                }
+                Encoder::Zstd(e)
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Algorithm::Xz => {
+                let preset = level.unwrap_or(XZ_DEFAULT_LEVEL) as u32;
+                let stream = if settings.threads > 1 {
+                    xz2::stream::MtStreamBuilder::new()
+                        .preset(preset)
+                        .threads(settings.threads)
+                        .encoder()?
+                } else {
+                    xz2::stream::Stream::new_easy_encoder(preset, xz2::stream::Check::Crc64)?
+                };
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(w, stream))
+            }
+            #[cfg(target_arch = "wasm32")]
+            Algorithm::Xz => return Err(xz_unsupported()),
+            Algorithm::Store => Encoder::Store(w),
+        })
+    }
+
+    /// Write any trailer and return the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Store(mut w) => w.flush().map(|_| w),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Store(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Store(w) => w.flush(),
+        }
+    }
+}
+
+/// Bytes read from the start of each file by `estimate`
+const SAMPLE_PER_FILE: u64 = 256 * 1024;
+/// `estimate` stops reading once this much has been sampled
+const SAMPLE_TOTAL: u64 = 32 * 1024 * 1024;
+
+/// Guess the compressed size of a `total`-byte stream made of `files`
+///
+/// The start of each file is compressed as one stream, like the real archive, and the
+/// resulting ratio is applied to `total`. Unreadable files are left out of the sample.
+pub fn estimate(settings: &Settings, files: &[PathBuf], total: u64) -> io::Result<u64> {
+    if settings.algorithm == Algorithm::Store {
+        return Ok(total);
+    }
+    let mut encoder = Encoder::new(settings, CountingWriter::new(io::sink()))?;
+    let mut sampled = 0;
+    for path in files {
+        if sampled >= SAMPLE_TOTAL {
+            break;
+        }
+        let Ok(f) = File::open(path) else {
+            continue;
+        };
+        let limit = SAMPLE_PER_FILE.min(SAMPLE_TOTAL - sampled);
+        sampled += io::copy(&mut f.take(limit), &mut encoder)?;
+    }
+    let compressed = encoder.finish()?.count();
+    if sampled == 0 {
+        return Ok(total);
+    }
+    Ok((total as f64 * compressed as f64 / sampled as f64) as u64)
+}
+
+/// The algorithm a stream starting with `head` was compressed with, from its magic bytes
+///
+/// Anything without a known magic is taken as uncompressed.
+pub fn detect(head: &[u8]) -> Algorithm {
+    if head.starts_with(GZIP_MAGIC) {
+        Algorithm::Gzip
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Algorithm::Zstd
+    } else if head.starts_with(XZ_MAGIC) {
+        Algorithm::Xz
+    } else {
+        Algorithm::Store
+    }
+}
+
+/// Sniff the stream's magic bytes and wrap it in the matching decoder
+///
+/// Streams without a known compression magic are read as an uncompressed tar.
+pub fn decoder<R: BufRead + 'static>(mut r: R) -> io::Result<Box<dyn Read>> {
+    Ok(match detect(r.fill_buf()?) {
+        Algorithm::Gzip => Box::new(flate2::bufread::GzDecoder::new(r)),
+        Algorithm::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(r)?),
+        #[cfg(not(target_arch = "wasm32"))]
+        Algorithm::Xz => Box::new(xz2::bufread::XzDecoder::new(r)),
+        #[cfg(target_arch = "wasm32")]
+        Algorithm::Xz => return Err(xz_unsupported()),
+        Algorithm::Store => Box::new(r),
+    })
+}
+
+/// liblzma isn't built for WebAssembly
+#[cfg(target_arch = "wasm32")]
+fn xz_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "xz is not available in the WebAssembly build",
+    )
+}
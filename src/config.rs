@@ -0,0 +1,198 @@
+//! Defaults from `~/.config/folder_lock/config.toml`, optionally overlaid by a profile
+//!
+//! ```toml
+//! [defaults]
+//! compression = "zstd"
+//! excludes = ["node_modules", "*.tmp"]
+//!
+//! [profiles.work]
+//! recipients = ["age1..."]
+//! recipient_files = ["~/.config/folder_lock/team.txt"]
+//! output = "/mnt/backup/{folder}-{date}.age"
+//! post_hook = "rclone copy \"$FOLDER_LOCK_ARCHIVE\" remote:backups"
+//! ```
+//!
+//! Command-line flags always win. Excludes add up; recipients from the command line (or a
+//! passphrase flag) replace the configured ones.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+
+use folder_lock::Algorithm;
+
+/// Which config file and profile to apply
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// Apply this `[profiles.NAME]` section of the config file on top of its defaults
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Config file to read instead of ~/.config/folder_lock/config.toml
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+/// Settings that may appear under `[defaults]` or a profile
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub compression: Option<String>,
+    pub level: Option<i32>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub recipient_files: Vec<PathBuf>,
+    /// Output path template used when `encrypt` gets no OUT; see `output_path`
+    pub output: Option<String>,
+    /// Commands run before and after `encrypt`; see `hooks`
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+}
+
+/// Default location, following `$XDG_CONFIG_HOME`
+fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("folder_lock").join("config.toml"))
+}
+
+impl ConfigArgs {
+    /// The effective settings; a missing default file just means no defaults
+    pub fn load(&self) -> Result<Settings> {
+        let path = match (&self.config, default_path()) {
+            (Some(path), _) => Some(path.clone()),
+            (None, Some(path)) if path.is_file() => Some(path),
+            _ => None,
+        };
+        let file: File = match &path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => File::default(),
+        };
+        let mut settings = file.defaults;
+        if let Some(name) = &self.profile {
+            let profile = file.profiles.get(name).with_context(|| match &path {
+                Some(path) => format!("no profile '{}' in {}", name, path.display()),
+                None => format!("no profile '{}': there is no config file", name),
+            })?;
+            settings.overlay(profile.clone());
+        }
+        if let Some(path) = &path {
+            log::debug!(
+                "using config {} (profile: {:?})",
+                path.display(),
+                self.profile
+            );
+        }
+        Ok(settings)
+    }
+}
+
+impl Settings {
+    /// Apply a profile: set values replace the defaults, excludes are added
+    fn overlay(&mut self, profile: Settings) {
+        if profile.compression.is_some() {
+            self.compression = profile.compression;
+            // A level only makes sense for the algorithm it was written for
+            self.level = None;
+        }
+        if profile.level.is_some() {
+            self.level = profile.level;
+        }
+        self.excludes.extend(profile.excludes);
+        if !profile.recipients.is_empty() || !profile.recipient_files.is_empty() {
+            self.recipients = profile.recipients;
+            self.recipient_files = profile.recipient_files;
+        }
+        if profile.output.is_some() {
+            self.output = profile.output;
+        }
+        if profile.pre_hook.is_some() {
+            self.pre_hook = profile.pre_hook;
+        }
+        if profile.post_hook.is_some() {
+            self.post_hook = profile.post_hook;
+        }
+    }
+
+    /// Algorithm and level, with the command line's winning
+    ///
+    /// The configured level is dropped when the command line picks the algorithm.
+    pub fn compression(
+        &self,
+        algorithm: Option<Algorithm>,
+        level: Option<i32>,
+    ) -> Result<(Algorithm, Option<i32>)> {
+        if let Some(algorithm) = algorithm {
+            return Ok((algorithm, level));
+        }
+        let algorithm = match self.compression.as_deref() {
+            Some(name) => Algorithm::from_str(name, true).map_err(|_| {
+                anyhow::anyhow!(
+                    "unknown compression '{}' in config file (gzip, zstd, xz, none)",
+                    name
+                )
+            })?,
+            None => Algorithm::default(),
+        };
+        Ok((algorithm, level.or(self.level)))
+    }
+
+    /// Add the configured recipients; meant for when the command line names no key
+    pub fn add_recipients(&self, recipients: &mut Vec<String>, files: &mut Vec<PathBuf>) {
+        recipients.extend(self.recipients.iter().cloned());
+        files.extend(self.recipient_files.iter().map(|p| expand_home(p)));
+    }
+
+    /// OUT for `encrypt FOLDER` from the `output` template
+    ///
+    /// `{folder}` is the folder's name, `{date}` today's UTC date (`2024-05-31`) and
+    /// `{timestamp}` the UTC time to the second (`20240531T142501Z`), so repeated runs
+    /// don't collide.
+    pub fn output_path(&self, folder: &Path) -> Result<PathBuf> {
+        let template = self
+            .output
+            .as_deref()
+            .context("no OUT given and no `output` template in the config file")?;
+        let name = folder
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "archive".to_string());
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let timestamp: String = now.chars().filter(|c| !matches!(c, '-' | ':')).collect();
+        let path = template
+            .replace("{folder}", &name)
+            .replace("{date}", &now[..10])
+            .replace("{timestamp}", &timestamp);
+        Ok(expand_home(Path::new(&path)))
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
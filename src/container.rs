@@ -0,0 +1,264 @@
+//! Which container holds the files inside the age encryption
+//!
+//! Tar is the default and stores everything `PackOptions` can record. Zip trades that for
+//! reach: after a plain `age -d`, it opens with the tools built into Windows and macOS.
+//! Each file is deflated on its own, so `--compression` doesn't apply. Zip has no room for
+//! owners, extended attributes, sparse files or hard links (linked files are stored once
+//! per name), and incremental archives need tar. The zip is written as a stream, with
+//! sizes after each entry's data, because the age writer can't seek.
+//!
+//! Readers take either: a zip is converted to a tar stream on the fly (`zip_as_tar`), so
+//! every subcommand works on it. Zip's directory sits at the end, so the decrypted zip is
+//! staged in an unlinked temporary file first; that puts plaintext on the temp directory's
+//! disk for as long as the conversion runs.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use zip::write::{SimpleFileOptions, ZipWriter};
+use zip::{CompressionMethod, DateTime, ZipArchive};
+
+use crate::checksum::{self, HashingReader};
+use crate::header::{Header, HEADER_PATH};
+use crate::pack::{self, ManifestSpool, PackOptions, Sources};
+use crate::progress;
+use crate::report::Stats;
+use crate::snapshot;
+use crate::streams;
+use crate::walk;
+
+/// Local file header signature every zip starts with
+pub const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Container {
+    /// Compressed tar stream, recording everything `PackOptions` asks for
+    #[default]
+    Tar,
+    /// Zip file, for opening with built-in tools after `age -d`
+    Zip,
+}
+
+/// The container a decrypted (and decompressed) stream starting with `head` holds
+///
+/// Tar has no magic number, so a header block is recognised by its checksum; an all-zero
+/// block is the end of an empty tar. `None` means neither, e.g. a `--raw` file. Shorter
+/// heads than one block can't be judged and count as tar.
+pub fn detect(head: &[u8]) -> Option<Container> {
+    if head.starts_with(ZIP_MAGIC) {
+        return Some(Container::Zip);
+    }
+    let Some(block) = head.get(..512) else {
+        return Some(Container::Tar);
+    };
+    if block.iter().all(|&b| b == 0) {
+        return Some(Container::Tar);
+    }
+    // The checksum field is summed as if it held spaces
+    let sum: u32 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                u32::from(b' ')
+            } else {
+                u32::from(b)
+            }
+        })
+        .sum();
+    let stored = std::str::from_utf8(&block[148..156])
+        .ok()
+        .and_then(|field| u32::from_str_radix(field.trim_matches([' ', '\0']), 8).ok());
+    (stored == Some(sum)).then_some(Container::Tar)
+}
+
+impl Container {
+    pub fn name(self) -> &'static str {
+        match self {
+            Container::Tar => "tar",
+            Container::Zip => "zip",
+        }
+    }
+}
+
+/// Write `sources` to `w` as a zip file, between `header` and the checksum manifest
+///
+/// `bar` advances by the file bytes read, before compression.
+pub fn write_zip<W: Write>(
+    w: W,
+    sources: &Sources,
+    options: &PackOptions,
+    header: &Header,
+    bar: &ProgressBar,
+) -> Result<Stats> {
+    if options.base.is_some() {
+        anyhow::bail!("incremental archives need the tar container");
+    }
+    let mut zip = ZipWriter::new_stream(w);
+    let mtime = pack::metadata_mtime(options);
+    zip.start_file(HEADER_PATH, entry_options(0o644, mtime))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(zip.write_all(&header.to_json())?))
+        .context("failed to add header to zip archive")?;
+    let mut manifest = ManifestSpool::new().context("failed to create the manifest spool")?;
+    let mut stats = Stats::default();
+    sources.visit(&options.filters, |entry| {
+        log::debug!("adding {}", entry.rel.display());
+        let bytes = append_entry(&mut zip, &entry, options, &mut manifest, bar)
+            .with_context(|| format!("failed to add '{}' to zip archive", entry.path.display()))?;
+        if !entry.is_dir {
+            stats.files += 1;
+            stats.bytes += bytes;
+            progress::set_files(bar, stats.files, "added", &entry.rel);
+        }
+        Ok(())
+    })?;
+
+    let contents = manifest.contents()?;
+    zip.start_file(checksum::MANIFEST_PATH, entry_options(0o644, mtime))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(io::copy(contents, &mut zip)?))
+        .context("failed to add checksum manifest to zip archive")?;
+    zip.finish().context("failed to finalize zip archive")?;
+    Ok(stats)
+}
+
+/// Add one walked entry, returning the number of content bytes stored
+fn append_entry<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    entry: &walk::Entry,
+    options: &PackOptions,
+    manifest: &mut ManifestSpool,
+    bar: &ProgressBar,
+) -> Result<u64> {
+    let name = snapshot::key(&entry.rel);
+    let meta = if entry.is_symlink {
+        std::fs::symlink_metadata(&entry.path)?
+    } else {
+        std::fs::metadata(&entry.path)?
+    };
+    let header = pack::header_for(&meta, options);
+    let file_options = entry_options(header.mode().unwrap_or(0o644), header.mtime().unwrap_or(0));
+    if entry.is_symlink {
+        let target = std::fs::read_link(&entry.path)?;
+        zip.add_symlink(name, target.to_string_lossy(), file_options)?;
+        return Ok(0);
+    }
+    if entry.is_dir {
+        zip.add_directory(format!("{}/", name), file_options)?;
+        return Ok(0);
+    }
+    let mut reader = HashingReader::new(bar.wrap_read(File::open(&entry.path)?));
+    zip.start_file(
+        name.as_str(),
+        file_options.large_file(meta.len() >= u64::from(u32::MAX)),
+    )?;
+    let bytes = io::copy(&mut reader, zip)?;
+    manifest.insert(&name, &reader.finish())?;
+    Ok(bytes)
+}
+
+fn entry_options(mode: u32, mtime: u64) -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(mode)
+        .last_modified_time(dos_time(mtime))
+}
+
+/// A Unix time as zip's MS-DOS timestamp (UTC, 2-second steps, 1980 to 2107)
+///
+/// Times outside that range become 1980-01-01.
+fn dos_time(secs: u64) -> DateTime {
+    let text = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs));
+    parse_dos_time(&text.to_string()).unwrap_or_default()
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` as a zip timestamp
+fn parse_dos_time(text: &str) -> Option<DateTime> {
+    let field = |range: Range<usize>| text.get(range)?.parse::<u8>().ok();
+    let year = text.get(0..4)?.parse().ok()?;
+    DateTime::from_date_and_time(
+        year,
+        field(5..7)?,
+        field(8..10)?,
+        field(11..13)?,
+        field(14..16)?,
+        field(17..19)?,
+    )
+    .ok()
+}
+
+/// Read the zip in `r` as the tar stream `write_zip` would have made for the same files
+///
+/// The zip is staged in a temporary file, then converted by a background thread (see
+/// `streams::tar_from`). Entries whose names would leave the output
+/// folder are skipped with a warning, as tar extraction does.
+pub fn zip_as_tar(mut r: impl Read) -> Result<Box<dyn Read>> {
+    let (mut file, path) = pack::spool_file("zip")?;
+    log::debug!("staging the decrypted zip in a temporary file");
+    io::copy(&mut r, &mut file).context("failed to stage the decrypted zip")?;
+    file.rewind()?;
+    let zip = ZipArchive::new(file).context("failed to read zip directory")?;
+
+    Ok(streams::tar_from(move |tar| {
+        let _path = path;
+        append_zip(zip, tar)
+    }))
+}
+
+fn append_zip<W: Write>(mut zip: ZipArchive<File>, tar: &mut tar::Builder<W>) -> Result<()> {
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i).context("failed to read zip entry")?;
+        let Some(path) = file.enclosed_name() else {
+            log::warn!("skipping unsafe zip entry '{}'", file.name());
+            continue;
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(file.last_modified().map_or(0, unix_time));
+        let mode = file.unix_mode().map(|mode| mode & 0o7777);
+        if file.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(mode.unwrap_or(0o755));
+            header.set_size(0);
+            tar.append_data(&mut header, &path, io::empty())?;
+        } else if file.is_symlink() {
+            let mut target = String::new();
+            file.read_to_string(&mut target)
+                .with_context(|| format!("failed to read link '{}'", file.name()))?;
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_mode(mode.unwrap_or(0o777));
+            header.set_size(0);
+            tar.append_link(&mut header, &path, Path::new(&target))?;
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(mode.unwrap_or(0o644));
+            header.set_size(file.size());
+            tar.append_data(&mut header, &path, &mut file)
+                .with_context(|| format!("failed to read '{}' from the zip", file.name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Inverse of `dos_time`, for a zip entry's timestamp (taken as UTC)
+fn unix_time(time: DateTime) -> u64 {
+    // Days since 1970-01-01 of the civil date, after Howard Hinnant's `days_from_civil`
+    let (month, day) = (i64::from(time.month()), i64::from(time.day()));
+    let year = i64::from(time.year()) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    secs.max(0) as u64
+}
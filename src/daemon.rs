@@ -0,0 +1,363 @@
+//! `daemon`: run encrypt and decrypt jobs for other programs over a Unix socket
+//!
+//! Each connection sends one JSON request per line and gets one JSON response per line:
+//!
+//! - `{"op": "set-passphrase", "passphrase": "…"}` caches a passphrase in the agent (see
+//!   `agent`) for later jobs, so a front-end asks the user once; `{"op": "forget"}` wipes
+//!   it. The daemon must run with `$FOLDER_LOCK_AGENT_SOCK` set, like any other command
+//! - `{"op": "encrypt", "sources": ["…"], "out": "…"}` starts a job, to `"recipients"` if
+//!   given and the cached passphrase otherwise; `"force": true` replaces OUT
+//! - `{"op": "decrypt", "input": "…", "out": "…"}` restores into the existing folder OUT,
+//!   with `"identities"` (files) or the cached passphrase
+//! - `{"op": "status", "job": N}` and `{"op": "list"}` report `state` (`running`, `done`,
+//!   `failed`, `cancelled`), `bytes`, `total_bytes`, `files` and any `error`; a finished
+//!   job is reported once and then forgotten, or after `JOB_TTL` if nobody asks
+//! - `{"op": "cancel", "job": N}` stops a running job; its output is never left behind
+//!
+//! Responses carry `"ok": true` or `"ok": false` with an `"error"`. Only processes of the
+//! user running the daemon can connect (see `socket`).
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+use folder_lock::agent;
+use folder_lock::extract::ExtractOptions;
+use folder_lock::keys::{parse_recipients, read_identities};
+use folder_lock::pack::{PackOptions, Sources};
+use folder_lock::{progress, socket};
+use folder_lock::{streams, Locker, Unlocker};
+
+/// How long a finished job is kept for a `status` or `list` that hasn't come yet
+const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    SetPassphrase {
+        #[serde(deserialize_with = "secret")]
+        passphrase: SecretString,
+    },
+    Forget,
+    Encrypt {
+        sources: Vec<PathBuf>,
+        out: PathBuf,
+        #[serde(default)]
+        recipients: Vec<String>,
+        #[serde(default)]
+        force: bool,
+    },
+    Decrypt {
+        input: PathBuf,
+        out: PathBuf,
+        #[serde(default)]
+        identities: Vec<PathBuf>,
+    },
+    Status {
+        job: u64,
+    },
+    List,
+    Cancel {
+        job: u64,
+    },
+}
+
+/// A string straight into a `SecretString`, so no other copy of it is left behind
+fn secret<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SecretString, D::Error> {
+    String::deserialize(deserializer).map(SecretString::new)
+}
+
+/// A job's progress, shared with the thread running it
+struct Job {
+    command: &'static str,
+    bar: ProgressBar,
+    /// Files added or restored so far
+    files: Arc<AtomicU64>,
+    cancel: Arc<AtomicBool>,
+    /// `None` while running, then the outcome and when it came
+    outcome: Arc<Mutex<Option<(Result<(), String>, Instant)>>>,
+}
+
+impl Job {
+    /// Finished before `since`; any finished job without `since`
+    fn finished(&self, since: Option<Instant>) -> bool {
+        match &*lock(&self.outcome) {
+            Some((_, at)) => since.map_or(true, |since| *at < since),
+            None => false,
+        }
+    }
+
+    fn status(&self, id: u64) -> Value {
+        let outcome = lock(&self.outcome);
+        let (state, error) = match &*outcome {
+            None => ("running", None),
+            Some((Ok(()), _)) => ("done", None),
+            Some((Err(_), _)) if self.cancel.load(Ordering::Relaxed) => ("cancelled", None),
+            Some((Err(e), _)) => ("failed", Some(e.clone())),
+        };
+        json!({
+            "job": id,
+            "command": self.command,
+            "state": state,
+            "bytes": self.bar.position(),
+            "total_bytes": self.bar.length(),
+            "files": self.files.load(Ordering::Relaxed),
+            "error": error,
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+}
+
+/// Default socket: `folder_lock.sock` in this user's `socket::runtime_dir`
+pub fn default_socket() -> Result<PathBuf> {
+    Ok(socket::runtime_dir()?.join("folder_lock.sock"))
+}
+
+/// Listen on `path` until killed
+pub fn serve(path: &Path) -> Result<()> {
+    let listener = socket::listen(path, "a daemon")?;
+    log::info!("Listening on '{}'", path.display());
+
+    let state = Arc::new(State::default());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        if !socket::peer_is_self(&stream) {
+            log::warn!("refused a connection from another user");
+            continue;
+        }
+        let state = state.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream, &state) {
+                log::debug!("connection closed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(stream: UnixStream, state: &Arc<State>) -> Result<()> {
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    // Requests may carry a passphrase: wipe each one once it's parsed
+    let mut line = Zeroizing::new(String::new());
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => respond(request, state),
+            Err(e) => Err(anyhow::anyhow!("invalid request: {}", e)),
+        };
+        let response = match response {
+            Ok(Value::Object(mut fields)) => {
+                fields.insert("ok".to_owned(), Value::Bool(true));
+                Value::Object(fields)
+            }
+            Ok(value) => json!({ "ok": true, "result": value }),
+            Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+        };
+        writeln!(out, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn respond(request: Request, state: &Arc<State>) -> Result<Value> {
+    match request {
+        Request::SetPassphrase { passphrase } => {
+            if passphrase.expose_secret().is_empty() {
+                anyhow::bail!("empty passphrase is not allowed");
+            }
+            agent::put(agent::PASSPHRASE, &passphrase);
+            if agent::get(agent::PASSPHRASE).is_none() {
+                anyhow::bail!(
+                    "no agent to keep the passphrase; start the daemon with {} set",
+                    agent::SOCKET_ENV
+                );
+            }
+            Ok(json!({}))
+        }
+        Request::Forget => {
+            agent::forget(agent::PASSPHRASE);
+            Ok(json!({}))
+        }
+        Request::Encrypt {
+            sources,
+            out,
+            recipients,
+            force,
+        } => {
+            let locker = Locker::with_paths(sources.clone());
+            let locker = if recipients.is_empty() {
+                locker.passphrase(cached()?)
+            } else {
+                locker.recipients(parse_recipients(&recipients)?)
+            };
+            streams::check_output(&out, force)?;
+            Ok(start(state, "encrypt", move |bar, cancel| {
+                let summary = Sources::new(sources)?.scan(&PackOptions::default().filters)?;
+                bar.set_length(summary.tar_bytes);
+                let mut w = Cancellable::new(streams::create_output(&out, force, None)?, cancel);
+                locker.progress(bar.clone()).encrypt_to(&mut w)?;
+                w.inner.flush().context("failed to flush output")?;
+                w.inner.commit()
+            }))
+        }
+        Request::Decrypt {
+            input,
+            out,
+            identities,
+        } => {
+            let passphrase = if identities.is_empty() {
+                Some(cached()?)
+            } else {
+                None
+            };
+            let identities = read_identities(&identities)?;
+            Ok(start(state, "decrypt", move |bar, cancel| {
+                let f = File::open(&input)
+                    .with_context(|| format!("failed to open {}", input.display()))?;
+                bar.set_length(f.metadata()?.len());
+                let r = Cancellable::new(bar.wrap_read(f), cancel);
+                let unlocker = Unlocker::new(r)
+                    .options(ExtractOptions::default())
+                    .progress(bar.clone());
+                let unlocker = match passphrase {
+                    Some(passphrase) => unlocker.passphrase(passphrase),
+                    None => unlocker.identities(identities),
+                };
+                unlocker.extract_to(&out).map(drop)
+            }))
+        }
+        Request::Status { job: id } => {
+            let mut jobs = lock(&state.jobs);
+            let job = jobs.get(&id).with_context(|| format!("no job {}", id))?;
+            let status = job.status(id);
+            if job.finished(None) {
+                jobs.remove(&id);
+            }
+            Ok(status)
+        }
+        Request::List => {
+            let mut jobs = lock(&state.jobs);
+            let list = jobs
+                .iter()
+                .map(|(id, job)| job.status(*id))
+                .collect::<Vec<_>>();
+            jobs.retain(|_, job| !job.finished(None));
+            Ok(json!({ "jobs": list }))
+        }
+        Request::Cancel { job: id } => {
+            let jobs = lock(&state.jobs);
+            let job = jobs.get(&id).with_context(|| format!("no job {}", id))?;
+            job.cancel.store(true, Ordering::Relaxed);
+            Ok(json!({}))
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn cached() -> Result<SecretString> {
+    agent::get(agent::PASSPHRASE).context("no passphrase cached; send set-passphrase first")
+}
+
+/// Run `work` on its own thread as a new job, returning its id
+fn start<F>(state: &Arc<State>, command: &'static str, work: F) -> Value
+where
+    F: FnOnce(&ProgressBar, Arc<AtomicBool>) -> Result<()> + Send + 'static,
+{
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let job = Job {
+        command,
+        bar: ProgressBar::hidden(),
+        files: Arc::new(AtomicU64::new(0)),
+        cancel: Arc::new(AtomicBool::new(false)),
+        outcome: Arc::new(Mutex::new(None)),
+    };
+    let (bar, cancel, outcome) = (job.bar.clone(), job.cancel.clone(), job.outcome.clone());
+    let files = job.files.clone();
+    let mut jobs = lock(&state.jobs);
+    // Finished jobs nobody asked about
+    let expired = Instant::now().checked_sub(JOB_TTL);
+    jobs.retain(|_, job| expired.map_or(true, |expired| !job.finished(Some(expired))));
+    jobs.insert(id, job);
+    drop(jobs);
+    std::thread::spawn(move || {
+        progress::count_files(files);
+        let result = work(&bar, cancel).map_err(|e| format!("{:#}", e));
+        match &result {
+            Ok(()) => log::info!("Job {} ({}) done", id, command),
+            Err(e) => log::warn!("Job {} ({}) failed: {}", id, command, e),
+        }
+        bar.finish();
+        *lock(&outcome) = Some((result, Instant::now()));
+    });
+    json!({ "job": id })
+}
+
+/// Fails reads and writes once its job is cancelled, which aborts the pipeline
+struct Cancellable<T> {
+    inner: T,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<T> Cancellable<T> {
+    fn new(inner: T, cancel: Arc<AtomicBool>) -> Self {
+        Self { inner, cancel }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            // Not `Interrupted`, which `io::copy` would retry
+            return Err(io::Error::other("job cancelled"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for Cancellable<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Cancellable<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,167 @@
+//! Comparing an archive's recorded state against a live folder, or against another archive
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::checksum::{self, Manifest};
+use crate::snapshot::{self, FileState, Snapshot};
+use crate::walk::{self, Filters};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// In the folder but not the archive
+    Added,
+    /// In the archive but no longer in the folder
+    Removed,
+    /// In both, with different contents
+    Modified,
+}
+
+impl Change {
+    /// One-letter marker for text output, as in `git status --short`
+    pub fn marker(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Removed => 'D',
+            Change::Modified => 'M',
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub path: String,
+    pub change: Change,
+}
+
+/// List what differs between `archived` and `folder`, sorted by path
+///
+/// Files are modified when their size or whole-second mtime differs. With `checksum`,
+/// files the manifest covers are compared by SHA-256 instead, so a touched but unchanged
+/// file is not reported (and a same-size, same-mtime edit is). Directories are only
+/// reported when added or removed.
+pub fn diff(
+    archived: &Snapshot,
+    manifest: Option<&Manifest>,
+    folder: &Path,
+    filters: &Filters,
+    checksum: bool,
+) -> Result<Vec<Difference>> {
+    let mut live = BTreeMap::new();
+    walk::walk(folder, filters, |entry| {
+        let meta = if entry.is_symlink {
+            std::fs::symlink_metadata(&entry.path)
+        } else {
+            std::fs::metadata(&entry.path)
+        }
+        .with_context(|| format!("failed to stat '{}'", entry.path.display()))?;
+        live.insert(
+            snapshot::key(&entry.rel),
+            (entry.path, FileState::on_disk(&meta)),
+        );
+        Ok(())
+    })?;
+
+    let mut differences = Vec::new();
+    for (key, archived_state) in &archived.files {
+        let change = match live.get(key) {
+            None => Some(Change::Removed),
+            Some((path, state)) => modified(key, path, archived_state, state, manifest, checksum)?
+                .then_some(Change::Modified),
+        };
+        if let Some(change) = change {
+            differences.push(Difference {
+                path: key.clone(),
+                change,
+            });
+        }
+    }
+    for key in live.keys() {
+        if !archived.files.contains_key(key) {
+            differences.push(Difference {
+                path: key.clone(),
+                change: Change::Added,
+            });
+        }
+    }
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(differences)
+}
+
+/// List what differs from archive `old` to archive `new`, sorted by path
+///
+/// Files are modified when their size differs, or their SHA-256 where both manifests list
+/// the file; without both hashes, also when their whole-second mtime differs. Directories
+/// are only reported when added or removed.
+pub fn diff_archives(
+    old: &Snapshot,
+    old_manifest: Option<&Manifest>,
+    new: &Snapshot,
+    new_manifest: Option<&Manifest>,
+) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    for (key, old_state) in &old.files {
+        let change = match new.files.get(key) {
+            None => Some(Change::Removed),
+            Some(new_state) => {
+                let hashes = old_manifest
+                    .and_then(|m| m.get(key))
+                    .zip(new_manifest.and_then(|m| m.get(key)));
+                let modified = if old_state.is_dir || new_state.is_dir {
+                    old_state.is_dir != new_state.is_dir
+                } else if old_state.size != new_state.size {
+                    true
+                } else if let Some((old_hash, new_hash)) = hashes {
+                    old_hash != new_hash
+                } else {
+                    old_state.mtime != new_state.mtime
+                };
+                modified.then_some(Change::Modified)
+            }
+        };
+        if let Some(change) = change {
+            differences.push(Difference {
+                path: key.clone(),
+                change,
+            });
+        }
+    }
+    for key in new.files.keys() {
+        if !old.files.contains_key(key) {
+            differences.push(Difference {
+                path: key.clone(),
+                change: Change::Added,
+            });
+        }
+    }
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+    differences
+}
+
+fn modified(
+    key: &str,
+    path: &Path,
+    archived: &FileState,
+    live: &FileState,
+    manifest: Option<&Manifest>,
+    checksum: bool,
+) -> Result<bool> {
+    if archived.is_dir || live.is_dir {
+        return Ok(archived.is_dir != live.is_dir);
+    }
+    if archived.size != live.size {
+        return Ok(true);
+    }
+    if checksum {
+        if let Some(expected) = manifest.and_then(|m| m.get(key)) {
+            let hash = checksum::hash_file(path)
+                .with_context(|| format!("failed to read '{}'", path.display()))?;
+            return Ok(&hash != expected);
+        }
+    }
+    Ok(archived.mtime != live.mtime)
+}
@@ -0,0 +1,97 @@
+//! The cleartext age header: what an archive can tell about itself without its key
+
+use std::io::{BufRead, BufReader, Read};
+
+use age::armor::ArmoredReader;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::kdf::PASSPHRASE_TAG;
+use crate::locker::KeyKind;
+
+/// A header is at most a few hundred bytes per recipient; past this it isn't an age file
+const HEADER_LIMIT: u64 = 1024 * 1024;
+
+/// Stanza type written for a passphrase
+const SCRYPT: &str = "scrypt";
+
+/// Columns of a full stanza body line; a shorter line ends the stanza
+const BODY_COLUMNS: usize = 64;
+
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    /// First header line, e.g. `age-encryption.org/v1`
+    pub version: String,
+    /// Wrapped in ASCII armor (`-----BEGIN AGE ENCRYPTED FILE-----`)
+    pub armored: bool,
+    /// Type of every stanza in header order: `X25519`, `scrypt`, `ssh-ed25519`, plugin names
+    pub stanzas: Vec<String>,
+    /// log2 of the scrypt work factor, for archives a passphrase opens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrypt_log_n: Option<u8>,
+}
+
+impl Envelope {
+    /// Which kind of key opens the archive
+    pub fn key_kind(&self) -> KeyKind {
+        if self.stanzas.iter().any(|s| s == SCRYPT) {
+            KeyKind::Passphrase
+        } else if self.stanzas.iter().any(|s| s == PASSPHRASE_TAG) {
+            KeyKind::Either
+        } else {
+            KeyKind::Identities
+        }
+    }
+}
+
+/// Parse the header at the start of `r`, armored or binary
+///
+/// Only the header is read; the encrypted payload after it is left alone.
+pub fn read<R: BufRead>(mut r: R) -> Result<Envelope> {
+    let armored = r
+        .fill_buf()
+        .context("failed to read age header")?
+        .starts_with(b"-----BEGIN");
+    let mut lines = BufReader::new(ArmoredReader::new(r)).take(HEADER_LIMIT);
+    let mut next_line = || -> Result<String> {
+        let mut line = String::new();
+        lines
+            .read_line(&mut line)
+            .context("not an age file: unreadable header")?;
+        if line.pop() != Some('\n') {
+            anyhow::bail!("not an age file: header is truncated");
+        }
+        Ok(line)
+    };
+
+    let version = next_line()?;
+    if !version.starts_with("age-encryption.org/") {
+        anyhow::bail!("not an age file");
+    }
+    let mut stanzas = Vec::new();
+    let mut scrypt_log_n = None;
+    loop {
+        let line = next_line()?;
+        // The MAC line closes the header
+        if line.starts_with("---") {
+            break;
+        }
+        let Some(args) = line.strip_prefix("-> ") else {
+            anyhow::bail!("not an age file: malformed header line '{}'", line);
+        };
+        let mut args = args.split(' ');
+        let kind = args.next().unwrap_or_default().to_string();
+        if kind == SCRYPT || kind == PASSPHRASE_TAG {
+            // `-> scrypt SALT LOG_N`
+            scrypt_log_n = args.nth(1).and_then(|n| n.parse().ok());
+        }
+        while next_line()?.len() >= BODY_COLUMNS {}
+        stanzas.push(kind);
+    }
+    Ok(Envelope {
+        version,
+        armored,
+        stanzas,
+        scrypt_log_n,
+    })
+}
@@ -0,0 +1,926 @@
+//! Unpacking decrypted tar entries into a destination folder
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::ProgressBar;
+
+use crate::checksum::{self, Manifest};
+use crate::failure::{Classify, Failure};
+use crate::header::{self, Header};
+use crate::names::{self, InvalidNames};
+use crate::progress;
+use crate::report::Stats;
+use crate::snapshot::{self, Snapshot};
+
+/// Selects which archive entries to restore; an empty filter selects everything
+#[derive(Clone, Default)]
+pub struct PathFilter {
+    set: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Build a filter from glob patterns; a pattern naming a directory also selects its contents
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        if patterns.is_empty() {
+            return Ok(Self { set: None });
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+            builder
+                .add(Glob::new(pattern).with_context(|| format!("invalid pattern '{}'", pattern))?);
+            builder.add(Glob::new(&format!("{}/**", pattern))?);
+        }
+        Ok(Self {
+            set: Some(builder.build().context("failed to build path filter")?),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_none()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        match &self.set {
+            None => true,
+            Some(set) => set.is_match(normalize(path)),
+        }
+    }
+}
+
+/// What to do when an entry would replace something already in the destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Existing {
+    /// Stop with an error (the default, so nothing is clobbered by accident)
+    #[default]
+    Error,
+    Overwrite,
+    /// Keep the file on disk and move on to the next entry
+    Skip,
+    /// Restore the entry beside the existing file as `name (1).ext`
+    Rename,
+    /// Prompt on the terminal for each conflict; needs an interactive stdin
+    Ask,
+}
+
+/// How `extract` restores entries
+#[derive(Clone, Default)]
+pub struct ExtractOptions {
+    pub filter: PathFilter,
+    pub existing: Existing,
+    /// chown entries to their stored uid/gid (requires root)
+    pub preserve_owner: bool,
+    /// Keep setuid/setgid/sticky bits and skip the umask
+    pub preserve_permissions: bool,
+    /// Restore `SCHILY.xattr.*` extended attributes (Unix only)
+    pub xattrs: bool,
+    /// Skip symlink entries instead of recreating them
+    pub no_symlinks: bool,
+    /// Leading path components dropped from every entry (and hard link target), like
+    /// GNU tar's `--strip-components`; entries with nothing left are skipped
+    pub strip_components: usize,
+    /// What to do with entry names that aren't valid UTF-8
+    pub invalid_names: InvalidNames,
+}
+
+impl ExtractOptions {
+    /// Fail before any work if the requested metadata can't be restored by this process
+    pub fn check_privileges(&self) -> Result<()> {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        #[cfg(unix)]
+        if self.preserve_owner && unsafe { libc::geteuid() } != 0 {
+            anyhow::bail!("--preserve-owner requires running as root");
+        }
+        #[cfg(not(unix))]
+        if self.preserve_owner || self.xattrs {
+            anyhow::bail!("--preserve-owner and --xattrs are only supported on Unix");
+        }
+        Ok(())
+    }
+}
+
+/// Strip `./` components so `./docs/a.txt` and `docs/a.txt` compare equal
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// Make an archive path safe to join onto the destination
+///
+/// Leading `/` and drive prefixes are stripped (flagged via the returned bool so the caller
+/// can warn); any `..` component makes the entry unsafe, since it could climb out.
+fn sanitize(path: &Path) -> std::result::Result<(PathBuf, bool), &'static str> {
+    let mut clean = PathBuf::new();
+    let mut stripped = false;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => stripped = true,
+            Component::CurDir => {}
+            Component::ParentDir => return Err("path contains '..'"),
+            Component::Normal(part) => clean.push(part),
+        }
+    }
+    Ok((clean, stripped))
+}
+
+/// Device names Windows reserves in every directory, with or without an extension
+const RESERVED_NAMES: [&str; 30] = [
+    "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+    "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// `part` changed so Windows can create it, or `None` if it's fine as is
+///
+/// Forbidden characters and trailing dots or spaces (which Windows would silently drop)
+/// become `_`, and reserved device names get a `_` after their stem (`CON.txt` → `CON_.txt`).
+fn windows_name(part: &str) -> Option<String> {
+    let mut name: String = part
+        .chars()
+        .map(|c| {
+            if c < ' ' || "<>:\"|?*".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let keep = name.trim_end_matches(['.', ' ']).len();
+    let dropped = name.len() - keep;
+    name.truncate(keep);
+    name.push_str(&"_".repeat(dropped));
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        name.insert(stem.len(), '_');
+    }
+    (name != part).then_some(name)
+}
+
+/// Where this run restores each archive path, kept distinct
+///
+/// `windows_name` maps several names onto one (`a.`, `a ` and `a_` all become `a_`, and
+/// `CON` becomes `CON_`), so a name already taken by another path of the archive is
+/// numbered like `--on-conflict rename` does (`a_ (1)`). A directory's name carries over
+/// to everything in it.
+#[derive(Default)]
+struct LocalPaths {
+    /// The local path of every archive path and directory seen so far
+    local: HashMap<PathBuf, PathBuf>,
+    /// The archive path each local path was given to
+    taken: HashMap<PathBuf, PathBuf>,
+}
+
+impl LocalPaths {
+    /// `rel` as it can be created here, and whether any component had to change
+    ///
+    /// Only Windows restricts names; elsewhere `rel` is returned untouched.
+    fn get(&mut self, rel: PathBuf) -> (PathBuf, bool) {
+        if !cfg!(windows) {
+            return (rel, false);
+        }
+        let local = self.assign(&rel);
+        let changed = local != rel;
+        (local, changed)
+    }
+
+    fn assign(&mut self, rel: &Path) -> PathBuf {
+        let mut archive = PathBuf::new();
+        let mut local = PathBuf::new();
+        for component in rel.components() {
+            archive.push(component);
+            if let Some(known) = self.local.get(&archive) {
+                local = known.clone();
+                continue;
+            }
+            let mut candidate = match windows_name(&component.as_os_str().to_string_lossy()) {
+                Some(name) => local.join(name),
+                None => local.join(component),
+            };
+            if self.taken.contains_key(&candidate) {
+                candidate = (1..)
+                    .map(|n| numbered(&candidate, n))
+                    .find(|numbered| !self.taken.contains_key(numbered))
+                    .expect("some numbered name is free");
+            }
+            self.taken.insert(candidate.clone(), archive.clone());
+            self.local.insert(archive.clone(), candidate.clone());
+            local = candidate;
+        }
+        local
+    }
+}
+
+/// `rel` without its first `n` components, or `None` if nothing is left
+fn strip(rel: &Path, n: usize) -> Option<PathBuf> {
+    let stripped: PathBuf = rel.components().skip(n).collect();
+    (!stripped.as_os_str().is_empty()).then_some(stripped)
+}
+
+/// Whether a symlink stored at `rel` pointing at `target` would resolve outside the root
+fn link_escapes(rel: &Path, target: &Path) -> bool {
+    // Depth of the directory holding the link, relative to the destination root
+    let mut depth = rel.components().count() as i64 - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return true;
+                }
+            }
+            Component::Prefix(_) | Component::RootDir => return true,
+        }
+    }
+    false
+}
+
+/// Check an entry before it touches the disk; `Err` carries the reason it was refused
+fn check_entry<R: Read>(
+    entry: &tar::Entry<R>,
+    path: &Path,
+) -> std::result::Result<PathBuf, String> {
+    let (rel, stripped) = sanitize(path).map_err(String::from)?;
+    if stripped {
+        log::warn!(
+            "stripped leading '/' from '{}' to keep it inside the output folder",
+            path.display()
+        );
+    }
+
+    let kind = entry.header().entry_type();
+    if rel.as_os_str().is_empty() && kind != tar::EntryType::Directory {
+        return Err("empty path".to_string());
+    }
+    if kind.is_symlink() || kind.is_hard_link() {
+        let target = entry
+            .link_name()
+            .map_err(|e| format!("invalid link target: {}", e))?
+            .ok_or("link entry without a target")?;
+        let escapes = if kind.is_symlink() {
+            link_escapes(&rel, &target)
+        } else {
+            // Hard link targets are archive paths, relative to the root
+            sanitize(&target).map_or(true, |(_, stripped)| stripped)
+        };
+        if escapes {
+            return Err(format!(
+                "link target '{}' points outside the output folder",
+                target.display()
+            ));
+        }
+    }
+    Ok(rel)
+}
+
+/// The entry's path, with `policy` applied if it isn't UTF-8; `None` skips the entry
+fn entry_path<R: Read>(entry: &tar::Entry<R>, policy: InvalidNames) -> Result<Option<PathBuf>> {
+    let bytes = entry.path_bytes();
+    if std::str::from_utf8(&bytes).is_ok() {
+        return Ok(Some(
+            entry
+                .path()
+                .context("invalid path in archive")?
+                .into_owned(),
+        ));
+    }
+    let shown = String::from_utf8_lossy(&bytes);
+    match policy {
+        InvalidNames::Keep => entry
+            .path()
+            .map(|path| Some(path.into_owned()))
+            .with_context(|| {
+                format!(
+                    "'{}' isn't valid UTF-8 (use --invalid-names escape or skip)",
+                    shown
+                )
+            }),
+        InvalidNames::Escape => {
+            let escaped = names::escape(&bytes);
+            log::warn!(
+                "restoring '{}' as '{}': the name isn't valid UTF-8",
+                shown,
+                escaped
+            );
+            Ok(Some(PathBuf::from(escaped)))
+        }
+        InvalidNames::Skip => {
+            log::warn!("skipping '{}': the name isn't valid UTF-8", shown);
+            Ok(None)
+        }
+        InvalidNames::Fail => anyhow::bail!(
+            "'{}' isn't valid UTF-8 (use --invalid-names escape or skip)",
+            shown
+        ),
+    }
+}
+
+/// Create `dest`'s parent directories and confirm they still resolve inside `root`
+///
+/// Catches symlinks (from this archive or already on disk) that would redirect the write.
+fn prepare_parent(root: &Path, dest: &Path) -> Result<()> {
+    let parent = match dest.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("failed to create directory {}", parent.display()))?;
+    let resolved = parent
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", parent.display()))?;
+    if !resolved.starts_with(root) {
+        anyhow::bail!(
+            "'{}' resolves outside the output folder (via a symlink?)",
+            dest.display()
+        );
+    }
+    Ok(())
+}
+
+/// Unpack the entries selected by `options.filter` into `out_folder`
+///
+/// Every entry path is validated first: absolute paths are made relative, and entries
+/// that would escape the folder (`..`, symlinks pointing out, writes through symlinked
+/// directories) are skipped with a warning. Directories are created last, deepest first,
+/// so their permissions and mtimes aren't disturbed by the files written into them (the
+/// same order `tar::Archive::unpack` uses).
+///
+/// Restored files are re-read and checked against the archive's checksum manifest, and
+/// incremental archives also remove the entries their snapshot lists as deleted. An error
+/// after the first file was restored is marked `Failure::Partial`.
+pub fn extract<R: Read>(
+    archive: &mut tar::Archive<R>,
+    out_folder: &Path,
+    options: &ExtractOptions,
+    bar: &ProgressBar,
+) -> Result<Stats> {
+    let mut stats = Stats::default();
+    match extract_into(archive, out_folder, options, bar, &mut stats) {
+        Err(e) if stats.files > 0 => Err(e).classify(
+            Failure::Partial,
+            format!("extraction stopped after restoring {} files", stats.files),
+        ),
+        result => result.map(|()| stats),
+    }
+}
+
+fn extract_into<R: Read>(
+    archive: &mut tar::Archive<R>,
+    out_folder: &Path,
+    options: &ExtractOptions,
+    bar: &ProgressBar,
+    stats: &mut Stats,
+) -> Result<()> {
+    let filter = &options.filter;
+    archive.set_preserve_ownerships(options.preserve_owner);
+    archive.set_preserve_permissions(options.preserve_permissions);
+    archive.set_unpack_xattrs(options.xattrs);
+    let root = out_folder
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", out_folder.display()))?;
+    let mut extracted = 0;
+    let mut existing = options.existing;
+    // Archive paths restored under another name, so hard links to them still resolve
+    let mut renamed = HashMap::new();
+    let mut local_paths = LocalPaths::default();
+    let mut directories = Vec::new();
+    let mut deleted = None;
+    let mut manifest = None;
+    // Files written by this run, to check against the manifest once everything is on disk
+    let mut written = Vec::new();
+    for entry in archive
+        .entries()
+        .classify(Failure::Corrupted, "failed to read archive entries")?
+    {
+        let mut entry = entry.classify(Failure::Corrupted, "failed to read archive entry")?;
+        // Manifest and snapshot keys are the lossy form of the stored name, however it's restored
+        let key = snapshot::key(Path::new(&*String::from_utf8_lossy(&entry.path_bytes())));
+        let Some(path) = entry_path(&entry, options.invalid_names)? else {
+            continue;
+        };
+        if snapshot::is_metadata(&key) {
+            if key == snapshot::SNAPSHOT_PATH {
+                let embedded: Snapshot = serde_json::from_reader(&mut entry)
+                    .context("failed to parse the archive's snapshot")?;
+                deleted = Some(embedded.deleted);
+            } else if key == checksum::MANIFEST_PATH {
+                manifest = Some(
+                    Manifest::parse(&mut entry).context("failed to parse the checksum manifest")?,
+                );
+            } else if key == header::HEADER_PATH {
+                Header::read(&mut entry)?;
+            }
+            continue;
+        }
+        if !filter.matches(&path) {
+            continue;
+        }
+        if options.no_symlinks && entry.header().entry_type().is_symlink() {
+            log::warn!("not restoring symlink '{}' (--no-symlinks)", path.display());
+            continue;
+        }
+        let rel = match check_entry(&entry, &path) {
+            Ok(rel) => rel,
+            Err(reason) => {
+                log::warn!("skipping unsafe entry '{}': {}", path.display(), reason);
+                continue;
+            }
+        };
+        let Some(rel) = strip(&rel, options.strip_components) else {
+            log::debug!(
+                "skipping '{}': no path left after stripping",
+                path.display()
+            );
+            continue;
+        };
+        let (rel, renamed_for_windows) = local_paths.get(rel);
+        if renamed_for_windows {
+            log::warn!(
+                "restoring '{}' as '{}': the name isn't valid on Windows or is already taken",
+                path.display(),
+                rel.display()
+            );
+        }
+        extracted += 1;
+        let mut dest = root.join(&rel);
+
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            // Existing directories are merged into, never treated as conflicts
+            log::debug!("extracting {}", path.display());
+            if dest != root {
+                prepare_parent(&root, &dest)?;
+            }
+            directories.push((dest, entry));
+            continue;
+        }
+
+        if dest.symlink_metadata().is_ok() {
+            let choice = match existing {
+                Existing::Ask => {
+                    let choice = ask(&dest, bar)?;
+                    if choice.all {
+                        existing = choice.action;
+                    }
+                    choice.action
+                }
+                other => other,
+            };
+            match choice {
+                Existing::Error | Existing::Ask => {
+                    return Err(Failure::OutputExists.error(format!(
+                        "'{}' already exists (use --force to overwrite, or --on-conflict)",
+                        dest.display()
+                    )))
+                }
+                Existing::Skip => {
+                    log::debug!("skipping existing {}", path.display());
+                    continue;
+                }
+                Existing::Rename => {
+                    dest = free_name(&dest);
+                    log::info!(
+                        "'{}' exists; restoring as '{}'",
+                        rel.display(),
+                        dest.display()
+                    );
+                    renamed.insert(rel.clone(), dest.clone());
+                }
+                Existing::Overwrite => {}
+            }
+        }
+        log::debug!("extracting {}", path.display());
+        prepare_parent(&root, &dest)?;
+        unpack_entry(
+            &mut entry,
+            &root,
+            &dest,
+            options,
+            &renamed,
+            &mut local_paths,
+        )
+        .with_context(|| format!("failed to unpack '{}'", path.display()))?;
+        if checksum::has_contents(entry.header().entry_type()) {
+            written.push((key, dest));
+        }
+        stats.files += 1;
+        stats.bytes += entry.header().size().unwrap_or(0);
+        progress::set_files(bar, stats.files, "extracted", &path);
+    }
+
+    directories.sort_by(|a, b| b.0.cmp(&a.0));
+    for (dest, mut dir) in directories {
+        dir.unpack(&dest)
+            .with_context(|| format!("failed to unpack '{}'", dest.display()))?;
+    }
+
+    match &manifest {
+        Some(manifest) => {
+            let mut actual = BTreeMap::new();
+            for (key, dest) in written {
+                let hash = checksum::hash_file(&dest)
+                    .with_context(|| format!("failed to re-read '{}'", dest.display()))?;
+                actual.insert(key, hash);
+            }
+            checksum::compare(manifest, &actual, false)
+                .classify(Failure::Corrupted, "restored files don't match the archive")?;
+            log::debug!(
+                "{} restored files match the checksum manifest",
+                actual.len()
+            );
+        }
+        None => log::debug!("archive has no checksum manifest; skipping content check"),
+    }
+
+    match deleted {
+        Some(deleted) => remove_deleted(
+            &root,
+            &deleted,
+            filter,
+            options.strip_components,
+            &mut local_paths,
+        )?,
+        // An increment may legitimately hold nothing under the requested paths
+        None if extracted == 0 && !filter.is_empty() => {
+            anyhow::bail!("no archive entries matched the given paths")
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// An answer to the conflict prompt; `all` applies it to every later conflict too
+struct Choice {
+    action: Existing,
+    all: bool,
+}
+
+/// Ask what to do about `dest`, which is already on disk; keeps asking until the answer is valid
+fn ask(dest: &Path, bar: &ProgressBar) -> Result<Choice> {
+    bar.suspend(|| loop {
+        eprint!(
+            "'{}' already exists. [o]verwrite, [s]kip, overwrite [a]ll, skip a[l]l, [r]ename? ",
+            dest.display()
+        );
+        let mut answer = String::new();
+        let read = io::stdin()
+            .read_line(&mut answer)
+            .context("failed to read the answer")?;
+        if read == 0 {
+            return Err(Failure::OutputExists.error(format!(
+                "'{}' already exists and no answer was given",
+                dest.display()
+            )));
+        }
+        let (action, all) = match answer.trim().to_ascii_lowercase().as_str() {
+            "o" | "overwrite" => (Existing::Overwrite, false),
+            "s" | "skip" => (Existing::Skip, false),
+            "a" | "overwrite all" => (Existing::Overwrite, true),
+            "l" | "skip all" => (Existing::Skip, true),
+            "r" | "rename" => (Existing::Rename, false),
+            _ => continue,
+        };
+        return Ok(Choice { action, all });
+    })
+}
+
+/// The first of `name (1).ext`, `name (2).ext`, … beside `dest` that doesn't exist yet
+fn free_name(dest: &Path) -> PathBuf {
+    (1..)
+        .map(|n| numbered(dest, n))
+        .find(|candidate| candidate.symlink_metadata().is_err())
+        .expect("some numbered name is free")
+}
+
+/// `path` renamed to `name (n).ext`
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    path.with_file_name(format!("{} ({}){}", stem, n, ext))
+}
+
+/// Remove the entries an increment records as deleted since its base, children first
+///
+/// `local_paths` holds the names this run restored, so a deleted path never maps onto one.
+fn remove_deleted(
+    root: &Path,
+    deleted: &[String],
+    filter: &PathFilter,
+    strip_components: usize,
+    local_paths: &mut LocalPaths,
+) -> Result<()> {
+    let mut removed = 0;
+    for rel in deleted.iter().rev() {
+        let rel = Path::new(rel);
+        if !filter.matches(rel) {
+            continue;
+        }
+        let rel = match sanitize(rel) {
+            Ok((rel, false)) if !rel.as_os_str().is_empty() => rel,
+            _ => {
+                log::warn!("not removing unsafe path '{}'", rel.display());
+                continue;
+            }
+        };
+        let Some(rel) = strip(&rel, strip_components) else {
+            continue;
+        };
+        let (rel, _) = local_paths.get(rel);
+        let dest = root.join(&rel);
+        let meta = match dest.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        // Never follow a symlinked parent out of the output folder
+        let parent_inside = dest
+            .parent()
+            .and_then(|p| p.canonicalize().ok())
+            .is_some_and(|p| p.starts_with(root));
+        if !parent_inside {
+            log::warn!(
+                "not removing '{}': it resolves outside the output folder",
+                dest.display()
+            );
+            continue;
+        }
+        log::debug!("removing {}", rel.display());
+        let result = if meta.is_dir() {
+            std::fs::remove_dir(&dest)
+        } else {
+            std::fs::remove_file(&dest)
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => log::warn!("failed to remove '{}': {}", dest.display(), e),
+        }
+    }
+    if removed > 0 {
+        log::info!("Removed {} entries deleted since the base archive", removed);
+    }
+    Ok(())
+}
+
+/// Write one non-directory entry to `dest`; hard links to `renamed` entries follow the new name
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    dest: &Path,
+    options: &ExtractOptions,
+    renamed: &HashMap<PathBuf, PathBuf>,
+    local_paths: &mut LocalPaths,
+) -> Result<()> {
+    if entry.header().entry_type().is_hard_link() {
+        // `Entry::unpack` would resolve the target against the working directory
+        let target = match entry.link_name_bytes() {
+            // Follow the target under the name it was restored as
+            Some(bytes)
+                if options.invalid_names == InvalidNames::Escape
+                    && std::str::from_utf8(&bytes).is_err() =>
+            {
+                PathBuf::from(names::escape(&bytes))
+            }
+            _ => entry
+                .link_name()?
+                .context("hard link without a target")?
+                .into_owned(),
+        };
+        let (target, _) = sanitize(&target).map_err(anyhow::Error::msg)?;
+        let target = strip(&target, options.strip_components).with_context(|| {
+            format!(
+                "hard link target '{}' has nothing left after stripping",
+                target.display()
+            )
+        })?;
+        let (target, _) = local_paths.get(target);
+        let on_disk = renamed
+            .get(&target)
+            .cloned()
+            .unwrap_or_else(|| root.join(&target));
+        let missing = || {
+            format!(
+                "failed to link to '{}' (was it excluded from extraction?)",
+                target.display()
+            )
+        };
+        // Like `prepare_parent`: a symlinked directory already on disk could lead the link
+        // anywhere. The name itself isn't resolved, since `hard_link` links a symlink as is.
+        let name = on_disk.file_name().with_context(missing)?;
+        let parent = on_disk.parent().unwrap_or(root);
+        let resolved = parent.canonicalize().with_context(missing)?.join(name);
+        if !resolved.starts_with(root) {
+            anyhow::bail!(
+                "hard link target '{}' resolves outside the output folder (via a symlink?)",
+                target.display()
+            );
+        }
+        if dest.symlink_metadata().is_ok() {
+            std::fs::remove_file(dest)?;
+        }
+        std::fs::hard_link(resolved, dest).with_context(missing)?;
+        return Ok(());
+    }
+    entry.unpack(dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tar archive of empty entries, with each path and link target written as is so
+    /// they can be unsafe
+    fn archive(entries: &[(tar::EntryType, &str, Option<&str>)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &(kind, path, link) in entries {
+            let mut header = tar::Header::new_old();
+            let old = header.as_old_mut();
+            old.name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link) = link {
+                old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(kind);
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_cksum();
+            bytes.extend_from_slice(header.as_bytes());
+        }
+        bytes.extend([0; 1024]);
+        bytes
+    }
+
+    /// A new, empty directory under the temp directory; the caller removes it
+    #[cfg(unix)]
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn check(kind: tar::EntryType, path: &str, link: Option<&str>) -> Result<PathBuf, String> {
+        let bytes = archive(&[(kind, path, link)]);
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let path = entry.path().unwrap().into_owned();
+        check_entry(&entry, &path)
+    }
+
+    #[test]
+    fn sanitize_strips_roots_and_refuses_parents() {
+        assert_eq!(
+            sanitize(Path::new("a/./b")),
+            Ok((PathBuf::from("a/b"), false))
+        );
+        assert_eq!(
+            sanitize(Path::new("/etc/passwd")),
+            Ok((PathBuf::from("etc/passwd"), true))
+        );
+        assert_eq!(sanitize(Path::new("./")), Ok((PathBuf::new(), false)));
+        assert!(sanitize(Path::new("../x")).is_err());
+        assert!(sanitize(Path::new("a/../../x")).is_err());
+        assert!(sanitize(Path::new("a/b/..")).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn sanitize_strips_windows_prefixes() {
+        assert_eq!(
+            sanitize(Path::new(r"C:\x\y")),
+            Ok((PathBuf::from(r"x\y"), true))
+        );
+        assert_eq!(sanitize(Path::new(r"C:x")), Ok((PathBuf::from("x"), true)));
+        assert_eq!(
+            sanitize(Path::new(r"\\server\share\x")),
+            Ok((PathBuf::from("x"), true))
+        );
+        assert_eq!(
+            sanitize(Path::new(r"\\?\C:\x")),
+            Ok((PathBuf::from("x"), true))
+        );
+    }
+
+    #[test]
+    fn symlinks_must_stay_inside() {
+        assert!(!link_escapes(Path::new("a/link"), Path::new("../b")));
+        assert!(!link_escapes(Path::new("a/link"), Path::new("b/../../c")));
+        assert!(!link_escapes(Path::new("link"), Path::new("./b")));
+        assert!(link_escapes(Path::new("link"), Path::new("../b")));
+        assert!(link_escapes(Path::new("a/link"), Path::new("../../b")));
+        assert!(link_escapes(Path::new("a/link"), Path::new("b/../../../c")));
+        assert!(link_escapes(Path::new("link"), Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn unsafe_entries_are_refused() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+        assert_eq!(check(Regular, "a/b", None), Ok(PathBuf::from("a/b")));
+        assert_eq!(check(Regular, "/abs", None), Ok(PathBuf::from("abs")));
+        assert_eq!(check(Directory, "./", None), Ok(PathBuf::new()));
+        assert!(check(Regular, "./", None).is_err());
+        assert!(check(Regular, "../x", None).is_err());
+        assert!(check(Regular, "a/../../x", None).is_err());
+
+        assert_eq!(
+            check(Symlink, "a/l", Some("../b")),
+            Ok(PathBuf::from("a/l"))
+        );
+        assert!(check(Symlink, "a/l", Some("../../etc")).is_err());
+        assert!(check(Symlink, "l", Some("/etc/passwd")).is_err());
+        assert!(check(Symlink, "l", None).is_err());
+
+        assert_eq!(check(Link, "x", Some("a/y")), Ok(PathBuf::from("x")));
+        assert!(check(Link, "x", Some("../y")).is_err());
+        assert!(check(Link, "a/x", Some("a/../../y")).is_err());
+        assert!(check(Link, "x", Some("/etc/passwd")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_dont_follow_symlinks_on_disk() {
+        let base = scratch();
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+        let bytes = archive(&[(tar::EntryType::Link, "x", Some("out/secret"))]);
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let options = ExtractOptions::default();
+        let result = extract(&mut archive, &root, &options, &ProgressBar::hidden());
+        let linked = root.join("x").symlink_metadata().is_ok();
+        std::fs::remove_dir_all(&base).unwrap();
+
+        let error = format!("{:#}", result.err().expect("the link was refused"));
+        assert!(
+            error.contains("resolves outside the output folder"),
+            "{}",
+            error
+        );
+        assert!(!linked);
+    }
+
+    #[test]
+    fn windows_names() {
+        assert_eq!(windows_name("a.txt"), None);
+        assert_eq!(windows_name("a:b?.txt").as_deref(), Some("a_b_.txt"));
+        assert_eq!(windows_name("a. ").as_deref(), Some("a__"));
+        for reserved in ["CON", "con.txt", "COM0", "LPT¹.log", "NUL .tar.gz"] {
+            let name = windows_name(reserved).unwrap();
+            let stem = name.split('.').next().unwrap();
+            assert!(stem.ends_with('_'), "{} became {}", reserved, name);
+        }
+        assert_eq!(windows_name("CONSOLE"), None);
+        assert_eq!(windows_name("COM10"), None);
+    }
+
+    #[test]
+    fn local_paths_are_distinct() {
+        let mut local = LocalPaths::default();
+        assert_eq!(local.assign(Path::new("a.")), Path::new("a_"));
+        assert_eq!(local.assign(Path::new("a ")), Path::new("a_ (1)"));
+        assert_eq!(local.assign(Path::new("a_")), Path::new("a_ (2)"));
+        assert_eq!(local.assign(Path::new("CON")), Path::new("CON_"));
+        assert_eq!(local.assign(Path::new("CON_")), Path::new("CON_ (1)"));
+        // The same archive path always lands in the same place
+        assert_eq!(local.assign(Path::new("a ")), Path::new("a_ (1)"));
+    }
+
+    #[test]
+    fn local_directories_carry_over() {
+        let mut local = LocalPaths::default();
+        assert_eq!(local.assign(Path::new("d?/x")), Path::new("d_/x"));
+        assert_eq!(local.assign(Path::new("d_/x")), Path::new("d_ (1)/x"));
+        assert_eq!(local.assign(Path::new("d_")), Path::new("d_ (1)"));
+        assert_eq!(local.assign(Path::new("d?/y.txt")), Path::new("d_/y.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_to_dangling_symlinks_link_the_symlink() {
+        use tar::EntryType::{Link, Symlink};
+
+        let root = scratch();
+        let bytes = archive(&[(Symlink, "l", Some("nowhere")), (Link, "x", Some("l"))]);
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let options = ExtractOptions::default();
+        let result = extract(&mut archive, &root, &options, &ProgressBar::hidden());
+        let target = std::fs::read_link(root.join("x"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        result.unwrap();
+        assert_eq!(target.unwrap(), Path::new("nowhere"));
+    }
+}
@@ -0,0 +1,76 @@
+//! Failure classes behind the binary's exit codes, so scripts can branch on what went wrong
+//!
+//! An error is classified by a [`Failure`]-marked message anywhere in its chain; anything
+//! unmarked exits with 1, and clap's usage errors with 2.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The passphrase or identities don't open the archive
+    WrongKey,
+    /// The output exists and `--force` wasn't given
+    OutputExists,
+    /// A source folder, file or archive doesn't exist
+    SourceMissing,
+    /// The archive isn't age, or fails to decrypt, decompress or match its manifest
+    Corrupted,
+    /// Extraction failed after some entries had been restored
+    Partial,
+}
+
+impl Failure {
+    /// Exit status of `folder_lock_rs` for this failure
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::WrongKey => 3,
+            Failure::OutputExists => 4,
+            Failure::SourceMissing => 5,
+            Failure::Corrupted => 6,
+            Failure::Partial => 7,
+        }
+    }
+
+    /// A new error with `message`, marked as this failure
+    pub fn error(self, message: impl fmt::Display) -> anyhow::Error {
+        anyhow::Error::new(Marked {
+            failure: self,
+            message: message.to_string(),
+        })
+    }
+
+    /// The outermost failure marked in `error`'s chain
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        error.downcast_ref::<Marked>().map(|marked| marked.failure)
+    }
+}
+
+/// Like `anyhow::Context`, but the added message also marks the error as a `Failure`
+pub trait Classify<T> {
+    fn classify(self, failure: Failure, message: impl fmt::Display) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, failure: Failure, message: impl fmt::Display) -> anyhow::Result<T> {
+        self.map_err(|e| {
+            e.into().context(Marked {
+                failure,
+                message: message.to_string(),
+            })
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Marked {
+    failure: Failure,
+    message: String,
+}
+
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Marked {}
@@ -0,0 +1,501 @@
+//! C bindings, so applications in other languages can write and read the same archives
+//!
+//! Every function returns 0 on success, or on failure the exit code `folder_lock_rs`
+//! would use (see `Failure::exit_code`; 1 when unclassified), and
+//! `folder_lock_last_error` describes what went wrong. Strings are NUL-terminated UTF-8.
+//! Panics are caught at the boundary and reported as failures. The header is generated
+//! from this file with `cbindgen --config cbindgen.toml --output include/folder_lock.h`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{self, Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use age::secrecy::SecretString;
+use anyhow::{Context, Result};
+
+use crate::failure::Failure;
+use crate::keys::{parse_recipients, read_identities};
+use crate::{streams, Locker, Unlocker};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Takes bytes of the archive being written: consumes up to `len` bytes at `buf` and
+/// returns how many it took, or -1 to abort with an error
+pub type FolderLockWriteFn =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, buf: *const u8, len: usize) -> isize>;
+
+/// Supplies bytes of the archive being read: fills up to `len` bytes at `buf` and
+/// returns how many it stored, 0 at the end of the archive, or -1 to abort with an error
+pub type FolderLockReadFn =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, buf: *mut u8, len: usize) -> isize>;
+
+/// Encrypt `folder` into a new archive at `out_path`, which must not exist yet
+///
+/// The archive is encrypted to `passphrase`, to the `n_recipients` age recipient strings
+/// at `recipients`, or to both; pass NULL for what isn't used. `out_path` may also be an
+/// `s3://` or `sftp://` URL when the library is built with those features.
+///
+/// # Safety
+///
+/// Every non-NULL pointer must be valid: strings NUL-terminated, and `recipients` an
+/// array of `n_recipients` strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn folder_lock_encrypt_folder_to_path(
+    folder: *const c_char,
+    out_path: *const c_char,
+    passphrase: *const c_char,
+    recipients: *const *const c_char,
+    n_recipients: usize,
+) -> c_int {
+    guard(|| {
+        // SAFETY: forwarded from the caller
+        let locker = unsafe { locker(folder, passphrase, recipients, n_recipients) }?;
+        let out = PathBuf::from(unsafe { required(out_path, "out_path") }?);
+        let mut output = streams::create_output(&out, false, None)?;
+        locker.encrypt_to(&mut output)?;
+        output.commit()
+    })
+}
+
+/// Like `folder_lock_encrypt_folder_to_path`, handing the archive to `write` as it is
+/// produced, with `ctx` as its first argument
+///
+/// # Safety
+///
+/// As for `folder_lock_encrypt_folder_to_path`; `write` must be safe to call with `ctx`
+/// from this thread until this function returns.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn folder_lock_encrypt_folder_to_callback(
+    folder: *const c_char,
+    passphrase: *const c_char,
+    recipients: *const *const c_char,
+    n_recipients: usize,
+    write: FolderLockWriteFn,
+    ctx: *mut c_void,
+) -> c_int {
+    guard(|| {
+        let write = write.context("write callback is NULL")?;
+        // SAFETY: forwarded from the caller
+        let locker = unsafe { locker(folder, passphrase, recipients, n_recipients) }?;
+        let mut w = CallbackWriter { write, ctx };
+        locker.encrypt_to(&mut w)?;
+        Ok(())
+    })
+}
+
+/// Decrypt the archive at `archive` into the existing folder `out_folder`, never
+/// replacing files already there
+///
+/// The archive is opened with `passphrase` or with the age identities in the file at
+/// `identity_file`; pass NULL for the one that isn't used.
+///
+/// # Safety
+///
+/// Every non-NULL pointer must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn folder_lock_decrypt_path_to_folder(
+    archive: *const c_char,
+    out_folder: *const c_char,
+    passphrase: *const c_char,
+    identity_file: *const c_char,
+) -> c_int {
+    guard(|| {
+        // SAFETY: forwarded from the caller
+        let archive = PathBuf::from(unsafe { required(archive, "archive") }?);
+        let input = streams::open_input(&archive)?;
+        unsafe { extract(input, out_folder, passphrase, identity_file) }
+    })
+}
+
+/// Like `folder_lock_decrypt_path_to_folder`, reading the archive from `read`, with `ctx`
+/// as its first argument
+///
+/// # Safety
+///
+/// As for `folder_lock_decrypt_path_to_folder`; `read` must be safe to call with `ctx`
+/// from this thread until this function returns.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn folder_lock_decrypt_callback_to_folder(
+    read: FolderLockReadFn,
+    ctx: *mut c_void,
+    out_folder: *const c_char,
+    passphrase: *const c_char,
+    identity_file: *const c_char,
+) -> c_int {
+    guard(|| {
+        let read = read.context("read callback is NULL")?;
+        // SAFETY: forwarded from the caller
+        unsafe {
+            extract(
+                CallbackReader { read, ctx },
+                out_folder,
+                passphrase,
+                identity_file,
+            )
+        }
+    })
+}
+
+/// What went wrong in this thread's last failed call, or NULL if it succeeded
+///
+/// The string stays valid until the next call into the library on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn folder_lock_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |e| e.as_ptr())
+    })
+}
+
+/// Run `f`, turning its error or panic into a return code and `LAST_ERROR`
+fn guard(f: impl FnOnce() -> Result<()>) -> c_int {
+    let result = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow::anyhow!("internal error: {}", message))
+    });
+    let (code, message) = match result {
+        Ok(()) => (0, None),
+        Err(e) => {
+            let message = format!("{:#}", e).replace('\0', " ");
+            (
+                Failure::of(&e).map_or(1, Failure::exit_code),
+                CString::new(message).ok(),
+            )
+        }
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+/// The string at `p`, or `None` for NULL
+///
+/// # Safety
+///
+/// `p` is NULL or a valid NUL-terminated string that outlives the result.
+unsafe fn optional<'a>(p: *const c_char, name: &str) -> Result<Option<&'a str>> {
+    if p.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: guaranteed by the caller
+    let s = unsafe { CStr::from_ptr(p) };
+    let s = s
+        .to_str()
+        .with_context(|| format!("{} is not valid UTF-8", name))?;
+    Ok(Some(s))
+}
+
+/// # Safety
+///
+/// As for `optional`.
+unsafe fn required<'a>(p: *const c_char, name: &str) -> Result<&'a str> {
+    // SAFETY: guaranteed by the caller
+    unsafe { optional(p, name) }?.with_context(|| format!("{} is NULL", name))
+}
+
+/// # Safety
+///
+/// As for `folder_lock_encrypt_folder_to_path`.
+unsafe fn locker(
+    folder: *const c_char,
+    passphrase: *const c_char,
+    recipients: *const *const c_char,
+    n_recipients: usize,
+) -> Result<Locker> {
+    // SAFETY: guaranteed by the caller
+    let folder = unsafe { required(folder, "folder") }?;
+    let passphrase = unsafe { optional(passphrase, "passphrase") }?;
+    let recipients = if recipients.is_null() || n_recipients == 0 {
+        Vec::new()
+    } else {
+        // SAFETY: the caller passes an array of `n_recipients` strings
+        let array = unsafe { std::slice::from_raw_parts(recipients, n_recipients) };
+        array
+            .iter()
+            .map(|&r| unsafe { required(r, "recipient") }.map(str::to_owned))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let locker = Locker::new(folder);
+    let passphrase = passphrase.map(|p| SecretString::new(p.to_owned()));
+    Ok(match (passphrase, recipients.is_empty()) {
+        (Some(pass), true) => locker.passphrase(pass),
+        (None, false) => locker.recipients(parse_recipients(&recipients)?),
+        (Some(pass), false) => {
+            locker.passphrase_and_recipients(pass, parse_recipients(&recipients)?)
+        }
+        (None, true) => anyhow::bail!("a passphrase or at least one recipient is needed"),
+    })
+}
+
+/// # Safety
+///
+/// As for `folder_lock_decrypt_path_to_folder`.
+unsafe fn extract(
+    input: impl Read + 'static,
+    out_folder: *const c_char,
+    passphrase: *const c_char,
+    identity_file: *const c_char,
+) -> Result<()> {
+    // SAFETY: guaranteed by the caller
+    let out_folder = unsafe { required(out_folder, "out_folder") }?;
+    let passphrase = unsafe { optional(passphrase, "passphrase") }?;
+    let identity_file = unsafe { optional(identity_file, "identity_file") }?;
+
+    let unlocker = Unlocker::new(input);
+    let unlocker = match (passphrase, identity_file) {
+        (Some(pass), None) => unlocker.passphrase(SecretString::new(pass.to_owned())),
+        (None, Some(file)) => unlocker.identities(read_identities(&[PathBuf::from(file)])?),
+        (Some(_), Some(_)) => anyhow::bail!("pass either a passphrase or an identity file"),
+        (None, None) => anyhow::bail!("a passphrase or an identity file is needed"),
+    };
+    unlocker.extract_to(out_folder)?;
+    Ok(())
+}
+
+struct CallbackWriter {
+    write: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> isize,
+    ctx: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: the caller of the public function vouched for `write` and `ctx`
+        let n = unsafe { (self.write)(self.ctx, buf.as_ptr(), buf.len()) };
+        match usize::try_from(n) {
+            Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) if n <= buf.len() => Ok(n),
+            _ => Err(io::Error::other("write callback failed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct CallbackReader {
+    read: unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> isize,
+    ctx: *mut c_void,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: the caller of the public function vouched for `read` and `ctx`
+        let n = unsafe { (self.read)(self.ctx, buf.as_mut_ptr(), buf.len()) };
+        match usize::try_from(n) {
+            Ok(n) if n <= buf.len() => Ok(n),
+            _ => Err(io::Error::other("read callback failed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::ptr::{null, null_mut};
+
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    fn c(path: impl AsRef<Path>) -> CString {
+        CString::new(path.as_ref().to_str().unwrap()).unwrap()
+    }
+
+    fn last_error() -> Option<String> {
+        let p = folder_lock_last_error();
+        // SAFETY: a non-NULL last error is a live string until the next call
+        (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned())
+    }
+
+    /// A new directory under the temp directory, holding a `source` folder, an empty `out`
+    /// folder and the identity file `key.txt`; returns it and the identity's recipient
+    fn scratch() -> (PathBuf, CString) {
+        let dir = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("source/docs")).unwrap();
+        std::fs::create_dir(dir.join("out")).unwrap();
+        std::fs::write(dir.join("source/a.txt"), b"first").unwrap();
+        std::fs::write(dir.join("source/docs/b.txt"), b"second").unwrap();
+        let identity = age::x25519::Identity::generate();
+        std::fs::write(dir.join("key.txt"), identity.to_string().expose_secret()).unwrap();
+        (dir, c(identity.to_public().to_string()))
+    }
+
+    fn restored(dir: &Path) -> (Vec<u8>, Vec<u8>) {
+        let a = std::fs::read(dir.join("out/a.txt")).unwrap();
+        let b = std::fs::read(dir.join("out/docs/b.txt")).unwrap();
+        (a, b)
+    }
+
+    /// Appends the archive to the `Vec<u8>` at `ctx`
+    unsafe extern "C" fn collect(ctx: *mut c_void, buf: *const u8, len: usize) -> isize {
+        // SAFETY: `ctx` is the Vec the test passed, and `buf` holds `len` bytes
+        let out = unsafe { &mut *(ctx as *mut Vec<u8>) };
+        out.extend_from_slice(unsafe { std::slice::from_raw_parts(buf, len) });
+        len as isize
+    }
+
+    /// Reads the archive from the `Cursor` at `ctx`
+    unsafe extern "C" fn replay(ctx: *mut c_void, buf: *mut u8, len: usize) -> isize {
+        // SAFETY: `ctx` is the Cursor the test passed, and `buf` has room for `len` bytes
+        let input = unsafe { &mut *(ctx as *mut io::Cursor<Vec<u8>>) };
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+        input.read(buf).map_or(-1, |n| n as isize)
+    }
+
+    unsafe extern "C" fn refuse(_ctx: *mut c_void, _buf: *const u8, _len: usize) -> isize {
+        -1
+    }
+
+    #[test]
+    fn archives_round_trip_through_paths() {
+        let (dir, recipient) = scratch();
+        let recipients = [recipient.as_ptr()];
+        let (source, archive) = (c(dir.join("source")), c(dir.join("a.age")));
+        let (out, key) = (c(dir.join("out")), c(dir.join("key.txt")));
+        // SAFETY: every pointer is a live string, and `recipients` holds one
+        let encrypt = || unsafe {
+            folder_lock_encrypt_folder_to_path(
+                source.as_ptr(),
+                archive.as_ptr(),
+                null(),
+                recipients.as_ptr(),
+                recipients.len(),
+            )
+        };
+        assert_eq!((encrypt(), last_error()), (0, None));
+        assert_eq!(encrypt(), Failure::OutputExists.exit_code());
+        assert!(last_error().is_some());
+
+        // SAFETY: as above
+        let code = unsafe {
+            folder_lock_decrypt_path_to_folder(archive.as_ptr(), out.as_ptr(), null(), key.as_ptr())
+        };
+        assert_eq!((code, last_error()), (0, None));
+        let contents = restored(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents, (b"first".to_vec(), b"second".to_vec()));
+    }
+
+    #[test]
+    fn archives_round_trip_through_callbacks() {
+        let (dir, recipient) = scratch();
+        let recipients = [recipient.as_ptr()];
+        let (source, out, key) = (
+            c(dir.join("source")),
+            c(dir.join("out")),
+            c(dir.join("key.txt")),
+        );
+        let mut archive = Vec::new();
+        // SAFETY: every pointer is a live string, and `collect` takes the Vec as `ctx`
+        let code = unsafe {
+            folder_lock_encrypt_folder_to_callback(
+                source.as_ptr(),
+                null(),
+                recipients.as_ptr(),
+                recipients.len(),
+                Some(collect),
+                &mut archive as *mut Vec<u8> as *mut c_void,
+            )
+        };
+        assert_eq!((code, last_error()), (0, None));
+
+        let mut input = io::Cursor::new(archive);
+        // SAFETY: as above, and `replay` takes the Cursor as `ctx`
+        let code = unsafe {
+            folder_lock_decrypt_callback_to_folder(
+                Some(replay),
+                &mut input as *mut io::Cursor<Vec<u8>> as *mut c_void,
+                out.as_ptr(),
+                null(),
+                key.as_ptr(),
+            )
+        };
+        assert_eq!((code, last_error()), (0, None));
+        let contents = restored(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents, (b"first".to_vec(), b"second".to_vec()));
+    }
+
+    #[test]
+    fn failures_are_reported_with_their_exit_code() {
+        // SAFETY: NULL is refused
+        let code = unsafe { folder_lock_encrypt_folder_to_path(null(), null(), null(), null(), 0) };
+        assert_eq!((code, last_error().as_deref()), (1, Some("folder is NULL")));
+
+        let (dir, recipient) = scratch();
+        let recipients = [recipient.as_ptr()];
+        let (source, out) = (c(dir.join("source")), c(dir.join("out")));
+        // SAFETY: every pointer is a live string; `refuse` ignores its arguments
+        let code = unsafe {
+            folder_lock_encrypt_folder_to_callback(
+                source.as_ptr(),
+                null(),
+                recipients.as_ptr(),
+                recipients.len(),
+                Some(refuse),
+                null_mut(),
+            )
+        };
+        assert_ne!(code, 0);
+        assert!(last_error().unwrap().contains("write callback failed"));
+
+        // SAFETY: as above
+        let code = unsafe {
+            folder_lock_encrypt_folder_to_callback(
+                source.as_ptr(),
+                null(),
+                null(),
+                0,
+                Some(refuse),
+                null_mut(),
+            )
+        };
+        assert_eq!(code, 1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("a passphrase or at least one recipient is needed")
+        );
+
+        // An archive for `recipient`, opened with another identity
+        let mut archive = Vec::new();
+        // SAFETY: as in `archives_round_trip_through_callbacks`
+        let code = unsafe {
+            folder_lock_encrypt_folder_to_callback(
+                source.as_ptr(),
+                null(),
+                recipients.as_ptr(),
+                recipients.len(),
+                Some(collect),
+                &mut archive as *mut Vec<u8> as *mut c_void,
+            )
+        };
+        assert_eq!(code, 0);
+        let other = age::x25519::Identity::generate();
+        std::fs::write(dir.join("other.txt"), other.to_string().expose_secret()).unwrap();
+        let other = c(dir.join("other.txt"));
+        let mut input = io::Cursor::new(archive);
+        // SAFETY: as above
+        let code = unsafe {
+            folder_lock_decrypt_callback_to_folder(
+                Some(replay),
+                &mut input as *mut io::Cursor<Vec<u8>> as *mut c_void,
+                out.as_ptr(),
+                null(),
+                other.as_ptr(),
+            )
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(code, Failure::WrongKey.exit_code());
+        assert!(last_error().is_some());
+    }
+}
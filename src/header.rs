@@ -0,0 +1,119 @@
+//! Versioned description of an archive's layout, stored as its first entry
+//!
+//! The header lives inside the container, at `HEADER_PATH`, rather than as a cleartext
+//! prefix, so `age -d | tar x` keeps working on every archive. Compression is still told
+//! apart by magic bytes (it wraps the header too); the header records what produced the
+//! archive so a reader can refuse a layout newer than it understands instead of guessing.
+//! Offsets can't be known when the first entry is written, so folder_lock's trailing
+//! entries (manifest, snapshot) are listed by path, in order, instead.
+//!
+//! It also carries the archive's `--label` and `--meta` pairs, which are encrypted with
+//! everything else; older readers ignore them.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum;
+use crate::compression::Settings;
+use crate::container::Container;
+use crate::snapshot;
+
+/// Archive path of the header, written before any other entry
+pub const HEADER_PATH: &str = ".folder-lock/header.json";
+
+/// Layout version written by this build; readers accept this and anything older
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Plaintext size of age's STREAM chunks, fixed by the age format
+pub const AGE_CHUNK_SIZE: u32 = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub format_version: u32,
+    /// `CARGO_PKG_VERSION` of the folder_lock that wrote the archive
+    pub tool_version: String,
+    pub container: String,
+    /// Compression of the tar stream (`none` inside a zip container)
+    pub compression: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+    pub age_chunk_size: u32,
+    /// Paths of folder_lock's own entries after the last file, in order
+    pub trailer: Vec<String>,
+    /// Name given with `--label`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Free-form `--meta key=value` pairs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl Header {
+    /// The header for an archive written with `compression` into `container`
+    pub fn new(compression: &Settings, container: Container, incremental: bool) -> Self {
+        let mut trailer = vec![checksum::MANIFEST_PATH.to_string()];
+        if incremental {
+            trailer.push(snapshot::SNAPSHOT_PATH.to_string());
+        }
+        let (name, level) = match container {
+            Container::Tar => (compression.algorithm.name(), compression.level),
+            Container::Zip => ("none", None),
+        };
+        Self {
+            format_version: FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            container: container.name().to_string(),
+            compression: name.to_string(),
+            level,
+            age_chunk_size: AGE_CHUNK_SIZE,
+            trailer,
+            label: None,
+            meta: BTreeMap::new(),
+        }
+    }
+
+    /// `label` and `meta` as `info` prints them, one line each
+    pub fn label_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(label) = &self.label {
+            lines.push(format!("label:       {}", label));
+        }
+        for (key, value) in &self.meta {
+            lines.push(format!("meta:        {}={}", key, value));
+        }
+        lines
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("header serializes")
+    }
+
+    /// Parse a header entry and refuse layouts newer than `FORMAT_VERSION`
+    pub fn read(r: impl Read) -> Result<Self> {
+        let header: Header =
+            serde_json::from_reader(r).context("failed to parse the archive header")?;
+        if header.format_version > FORMAT_VERSION {
+            anyhow::bail!(
+                "archive uses format version {} (written by folder_lock {}); this build \
+                 reads up to version {}, please upgrade",
+                header.format_version,
+                header.tool_version,
+                FORMAT_VERSION
+            );
+        }
+        Ok(header)
+    }
+}
+
+/// Parse a `KEY=VALUE` pair for `--meta`; the value may be empty and contain `=`
+pub fn parse_meta(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("invalid metadata '{}' (expected KEY=VALUE)", s)),
+    }
+}
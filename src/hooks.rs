@@ -0,0 +1,107 @@
+//! `--pre-hook` and `--post-hook` commands run around `encrypt`
+//!
+//! A hook is a shell command line (`sh -c`, or `cmd /C` on Windows), given on the command
+//! line or as `pre_hook`/`post_hook` in the config file. The pre-hook runs before any
+//! secret is asked for, e.g. to dump a database into the folder; if it fails, nothing is
+//! encrypted. The post-hook runs once the archive is in place, or after encryption failed,
+//! e.g. to upload or notify. Hooks see:
+//!
+//! - `FOLDER_LOCK_ARCHIVE`: the output path
+//! - `FOLDER_LOCK_SOURCES`: the sources, one per line
+//! - `FOLDER_LOCK_STATUS` (post-hook): `success` or `failure`
+//! - `FOLDER_LOCK_ERROR` (post-hook, on failure): the error message
+//! - `FOLDER_LOCK_FILES`, `FOLDER_LOCK_BYTES` (post-hook, on success): files stored and
+//!   bytes written
+//!
+//! Their output goes to stderr, as stdout may carry the archive or the JSON report.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use folder_lock::report::Report;
+
+pub struct Hooks {
+    pre: Option<String>,
+    post: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(pre: Option<String>, post: Option<String>) -> Self {
+        Self { pre, post }
+    }
+
+    /// Run the pre-hook, if any; its failure is an error
+    pub fn pre(&self, archive: &Path, sources: &[PathBuf]) -> Result<()> {
+        let Some(command) = &self.pre else {
+            return Ok(());
+        };
+        run(command, &common(archive, sources)).context("pre-hook failed; nothing was encrypted")
+    }
+
+    /// Run the post-hook, if any, with the outcome of the run
+    ///
+    /// When the run itself failed, a failing post-hook is only logged, so the original error
+    /// is the one reported.
+    pub fn post(&self, archive: &Path, sources: &[PathBuf], result: &Result<Report>) -> Result<()> {
+        let Some(command) = &self.post else {
+            return Ok(());
+        };
+        let mut env = common(archive, sources);
+        match result {
+            Ok(report) => {
+                env.push(("FOLDER_LOCK_STATUS", "success".to_string()));
+                env.push(("FOLDER_LOCK_FILES", report.files.to_string()));
+                env.push(("FOLDER_LOCK_BYTES", report.bytes_out.to_string()));
+            }
+            Err(e) => {
+                env.push(("FOLDER_LOCK_STATUS", "failure".to_string()));
+                env.push(("FOLDER_LOCK_ERROR", format!("{:#}", e)));
+            }
+        }
+        match (run(command, &env), result) {
+            (Err(e), Err(_)) => {
+                log::warn!("post-hook failed: {:#}", e);
+                Ok(())
+            }
+            (hook, _) => hook.context("post-hook failed"),
+        }
+    }
+}
+
+fn common(archive: &Path, sources: &[PathBuf]) -> Vec<(&'static str, String)> {
+    let sources = sources
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    vec![
+        ("FOLDER_LOCK_ARCHIVE", archive.display().to_string()),
+        ("FOLDER_LOCK_SOURCES", sources.join("\n")),
+    ]
+}
+
+fn run(command: &str, env: &[(&str, String)]) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    log::debug!("running hook: {}", command);
+    let status = shell
+        .arg(command)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(io::stderr()))
+        .status()
+        .with_context(|| format!("failed to run '{}'", command))?;
+    if !status.success() {
+        anyhow::bail!("'{}' exited with {}", command, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,317 @@
+//! scrypt passphrase encryption with a chosen work factor, for `encrypt --kdf-cost`
+//!
+//! age picks the work factor itself, calibrating scrypt to take about a second on the
+//! encrypting machine. For archives meant to sit in cold storage for years it can be worth
+//! far more. `Recipient` writes the same `scrypt` stanza as age, with a fixed log2(N)
+//! instead, so age (and `age -d`) decrypt the result like any passphrase file: the stanza
+//! records the salt and work factor.
+//!
+//! age also requires a `scrypt` stanza to be the only one in a header, so a passphrase can't
+//! sit beside recipients. For `encrypt --with-passphrase`, `Recipient::beside_recipients`
+//! writes the same stanza under `PASSPHRASE_TAG` instead, which age skips like any stanza
+//! it has no identity for, and `Identity` unwraps it: such archives open with the
+//! passphrase here, or with any of the recipients' identities here and in `age -d`.
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::{ExposeSecret, SecretString};
+use age_core::format::{FileKey, Stanza};
+use age_core::primitives::{aead_decrypt, aead_encrypt};
+use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
+use rand::RngCore;
+
+/// Lowest `--kdf-cost` accepted: age's own minimum calibration
+pub const MIN_COST: u8 = 10;
+/// Highest `--kdf-cost` accepted; scrypt then needs 4 GiB of memory
+///
+/// Decryption allows work factors up to this, so every archive written here opens without
+/// extra flags, while a hostile header can't demand more memory than this.
+pub const MAX_COST: u8 = 22;
+
+/// Cost of a passphrase beside recipients when none is chosen: age's calibration is only
+/// available for `scrypt` stanzas, and this takes about a second on current hardware
+pub const DEFAULT_COST: u8 = 18;
+
+/// Stanza type of a passphrase written beside recipients
+pub const PASSPHRASE_TAG: &str = "folder-lock-scrypt";
+
+const SALT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+const SALT_LEN: usize = 16;
+const FILE_KEY_BYTES: usize = 16;
+
+/// A passphrase with an explicit scrypt work factor
+pub struct Recipient {
+    passphrase: SecretString,
+    log_n: u8,
+    tag: &'static str,
+}
+
+impl Recipient {
+    /// `log_n` is log2 of scrypt's N, between `MIN_COST` and `MAX_COST`
+    pub fn new(passphrase: SecretString, log_n: u8) -> anyhow::Result<Self> {
+        if !(MIN_COST..=MAX_COST).contains(&log_n) {
+            anyhow::bail!(
+                "scrypt cost {} is out of range ({}-{})",
+                log_n,
+                MIN_COST,
+                MAX_COST
+            );
+        }
+        Ok(Self {
+            passphrase,
+            log_n,
+            tag: "scrypt",
+        })
+    }
+
+    /// Write a `PASSPHRASE_TAG` stanza, which may share the header with recipients
+    pub fn beside_recipients(mut self) -> Self {
+        self.tag = PASSPHRASE_TAG;
+        self
+    }
+}
+
+impl age::Recipient for Recipient {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, age::EncryptError> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        let key = derive(&self.passphrase, &salt, self.log_n)
+            .map_err(|e| age::EncryptError::Io(std::io::Error::other(e)))?;
+
+        Ok(vec![Stanza {
+            tag: self.tag.to_owned(),
+            args: vec![BASE64_STANDARD_NO_PAD.encode(salt), self.log_n.to_string()],
+            body: aead_encrypt(&key, file_key.expose_secret()),
+        }])
+    }
+}
+
+/// Opens the `PASSPHRASE_TAG` stanza of an archive encrypted to a passphrase and recipients
+pub struct Identity {
+    passphrase: SecretString,
+    scrypt: bool,
+}
+
+impl Identity {
+    pub fn new(passphrase: SecretString) -> Self {
+        Self {
+            passphrase,
+            scrypt: false,
+        }
+    }
+
+    /// Open `scrypt` stanzas too, as `Recipient::new` writes them; age opens those itself
+    /// when it reads the archive
+    pub fn with_scrypt(mut self) -> Self {
+        self.scrypt = true;
+        self
+    }
+}
+
+impl age::Identity for Identity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        if stanza.tag != PASSPHRASE_TAG && !(self.scrypt && stanza.tag == "scrypt") {
+            return None;
+        }
+        // `-> folder-lock-scrypt SALT LOG_N`, as for `scrypt`
+        let [salt, log_n] = &stanza.args[..] else {
+            return Some(Err(age::DecryptError::InvalidHeader));
+        };
+        let salt = match BASE64_STANDARD_NO_PAD.decode(salt) {
+            Ok(salt) if salt.len() == SALT_LEN => salt,
+            _ => return Some(Err(age::DecryptError::InvalidHeader)),
+        };
+        let log_n = match log_n.parse::<u8>() {
+            Ok(log_n) if log_n > MAX_COST => {
+                return Some(Err(age::DecryptError::ExcessiveWork {
+                    required: log_n,
+                    target: MAX_COST,
+                }))
+            }
+            Ok(log_n) => log_n,
+            Err(_) => return Some(Err(age::DecryptError::InvalidHeader)),
+        };
+        let key = match derive(&self.passphrase, &salt, log_n) {
+            Ok(key) => key,
+            Err(_) => return Some(Err(age::DecryptError::InvalidHeader)),
+        };
+        Some(
+            aead_decrypt(&key, FILE_KEY_BYTES, &stanza.body)
+                .map(|plain| {
+                    let plain = Zeroizing::new(plain);
+                    let file_key: [u8; FILE_KEY_BYTES] = plain[..]
+                        .try_into()
+                        .expect("aead_decrypt checks the length");
+                    file_key.into()
+                })
+                .map_err(|_| age::DecryptError::DecryptionFailed),
+        )
+    }
+}
+
+/// The stanza key for `passphrase`, derived the way age does: scrypt with r = 8, p = 1 and
+/// a 32-byte output, over the salt prefixed with age's label
+fn derive(
+    passphrase: &SecretString,
+    salt: &[u8],
+    log_n: u8,
+) -> Result<Zeroizing<[u8; 32]>, String> {
+    let mut inner_salt = SALT_LABEL.to_vec();
+    inner_salt.extend_from_slice(salt);
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| e.to_string())?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(
+        passphrase.expose_secret().as_bytes(),
+        &inner_salt,
+        &params,
+        &mut key[..],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    const KEY: [u8; FILE_KEY_BYTES] = [9; FILE_KEY_BYTES];
+
+    fn passphrase(s: &str) -> SecretString {
+        SecretString::new(s.to_owned())
+    }
+
+    fn wrap(recipient: &Recipient) -> Stanza {
+        let mut stanzas = age::Recipient::wrap_file_key(recipient, &KEY.into()).unwrap();
+        assert_eq!(stanzas.len(), 1);
+        stanzas.remove(0)
+    }
+
+    fn unwrap(identity: &Identity, stanza: &Stanza) -> Result<FileKey, age::DecryptError> {
+        age::Identity::unwrap_stanza(identity, stanza).expect("the stanza is ours")
+    }
+
+    #[test]
+    fn stanzas_round_trip() {
+        let recipient = Recipient::new(passphrase("correct horse"), MIN_COST).unwrap();
+        let stanza = wrap(&recipient.beside_recipients());
+        assert_eq!(stanza.tag, PASSPHRASE_TAG);
+        assert_eq!(stanza.args[1], MIN_COST.to_string());
+        let file_key = unwrap(&Identity::new(passphrase("correct horse")), &stanza).unwrap();
+        assert_eq!(file_key.expose_secret(), &KEY);
+    }
+
+    #[test]
+    fn scrypt_stanzas_need_with_scrypt() {
+        let stanza = wrap(&Recipient::new(passphrase("pw"), MIN_COST).unwrap());
+        assert_eq!(stanza.tag, "scrypt");
+        assert!(age::Identity::unwrap_stanza(&Identity::new(passphrase("pw")), &stanza).is_none());
+        let identity = Identity::new(passphrase("pw")).with_scrypt();
+        assert_eq!(unwrap(&identity, &stanza).unwrap().expose_secret(), &KEY);
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_to_decrypt() {
+        let stanza = wrap(&Recipient::new(passphrase("right"), MIN_COST).unwrap());
+        let identity = Identity::new(passphrase("wrong")).with_scrypt();
+        assert!(matches!(
+            unwrap(&identity, &stanza),
+            Err(age::DecryptError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn tampered_work_factors_are_refused() {
+        let stanza = wrap(&Recipient::new(passphrase("pw"), MIN_COST).unwrap());
+        let identity = Identity::new(passphrase("pw")).with_scrypt();
+        let with_log_n = |log_n: &str| Stanza {
+            args: vec![stanza.args[0].clone(), log_n.to_owned()],
+            ..stanza.clone()
+        };
+        // Another cost derives another key
+        let lower = with_log_n(&(MIN_COST - 1).to_string());
+        assert!(matches!(
+            unwrap(&identity, &lower),
+            Err(age::DecryptError::DecryptionFailed)
+        ));
+        let huge = with_log_n(&(MAX_COST + 1).to_string());
+        assert!(matches!(
+            unwrap(&identity, &huge),
+            Err(age::DecryptError::ExcessiveWork { required, target: MAX_COST })
+                if required == MAX_COST + 1
+        ));
+        for bad in ["", "ten", "-1", "300"] {
+            let bad = with_log_n(bad);
+            assert!(matches!(
+                unwrap(&identity, &bad),
+                Err(age::DecryptError::InvalidHeader)
+            ));
+        }
+    }
+
+    #[test]
+    fn costs_out_of_range_are_refused() {
+        assert!(Recipient::new(passphrase("pw"), MIN_COST - 1).is_err());
+        assert!(Recipient::new(passphrase("pw"), MAX_COST + 1).is_err());
+    }
+
+    /// `plain` encrypted to `recipient` with the age crate
+    fn encrypt(recipient: Box<dyn age::Recipient + Send>, plain: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let encryptor = age::Encryptor::with_recipients(vec![recipient]).unwrap();
+        let mut w = encryptor.wrap_output(&mut out).unwrap();
+        w.write_all(plain).unwrap();
+        w.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn age_opens_our_scrypt_stanzas() {
+        let recipient = Recipient::new(passphrase("interop"), MIN_COST).unwrap();
+        let archive = encrypt(Box::new(recipient), b"hello");
+        let age::Decryptor::Passphrase(decryptor) = age::Decryptor::new(&archive[..]).unwrap()
+        else {
+            panic!("age reads a lone scrypt stanza as a passphrase archive");
+        };
+        let mut plain = Vec::new();
+        let mut r = decryptor
+            .decrypt(&passphrase("interop"), Some(MAX_COST))
+            .unwrap();
+        r.read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, b"hello");
+    }
+
+    #[test]
+    fn passphrases_beside_recipients_open_through_age() {
+        let x25519 = age::x25519::Identity::generate();
+        let passphrase_recipient = Recipient::new(passphrase("beside"), MIN_COST).unwrap();
+        let mut out = Vec::new();
+        let recipients: Vec<Box<dyn age::Recipient + Send>> = vec![
+            Box::new(x25519.to_public()),
+            Box::new(passphrase_recipient.beside_recipients()),
+        ];
+        let encryptor = age::Encryptor::with_recipients(recipients).unwrap();
+        let mut w = encryptor.wrap_output(&mut out).unwrap();
+        w.write_all(b"hello").unwrap();
+        w.finish().unwrap();
+
+        let identity = Identity::new(passphrase("beside"));
+        let identities: [&dyn age::Identity; 2] = [&identity, &x25519];
+        for identity in identities {
+            let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(&out[..]).unwrap()
+            else {
+                panic!("age reads stanzas beside recipients as a recipients archive");
+            };
+            let mut plain = Vec::new();
+            let mut r = decryptor.decrypt(std::iter::once(identity)).unwrap();
+            r.read_to_end(&mut plain).unwrap();
+            assert_eq!(plain, b"hello");
+        }
+        let wrong = Identity::new(passphrase("wrong"));
+        let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(&out[..]).unwrap() else {
+            panic!("a recipients archive");
+        };
+        let result = decryptor.decrypt(std::iter::once(&wrong as &dyn age::Identity));
+        assert!(matches!(result, Err(age::DecryptError::DecryptionFailed)));
+    }
+}
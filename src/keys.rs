@@ -0,0 +1,180 @@
+//! Parsing age recipients and loading identities, the same inputs `age -r/-R/-i` accept
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, Result};
+
+use crate::{agent, passphrase, shares};
+
+/// Ask for an X25519 identity on the terminal, for archives opened without `-i`
+///
+/// One cached by the agent is used instead, and a typed one is handed to it.
+pub fn prompt_identity() -> Result<Box<dyn age::Identity + Send>> {
+    if let Some(key) = agent::get(agent::IDENTITY) {
+        if let Ok(identity) = key.expose_secret().parse::<age::x25519::Identity>() {
+            log::info!("Using the identity cached by the agent");
+            return Ok(Box::new(identity));
+        }
+    }
+    let key = passphrase::prompt("Enter age identity (AGE-SECRET-KEY-..., input hidden):")
+        .context("failed to read identity")?;
+    let key = key.expose_secret();
+    let identity = key
+        .trim()
+        .parse::<age::x25519::Identity>()
+        .map_err(|e| anyhow::anyhow!("invalid age identity: {}", e))?;
+    agent::put(agent::IDENTITY, &SecretString::new(key.trim().to_owned()));
+    Ok(Box::new(identity))
+}
+
+/// Parse `age1...`, plugin (`age1yubikey1...`) or `ssh-ed25519`/`ssh-rsa` recipient strings
+/// into boxed age recipients
+///
+/// Plugin recipients are wrapped by the matching `age-plugin-NAME` binary from `PATH`
+/// (not in the WebAssembly build).
+pub fn parse_recipients(recipients: &[String]) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+    let mut parsed: Vec<Box<dyn age::Recipient + Send>> = Vec::new();
+    // All recipients of one plugin go to a single plugin process
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut plugins: BTreeMap<String, Vec<age::plugin::Recipient>> = BTreeMap::new();
+    for r in recipients {
+        if let Ok(r) = r.parse::<age::x25519::Recipient>() {
+            parsed.push(Box::new(r));
+            continue;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Ok(r) = r.parse::<age::plugin::Recipient>() {
+            plugins.entry(r.plugin().to_owned()).or_default().push(r);
+            continue;
+        }
+        match r.parse::<age::ssh::Recipient>() {
+            Ok(r) => parsed.push(Box::new(r)),
+            Err(age::ssh::ParseRecipientKeyError::Unsupported(key_type)) => {
+                anyhow::bail!("unsupported SSH key type '{}' in recipient", key_type)
+            }
+            Err(_) => anyhow::bail!("invalid recipient '{}'", r),
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    for (name, recipients) in plugins {
+        let plugin = age::plugin::RecipientPluginV1::new(&name, &recipients, &[], TermCallbacks)
+            .with_context(|| format!("failed to start age-plugin-{}", name))?;
+        parsed.push(Box::new(plugin));
+    }
+    Ok(parsed)
+}
+
+/// Read recipient lines from a file, skipping blanks and `#` comments (like `age -R`)
+pub fn read_recipients_file(file: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("failed to read recipients file {}", file.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Load every identity from the given age or SSH identity files, like `age -i`
+///
+/// File contents are wiped from memory once parsed; the identities wipe themselves on drop.
+/// Plugin identities (`AGE-PLUGIN-YUBIKEY-...`) are unwrapped by their `age-plugin-NAME`
+/// binary, which may ask for a PIN or a touch. Key share files (`encrypt --split-key`) are
+/// pooled into one identity that opens the archive once enough of them are given.
+pub fn read_identities(files: &[PathBuf]) -> Result<Vec<Box<dyn age::Identity + Send>>> {
+    let mut identities: Vec<Box<dyn age::Identity + Send>> = Vec::new();
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut plugins: BTreeMap<String, Vec<age::plugin::Identity>> = BTreeMap::new();
+    let mut shares: Option<shares::Identity> = None;
+    for file in files {
+        let contents = std::fs::read_to_string(file)
+            .map(Zeroizing::new)
+            .with_context(|| format!("failed to read identity file {}", file.display()))?;
+        if shares::is_share_file(&contents) {
+            shares
+                .get_or_insert_with(Default::default)
+                .add(&contents)
+                .with_context(|| format!("failed to read share file {}", file.display()))?;
+            continue;
+        }
+        if contents.starts_with("-----BEGIN") {
+            identities.push(read_ssh_identity(file, &contents)?);
+            continue;
+        }
+
+        let identity_file = age::IdentityFile::from_file(file.to_string_lossy().into_owned())
+            .with_context(|| format!("failed to read identity file {}", file.display()))?;
+        let entries = identity_file.into_identities();
+        if entries.is_empty() {
+            anyhow::bail!("identity file {} contains no identities", file.display());
+        }
+        for entry in entries {
+            match entry {
+                age::IdentityFileEntry::Native(identity) => identities.push(Box::new(identity)),
+                #[cfg(not(target_arch = "wasm32"))]
+                age::IdentityFileEntry::Plugin(identity) => plugins
+                    .entry(identity.plugin().to_owned())
+                    .or_default()
+                    .push(identity),
+            }
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    for (name, plugin_identities) in plugins {
+        let plugin = age::plugin::IdentityPluginV1::new(&name, &plugin_identities, TermCallbacks)
+            .with_context(|| format!("failed to start age-plugin-{}", name))?;
+        identities.push(Box::new(plugin));
+    }
+    if let Some(shares) = shares {
+        identities.push(Box::new(shares));
+    }
+    Ok(identities)
+}
+
+/// Parse an OpenSSH private key; encrypted keys prompt for their passphrase on use
+fn read_ssh_identity(file: &Path, contents: &str) -> Result<Box<dyn age::Identity + Send>> {
+    let filename = file.to_string_lossy().into_owned();
+    let identity = age::ssh::Identity::from_buffer(contents.as_bytes(), Some(filename))
+        .with_context(|| format!("failed to parse SSH key {}", file.display()))?;
+    match identity {
+        age::ssh::Identity::Unencrypted(_) => Ok(Box::new(identity)),
+        age::ssh::Identity::Encrypted(_) => Ok(Box::new(identity.with_callbacks(TermCallbacks))),
+        age::ssh::Identity::Unsupported(k) => {
+            anyhow::bail!("unsupported SSH key {}: {:?}", file.display(), k)
+        }
+    }
+}
+
+/// Terminal callbacks used by age when an identity needs user interaction
+#[derive(Clone, Copy)]
+struct TermCallbacks;
+
+impl age::Callbacks for TermCallbacks {
+    fn display_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        eprintln!("{} [{}/{}]", message, yes_string, no_string.unwrap_or("no"));
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).ok()?;
+        Some(answer.trim().eq_ignore_ascii_case(yes_string))
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        eprintln!("{}", description);
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).ok()?;
+        Some(answer.trim().to_string())
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<age::secrecy::SecretString> {
+        passphrase::prompt(&format!("{} (input hidden):", description)).ok()
+    }
+}
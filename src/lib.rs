@@ -0,0 +1,63 @@
+//! Package folders as compressed tar streams and encrypt them with age
+//!
+//! [`Locker`] writes an archive and [`Unlocker`] reads one back; the modules underneath
+//! (filters, packing, extraction, checksums, snapshots) are public for finer control.
+//! The `folder_lock_rs` binary is a thin command-line layer over this crate. With the
+//! `async` feature, [`async_io`] adapts both ends to tokio's `AsyncRead`/`AsyncWrite`;
+//! [`ffi`] exposes them to C (`include/folder_lock.h`), and the `python` feature to Python.
+//! Built for `wasm32-unknown-unknown`, `wasm` lists and decrypts archives in a browser.
+
+pub mod agent;
+#[cfg(feature = "async")]
+pub mod async_io;
+#[cfg(feature = "tui")]
+pub mod browse;
+mod checkpoint;
+pub mod checksum;
+pub mod compression;
+pub mod container;
+pub mod diff;
+pub mod envelope;
+pub mod extract;
+pub mod failure;
+pub mod ffi;
+pub mod header;
+pub mod kdf;
+pub mod keys;
+mod locker;
+pub mod merge;
+#[cfg(all(feature = "fuse", unix))]
+pub mod mount;
+pub mod names;
+pub mod pack;
+pub mod padding;
+pub mod paper;
+pub mod passphrase;
+pub mod pinentry;
+pub mod progress;
+pub mod prune;
+#[cfg(feature = "python")]
+mod python;
+pub mod repo;
+pub mod report;
+mod rewrite;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod shares;
+pub mod shred;
+pub mod signature;
+pub mod snapshot;
+#[cfg(unix)]
+pub mod socket;
+mod sparse;
+pub mod streams;
+pub mod tpm;
+pub mod walk;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+pub mod watch;
+
+pub use compression::Algorithm;
+pub use locker::{decrypt, open_archive, Key, KeyKind, Locked, Locker, Unlocker};
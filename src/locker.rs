@@ -0,0 +1,711 @@
+//! Builder-style entry points for encrypting and decrypting folders from other programs
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use tar::Builder;
+
+use crate::checkpoint;
+use crate::compression::{self, Algorithm, Settings};
+use crate::container::{self, Container};
+use crate::diff::{Change, Difference};
+use crate::envelope;
+use crate::extract::{self, ExtractOptions, PathFilter};
+use crate::failure::{Classify, Failure};
+use crate::header::{Header, HEADER_PATH};
+use crate::kdf;
+use crate::merge::Plan;
+use crate::pack::{self, PackOptions, Sources};
+use crate::padding::{self, DrainAfter, Tally};
+use crate::progress::ProgressWriter;
+use crate::report::Stats;
+use crate::rewrite::{self, Selection};
+use crate::snapshot::Snapshot;
+use crate::streams::CountingWriter;
+use crate::walk::Filters;
+
+/// A secret that opens an archive
+///
+/// Identities are `Send` so an `Unlocker` can move to a worker thread. Both kinds wipe
+/// their key material from memory when dropped, clones included.
+pub enum Key {
+    Passphrase(SecretString),
+    Identities(Vec<Box<dyn age::Identity + Send>>),
+}
+
+/// Which kind of `Key` an archive's header asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Passphrase,
+    Identities,
+    /// Encrypted to recipients and a passphrase (`Locker::passphrase_and_recipients`):
+    /// either key opens it
+    Either,
+}
+
+/// Encrypts a folder (or files) into an age-wrapped compressed tar stream
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use folder_lock::{Algorithm, Locker};
+///
+/// let recipient: age::x25519::Recipient = "age1...".parse().map_err(anyhow::Error::msg)?;
+/// let out = std::fs::File::create("backup.age")?;
+/// Locker::new("photos")
+///     .compression(Algorithm::Zstd)
+///     .recipients(vec![Box::new(recipient)])
+///     .encrypt_to(out)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Locker {
+    paths: Vec<PathBuf>,
+    key: Option<Encryption>,
+    options: PackOptions,
+    compression: Settings,
+    progress: ProgressBar,
+    raw: bool,
+    armor: bool,
+    pad_to: Option<u64>,
+    kdf_cost: Option<u8>,
+    remove: Option<PathFilter>,
+}
+
+enum Encryption {
+    Passphrase(SecretString),
+    Recipients(Vec<Box<dyn age::Recipient + Send>>),
+    Both(SecretString, Vec<Box<dyn age::Recipient + Send>>),
+}
+
+/// What goes into the tar archive between its header and the end
+enum Body<'a> {
+    /// The `Locker`'s paths
+    Sources,
+    /// An existing archive, updated by `changes` if given (`append_to`, `update_to`)
+    Rewrite(
+        &'a mut tar::Archive<Box<dyn Read>>,
+        Option<&'a [Difference]>,
+    ),
+    Merge(Vec<tar::Archive<Box<dyn Read>>>, &'a Plan),
+}
+
+/// What `Locker::encrypt_to` stored
+pub struct Locked {
+    pub stats: Stats,
+    /// State of every selected entry, usable as the base of a later incremental archive;
+    /// empty unless `PackOptions::snapshot` or `base` was set
+    pub snapshot: Snapshot,
+}
+
+impl Locker {
+    /// Archive a folder's contents, or a single file under its own name
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::with_paths(vec![path.into()])
+    }
+
+    /// Archive several files and folders, each under its own name (see `Sources::new`)
+    pub fn with_paths(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            key: None,
+            options: PackOptions::default(),
+            compression: Settings::default(),
+            progress: ProgressBar::hidden(),
+            raw: false,
+            armor: false,
+            pad_to: None,
+            kdf_cost: None,
+            remove: None,
+        }
+    }
+
+    /// Encrypt with a passphrase (scrypt); replaces any recipients
+    pub fn passphrase(mut self, passphrase: SecretString) -> Self {
+        self.key = Some(Encryption::Passphrase(passphrase));
+        self
+    }
+
+    /// log2 of the scrypt work factor for a passphrase, instead of age's calibration for
+    /// this machine (see `kdf`)
+    pub fn kdf_cost(mut self, log_n: u8) -> Self {
+        self.kdf_cost = Some(log_n);
+        self
+    }
+
+    /// Encrypt to age or SSH public keys; replaces any passphrase
+    pub fn recipients(mut self, recipients: Vec<Box<dyn age::Recipient + Send>>) -> Self {
+        self.key = Some(Encryption::Recipients(recipients));
+        self
+    }
+
+    /// Encrypt to recipients, and let a passphrase open the archive too
+    ///
+    /// The passphrase goes into a `kdf::PASSPHRASE_TAG` stanza at `kdf_cost`, or
+    /// `kdf::DEFAULT_COST`: `age -d` opens such archives with an identity only.
+    pub fn passphrase_and_recipients(
+        mut self,
+        passphrase: SecretString,
+        recipients: Vec<Box<dyn age::Recipient + Send>>,
+    ) -> Self {
+        self.key = Some(Encryption::Both(passphrase, recipients));
+        self
+    }
+
+    /// Compression algorithm, at its default level
+    pub fn compression(mut self, algorithm: Algorithm) -> Self {
+        self.compression.algorithm = algorithm;
+        self.compression.level = None;
+        self
+    }
+
+    /// Compression level, checked against the algorithm's range by `encrypt_to`
+    pub fn level(mut self, level: i32) -> Self {
+        self.compression.level = Some(level);
+        self
+    }
+
+    /// Worker threads for zstd and xz
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.compression.threads = threads;
+        self
+    }
+
+    pub fn compression_settings(mut self, settings: Settings) -> Self {
+        self.compression = settings;
+        self
+    }
+
+    /// Which entries of the folder are archived; the default takes everything
+    pub fn filters(mut self, filters: Filters) -> Self {
+        self.options.filters = filters;
+        self
+    }
+
+    /// Name the archive; the label is stored encrypted in its header, like `meta` pairs
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.options.label = Some(label.into());
+        self
+    }
+
+    /// Store a free-form `key`/`value` pair in the archive's header, replacing any value
+    /// `key` had
+    pub fn meta(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.meta.insert(key.into(), value.into());
+        self
+    }
+
+    /// Every packing option at once, including filters
+    pub fn options(mut self, options: PackOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Advance `bar` by the uncompressed tar bytes written (hidden by default)
+    pub fn progress(mut self, bar: ProgressBar) -> Self {
+        self.progress = bar;
+        self
+    }
+
+    /// Encrypt a single file's bytes as they are, without tar or compression
+    ///
+    /// The result is a plain age file that `age -d` decrypts to the original. Packing and
+    /// compression options are ignored.
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.raw = raw;
+        self
+    }
+
+    /// Write age's ASCII armor (`-----BEGIN AGE ENCRYPTED FILE-----`) instead of binary
+    pub fn armor(mut self, armor: bool) -> Self {
+        self.armor = armor;
+        self
+    }
+
+    /// Pad the binary age file to a multiple of `granularity` bytes, hiding its exact
+    /// length (see `padding`); not available for raw or zip archives
+    pub fn pad_to(mut self, granularity: u64) -> Self {
+        self.pad_to = Some(granularity);
+        self
+    }
+
+    /// Leave out the entries of the existing archive given to `append_to` that `filter`
+    /// matches, folders with everything in them; at least one entry must match
+    pub fn remove(mut self, filter: PathFilter) -> Self {
+        self.remove = Some(filter);
+        self
+    }
+
+    /// Write the encrypted archive to `w`
+    ///
+    /// Every layer is finished before returning, but `w` itself is not flushed.
+    pub fn encrypt_to<W: Write>(self, w: W) -> Result<Locked> {
+        self.write(w, Body::Sources)
+    }
+
+    /// Write the encrypted archive to the file `out`, or to `split_size`-byte volumes of
+    /// it, keeping `checkpoint` up to date so that a run cut short continues where it
+    /// stopped when called again with the same arguments (see `checkpoint`)
+    ///
+    /// Only passphrase archives compressed with zstd or not at all can be resumed, and
+    /// entries are walked in name order. The output appears under its real name when done.
+    pub fn encrypt_resumable(
+        mut self,
+        out: &Path,
+        split_size: Option<u64>,
+        force: bool,
+        checkpoint: &Path,
+    ) -> Result<Locked> {
+        if self.raw || self.armor || self.pad_to.is_some() {
+            anyhow::bail!("raw, armored and padded archives can't be resumed");
+        }
+        if self.options.container == Container::Zip
+            || self.options.base.is_some()
+            || self.options.snapshot
+        {
+            anyhow::bail!("only plain, non-incremental tar archives can be resumed");
+        }
+        self.compression
+            .algorithm
+            .check_level(self.compression.level)?;
+        if self.compression.threads == 0 {
+            anyhow::bail!("compression threads must be at least 1");
+        }
+        let log_n = self.kdf_cost.unwrap_or(kdf::DEFAULT_COST);
+        let (passphrase, mut recipients) = match self.key {
+            Some(Encryption::Passphrase(passphrase)) => (passphrase, Vec::new()),
+            Some(Encryption::Both(_, recipients)) if recipients.is_empty() => {
+                anyhow::bail!("no recipients given")
+            }
+            Some(Encryption::Both(passphrase, recipients)) => (passphrase, recipients),
+            Some(Encryption::Recipients(_)) => {
+                anyhow::bail!("resuming opens the partial archive again, so it needs a passphrase")
+            }
+            None => anyhow::bail!("no passphrase or recipients to encrypt to"),
+        };
+        let copy = SecretString::new(passphrase.expose_secret().clone());
+        let stanza = kdf::Recipient::new(copy, log_n)?;
+        let stanza = if recipients.is_empty() {
+            stanza
+        } else {
+            stanza.beside_recipients()
+        };
+        recipients.push(Box::new(stanza));
+        // A rerun must visit entries in the same order to know which are stored
+        self.options.filters.sorted = true;
+        let job = checkpoint::Job {
+            sources: Sources::new(self.paths)?,
+            options: &self.options,
+            compression: self.compression,
+            progress: &self.progress,
+            passphrase,
+            recipients,
+        };
+        let stats = checkpoint::encrypt(job, out, split_size, force, checkpoint)?;
+        Ok(Locked {
+            stats,
+            snapshot: Snapshot::default(),
+        })
+    }
+
+    /// Write a new archive to `w` holding the entries of `existing`, then the paths, each
+    /// under its own name; a path replaces an existing entry of the same name
+    ///
+    /// With no paths, the result is `existing` without what `remove` matches.
+    ///
+    /// `existing` is read to the end and checked against its manifest, but only while `w`
+    /// is being written: discard the output if this fails. See `rewrite`.
+    pub fn append_to<W: Write>(
+        self,
+        mut existing: tar::Archive<Box<dyn Read>>,
+        w: W,
+    ) -> Result<Locked> {
+        if self.raw || self.options.container == Container::Zip || self.options.base.is_some() {
+            anyhow::bail!("entries can only be appended to plain tar archives");
+        }
+        if self.paths.is_empty() && self.remove.is_none() {
+            anyhow::bail!("nothing to add or remove");
+        }
+        self.write(w, Body::Rewrite(&mut existing, None))
+    }
+
+    /// Write a new archive to `w` from `existing`, which was made from this folder: what
+    /// `changes` lists as added or modified is stored again, what it lists as removed is
+    /// left out, and everything else is copied
+    ///
+    /// `changes` is `diff::diff` of `existing` against the folder; leave out its `Removed`
+    /// entries to keep files that were deleted since. Discard the output on failure, as with
+    /// `append_to`.
+    pub fn update_to<W: Write>(
+        self,
+        mut existing: tar::Archive<Box<dyn Read>>,
+        changes: &[Difference],
+        w: W,
+    ) -> Result<Locked> {
+        if self.raw || self.options.container == Container::Zip || self.options.base.is_some() {
+            anyhow::bail!("only plain tar archives can be updated");
+        }
+        self.write(w, Body::Rewrite(&mut existing, Some(changes)))
+    }
+
+    /// Write one archive to `w` from the entries of `archives`, which `plan` was made for
+    ///
+    /// Paths given to the `Locker` are ignored. Discard the output on failure, as with
+    /// `append_to`.
+    pub fn merge_to<W: Write>(
+        self,
+        archives: Vec<tar::Archive<Box<dyn Read>>>,
+        plan: &Plan,
+        w: W,
+    ) -> Result<Locked> {
+        if self.raw || self.options.container == Container::Zip || self.options.base.is_some() {
+            anyhow::bail!("archives can only be merged into a plain tar archive");
+        }
+        if archives.len() != plan.len() {
+            anyhow::bail!(
+                "the merge plan is for {} archives, not {}",
+                plan.len(),
+                archives.len()
+            );
+        }
+        self.write(w, Body::Merge(archives, plan))
+    }
+
+    fn write<W: Write>(self, w: W, body: Body) -> Result<Locked> {
+        let sources = match &body {
+            Body::Rewrite(_, Some(_)) => match Sources::new(self.paths)? {
+                folder @ Sources::Folder(_) => folder,
+                Sources::Named(_) => anyhow::bail!("an archive is updated from a single folder"),
+            },
+            Body::Rewrite(..) if !self.paths.is_empty() => Sources::named(self.paths)?,
+            Body::Rewrite(..) | Body::Merge(..) => Sources::Named(Vec::new()),
+            Body::Sources => Sources::new(self.paths)?,
+        };
+        if self.raw && !matches!(&sources, Sources::Named(paths) if paths.len() == 1) {
+            anyhow::bail!("raw mode encrypts exactly one file");
+        }
+        self.compression
+            .algorithm
+            .check_level(self.compression.level)?;
+        if self.compression.threads == 0 {
+            anyhow::bail!("compression threads must be at least 1");
+        }
+        match self.pad_to {
+            Some(0) => anyhow::bail!("the padding granularity must be at least 1 byte"),
+            Some(_) if self.raw || self.options.container == Container::Zip => {
+                anyhow::bail!("padding is only supported for tar archives")
+            }
+            _ => {}
+        }
+        let encryptor = match self.key {
+            Some(Encryption::Passphrase(passphrase)) => match self.kdf_cost {
+                Some(log_n) => {
+                    let recipient: Box<dyn age::Recipient + Send> =
+                        Box::new(kdf::Recipient::new(passphrase, log_n)?);
+                    age::Encryptor::with_recipients(vec![recipient])
+                        .context("no recipients given")?
+                }
+                None => age::Encryptor::with_user_passphrase(passphrase),
+            },
+            Some(Encryption::Recipients(recipients)) => {
+                age::Encryptor::with_recipients(recipients).context("no recipients given")?
+            }
+            Some(Encryption::Both(_, recipients)) if recipients.is_empty() => {
+                anyhow::bail!("no recipients given")
+            }
+            Some(Encryption::Both(passphrase, mut recipients)) => {
+                let log_n = self.kdf_cost.unwrap_or(kdf::DEFAULT_COST);
+                recipients.push(Box::new(
+                    kdf::Recipient::new(passphrase, log_n)?.beside_recipients(),
+                ));
+                age::Encryptor::with_recipients(recipients).context("no recipients given")?
+            }
+            None => anyhow::bail!("no passphrase or recipients to encrypt to"),
+        };
+
+        let format = if self.armor {
+            Format::AsciiArmor
+        } else {
+            Format::Binary
+        };
+        let armored = ArmoredWriter::wrap_output(w, format).context("failed to create armor")?;
+        // Counted below the armor, so padding applies to the binary length
+        let written = Rc::new(Cell::new(0));
+        let mut age_writer = encryptor
+            .wrap_output(Tally::new(armored, written.clone()))
+            .context("failed to create age encrypting writer")?;
+        // The age header and payload nonce are written up front
+        let overhead = written.get();
+        if let (true, Sources::Named(files)) = (self.raw, &sources) {
+            let mut f = File::open(&files[0])
+                .with_context(|| format!("failed to open '{}'", files[0].display()))?;
+            let mut writer = ProgressWriter::new(&mut age_writer, self.progress.clone());
+            let bytes = io::copy(&mut f, &mut writer)
+                .with_context(|| format!("failed to encrypt '{}'", files[0].display()))?;
+            age_writer
+                .finish()
+                .and_then(|w| w.into_inner().finish())
+                .context("failed to finalize age writer")?;
+            return Ok(Locked {
+                stats: Stats { files: 1, bytes },
+                snapshot: Snapshot::default(),
+            });
+        }
+        let mut header = Header::new(
+            &self.compression,
+            self.options.container,
+            self.options.base.is_some(),
+        );
+        header.label = self.options.label.clone();
+        header.meta = self.options.meta.clone();
+        if self.options.container == Container::Zip {
+            let stats = container::write_zip(
+                &mut age_writer,
+                &sources,
+                &self.options,
+                &header,
+                &self.progress,
+            )?;
+            age_writer
+                .finish()
+                .and_then(|w| w.into_inner().finish())
+                .context("failed to finalize age writer")?;
+            return Ok(Locked {
+                stats,
+                snapshot: Snapshot::default(),
+            });
+        }
+        // tar → progress → compressor → age → w
+        let encoder =
+            compression::Encoder::new(&self.compression, CountingWriter::new(&mut age_writer))
+                .context("failed to create compressor")?;
+        let mut tar = Builder::new(ProgressWriter::new(encoder, self.progress.clone()));
+        pack::append_metadata_file(&mut tar, HEADER_PATH, &header.to_json(), &self.options)
+            .context("failed to add header to tar archive")?;
+        let (stats, snapshot) = match body {
+            Body::Rewrite(old, changes) => {
+                let mut selection = Selection {
+                    remove: self.remove.as_ref(),
+                    ..Selection::default()
+                };
+                if let Some(changes) = changes {
+                    let (deleted, stored): (Vec<_>, Vec<_>) =
+                        changes.iter().partition(|c| c.change == Change::Removed);
+                    selection.deleted = deleted.into_iter().map(|c| c.path.clone()).collect();
+                    selection.only = Some(stored.into_iter().map(|c| c.path.clone()).collect());
+                }
+                rewrite::append(
+                    &mut tar,
+                    old,
+                    &selection,
+                    &sources,
+                    &self.options,
+                    &self.progress,
+                )?
+            }
+            Body::Merge(archives, plan) => (
+                rewrite::merge(&mut tar, archives, plan, &self.options)?,
+                Snapshot::default(),
+            ),
+            Body::Sources => {
+                pack::append_sources(&mut tar, &sources, &self.options, &self.progress)?
+            }
+        };
+
+        // Finish inside out, so each trailer reaches the layer below it
+        let encoder = tar.into_inner().context("failed to finalize tar archive")?;
+        let plain = encoder
+            .into_inner()
+            .finish()
+            .context("failed to finalize compression")?
+            .count();
+        if let Some(granularity) = self.pad_to {
+            let algorithm = self.compression.algorithm;
+            let len = padding::padding_len(overhead, plain, granularity, algorithm);
+            padding::write_padding(&mut age_writer, algorithm, len)
+                .context("failed to write padding")?;
+        }
+        age_writer
+            .finish()
+            .and_then(|w| w.into_inner().finish())
+            .context("failed to finalize age writer")?;
+        Ok(Locked { stats, snapshot })
+    }
+}
+
+/// Decrypts an archive from a reader, into a folder or as a tar stream
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use age::secrecy::SecretString;
+/// use folder_lock::Unlocker;
+///
+/// let input = std::fs::File::open("backup.age")?;
+/// Unlocker::new(input)
+///     .passphrase(SecretString::new("correct horse".into()))
+///     .extract_to("restored")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Unlocker<R> {
+    reader: R,
+    key: Option<Key>,
+    options: ExtractOptions,
+    progress: ProgressBar,
+}
+
+impl<R: Read + 'static> Unlocker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            key: None,
+            options: ExtractOptions::default(),
+            progress: ProgressBar::hidden(),
+        }
+    }
+
+    pub fn passphrase(mut self, passphrase: SecretString) -> Self {
+        self.key = Some(Key::Passphrase(passphrase));
+        self
+    }
+
+    pub fn identities(mut self, identities: Vec<Box<dyn age::Identity + Send>>) -> Self {
+        self.key = Some(Key::Identities(identities));
+        self
+    }
+
+    /// Which entries are restored and how; the default restores everything, never overwriting
+    pub fn options(mut self, options: ExtractOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Advance `bar` by the restored file count (hidden by default)
+    pub fn progress(mut self, bar: ProgressBar) -> Self {
+        self.progress = bar;
+        self
+    }
+
+    /// Decrypt and decompress, returning the tar archive for custom processing
+    pub fn archive(self) -> Result<tar::Archive<Box<dyn Read>>> {
+        let key = self
+            .key
+            .context("no passphrase or identities to decrypt with")?;
+        // `decrypt` reports a key of the wrong kind for this archive
+        let plain = decrypt(BufReader::new(self.reader), move |_| Ok(key))?;
+        open_archive(plain)
+    }
+
+    /// Restore the archive into the existing folder `out_folder`
+    pub fn extract_to(self, out_folder: impl AsRef<Path>) -> Result<Stats> {
+        let options = self.options.clone();
+        options.check_privileges()?;
+        let bar = self.progress.clone();
+        let mut archive = self.archive()?;
+        extract::extract(&mut archive, out_folder.as_ref(), &options, &bar)
+    }
+}
+
+/// Open the age stream `r`, calling `key` for the secret once the header says which kind
+///
+/// Works for passphrase archives and recipient archives alike, so callers can prompt for
+/// only what is needed. ASCII-armored input is recognised and decoded on the fly.
+/// `KeyKind::Either` is only reported when the whole header fits in `r`'s buffer; it
+/// always does for stanzas written here, and otherwise an identity is asked for.
+pub fn decrypt<R: BufRead + 'static>(
+    mut r: R,
+    key: impl FnOnce(KeyKind) -> Result<Key>,
+) -> Result<Box<dyn Read>> {
+    let head = r
+        .fill_buf()
+        .classify(Failure::Corrupted, "not an age file")?;
+    let either = envelope::read(head).is_ok_and(|e| e.key_kind() == KeyKind::Either);
+    let decryptor = age::Decryptor::new(ArmoredReader::new(r))
+        .classify(Failure::Corrupted, "not an age file")?;
+    let plain: Box<dyn Read> = match decryptor {
+        age::Decryptor::Recipients(dec) => {
+            let kind = if either {
+                KeyKind::Either
+            } else {
+                KeyKind::Identities
+            };
+            let identities: Vec<Box<dyn age::Identity + Send>> = match key(kind)? {
+                Key::Identities(identities) => identities,
+                Key::Passphrase(passphrase) if either => {
+                    let identity = kdf::Identity::new(passphrase);
+                    let identity = &identity as &dyn age::Identity;
+                    let plain = classify_decrypt(
+                        dec.decrypt(std::iter::once(identity)),
+                        "failed to decrypt: wrong passphrase?",
+                    )?;
+                    return Ok(Box::new(plain));
+                }
+                Key::Passphrase(_) => {
+                    return Err(Failure::WrongKey
+                        .error("archive is encrypted to recipients; an identity is needed"))
+                }
+            };
+            Box::new(classify_decrypt(
+                dec.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity)),
+                "failed to decrypt: no identity matched any recipient",
+            )?)
+        }
+        age::Decryptor::Passphrase(dec) => {
+            let Key::Passphrase(passphrase) = key(KeyKind::Passphrase)? else {
+                return Err(Failure::WrongKey
+                    .error("archive is passphrase-encrypted; a passphrase is needed"));
+            };
+            // Accept every cost `--kdf-cost` can write, whatever age calibrates for here
+            Box::new(classify_decrypt(
+                dec.decrypt(&passphrase, Some(kdf::MAX_COST)),
+                "failed to decrypt: wrong passphrase?",
+            )?)
+        }
+    };
+    Ok(plain)
+}
+
+/// Mark a failed `decrypt` with `wrong_key` as `Failure::WrongKey` only if the key didn't
+/// open the header; a malformed header, a bad MAC or an excessive work factor is
+/// `Failure::Corrupted`, so no other key would help
+fn classify_decrypt<T>(result: Result<T, age::DecryptError>, wrong_key: &str) -> Result<T> {
+    match result {
+        Err(e @ (age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys)) => {
+            Err(e).classify(Failure::WrongKey, wrong_key)
+        }
+        result => result.classify(Failure::Corrupted, "failed to decrypt"),
+    }
+}
+
+/// Wrap a decrypted stream in a tar reader, detecting its compression and container
+///
+/// gzip, zstd, xz and uncompressed streams are told apart by their magic bytes, then tar
+/// by its header checksum. Zip payloads (`Container::Zip`, or any zip encrypted with age)
+/// are converted to tar on the fly, see `container::zip_as_tar`.
+/// Anything after the compressed stream (the padding of `Locker::pad_to`) is read, and so
+/// authenticated, once the stream ends.
+pub fn open_archive(plain: Box<dyn Read>) -> Result<tar::Archive<Box<dyn Read>>> {
+    let decoder = DrainAfter::new(plain, |plain| compression::decoder(BufReader::new(plain)))
+        .classify(Failure::Corrupted, "failed to read compressed stream")?;
+    let mut decoded = BufReader::new(decoder);
+    let head = decoded
+        .fill_buf()
+        .classify(Failure::Corrupted, "failed to read compressed stream")?;
+    let inner: Box<dyn Read> = match container::detect(head) {
+        Some(Container::Tar) => Box::new(decoded),
+        Some(Container::Zip) => container::zip_as_tar(decoded)?,
+        None => {
+            return Err(Failure::Corrupted.error(
+                "decrypted data is neither a tar nor a zip archive (made with --raw? then use \
+                 `decrypt --raw`)",
+            ))
+        }
+    };
+    Ok(tar::Archive::new(inner))
+}
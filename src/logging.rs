@@ -0,0 +1,107 @@
+//! Status and per-file logging to stderr and an optional log file
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use folder_lock::progress;
+
+struct Logger {
+    /// What reaches the terminal (`-q`/`-v`)
+    stderr_level: LevelFilter,
+    /// The log file always records at least status messages, even under `-q`
+    file_level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.stderr_level
+            || (self.file.is_some() && metadata.level() <= self.file_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.stderr_level {
+            let line = match record.level() {
+                Level::Error => format!("error: {}", record.args()),
+                Level::Warn => format!("warning: {}", record.args()),
+                _ => record.args().to_string(),
+            };
+            // Keep progress bars intact while printing above them
+            progress::suspend(|| eprintln!("{}", line));
+        }
+
+        if let Some(file) = &self.file {
+            if record.level() <= self.file_level {
+                if let Ok(mut f) = file.lock() {
+                    let _ = writeln!(
+                        f,
+                        "{} {:<5} {}",
+                        humantime::format_rfc3339_seconds(SystemTime::now()),
+                        record.level(),
+                        record.args()
+                    );
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut f) = file.lock() {
+                let _ = f.flush();
+            }
+        }
+    }
+}
+
+/// Install the global logger
+///
+/// Default shows status messages; `-v` adds each file as it is processed, `-vv` adds
+/// internal detail. `quiet` limits the terminal to errors and hides progress bars.
+pub fn init(verbose: u8, quiet: bool, log_file: Option<&Path>) -> Result<()> {
+    let stderr_level = if quiet {
+        LevelFilter::Error
+    } else {
+        match verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    let file_level = stderr_level.max(LevelFilter::Info);
+
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?,
+        )),
+        None => None,
+    };
+
+    let max_level = if file.is_some() {
+        stderr_level.max(file_level)
+    } else {
+        stderr_level
+    };
+    if quiet {
+        progress::disable();
+    }
+
+    log::set_boxed_logger(Box::new(Logger {
+        stderr_level,
+        file_level,
+        file,
+    }))
+    .context("logger already initialized")?;
+    log::set_max_level(max_level);
+    Ok(())
+}
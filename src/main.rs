@@ -1,105 +1,3160 @@
-use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::ExposeSecret;
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use rpassword::read_password;
-use tar::Builder;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use indicatif::{HumanBytes, ProgressBar};
+
+use folder_lock::checksum::{self, HashingWriter};
+use folder_lock::container::Container;
+use folder_lock::extract::{self, Existing, ExtractOptions, PathFilter};
+use folder_lock::failure::{Classify, Failure};
+use folder_lock::header::{self, Header};
+use folder_lock::keys::{parse_recipients, prompt_identity, read_identities, read_recipients_file};
+use folder_lock::names::InvalidNames;
+use folder_lock::pack::{PackOptions, Sources};
+use folder_lock::passphrase::{self, PassphraseArgs};
+use folder_lock::progress::ProgressFormat;
+use folder_lock::repo::Repo;
+use folder_lock::report::{entry_kind, EntryInfo, OutputFormat, Report};
+use folder_lock::shares::{self, SplitKey};
+use folder_lock::snapshot::{self, Snapshot};
+use folder_lock::streams::{self, CountingWriter};
+use folder_lock::walk::{self, Filters, Symlinks};
+use folder_lock::{
+    agent, compression, diff, kdf, merge, paper, pinentry, progress, prune, signature, tpm,
+    Algorithm, Key, KeyKind, Locker,
+};
+
+use config::ConfigArgs;
+use hooks::Hooks;
+
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod hooks;
+mod logging;
+
+/// Exit statuses, listed under `--help` (kept in sync with `Failure::exit_code`)
+const EXIT_CODES: &str = "\
+Exit status:
+  0  success
+  1  any other error
+  2  invalid command line
+  3  wrong passphrase or identity
+  4  output already exists
+  5  source does not exist
+  6  archive is corrupted
+  7  extraction stopped partway; some files were restored";
 
 /// Command Line Interface
 #[derive(Parser)]
 #[command(name = "folder_lock_rs")]
-#[command(about = "Packages (tar.gz) and encrypts a folder using a passphrase (age)", long_about = None)]
+#[command(about = "Packages (tar.gz/tar.zst/tar.xz) and encrypts a folder using a passphrase or age recipients", long_about = None)]
+#[command(after_help = EXIT_CODES)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print each file as it is processed (-vv for more detail)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Only print errors; hides progress bars
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also append log messages to this file
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+    /// Result format: human-readable text, or one JSON object on stdout
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output: OutputFormat,
+    /// After the command, print files, bytes in/out, ratio, throughput, wall time and peak
+    /// memory to stderr (always part of `--output json`)
+    #[arg(long, global = true)]
+    stats: bool,
+    /// Show a desktop notification when the command finishes or fails, for long runs in
+    /// a background window (feature `notify`)
+    #[arg(long, global = true)]
+    notify: bool,
+    /// How to show progress: bars on a terminal, or JSON lines for a front-end (see
+    /// --progress-fd)
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: ProgressFormat,
+    /// File descriptor that `--progress json` events go to (default: stderr; Unix only)
+    #[arg(long, global = true, value_name = "FD")]
+    progress_fd: Option<i32>,
+    /// Ask for passphrases in a pinentry dialog, like GnuPG, instead of on the terminal;
+    /// PROGRAM defaults to gpg-agent's `pinentry-program`. Used anyway without a terminal
+    /// in a graphical session
+    #[arg(long, global = true, value_name = "PROGRAM", num_args = 0..=1, require_equals = true)]
+    pinentry: Option<Option<PathBuf>>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Encrypt a folder into an .age file
+    /// Encrypt a folder, or several folders and files, into an .age file
     Encrypt {
-        /// Folder to encrypt
+        /// Folders or files, then the output: an .age file, `s3://bucket/key` (feature `s3`),
+        /// `sftp://[user@]host/path` (feature `sftp`), or `-` for stdout. A lone folder's
+        /// contents form the archive; several sources each go under their own name.
+        /// With a single source, OUT defaults to the config file's `output` template
+        #[arg(value_name = "SOURCE... OUT", required = true)]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[command(flatten)]
+        key: EncryptKeyArgs,
+        /// Generate a random passphrase, print it once to stderr, and use it
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "passphrase_file", "passphrase_fd", "passphrase_credential",
+                "passphrase_command", "use_keyring", "recipients"
+            ]
+        )]
+        generate_passphrase: bool,
+        /// Let a passphrase open the archive too, beside the recipients (e.g. a memorized
+        /// one next to an offline escrow key); `age -d` only opens it with an identity
+        #[arg(long, conflicts_with = "generate_passphrase")]
+        with_passphrase: bool,
+        /// Also encrypt to this machine's TPM (with `age-plugin-tpm`, Linux), so the archive
+        /// opens here with `--tpm` and no passphrase, and on no other machine
+        #[arg(long, conflicts_with = "generate_passphrase")]
+        tpm: bool,
+        /// Split the key into N share files (`OUT.share1`, …), any K of which open the
+        /// archive with `decrypt -i`, instead of a passphrase or recipients (e.g. 3-of-5)
+        #[arg(
+            long,
+            value_name = "K-of-N",
+            value_parser = shares::parse_split_key,
+            conflicts_with_all = [
+                "recipients", "recipient_files", "passphrase_file", "passphrase_fd",
+                "passphrase_credential", "passphrase_command", "use_keyring",
+                "generate_passphrase", "with_passphrase", "tpm", "kdf_cost", "min_entropy",
+                "split_size"
+            ]
+        )]
+        split_key: Option<SplitKey>,
+        /// log2 of the scrypt work factor for the passphrase (each step doubles the time
+        /// and memory to try one; 20 needs 1 GiB); defaults to about a second on this machine
+        #[arg(
+            long,
+            value_name = "LOG_N",
+            value_parser = clap::value_parser!(u8).range(kdf::MIN_COST as i64..=kdf::MAX_COST as i64)
+        )]
+        kdf_cost: Option<u8>,
+        /// Refuse a passphrase whose estimated strength is below this many bits (weak ones
+        /// only get a warning otherwise); 60 or more resists a well-funded offline attack
+        #[arg(long, value_name = "BITS")]
+        min_entropy: Option<u32>,
+        #[command(flatten)]
+        filters: FilterArgs,
+        /// Store holes in sparse files efficiently (GNU sparse entries)
+        #[arg(short = 'S', long)]
+        sparse: bool,
+        /// Store names restored with `--invalid-names escape` under their original,
+        /// non-UTF-8 bytes again (Unix only)
+        #[arg(long)]
+        unescape_names: bool,
+        /// Produce byte-identical plaintext for identical trees: sorted entries, clamped
+        /// mtimes, no owners, single-threaded compression
+        #[arg(long)]
+        reproducible: bool,
+        /// mtime clamp for --reproducible (default: $SOURCE_DATE_EPOCH, else 0); implies it
+        #[arg(long, value_name = "SECS")]
+        source_date_epoch: Option<u64>,
+        /// Only store files changed since --base; restore with `decrypt BASE --increment FILE`
+        #[arg(long, requires = "base")]
+        incremental: bool,
+        /// Previous backup to compare against: an .age archive or a --write-snapshot file
+        /// (a passphrase base is opened with the same passphrase source as the new archive)
+        #[arg(long, value_name = "ARCHIVE|SNAPSHOT", requires = "incremental")]
+        base: Option<PathBuf>,
+        /// age or SSH identity to open a recipient-encrypted --base archive; repeatable
+        #[arg(long, value_name = "FILE", requires = "base")]
+        base_identity: Vec<PathBuf>,
+        /// Also save this run's snapshot to a file, usable as a later --base without decrypting
+        #[arg(long, value_name = "FILE")]
+        write_snapshot: Option<PathBuf>,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        /// Store the tar stream uncompressed (same as `--compression none`)
+        #[arg(long, conflicts_with_all = ["compression", "level"])]
+        no_compress: bool,
+        /// Compression worker threads for zstd/xz (default: one per core)
+        #[arg(long, value_name = "N")]
+        threads: Option<u32>,
+        /// Split the archive into volumes of this size (`OUT.001`, `OUT.002`, …), e.g. 2G
+        #[arg(long, value_name = "SIZE", value_parser = streams::parse_size)]
+        split_size: Option<u64>,
+        /// Replace the output file if it already exists
+        #[arg(short, long)]
+        force: bool,
+        /// Encrypt a single file's bytes as they are, without tar or compression, so
+        /// `age -d` (or `decrypt --raw`) gives back the file itself
+        #[arg(long, conflicts_with_all = ["compression", "level", "no_compress", "incremental", "sparse"])]
+        raw: bool,
+        /// Write ASCII-armored age (PEM-style text) that can be pasted into emails or tickets
+        #[arg(short, long)]
+        armor: bool,
+        /// Pad the encrypted file up to a multiple of this size (e.g. 16M), so its length
+        /// doesn't reveal how much the folder holds
+        #[arg(long, value_name = "SIZE", value_parser = streams::parse_size, conflicts_with = "raw")]
+        pad_to: Option<u64>,
+        /// Container inside the encryption: `zip` opens with built-in Windows and macOS tools
+        /// after `age -d`, but ignores --compression and can't be incremental
+        #[arg(
+            long = "format",
+            value_enum,
+            default_value_t,
+            conflicts_with_all = ["raw", "incremental", "write_snapshot", "sparse", "pad_to"]
+        )]
+        container: Container,
+        /// Name the archive (e.g. laptop-home-2024-06); it is encrypted with the contents
+        /// and shown by `list` and `info --decrypt`
+        #[arg(long, value_name = "TEXT", conflicts_with = "raw")]
+        label: Option<String>,
+        /// Store a free-form KEY=VALUE pair with the archive, like the label; repeatable
+        #[arg(
+            long = "meta",
+            value_name = "KEY=VALUE",
+            value_parser = header::parse_meta,
+            conflicts_with = "raw"
+        )]
+        meta: Vec<(String, String)>,
+        /// List what would be archived, with total and estimated compressed size, without
+        /// asking for a passphrase or writing anything (a --base archive is still opened)
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't show the size summary and ask before encrypting (never asked without a
+        /// terminal)
+        #[arg(short, long)]
+        yes: bool,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        /// Shell command to run first, e.g. to dump a database into the folder; encryption
+        /// is called off if it fails (the config file's `pre_hook` otherwise)
+        #[arg(long, value_name = "COMMAND")]
+        pre_hook: Option<String>,
+        /// Shell command to run afterwards, also on failure, with the outcome in
+        /// `FOLDER_LOCK_*` variables (the config file's `post_hook` otherwise)
+        #[arg(long, value_name = "COMMAND")]
+        post_hook: Option<String>,
+        /// Sign the finished archive with this minisign secret key, writing
+        /// `OUT.minisig` beside it (`decrypt --verify-sig` or `minisign -V` check it)
+        #[arg(long, value_name = "SECRET-KEY", conflicts_with = "split_size")]
+        sign: Option<PathBuf>,
+        /// Record progress in this file while encrypting, and continue from it if it exists:
+        /// an interrupted run picks up after the last completed stretch (every 64 MiB)
+        /// instead of starting over. Needs a passphrase and zstd or no compression
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = [
+                "raw", "armor", "pad_to", "container", "incremental", "write_snapshot",
+                "split_key", "generate_passphrase"
+            ]
+        )]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Keep an .age file up to date: re-encrypt the folder whenever something in it changes
+    Watch {
+        /// Folder to watch and encrypt
         folder: PathBuf,
-        /// Output encrypted file (.age)
+        /// Output encrypted file, replaced atomically on every run (must be outside FOLDER)
         out: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Passphrase source; it is read once and reused for every run
+        #[command(flatten)]
+        key: EncryptKeyArgs,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        /// Quiet period after the last change before re-encrypting (e.g. 500ms, 10s)
+        #[arg(long, value_name = "DURATION", default_value = "2s", value_parser = humantime::parse_duration)]
+        debounce: Duration,
+    },
+    /// Encrypt many folders to many archives in one run, asking for the passphrase once
+    ///
+    /// MANIFEST (`-` for stdin) lists one job per line: a source folder or file, a tab, and
+    /// the archive to write; without the tab and archive, the config file's `output`
+    /// template names it. Blank lines and `#` comments are skipped. Every job shares the
+    /// key, filters, compression and the config file's hooks, and every output is checked
+    /// before the first one is written.
+    Batch {
+        /// Job list file, or `-` for stdin
+        manifest: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Passphrase source; it is read once and used for every archive
+        #[command(flatten)]
+        key: EncryptKeyArgs,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        /// Replace output files that already exist
+        #[arg(short, long)]
+        force: bool,
+        /// Go on with the remaining jobs after one fails, and report all failures at the end
+        #[arg(long)]
+        keep_going: bool,
+        /// Run up to N jobs at once; fewer when the cores or available memory run out
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        jobs: u32,
+        /// Memory each job may use, when limiting --jobs [default: estimated from the
+        /// compression settings]
+        #[arg(long, value_name = "SIZE", value_parser = streams::parse_size)]
+        job_memory: Option<u64>,
     },
     /// Decrypt an .age file back into a folder
     Decrypt {
-        /// Input encrypted file (.age)
+        /// Input encrypted file (.age, or the .001 volume of a split archive),
+        /// `s3://bucket/key` (feature `s3`), `sftp://[user@]host/path` (feature `sftp`),
+        /// or `-` for stdin
+        input: PathBuf,
+        /// Output folder (must exist); with --raw, the output file, or `-` for stdout
+        out_folder: PathBuf,
+        /// Only restore entries matching these paths or globs (e.g. `docs/**`)
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+        /// Incremental archive to apply on top of INPUT, oldest first; repeatable
+        #[arg(long = "increment", value_name = "FILE")]
+        increments: Vec<PathBuf>,
+        /// Overwrite files that already exist in the output folder
+        #[arg(
+            short,
+            long,
+            visible_alias = "overwrite",
+            conflicts_with = "skip_existing"
+        )]
+        force: bool,
+        /// Leave files that already exist in the output folder untouched
+        #[arg(long)]
+        skip_existing: bool,
+        /// What to do with files that already exist in the output folder; defaults to `ask`
+        /// on a terminal and `error` otherwise
+        #[arg(long, value_enum, value_name = "ACTION", conflicts_with_all = ["force", "skip_existing"])]
+        on_conflict: Option<Existing>,
+        /// Don't recreate symlinks stored in the archive
+        #[arg(long)]
+        no_symlinks: bool,
+        /// Drop this many leading path components from every entry, like GNU tar
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        strip_components: usize,
+        /// What to do with names that aren't valid UTF-8; defaults to `keep` on Unix and
+        /// `escape` elsewhere
+        #[arg(long, value_enum, value_name = "POLICY")]
+        invalid_names: Option<InvalidNames>,
+        /// INPUT was made with `encrypt --raw`: write its single file to OUT_FOLDER as is
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "paths", "increments", "skip_existing", "on_conflict", "no_symlinks",
+                "strip_components", "invalid_names"
+            ]
+        )]
+        raw: bool,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        #[command(flatten)]
+        signature: SignatureArgs,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Encrypt a folder to `<folder>.age` beside it, then delete the folder
+    ///
+    /// The folder is only removed once the archive has been synced to disk, read back, and
+    /// (for passphrase archives) decrypted and checked against its manifest, and is found to
+    /// cover every entry in the folder.
+    Lock {
+        /// Folder to lock
+        folder: PathBuf,
+        #[command(flatten)]
+        key: EncryptKeyArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        /// Overwrite file contents with random data before deleting them (best effort on
+        /// copy-on-write file systems and SSDs)
+        #[arg(long)]
+        shred: bool,
+    },
+    /// Restore a `lock`ed `<name>.age` as the folder `<name>`, then delete the archive
+    Unlock {
+        /// Archive made by `lock`
+        input: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// List the contents of an .age file without extracting
+    List {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Search entry names in one or more .age files, printing matches like `list`
+    ///
+    /// A pattern without `/` is matched against file names, one with `/` against whole
+    /// archive paths. Exits with 5 when nothing matches.
+    Find {
+        /// Encrypted files to search (each asks for its own secret), then the glob pattern
+        /// (or regex, with --regex)
+        #[arg(value_name = "ARCHIVE... PATTERN", num_args = 2.., required = true)]
+        args: Vec<PathBuf>,
+        /// Treat PATTERN as a regular expression, searched anywhere in the archive path
+        #[arg(short = 'E', long)]
+        regex: bool,
+        /// Match regardless of case
+        #[arg(short = 'i', long)]
+        ignore_case: bool,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Write one archived file's contents to stdout
+    ///
+    /// Decryption stops as soon as the file has been written, so the rest of the archive is
+    /// neither read nor checked against the manifest.
+    Cat {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        /// Path of the file inside the archive, as `list` shows it
+        path: String,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Check that an .age file decrypts and its archive is intact, without extracting
+    Verify {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        #[command(flatten)]
+        signature: SignatureArgs,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Check that the passphrase or identity opens an .age file, without touching the disk
+    ///
+    /// The whole file is decrypted and the plaintext discarded; nothing is decompressed or
+    /// unpacked, so this also works for `--raw` files.
+    Test {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Show how an .age file is encrypted, read from its cleartext header without a key
+    Info {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        /// Also decrypt the start of the file to report its compression (asks for the key)
+        #[arg(long)]
+        decrypt: bool,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Browse an .age file as a read-only file system until it is unmounted
+    #[cfg(all(feature = "fuse", unix))]
+    Mount {
+        /// Input encrypted file (.age, or the .001 volume of a split archive); stdin won't do,
+        /// since contents are re-read on demand
+        input: PathBuf,
+        /// Empty directory to mount on
+        mountpoint: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Explore an .age file in a terminal UI, previewing small files and extracting marked ones
+    #[cfg(feature = "tui")]
+    Browse {
+        /// Input encrypted file (.age, or the .001 volume of a split archive); stdin won't do,
+        /// since marked entries are extracted in a second pass
+        input: PathBuf,
+        /// Folder to extract marked entries into (must exist)
+        #[arg(
+            short = 'C',
+            long = "directory",
+            value_name = "DIR",
+            default_value = "."
+        )]
+        out_folder: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Compare an .age file against a folder: lists added (A), removed (D), modified (M) paths
+    Diff {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        /// Folder to compare against, usually the one that was encrypted
+        folder: PathBuf,
+        /// Compare file contents by SHA-256 instead of size and mtime (slower)
+        #[arg(long)]
+        checksum: bool,
+        /// The filter flags the archive was created with, so excluded files don't show as added
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Compare two .age files: lists entries only in NEW (A) or OLD (D), and those whose
+    /// size or contents differ (M)
+    ///
+    /// Contents are compared by the SHA-256 in each archive's manifest; files missing from
+    /// either manifest are compared by size and mtime.
+    DiffArchives {
+        /// Encrypted file to compare from (.age, or the .001 volume of a split archive), or
+        /// `-` for stdin
+        old: PathBuf,
+        /// Encrypted file to compare to (asks for its own secret), or `-` for stdin
+        new: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Re-encrypt an .age file with a new passphrase or recipients, never writing plaintext
+    Rekey {
+        /// Input encrypted file (.age, or the .001 volume of a split archive), or `-` for stdin
+        input: PathBuf,
+        /// Output encrypted file, or `-` for stdout (may equal the input with --force)
+        out: PathBuf,
+        #[command(flatten)]
+        new_key: NewKeyArgs,
+        /// Write the new file ASCII-armored (the input may be either way)
+        #[arg(short, long)]
+        armor: bool,
+        /// Replace the output file if it already exists
+        #[arg(short, long)]
+        force: bool,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Add files and folders to an .age file, replacing it with the result
+    ///
+    /// The archive is decrypted and streamed into a new one, each added path going in under
+    /// its own name (an entry already stored under that name is replaced). The original is
+    /// only replaced once the new file is complete and the old entries matched its manifest.
+    Append {
+        /// Encrypted file (.age) to add to; incremental and zip archives can't be appended to
+        input: PathBuf,
+        /// Files and folders to add
+        #[arg(value_name = "PATH", required = true)]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        #[command(flatten)]
+        rewrite: RewriteArgs,
+    },
+    /// Delete entries from an .age file, replacing it with the result
+    ///
+    /// Like `append`, the archive is streamed into a new one without writing plaintext to
+    /// disk, and only replaced once that is complete. Exits with 5 when nothing matches.
+    Remove {
+        /// Encrypted file (.age) to remove from; incremental and zip archives can't be changed
         input: PathBuf,
+        /// Paths or globs of the entries to remove (e.g. `secrets/old.key`, `**/*.tmp`); a
+        /// folder goes with everything in it
+        #[arg(value_name = "PATH", required = true)]
+        paths: Vec<String>,
+        #[command(flatten)]
+        rewrite: RewriteArgs,
+    },
+    /// Refresh an .age file from the folder it was made from, replacing it with the result
+    ///
+    /// Only added and modified files (see `diff`) are read from the folder; every other entry
+    /// is copied from the old archive. The original is replaced once the new file is complete.
+    Update {
+        /// Encrypted file (.age) to refresh; incremental and zip archives can't be updated
+        input: PathBuf,
+        /// Folder the archive was made from
+        folder: PathBuf,
+        /// Compare file contents by SHA-256 instead of size and mtime, so touched but
+        /// unchanged files are left alone (slower)
+        #[arg(long)]
+        checksum: bool,
+        /// Also drop entries whose file is gone from the folder
+        #[arg(long)]
+        delete: bool,
+        /// The filter flags the archive was created with, so excluded files aren't added
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        #[command(flatten)]
+        rewrite: RewriteArgs,
+    },
+    /// Combine the entries of several .age files into one new archive
+    ///
+    /// Each archive is opened with its own secret (asked for once) and read twice: first to
+    /// settle overlapping paths, then to copy. The new archive gets a new passphrase or
+    /// recipients, like `rekey`.
+    Merge {
+        /// Encrypted files to merge, in order, then the output file
+        #[arg(value_name = "ARCHIVE... OUT", num_args = 3.., required = true)]
+        args: Vec<PathBuf>,
+        /// What to do with a path stored in more than one archive
+        #[arg(long, value_enum, value_name = "POLICY", default_value_t)]
+        on_conflict: merge::Conflict,
+        #[command(flatten)]
+        new_key: NewKeyArgs,
+        /// Compression algorithm of the result [default: the first archive's]
+        #[arg(long, value_enum)]
+        compression: Option<Algorithm>,
+        /// Compression level (gzip: 0-9, zstd: 1-22, xz: 0-9); defaults per algorithm
+        #[arg(long)]
+        level: Option<i32>,
+        /// Write the new file ASCII-armored
+        #[arg(short, long)]
+        armor: bool,
+        /// Replace the output file if it already exists
+        #[arg(short, long)]
+        force: bool,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Cache a typed passphrase or identity for later commands, like ssh-agent
+    ///
+    /// Runs in the foreground and prints the shell line that points later commands at it;
+    /// `eval "$(folder_lock_rs agent &)"` starts it in the background. Cached secrets live
+    /// in locked memory and are wiped `--ttl` after they were stored. Only commands that
+    /// have `FOLDER_LOCK_AGENT_SOCK` set use an agent.
+    #[cfg(unix)]
+    Agent {
+        /// How long a cached passphrase or identity is kept
+        #[arg(long, value_name = "DURATION", default_value = "15m", value_parser = humantime::parse_duration)]
+        ttl: Duration,
+        /// Socket to listen on [default: $FOLDER_LOCK_AGENT_SOCK, else
+        /// folder_lock-agent.sock in $XDG_RUNTIME_DIR or a private temp directory]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+    /// Serve encrypt and decrypt jobs to front-ends over a Unix socket, as JSON lines
+    ///
+    /// Requests start jobs, report their progress, cancel them, and cache a passphrase in
+    /// the agent for later jobs (`FOLDER_LOCK_AGENT_SOCK` must be set); see the `daemon`
+    /// module for the protocol.
+    #[cfg(unix)]
+    Daemon {
+        /// Socket to listen on [default: folder_lock.sock in $XDG_RUNTIME_DIR or a private
+        /// temp directory]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+    /// Generate a new age X25519 identity and print its recipient
+    Keygen {
+        /// Output identity file (created with 0600 permissions, never overwritten)
+        out: PathBuf,
+    },
+    /// Print an identity or passphrase for a paper backup, as words and a QR code
+    ///
+    /// An identity becomes 24 BIP-39 words, and its QR code holds the `AGE-SECRET-KEY-1…`
+    /// line; `import-key` takes either. Anyone holding the printout can decrypt.
+    ExportKey {
+        /// Identity file to export; without it, a passphrase is asked for (or read from
+        /// --passphrase-file)
+        #[arg(short, long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        /// Read the passphrase to export from this file
+        #[arg(long, value_name = "FILE", conflicts_with = "identity")]
+        passphrase_file: Option<PathBuf>,
+        /// Also print the secret as a QR code
+        #[arg(long)]
+        qr: bool,
+        /// Write the QR code to this SVG file for printing, instead of to the terminal
+        #[arg(long, value_name = "FILE", requires = "qr")]
+        svg: Option<PathBuf>,
+    },
+    /// Restore an identity file from the words or QR text printed by `export-key`
+    ImportKey {
+        /// Output identity file (created with 0600 permissions, never overwritten)
+        out: PathBuf,
+        /// Read the words or QR text from this file instead of the terminal
+        #[arg(long, value_name = "FILE")]
+        from: Option<PathBuf>,
+    },
+    /// Delete old dated backups from a folder, keeping those a retention policy picks
+    ///
+    /// Archives are recognised by the config file's `output` template (or --template), and
+    /// each `{folder}` is pruned separately. The newest archive of a folder is always kept.
+    Prune {
+        /// Folder holding the backups
+        dir: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// File name pattern of the archives, e.g. '{folder}-{date}.age' (default: the
+        /// config file's `output` template)
+        #[arg(long, value_name = "TEMPLATE")]
+        template: Option<String>,
+        /// Keep the N newest archives
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_last: Option<u32>,
+        /// Keep the newest archive of each of the last N days that have one
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_daily: Option<u32>,
+        /// Keep the newest archive of each of the last N weeks (Monday to Sunday)
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_weekly: Option<u32>,
+        /// Keep the newest archive of each of the last N months
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_monthly: Option<u32>,
+        /// Keep the newest archive of each of the last N years
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_yearly: Option<u32>,
+        /// List what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Deduplicating backups: each run stores only the chunks a repository doesn't have yet
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommand,
+    },
+    /// Print a shell completion script to stdout
+    ///
+    /// e.g. `folder_lock_rs completions zsh > ~/.zfunc/_folder_lock_rs`
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Subcommand)]
+enum RepoCommand {
+    /// Create an empty repository, its key protected by a passphrase or recipients
+    Init {
+        /// Folder for the repository (created if missing; must be empty)
+        dir: PathBuf,
+        #[command(flatten)]
+        key: EncryptKeyArgs,
+    },
+    /// Store a new snapshot of folders or files
+    Backup {
+        /// Repository folder
+        dir: PathBuf,
+        /// A folder, whose contents form the snapshot, or several sources, each under its
+        /// own name
+        #[arg(value_name = "SOURCE", required = true)]
+        sources: Vec<PathBuf>,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Print the repository's snapshots, oldest first
+    List {
+        /// Repository folder
+        dir: PathBuf,
+    },
+    /// Restore a snapshot into a folder
+    Restore {
+        /// Repository folder
+        dir: PathBuf,
+        /// Snapshot name, as printed by `repo list`, or `latest`
+        snapshot: String,
         /// Output folder (must exist)
         out_folder: PathBuf,
+        /// Only restore entries matching these paths or globs (e.g. `docs/**`)
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+        /// Overwrite files that already exist in the output folder
+        #[arg(short, long, conflicts_with = "skip_existing")]
+        force: bool,
+        /// Leave files that already exist in the output folder untouched
+        #[arg(long)]
+        skip_existing: bool,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        #[command(flatten)]
+        keys: KeyArgs,
     },
 }
 
-fn main() -> Result<()> {
+/// Which entries of the source folder are archived
+#[derive(Args)]
+struct FilterArgs {
+    /// Only archive files matching this glob; repeatable
+    #[arg(long = "include", value_name = "GLOB")]
+    includes: Vec<String>,
+    /// Skip files and directories matching this glob (e.g. `node_modules`); repeatable
+    #[arg(long = "exclude", value_name = "GLOB")]
+    excludes: Vec<String>,
+    /// Honor .gitignore/.ignore files (.folderlockignore is always honored)
+    #[arg(long)]
+    use_gitignore: bool,
+    /// Archive what symlinks point to instead of the links themselves
+    #[arg(long, conflicts_with = "skip_symlinks")]
+    follow_symlinks: bool,
+    /// Leave symlinks out of the archive
+    #[arg(long)]
+    skip_symlinks: bool,
+    /// Leave dotfiles and dot-directories out of the archive
+    #[arg(long, overrides_with = "hidden")]
+    skip_hidden: bool,
+    /// Archive dotfiles and dot-directories (the default)
+    #[arg(long, overrides_with = "skip_hidden")]
+    hidden: bool,
+    /// Only descend this many levels; 1 archives the folder's direct entries
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>,
+}
+
+impl FilterArgs {
+    fn build(&self) -> Result<Filters> {
+        let mut filters = Filters::new(
+            &self.includes,
+            &self.excludes,
+            self.use_gitignore,
+            if self.follow_symlinks {
+                Symlinks::Follow
+            } else if self.skip_symlinks {
+                Symlinks::Skip
+            } else {
+                Symlinks::Preserve
+            },
+        )?;
+        filters.skip_hidden = self.skip_hidden && !self.hidden;
+        filters.max_depth = self.max_depth.map(|d| d as usize);
+        Ok(filters)
+    }
+}
+
+/// Who a new archive is encrypted to: age recipients, or else a passphrase
+#[derive(Args)]
+struct EncryptKeyArgs {
+    /// Encrypt to an age recipient (public key) instead of a passphrase; repeatable
+    #[arg(short, long = "recipient", value_name = "AGE-PUBKEY")]
+    recipients: Vec<String>,
+    /// Read recipients (age or SSH public keys, one per line) from a file; repeatable
+    #[arg(short = 'R', long = "recipient-file", value_name = "FILE")]
+    recipient_files: Vec<PathBuf>,
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+    /// Don't ask for the passphrase a second time
+    #[arg(long)]
+    no_confirm: bool,
+}
+
+impl EncryptKeyArgs {
+    /// Whether the command line chose how to encrypt, overriding configured recipients
+    fn names_key(&self, generate_passphrase: bool) -> bool {
+        let passphrase = &self.passphrase;
+        !self.recipients.is_empty()
+            || !self.recipient_files.is_empty()
+            || passphrase.passphrase_file.is_some()
+            || passphrase.passphrase_fd.is_some()
+            || passphrase.passphrase_credential.is_some()
+            || passphrase.passphrase_command.is_some()
+            || passphrase.use_keyring
+            || generate_passphrase
+    }
+
+    /// The -r recipients, then those of each -R file
+    fn read_recipients(&self) -> Result<Vec<String>> {
+        let mut recipients = self.recipients.clone();
+        for file in &self.recipient_files {
+            recipients.extend(read_recipients_file(file)?);
+        }
+        Ok(recipients)
+    }
+}
+
+/// The new secret of `rekey` and `merge`
+#[derive(Args)]
+struct NewKeyArgs {
+    /// Encrypt to an age recipient (public key) instead of a passphrase; repeatable
+    #[arg(short, long = "recipient", value_name = "AGE-PUBKEY")]
+    recipients: Vec<String>,
+    /// Read recipients (age or SSH public keys, one per line) from a file; repeatable
+    #[arg(short = 'R', long = "recipient-file", value_name = "FILE")]
+    recipient_files: Vec<PathBuf>,
+    /// Read the new passphrase from the first line of a file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["recipients", "recipient_files"])]
+    new_passphrase_file: Option<PathBuf>,
+    /// Don't ask for the new passphrase a second time
+    #[arg(long)]
+    no_confirm: bool,
+    /// Generate a random new passphrase, print it once to stderr, and use it
+    #[arg(long, conflicts_with_all = ["new_passphrase_file", "recipients", "recipient_files"])]
+    generate_passphrase: bool,
+}
+
+impl NewKeyArgs {
+    fn new_key(self) -> Result<NewKey> {
+        let mut recipients = self.recipients;
+        for file in &self.recipient_files {
+            recipients.extend(read_recipients_file(file)?);
+        }
+        Ok(if self.generate_passphrase {
+            NewKey::Generated
+        } else if recipients.is_empty() {
+            NewKey::Passphrase {
+                file: self.new_passphrase_file,
+                confirm: !self.no_confirm,
+            }
+        } else {
+            NewKey::Recipients(recipients)
+        })
+    }
+}
+
+/// Compression of the inner tar stream
+#[derive(Args)]
+struct CompressionArgs {
+    /// Compression algorithm for the inner tar stream [default: gzip]
+    #[arg(long, value_enum)]
+    compression: Option<Algorithm>,
+    /// Compression level (gzip: 0-9, zstd: 1-22, xz: 0-9); defaults per algorithm
+    #[arg(long)]
+    level: Option<i32>,
+}
+
+/// Secrets used to open an existing archive
+#[derive(Args)]
+struct KeyArgs {
+    /// age, age-plugin or SSH identity file to decrypt recipient-encrypted archives; repeatable
+    #[arg(short, long = "identity", value_name = "FILE")]
+    identities: Vec<PathBuf>,
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+    /// Ask again this many times after a mistyped passphrase (only when it is typed at the
+    /// prompt, and the input isn't stdin)
+    #[arg(long, value_name = "N", default_value_t = PASSPHRASE_RETRIES)]
+    passphrase_retries: u32,
+    /// Open with this machine's TPM identity, for archives made with `encrypt --tpm`
+    #[arg(long)]
+    tpm: bool,
+}
+
+impl KeyArgs {
+    /// Whether an identity was given, with -i or --tpm
+    fn has_identities(&self) -> bool {
+        !self.identities.is_empty() || self.tpm
+    }
+
+    /// Load the -i identities, and the TPM one with --tpm
+    fn read_identities(&self) -> Result<Vec<Box<dyn age::Identity + Send>>> {
+        let mut files = self.identities.clone();
+        if self.tpm {
+            files.push(tpm::identity()?);
+        }
+        read_identities(&files)
+    }
+}
+
+/// Default of `--passphrase-retries`
+const PASSPHRASE_RETRIES: u32 = 2;
+
+/// How `append`, `remove` and `update` open an archive and encrypt its replacement
+#[derive(Args)]
+struct RewriteArgs {
+    /// Encrypt the result to an age recipient (needed for recipient-encrypted archives,
+    /// whose recipients can't be read back; passphrase archives keep their passphrase
+    /// otherwise); repeatable
+    #[arg(short, long = "recipient", value_name = "AGE-PUBKEY")]
+    recipients: Vec<String>,
+    /// Read recipients (age or SSH public keys, one per line) from a file; repeatable
+    #[arg(short = 'R', long = "recipient-file", value_name = "FILE")]
+    recipient_files: Vec<PathBuf>,
+    /// Compression algorithm of the result [default: the archive's own]
+    #[arg(long, value_enum)]
+    compression: Option<Algorithm>,
+    /// Compression level (gzip: 0-9, zstd: 1-22, xz: 0-9); defaults per algorithm
+    #[arg(long)]
+    level: Option<i32>,
+    #[command(flatten)]
+    keys: KeyArgs,
+}
+
+/// File metadata to store on encrypt, or restore on decrypt
+#[derive(Args)]
+struct MetadataArgs {
+    /// Owner uid/gid (restoring requires root)
+    #[arg(long)]
+    preserve_owner: bool,
+    /// Full mode bits including setuid/setgid/sticky, ignoring the umask on restore
+    #[arg(long)]
+    preserve_permissions: bool,
+    /// Extended attributes (Unix only)
+    #[arg(long)]
+    xattrs: bool,
+}
+
+/// Detached signature to check before anything is decrypted
+#[derive(Args)]
+struct SignatureArgs {
+    /// Require a valid `INPUT.minisig` made by this minisign public key (`RWQ…` or a
+    /// `minisign.pub` file) before decrypting
+    #[arg(long, value_name = "PUBKEY")]
+    verify_sig: Option<String>,
+}
+
+impl SignatureArgs {
+    fn check(&self, input: &Path) -> Result<()> {
+        let Some(key) = &self.verify_sig else {
+            return Ok(());
+        };
+        if streams::is_stdio(input) || streams::is_remote(input) {
+            anyhow::bail!("--verify-sig needs a local archive file");
+        }
+        if input.extension().is_some_and(|ext| ext == "001") {
+            anyhow::bail!("--verify-sig doesn't support split archives");
+        }
+        signature::verify(input, &signature::public_key(key)?)?;
+        log::info!("Good signature on '{}'", input.display());
+        Ok(())
+    }
+}
+
+fn main() {
     let cli = Cli::parse();
+    if let Err(e) = logging::init(cli.verbose, cli.quiet, cli.log_file.as_deref()) {
+        eprintln!("error: {:#}", e);
+        process::exit(1);
+    }
+    if cli.progress == ProgressFormat::Json {
+        match progress_output(cli.progress_fd) {
+            Ok(w) => progress::json_events(w),
+            Err(e) => {
+                log::error!("{:#}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if cli.notify && !cfg!(feature = "notify") {
+        log::error!("--notify needs folder_lock built with the `notify` feature");
+        process::exit(1);
+    }
+    if let Some(program) = cli.pinentry.clone() {
+        pinentry::enable(program);
+    }
 
-    match cli.command {
-        Commands::Encrypt { folder, out } => encrypt_folder(&folder, &out)?,
-        Commands::Decrypt { input, out_folder } => decrypt_file(&input, &out_folder)?,
+    let name = cli.command.name();
+    // Archive data owns stdout when streaming, so the JSON report moves to stderr
+    let report_to_stderr = cli.command.writes_stdout();
+    let started = Instant::now();
+    match run(cli.command, cli.output) {
+        Ok(mut report) => {
+            report.finish(started.elapsed().as_secs_f64());
+            progress::emit(
+                "finished",
+                serde_json::to_value(&report).unwrap_or_default(),
+            );
+            if cli.notify {
+                notify(
+                    &format!("folder-lock {} finished", name),
+                    &format!(
+                        "{} files, {} in {:.0}s",
+                        report.files,
+                        HumanBytes(report.bytes_out),
+                        report.duration_secs
+                    ),
+                );
+            }
+            if cli.stats {
+                report.print_stats();
+            }
+            if cli.output == OutputFormat::Json {
+                report.print_json(report_to_stderr);
+            }
+        }
+        Err(e) => {
+            log::error!("{:#}", e);
+            // Don't let the agent hand the same wrong key to the next command
+            match decrypt_error(&e) {
+                Some(age::DecryptError::DecryptionFailed) => agent::forget(agent::PASSPHRASE),
+                Some(age::DecryptError::NoMatchingKeys) => agent::forget(agent::IDENTITY),
+                _ => {}
+            }
+            progress::emit(
+                "error",
+                serde_json::json!({
+                    "error": format!("{:#}", e),
+                    "exit_code": Failure::of(&e).map_or(1, Failure::exit_code),
+                }),
+            );
+            if cli.notify {
+                notify(&format!("folder-lock {} failed", name), &format!("{:#}", e));
+            }
+            if cli.output == OutputFormat::Json {
+                let mut report = Report::failed(name, &e);
+                report.finish(started.elapsed().as_secs_f64());
+                report.print_json(report_to_stderr);
+            }
+            log::logger().flush();
+            process::exit(Failure::of(&e).map_or(1, Failure::exit_code));
+        }
+    }
+    log::logger().flush();
+}
+
+impl Commands {
+    /// Subcommand name as reported in JSON output
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Encrypt { .. } => "encrypt",
+            Commands::Watch { .. } => "watch",
+            Commands::Batch { .. } => "batch",
+            Commands::Decrypt { .. } => "decrypt",
+            Commands::Lock { .. } => "lock",
+            Commands::Unlock { .. } => "unlock",
+            Commands::List { .. } => "list",
+            Commands::Find { .. } => "find",
+            Commands::Cat { .. } => "cat",
+            Commands::Verify { .. } => "verify",
+            Commands::Test { .. } => "test",
+            Commands::Info { .. } => "info",
+            #[cfg(all(feature = "fuse", unix))]
+            Commands::Mount { .. } => "mount",
+            #[cfg(feature = "tui")]
+            Commands::Browse { .. } => "browse",
+            Commands::Diff { .. } => "diff",
+            Commands::DiffArchives { .. } => "diff-archives",
+            Commands::Rekey { .. } => "rekey",
+            Commands::Append { .. } => "append",
+            Commands::Remove { .. } => "remove",
+            Commands::Update { .. } => "update",
+            Commands::Merge { .. } => "merge",
+            #[cfg(unix)]
+            Commands::Agent { .. } => "agent",
+            #[cfg(unix)]
+            Commands::Daemon { .. } => "daemon",
+            Commands::Keygen { .. } => "keygen",
+            Commands::ExportKey { .. } => "export-key",
+            Commands::ImportKey { .. } => "import-key",
+            Commands::Prune { .. } => "prune",
+            Commands::Repo { .. } => "repo",
+            Commands::Completions { .. } => "completions",
+        }
     }
 
+    /// Whether the command streams data (an archive, a completion script) to stdout
+    fn writes_stdout(&self) -> bool {
+        match self {
+            Commands::Encrypt { paths, .. } => {
+                paths.len() > 1 && paths.last().is_some_and(|out| streams::is_stdio(out))
+            }
+            Commands::Decrypt {
+                out_folder, raw, ..
+            } => *raw && streams::is_stdio(out_folder),
+            Commands::Rekey { out, .. } => streams::is_stdio(out),
+            Commands::Cat { .. } | Commands::Completions { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Where `--progress json` events go: stderr, or the inherited descriptor `fd`
+fn progress_output(fd: Option<i32>) -> Result<Box<dyn Write + Send>> {
+    match fd {
+        None => Ok(Box::new(io::stderr())),
+        #[cfg(unix)]
+        Some(fd) => {
+            use std::os::unix::io::FromRawFd;
+            // SAFETY: the caller hands us ownership of the descriptor, like --passphrase-fd
+            Ok(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }))
+        }
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("--progress-fd is only supported on Unix"),
+    }
+}
+
+/// Show a desktop notification; failing to is only worth a warning
+#[cfg(feature = "notify")]
+fn notify(summary: &str, body: &str) {
+    let shown = notify_rust::Notification::new()
+        .appname("folder-lock")
+        .summary(summary)
+        .body(body)
+        .show();
+    if let Err(e) = shown {
+        log::warn!("failed to show a desktop notification: {}", e);
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+fn notify(_summary: &str, _body: &str) {}
+
+fn run(command: Commands, format: OutputFormat) -> Result<Report> {
+    let report = match command {
+        Commands::Encrypt {
+            paths,
+            config,
+            mut key,
+            generate_passphrase,
+            with_passphrase,
+            tpm,
+            split_key,
+            kdf_cost,
+            min_entropy,
+            mut filters,
+            sparse,
+            unescape_names,
+            reproducible,
+            source_date_epoch,
+            incremental: _,
+            base,
+            base_identity,
+            write_snapshot,
+            compression,
+            no_compress,
+            threads,
+            split_size,
+            force,
+            raw,
+            armor,
+            pad_to,
+            container,
+            label,
+            meta,
+            dry_run,
+            yes,
+            metadata,
+            pre_hook,
+            post_hook,
+            sign,
+            checkpoint,
+        } => {
+            let (sources, out) = match paths.split_last() {
+                Some((out, sources)) if !sources.is_empty() => {
+                    (sources.to_vec(), Some(out.clone()))
+                }
+                _ => (paths, None),
+            };
+            if unescape_names && !cfg!(unix) {
+                anyhow::bail!("--unescape-names is only supported on Unix");
+            }
+            if tpm {
+                key.recipients.push(tpm::recipient()?);
+            }
+            let named_recipients = !key.recipients.is_empty() || !key.recipient_files.is_empty();
+            let passphrase_only = kdf_cost.is_some() || min_entropy.is_some();
+            if named_recipients && !with_passphrase && passphrase_only {
+                anyhow::bail!(
+                    "--kdf-cost and --min-entropy apply to a passphrase; add --with-passphrase \
+                     to use one beside recipients"
+                );
+            }
+            let config = config.load()?;
+            if (split_key.is_none() && !key.names_key(generate_passphrase))
+                || (with_passphrase && !named_recipients)
+            {
+                config.add_recipients(&mut key.recipients, &mut key.recipient_files);
+            }
+            if with_passphrase && key.recipients.is_empty() && key.recipient_files.is_empty() {
+                anyhow::bail!("--with-passphrase needs recipients (-r/-R or the config file)");
+            }
+            filters.excludes.extend(config.excludes.iter().cloned());
+            let recipients = key.read_recipients()?;
+            let source_date_epoch = match source_date_epoch {
+                Some(epoch) => Some(epoch),
+                None if reproducible => Some(env_source_date_epoch()?),
+                None => None,
+            };
+            let mut filters = filters.build()?;
+            filters.sorted = source_date_epoch.is_some();
+            let base = match &base {
+                Some(base) => Some(load_base(base, &base_identity, &key.passphrase)?),
+                None => None,
+            };
+            let pack_options = PackOptions {
+                filters,
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                sparse,
+                source_date_epoch,
+                base,
+                snapshot: write_snapshot.is_some(),
+                unescape_names,
+                container,
+                label,
+                meta: meta.into_iter().collect(),
+            };
+            let (compression, level) = if no_compress {
+                (Algorithm::Store, None)
+            } else {
+                config.compression(compression.compression, compression.level)?
+            };
+            // Thread count changes how xz splits blocks, so pin it for reproducible output
+            let threads = if source_date_epoch.is_some() {
+                Some(1)
+            } else {
+                threads
+            };
+            let compression = compression::Settings::new(compression, level, threads)?;
+            if dry_run {
+                // Recipients are still parsed, so typos show up before the real run
+                parse_recipients(&recipients)?;
+                let sources = Sources::new(sources)?;
+                dry_run_encrypt(&sources, &pack_options, &compression, format)?
+            } else {
+                let out = match out {
+                    Some(out) => out,
+                    None => config.output_path(&sources[0])?,
+                };
+                match split_size {
+                    Some(_) => streams::check_split_output(&out, force)?,
+                    None => streams::check_output(&out, force)?,
+                }
+                if let Some(path) = &write_snapshot {
+                    streams::check_output(path, force)?;
+                }
+                if sign.is_some() {
+                    if streams::is_stdio(&out) || streams::is_remote(&out) {
+                        anyhow::bail!("--sign needs a local output file to sign");
+                    }
+                    streams::check_output(&signature::signature_path(&out), force)?;
+                }
+                if checkpoint.is_some() && (streams::is_stdio(&out) || streams::is_remote(&out)) {
+                    anyhow::bail!("--checkpoint needs a local output file to resume");
+                }
+                if let Some(split) = split_key {
+                    if streams::is_stdio(&out) || streams::is_remote(&out) {
+                        anyhow::bail!("--split-key writes share files beside a local output");
+                    }
+                    for i in 1..=split.shares {
+                        streams::check_output(&shares::share_path(&out, i), force)?;
+                    }
+                }
+                let hooks = Hooks::new(
+                    pre_hook.or_else(|| config.pre_hook.clone()),
+                    post_hook.or_else(|| config.post_hook.clone()),
+                );
+                hooks.pre(&out, &sources)?;
+                let options = EncryptOptions {
+                    raw,
+                    armor,
+                    pad_to,
+                    recipients,
+                    passphrase: key.passphrase,
+                    confirm: !key.no_confirm,
+                    generate_passphrase,
+                    with_passphrase,
+                    split_key,
+                    kdf_cost,
+                    min_entropy,
+                    split_size,
+                    write_snapshot,
+                    checkpoint,
+                    force,
+                    ask: !yes,
+                };
+                let result = encrypt_folder(&sources, &out, pack_options, &compression, &options)
+                    .and_then(|report| {
+                        if let Some(key) = &sign {
+                            let path = signature::sign(&out, key, force)?;
+                            log::info!("Signed '{}' → '{}'", out.display(), path.display());
+                        }
+                        Ok(report)
+                    });
+                hooks.post(&out, &sources, &result)?;
+                result?
+            }
+        }
+        Commands::Decrypt {
+            input,
+            out_folder,
+            force,
+            raw: true,
+            signature,
+            keys,
+            ..
+        } => {
+            signature.check(&input)?;
+            decrypt_raw(&input, &out_folder, force, &keys)?
+        }
+        Commands::Decrypt {
+            input,
+            out_folder,
+            paths,
+            increments,
+            force,
+            skip_existing,
+            on_conflict,
+            no_symlinks,
+            strip_components,
+            invalid_names,
+            raw: _,
+            metadata,
+            signature,
+            keys,
+        } => {
+            // Validate patterns before asking for any secret
+            let options = ExtractOptions {
+                filter: PathFilter::new(&paths)?,
+                existing: match on_conflict {
+                    Some(Existing::Ask) if input == Path::new("-") => {
+                        anyhow::bail!("--on-conflict ask can't prompt with the archive on stdin")
+                    }
+                    Some(action) => action,
+                    None if force => Existing::Overwrite,
+                    None if skip_existing => Existing::Skip,
+                    None if input != Path::new("-")
+                        && io::stdin().is_terminal()
+                        && io::stderr().is_terminal() =>
+                    {
+                        Existing::Ask
+                    }
+                    None => Existing::Error,
+                },
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                no_symlinks,
+                strip_components,
+                invalid_names: invalid_names.unwrap_or_default(),
+            };
+            options.check_privileges()?;
+            signature.check(&input)?;
+            decrypt_file(&input, &increments, &out_folder, &options, &keys)?
+        }
+        Commands::Lock {
+            folder,
+            key,
+            compression,
+            shred,
+        } => {
+            let recipients = key.read_recipients()?;
+            let compression = compression::Settings::new(
+                compression.compression.unwrap_or_default(),
+                compression.level,
+                None,
+            )?;
+            lock_folder(
+                &folder,
+                &recipients,
+                &key.passphrase,
+                !key.no_confirm,
+                &compression,
+                shred,
+            )?
+        }
+        Commands::Unlock { input, keys } => unlock_archive(&input, &keys)?,
+        Commands::List { input, keys } => list_archive(&input, &keys, format)?,
+        Commands::Find {
+            mut args,
+            regex,
+            ignore_case,
+            keys,
+        } => {
+            let pattern = args.pop().expect("clap requires two arguments");
+            let matcher = NameMatcher::new(&pattern.to_string_lossy(), regex, ignore_case)?;
+            find_entries(&args, &matcher, &keys, format)?
+        }
+        Commands::Cat { input, path, keys } => cat_file(&input, &path, &keys)?,
+        Commands::Verify {
+            input,
+            signature,
+            keys,
+        } => {
+            signature.check(&input)?;
+            verify_archive(&input, &keys)?
+        }
+        Commands::Test { input, keys } => test_archive(&input, &keys)?,
+        Commands::Info {
+            input,
+            decrypt,
+            keys,
+        } => archive_info(&input, decrypt, &keys, format)?,
+        #[cfg(all(feature = "fuse", unix))]
+        Commands::Mount {
+            input,
+            mountpoint,
+            keys,
+        } => mount_archive(input, &mountpoint, keys)?,
+        #[cfg(feature = "tui")]
+        Commands::Browse {
+            input,
+            out_folder,
+            keys,
+        } => browse_archive(input, &out_folder, keys)?,
+        Commands::Diff {
+            input,
+            folder,
+            checksum,
+            filters,
+            keys,
+        } => diff_archive(&input, &folder, &filters.build()?, checksum, &keys, format)?,
+        Commands::DiffArchives { old, new, keys } => diff_archives(&old, &new, &keys, format)?,
+        Commands::Rekey {
+            input,
+            out,
+            new_key,
+            armor,
+            force,
+            keys,
+        } => {
+            let new_key = new_key.new_key()?;
+            streams::check_output(&out, force)?;
+            rekey(&input, &out, &new_key, &keys, armor, force)?
+        }
+        Commands::Watch {
+            folder,
+            out,
+            config,
+            mut key,
+            mut filters,
+            compression,
+            debounce,
+        } => {
+            let config = config.load()?;
+            if !key.names_key(false) {
+                config.add_recipients(&mut key.recipients, &mut key.recipient_files);
+            }
+            filters.excludes.extend(config.excludes.iter().cloned());
+            let (compression, level) =
+                config.compression(compression.compression, compression.level)?;
+            let recipients = key.read_recipients()?;
+            let key = if recipients.is_empty() {
+                SharedKey::Passphrase(passphrase::read_new(
+                    &key.passphrase,
+                    &out,
+                    !key.no_confirm,
+                    None,
+                )?)
+            } else {
+                // Parse now so typos fail before watching starts
+                parse_recipients(&recipients)?;
+                SharedKey::Recipients(recipients)
+            };
+            let compression = compression::Settings::new(compression, level, None)?;
+            watch_folder(
+                &folder,
+                &out,
+                &key,
+                &filters.build()?,
+                &compression,
+                debounce,
+            )?
+        }
+        Commands::Batch {
+            manifest,
+            config,
+            mut key,
+            mut filters,
+            compression,
+            force,
+            keep_going,
+            jobs: parallel,
+            job_memory,
+        } => {
+            let config = config.load()?;
+            if !key.names_key(false) {
+                config.add_recipients(&mut key.recipients, &mut key.recipient_files);
+            }
+            filters.excludes.extend(config.excludes.iter().cloned());
+            let (compression, level) =
+                config.compression(compression.compression, compression.level)?;
+            let recipients = key.read_recipients()?;
+            let jobs = read_batch_manifest(&manifest, &config)?;
+            for (_, out) in &jobs {
+                streams::check_output(out, force)?;
+            }
+            let key = if recipients.is_empty() {
+                let out = &jobs[0].1;
+                SharedKey::Passphrase(passphrase::read_new(
+                    &key.passphrase,
+                    out,
+                    !key.no_confirm,
+                    None,
+                )?)
+            } else {
+                parse_recipients(&recipients)?;
+                SharedKey::Recipients(recipients)
+            };
+            let mut compression = compression::Settings::new(compression, level, None)?;
+            let parallel = batch_parallelism(parallel, jobs.len(), &mut compression, job_memory);
+            let hooks = Hooks::new(config.pre_hook.clone(), config.post_hook.clone());
+            let filters = filters.build()?;
+            let options = BatchOptions {
+                force,
+                keep_going,
+                parallel,
+            };
+            batch_encrypt(&jobs, &key, &filters, &compression, options, &hooks)?
+        }
+        Commands::Append {
+            input,
+            paths,
+            filters,
+            metadata,
+            rewrite,
+        } => {
+            let options = PackOptions {
+                filters: filters.build()?,
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                ..PackOptions::default()
+            };
+            rewrite_archive(&input, Edit::Append(paths), options, &rewrite)?
+        }
+        Commands::Remove {
+            input,
+            paths,
+            rewrite,
+        } => {
+            let edit = Edit::Remove(PathFilter::new(&paths)?);
+            rewrite_archive(&input, edit, PackOptions::default(), &rewrite)?
+        }
+        Commands::Update {
+            input,
+            folder,
+            checksum,
+            delete,
+            filters,
+            metadata,
+            rewrite,
+        } => {
+            let options = PackOptions {
+                filters: filters.build()?,
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                ..PackOptions::default()
+            };
+            let edit = Edit::Update {
+                folder,
+                checksum,
+                delete,
+            };
+            rewrite_archive(&input, edit, options, &rewrite)?
+        }
+        Commands::Merge {
+            mut args,
+            on_conflict,
+            new_key,
+            compression,
+            level,
+            armor,
+            force,
+            keys,
+        } => {
+            let out = args.pop().expect("clap requires OUT");
+            let new_key = new_key.new_key()?;
+            streams::check_output(&out, force)?;
+            let merged = Merged {
+                on_conflict,
+                new_key,
+                compression,
+                level,
+                armor,
+                force,
+            };
+            merge_archives(&args, &out, &merged, &keys)?
+        }
+        #[cfg(unix)]
+        Commands::Agent { ttl, socket } => {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => agent::default_socket()?,
+            };
+            println!(
+                "{}={}; export {};",
+                agent::SOCKET_ENV,
+                socket.display(),
+                agent::SOCKET_ENV
+            );
+            io::stdout().flush()?;
+            agent::serve(&socket, ttl)?;
+            Report::new("agent")
+        }
+        #[cfg(unix)]
+        Commands::Daemon { socket } => {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => daemon::default_socket()?,
+            };
+            daemon::serve(&socket)?;
+            Report::new("daemon")
+        }
+        Commands::Keygen { out } => keygen(&out, format)?,
+        Commands::ExportKey {
+            identity,
+            passphrase_file,
+            qr,
+            svg,
+        } => export_key(
+            identity.as_deref(),
+            passphrase_file.as_deref(),
+            qr,
+            svg.as_deref(),
+            format,
+        )?,
+        Commands::ImportKey { out, from } => import_key(&out, from.as_deref(), format)?,
+        Commands::Prune {
+            dir,
+            config,
+            template,
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_yearly,
+            dry_run,
+        } => {
+            let policy = prune::Policy {
+                last: keep_last,
+                daily: keep_daily,
+                weekly: keep_weekly,
+                monthly: keep_monthly,
+                yearly: keep_yearly,
+            };
+            if policy.is_empty() {
+                anyhow::bail!("give at least one --keep-* option; prune never deletes everything");
+            }
+            let template = match template {
+                Some(template) => template,
+                None => config
+                    .load()?
+                    .output
+                    .context("no --template given and no `output` template in the config file")?,
+            };
+            prune_backups(&dir, &prune::Template::new(&template)?, &policy, dry_run)?
+        }
+        Commands::Repo { command } => repo_command(command, format)?,
+        Commands::Completions { shell } => {
+            let mut cli = Cli::command();
+            let name = cli.get_name().to_string();
+            clap_complete::generate(shell, &mut cli, name, &mut io::stdout());
+            Report::new("completions")
+        }
+    };
+
+    Ok(report)
+}
+
+/// `SOURCE_DATE_EPOCH` from the environment (the reproducible-builds.org convention), or 0
+fn env_source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid SOURCE_DATE_EPOCH '{}'", value)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// How `encrypt_folder` keys and writes its archive
+struct EncryptOptions {
+    /// Encrypt a single file's bytes, without tar or compression
+    raw: bool,
+    armor: bool,
+    /// Pad the output up to a multiple of this size
+    pad_to: Option<u64>,
+    /// Recipients, those of the -R files included; without any, a passphrase is used
+    recipients: Vec<String>,
+    passphrase: PassphraseArgs,
+    /// Ask for a new passphrase twice
+    confirm: bool,
+    generate_passphrase: bool,
+    /// Add a passphrase stanza beside the recipients
+    with_passphrase: bool,
+    split_key: Option<SplitKey>,
+    kdf_cost: Option<u8>,
+    min_entropy: Option<u32>,
+    split_size: Option<u64>,
+    write_snapshot: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    force: bool,
+    /// Show the size summary and ask before encrypting, on a terminal
+    ask: bool,
+}
+
+fn encrypt_folder(
+    sources: &[PathBuf],
+    out: &PathBuf,
+    pack_options: PackOptions,
+    compression: &compression::Settings,
+    options: &EncryptOptions,
+) -> Result<Report> {
+    let EncryptOptions {
+        raw,
+        armor,
+        pad_to,
+        ref recipients,
+        ref passphrase,
+        confirm,
+        generate_passphrase,
+        with_passphrase,
+        split_key,
+        kdf_cost,
+        min_entropy,
+        split_size,
+        ref write_snapshot,
+        ref checkpoint,
+        force,
+        ask,
+    } = *options;
+    // Pre-scan so the progress bar has a total, and a surprisingly big run can be called off
+    let names = sources
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    progress::emit("scan-started", serde_json::json!({ "sources": names }));
+    let summary = Sources::new(sources.to_vec())?.scan(&pack_options.filters)?;
+    if ask && io::stdin().is_terminal() && io::stderr().is_terminal() {
+        let estimate = if raw {
+            summary.bytes
+        } else {
+            estimate_size(sources, &pack_options, compression, summary.tar_bytes)?
+        };
+        confirm_size(&summary, estimate, out)?;
+    }
+
+    // Settle the key up front so bad recipients fail before any output is created
+    let mut locker = Locker::with_paths(sources.to_vec()).raw(raw).armor(armor);
+    if let Some(granularity) = pad_to {
+        locker = locker.pad_to(granularity);
+    }
+    if let Some(log_n) = kdf_cost {
+        locker = locker.kdf_cost(log_n);
+    }
+    // Continuing a checkpoint, the passphrase must be the one already in the archive
+    let read_passphrase = || match checkpoint {
+        Some(path) if path.exists() => passphrase::read(passphrase, out),
+        _ => passphrase::read_new(passphrase, out, confirm, min_entropy),
+    };
+    let mut dealt = None;
+    let locker = if let Some(split) = split_key {
+        let (recipient, shares) = shares::Recipient::new(split);
+        dealt = Some(shares);
+        locker.recipients(vec![Box::new(recipient)])
+    } else if generate_passphrase {
+        locker.passphrase(generated_passphrase())
+    } else if recipients.is_empty() {
+        locker.passphrase(read_passphrase()?)
+    } else if with_passphrase {
+        let recipients = parse_recipients(recipients)?;
+        locker.passphrase_and_recipients(read_passphrase()?, recipients)
+    } else {
+        locker.recipients(parse_recipients(recipients)?)
+    };
+
+    // The zip writer reports file bytes read rather than tar bytes written
+    let zip = pack_options.container == Container::Zip;
+    let bar = progress::bar(if raw || zip {
+        summary.bytes
+    } else {
+        summary.tar_bytes
+    });
+
+    let archive = match split_size {
+        Some(_) => streams::volume_path(out, 1),
+        None => out.clone(),
+    };
+    let locker = locker
+        .options(pack_options)
+        .compression_settings(*compression)
+        .progress(bar.clone());
+    let (locked, bytes_out) = if let Some(checkpoint) = checkpoint {
+        progress::start(&bar);
+        let locked = locker.encrypt_resumable(out, split_size, force, checkpoint)?;
+        bar.finish_and_clear();
+        (locked, streams::input_len(&archive).unwrap_or(0))
+    } else {
+        // Create output file (or stdout)
+        let mut w = CountingWriter::new(streams::create_output(out, force, split_size)?);
+
+        progress::start(&bar);
+        let locked = locker.encrypt_to(&mut w)?;
+        bar.finish_and_clear();
+
+        // Flush buffered output and move the finished archive into place
+        w.flush().context("failed to flush output buffer")?;
+        let bytes_out = w.count();
+        w.into_inner().commit()?;
+        (locked, bytes_out)
+    };
+    let (stats, mut snapshot) = (locked.stats, locked.snapshot);
+
+    if let Some(path) = write_snapshot {
+        snapshot.deleted.clear();
+        snapshot.save(path, force)?;
+    }
+    if let Some(dealt) = dealt {
+        let paths = dealt.write(out, force)?;
+        log::info!(
+            "Wrote {} key shares: '{}'; hand one to each custodian",
+            paths.len(),
+            paths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join("', '")
+        );
+    }
+
+    let sources = sources
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    log::info!(
+        "Encrypted '{}' → '{}'",
+        sources.join("', '"),
+        archive.display()
+    );
+    Ok(Report {
+        archive: Some(archive),
+        files: stats.files,
+        bytes_in: stats.bytes,
+        bytes_out,
+        compression_ratio: Report::ratio(stats.bytes, bytes_out),
+        ..Report::new("encrypt")
+    })
+}
+
+/// Rough archive size for `sources`, whose tar stream is `tar_bytes` long
+fn estimate_size(
+    sources: &[PathBuf],
+    options: &PackOptions,
+    compression: &compression::Settings,
+    tar_bytes: u64,
+) -> Result<u64> {
+    let files = folder_lock::pack::plan(&Sources::new(sources.to_vec())?, options)?
+        .into_iter()
+        .filter(|item| !item.entry.is_dir && !item.entry.is_symlink)
+        .map(|item| item.entry.path)
+        .collect::<Vec<_>>();
+    compression::estimate(compression, &files, tar_bytes)
+        .context("failed to sample files for the size estimate")
+}
+
+/// Show how much `encrypt` is about to store and ask whether to go on
+fn confirm_size(summary: &walk::Summary, estimate: u64, out: &Path) -> Result<()> {
+    eprintln!(
+        "About to encrypt {} files ({}) to '{}', estimated at {}.",
+        summary.files,
+        HumanBytes(summary.bytes),
+        out.display(),
+        HumanBytes(estimate)
+    );
+    if let Some(free) = streams::free_space(out) {
+        if estimate > free {
+            eprintln!(
+                "warning: only {} is free at the destination",
+                HumanBytes(free)
+            );
+        }
+    }
+    eprint!("Continue? [y/N] ");
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("failed to read the answer")?;
+    if !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+        anyhow::bail!("cancelled; nothing was written");
+    }
     Ok(())
 }
 
-fn encrypt_folder(folder: &PathBuf, out: &PathBuf) -> Result<()> {
+/// Print what `encrypt` would store and how big the archive would roughly be
+fn dry_run_encrypt(
+    sources: &Sources,
+    options: &PackOptions,
+    compression: &compression::Settings,
+    format: OutputFormat,
+) -> Result<Report> {
+    let planned = folder_lock::pack::plan(sources, options)?;
+    let mut report = Report::new("encrypt");
+    // Root entry, then one header per entry plus padded data, then the two end blocks
+    let mut tar_bytes = 3 * 512;
+    let mut files = Vec::new();
+    for item in &planned {
+        let entry = &item.entry;
+        tar_bytes += 512 + item.size.div_ceil(512) * 512;
+        if !entry.is_dir {
+            report.files += 1;
+            report.bytes_in += item.size;
+            if !entry.is_symlink {
+                files.push(entry.path.clone());
+            }
+        }
+        let kind = if entry.is_dir {
+            "dir"
+        } else if entry.is_symlink {
+            "symlink"
+        } else {
+            "file"
+        };
+        if format == OutputFormat::Json {
+            report.entries.push(EntryInfo {
+                archive: None,
+                path: snapshot::key(&entry.rel),
+                kind,
+                mode: item.mode,
+                size: item.size,
+                mtime: item.mtime,
+            });
+        } else {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            println!("{:>12} {}{}", item.size, entry.rel.display(), suffix);
+        }
+    }
+    report.bytes_out = compression::estimate(compression, &files, tar_bytes)
+        .context("failed to sample files for the size estimate")?;
+    report.compression_ratio = Report::ratio(report.bytes_in, report.bytes_out);
+    log::info!(
+        "Would archive {} files, {} bytes; estimated {} bytes with {:?} (dry run, nothing written)",
+        report.files,
+        report.bytes_in,
+        report.bytes_out,
+        compression.algorithm
+    );
+    Ok(report)
+}
+
+/// The secret every `watch` run, or every `batch` job, encrypts to
+enum SharedKey {
+    Passphrase(age::secrecy::SecretString),
+    /// Kept as strings and parsed per run, since parsed recipients can't be reused
+    Recipients(Vec<String>),
+}
+
+impl SharedKey {
+    fn locker(&self, paths: Vec<PathBuf>) -> Result<Locker> {
+        let locker = Locker::with_paths(paths);
+        Ok(match self {
+            SharedKey::Passphrase(pass) => locker.passphrase(pass.clone()),
+            SharedKey::Recipients(recipients) => locker.recipients(parse_recipients(recipients)?),
+        })
+    }
+}
+
+/// Encrypt `folder` to `out` now and after every change, until interrupted
+fn watch_folder(
+    folder: &Path,
+    out: &Path,
+    key: &SharedKey,
+    filters: &Filters,
+    compression: &compression::Settings,
+    debounce: Duration,
+) -> Result<Report> {
+    check_folder(folder)?;
+    if !streams::is_remote(out) {
+        let root = folder
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {}", folder.display()))?;
+        let out_dir = match out.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let out_dir = out_dir
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {}", out_dir.display()))?;
+        if out_dir.starts_with(&root) {
+            // Every write would trigger another run
+            anyhow::bail!(
+                "output '{}' must be outside the watched folder",
+                out.display()
+            );
+        }
+    }
+
+    log::info!("Watching '{}' (Ctrl-C to stop)", folder.display());
+    folder_lock::watch::watch(folder, debounce, || {
+        let locker = key.locker(vec![folder.to_path_buf()])?;
+        let mut w = CountingWriter::new(streams::create_output(out, true, None)?);
+        let locked = locker
+            .filters(filters.clone())
+            .compression_settings(*compression)
+            .encrypt_to(&mut w)?;
+        w.flush().context("failed to flush output buffer")?;
+        let bytes_out = w.count();
+        w.into_inner().commit()?;
+        log::info!(
+            "Encrypted '{}' → '{}' ({} files, {} bytes)",
+            folder.display(),
+            out.display(),
+            locked.stats.files,
+            bytes_out
+        );
+        Ok(())
+    })?;
+    Ok(Report::new("watch"))
+}
+
+/// The `(source, archive)` jobs listed in a `batch` manifest
+fn read_batch_manifest(
+    manifest: &Path,
+    config: &config::Settings,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let text = if streams::is_stdio(manifest) {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("failed to read the job list from stdin")?;
+        text
+    } else {
+        std::fs::read_to_string(manifest)
+            .with_context(|| format!("failed to read {}", manifest.display()))?
+    };
+    let mut jobs: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let (source, out) = match line.split_once('\t') {
+            Some((source, out)) if !out.trim().is_empty() => {
+                (PathBuf::from(source.trim()), PathBuf::from(out.trim()))
+            }
+            _ => {
+                let source = PathBuf::from(line.trim());
+                let out = config.output_path(&source).with_context(|| {
+                    format!("{}:{}: no output archive", manifest.display(), n + 1)
+                })?;
+                (source, out)
+            }
+        };
+        // A second job writing the same archive would replace the first one's
+        if jobs.iter().any(|(_, other)| *other == out) {
+            anyhow::bail!(
+                "{}:{}: '{}' is the output of an earlier job too",
+                manifest.display(),
+                n + 1,
+                out.display()
+            );
+        }
+        jobs.push((source, out));
+    }
+    if jobs.is_empty() {
+        anyhow::bail!("{} lists no jobs", manifest.display());
+    }
+    Ok(jobs)
+}
+
+/// How `batch_encrypt` runs its jobs
+#[derive(Clone, Copy)]
+struct BatchOptions {
+    force: bool,
+    /// Run every job even after one fails
+    keep_going: bool,
+    /// Jobs running at once
+    parallel: usize,
+}
+
+/// How many of `jobs` jobs can run at once, at most `requested`
+///
+/// Each job gets an even share of the cores for its compression threads, and as many run
+/// as fit in available memory at `job_memory` each (the compression settings' estimate
+/// when `None`).
+fn batch_parallelism(
+    requested: u32,
+    jobs: usize,
+    compression: &mut compression::Settings,
+    job_memory: Option<u64>,
+) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut parallel = (requested as usize).min(jobs).min(cores);
+    if parallel < requested as usize && parallel < jobs {
+        log::warn!("running {} jobs at once, one per core", parallel);
+    }
+    compression.threads = (compression.threads / parallel as u32).max(1);
+    let job_memory = job_memory.unwrap_or_else(|| compression.memory());
+    if let Some(available) = available_memory() {
+        let fit = (available / job_memory.max(1)).max(1) as usize;
+        if fit < parallel {
+            log::warn!(
+                "running {} jobs at once, as {} is available and each may take {}",
+                fit,
+                HumanBytes(available),
+                HumanBytes(job_memory)
+            );
+            parallel = fit;
+        }
+    }
+    parallel
+}
+
+/// Memory the system can hand out without swapping, from `/proc/meminfo`
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+/// Run the `batch` jobs, `options.parallel` at a time, adding up their reports in job order
+///
+/// No further jobs start after a failure unless `keep_going`, which runs every job and
+/// fails at the end with the list of jobs that didn't make it. Running jobs draw one
+/// progress bar each, labelled with their archive.
+fn batch_encrypt(
+    jobs: &[(PathBuf, PathBuf)],
+    key: &SharedKey,
+    filters: &Filters,
+    compression: &compression::Settings,
+    options: BatchOptions,
+    hooks: &Hooks,
+) -> Result<Report> {
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<Report>>>> =
+        Mutex::new(std::iter::repeat_with(|| None).take(jobs.len()).collect());
+    let worker = || {
+        while !stopped.load(Ordering::Relaxed) {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some((source, out)) = jobs.get(i) else {
+                break;
+            };
+            log::debug!(
+                "Job {} of {}: '{}' → '{}'",
+                i + 1,
+                jobs.len(),
+                source.display(),
+                out.display()
+            );
+            let sources = [source.clone()];
+            let label = (options.parallel > 1).then(|| out.display().to_string());
+            let result = hooks.pre(out, &sources).and_then(|()| {
+                let result =
+                    encrypt_job(source, out, key, filters, compression, options.force, label);
+                hooks.post(out, &sources, &result)?;
+                result
+            });
+            if let Err(e) = &result {
+                if options.keep_going {
+                    log::error!("'{}': {:#}", source.display(), e);
+                } else {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+            }
+            results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+        }
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..options.parallel {
+            scope.spawn(worker);
+        }
+        worker();
+    });
+
+    let results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    let written = results.iter().filter(|r| matches!(r, Some(Ok(_)))).count();
+    let mut report = Report::new("batch");
+    let mut failures = Vec::new();
+    for (i, result) in results.into_iter().enumerate() {
+        let source = &jobs[i].0;
+        match result {
+            Some(Ok(job)) => {
+                report.files += job.files;
+                report.bytes_in += job.bytes_in;
+                report.bytes_out += job.bytes_out;
+                report.jobs.push(job);
+            }
+            Some(Err(e)) if !options.keep_going => {
+                return Err(e.context(format!(
+                    "job {} of {} failed ('{}'); {} archives were written",
+                    i + 1,
+                    jobs.len(),
+                    source.display(),
+                    written
+                )));
+            }
+            Some(Err(e)) => failures.push(format!("'{}': {:#}", source.display(), e)),
+            // Not started, after another job failed
+            None => {}
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} of {} jobs failed:\n  {}",
+            failures.len(),
+            jobs.len(),
+            failures.join("\n  ")
+        );
+    }
+    log::info!(
+        "Wrote {} archives ({} files, {})",
+        report.jobs.len(),
+        report.files,
+        HumanBytes(report.bytes_out)
+    );
+    report.compression_ratio = Report::ratio(report.bytes_in, report.bytes_out);
+    Ok(report)
+}
+
+/// Encrypt one `batch` job's `source` to `out`, its progress bar labelled with `label`
+fn encrypt_job(
+    source: &Path,
+    out: &Path,
+    key: &SharedKey,
+    filters: &Filters,
+    compression: &compression::Settings,
+    force: bool,
+    label: Option<String>,
+) -> Result<Report> {
+    let sources = vec![source.to_path_buf()];
+    let summary = Sources::new(sources.clone())?.scan(filters)?;
+    let locker = key.locker(sources)?;
+    let bar = progress::bar(summary.tar_bytes);
+    if let Some(label) = label {
+        progress::label(&bar, label);
+    }
+    let mut w = CountingWriter::new(streams::create_output(out, force, None)?);
+    progress::start(&bar);
+    let locked = locker
+        .filters(filters.clone())
+        .compression_settings(*compression)
+        .progress(bar.clone())
+        .encrypt_to(&mut w)?;
+    bar.finish_and_clear();
+    w.flush().context("failed to flush output buffer")?;
+    let bytes_out = w.count();
+    w.into_inner().commit()?;
+    log::info!("Encrypted '{}' → '{}'", source.display(), out.display());
+    Ok(Report {
+        archive: Some(out.to_path_buf()),
+        files: locked.stats.files,
+        bytes_in: locked.stats.bytes,
+        bytes_out,
+        compression_ratio: Report::ratio(locked.stats.bytes, bytes_out),
+        ..Report::new("encrypt")
+    })
+}
+
+/// Delete the archives in `dir` that `policy` doesn't keep
+fn prune_backups(
+    dir: &Path,
+    template: &prune::Template,
+    policy: &prune::Policy,
+    dry_run: bool,
+) -> Result<Report> {
+    check_folder(dir)?;
+    let plan = prune::plan(prune::scan(dir, template)?, policy);
+    for backup in &plan.keep {
+        log::debug!("keeping '{}'", backup.path.display());
+    }
+    let mut report = Report::new("prune");
+    for backup in plan.remove {
+        if dry_run {
+            log::info!("Would delete '{}'", backup.path.display());
+        } else {
+            for file in &backup.files {
+                std::fs::remove_file(file)
+                    .with_context(|| format!("failed to delete {}", file.display()))?;
+            }
+            log::info!("Deleted '{}'", backup.path.display());
+        }
+        report.files += 1;
+        report.removed.push(backup.path);
+    }
+    log::info!(
+        "{} {} archives, kept {}",
+        if dry_run { "Would delete" } else { "Deleted" },
+        report.files,
+        plan.keep.len()
+    );
+    Ok(report)
+}
+
+fn repo_command(command: RepoCommand, format: OutputFormat) -> Result<Report> {
+    match command {
+        RepoCommand::Init { dir, key } => {
+            let recipients = key.read_recipients()?;
+            let encryptor = if recipients.is_empty() {
+                age::Encryptor::with_user_passphrase(passphrase::read_new(
+                    &key.passphrase,
+                    &Repo::key_path(&dir),
+                    !key.no_confirm,
+                    None,
+                )?)
+            } else {
+                recipients_encryptor(&recipients)?
+            };
+            Repo::init(&dir, encryptor)?;
+            log::info!("Created repository '{}'", dir.display());
+            Ok(Report {
+                archive: Some(dir),
+                ..Report::new("repo")
+            })
+        }
+        RepoCommand::Backup {
+            dir,
+            sources,
+            filters,
+            metadata,
+            keys,
+        } => {
+            let repo = open_repo(&dir, &keys)?;
+            let options = PackOptions {
+                filters: filters.build()?,
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                ..PackOptions::default()
+            };
+            let names = sources.clone();
+            let sources = Sources::new(sources)?;
+            let bar = progress::bar(sources.scan(&options.filters)?.bytes);
+            progress::start(&bar);
+            let stats = repo.backup(&sources, &names, &options, &bar)?;
+            bar.finish_and_clear();
+            log::info!(
+                "Stored snapshot '{}': {} files, {} new of {} chunks ({})",
+                stats.snapshot,
+                stats.files,
+                stats.new_chunks,
+                stats.chunks,
+                HumanBytes(stats.new_bytes)
+            );
+            Ok(Report {
+                archive: Some(dir),
+                files: stats.files,
+                bytes_in: stats.bytes,
+                bytes_out: stats.new_bytes,
+                snapshot: Some(stats.snapshot),
+                ..Report::new("repo")
+            })
+        }
+        RepoCommand::List { dir } => {
+            let snapshots = Repo::snapshots(&dir)?;
+            if format != OutputFormat::Json {
+                for name in &snapshots {
+                    println!("{}", name);
+                }
+            }
+            Ok(Report {
+                archive: Some(dir),
+                snapshots,
+                ..Report::new("repo")
+            })
+        }
+        RepoCommand::Restore {
+            dir,
+            snapshot,
+            out_folder,
+            paths,
+            force,
+            skip_existing,
+            metadata,
+            keys,
+        } => {
+            check_folder(&out_folder)?;
+            let options = ExtractOptions {
+                filter: PathFilter::new(&paths)?,
+                existing: if force {
+                    Existing::Overwrite
+                } else if skip_existing {
+                    Existing::Skip
+                } else {
+                    Existing::Error
+                },
+                preserve_owner: metadata.preserve_owner,
+                preserve_permissions: metadata.preserve_permissions,
+                xattrs: metadata.xattrs,
+                ..ExtractOptions::default()
+            };
+            options.check_privileges()?;
+            let mut archive = open_repo(&dir, &keys)?.restore(&snapshot)?;
+            let bar = progress::bar(0);
+            progress::start(&bar);
+            let stats = extract::extract(&mut archive, &out_folder, &options, &bar)?;
+            bar.finish_and_clear();
+            log::info!("Restored '{}' → '{}'", snapshot, out_folder.display());
+            Ok(Report {
+                archive: Some(dir),
+                files: stats.files,
+                bytes_out: stats.bytes,
+                snapshot: Some(snapshot),
+                ..Report::new("repo")
+            })
+        }
+    }
+}
+
+/// Open the repository at `dir`, decrypting its key with `keys`
+fn open_repo(dir: &Path, keys: &KeyArgs) -> Result<Repo> {
+    let key = open_decrypted(&Repo::key_path(dir), keys, &ProgressBar::hidden())
+        .context("failed to open the repository key")?;
+    Repo::open(dir, key)
+}
+
+/// Fail unless `folder` is an existing directory
+fn check_folder(folder: &Path) -> Result<()> {
+    if folder.symlink_metadata().is_err() {
+        return Err(Failure::SourceMissing.error(format!("'{}' does not exist", folder.display())));
+    }
     if !folder.is_dir() {
         anyhow::bail!("'{}' is not a directory", folder.display());
     }
+    Ok(())
+}
 
-    println!("Enter passphrase (input hidden):");
-    let pass = read_password().context("failed to read passphrase")?;
-    if pass.is_empty() {
-        anyhow::bail!("empty passphrase is not allowed");
-    }
+/// A random passphrase, shown once on stderr
+fn generated_passphrase() -> age::secrecy::SecretString {
+    let pass = passphrase::generate();
+    eprintln!("Using autogenerated passphrase:");
+    eprintln!("    {}", pass.expose_secret());
+    pass
+}
 
-    // Create output file
-    let fout = File::create(out)
-        .with_context(|| format!("failed to create output file {}", out.display()))?;
-    let mut w = BufWriter::new(fout);
+fn recipients_encryptor(recipients: &[String]) -> Result<age::Encryptor> {
+    let recipients = parse_recipients(recipients)?;
+    age::Encryptor::with_recipients(recipients).context("no recipients given")
+}
 
-    // Create age encryptor with passphrase
-    // NOTE: The `age` crate provides helpers to create an encryptor that writes to a writer.
-    // Here we use a passphrase-based encryptor which produces an encrypting writer.
-    let encryptor = age::Encryptor::with_user_passphrase(pass.into_bytes());
-    let mut age_writer = encryptor
-        .wrap_output(&mut w)
-        .context("failed to create age encrypting writer")?;
+/// The secret `rekey` re-encrypts to
+enum NewKey {
+    Passphrase {
+        file: Option<PathBuf>,
+        confirm: bool,
+    },
+    Generated,
+    Recipients(Vec<String>),
+}
 
-    // Create gzip encoder that writes into the age_writer,
-    // then use a tar builder that writes into the gzip encoder.
-    let gz = GzEncoder::new(&mut age_writer, Compression::default());
-    let mut tar = Builder::new(gz);
+/// Stream the decrypted payload of `input` straight into a new age envelope at `out`
+///
+/// The compressed tar stream is copied as-is, so nothing is unpacked or recompressed and
+/// plaintext only ever exists in memory. The old secret is asked for before the new one.
+fn rekey(
+    input: &PathBuf,
+    out: &PathBuf,
+    new_key: &NewKey,
+    keys: &KeyArgs,
+    armor: bool,
+    force: bool,
+) -> Result<Report> {
+    let bar = progress::bar(0);
+    let mut plain_reader = open_decrypted(input, keys, &bar)?;
 
-    // Append folder contents into tar archive
-    tar.append_dir_all(".", folder)
-        .with_context(|| format!("failed to add '{}' to tar archive", folder.display()))?;
+    let encryptor = match new_key {
+        NewKey::Passphrase { file, confirm } => age::Encryptor::with_user_passphrase(
+            passphrase::read_replacement(file.as_deref(), *confirm)?,
+        ),
+        NewKey::Generated => age::Encryptor::with_user_passphrase(generated_passphrase()),
+        NewKey::Recipients(recipients) => recipients_encryptor(recipients)?,
+    };
 
-    // Finalize tar (which also flushes gzip data)
-    tar.finish().context("failed to finalize tar archive")?;
+    let mut w = CountingWriter::new(streams::create_output(out, force, None)?);
+    let format = if armor {
+        age::armor::Format::AsciiArmor
+    } else {
+        age::armor::Format::Binary
+    };
+    let armored =
+        age::armor::ArmoredWriter::wrap_output(&mut w, format).context("failed to create armor")?;
+    let mut age_writer = encryptor
+        .wrap_output(armored)
+        .context("failed to create age encrypting writer")?;
 
-    // Drop tar to ensure GzEncoder is flushed
-    drop(tar);
+    progress::start(&bar);
+    // Every chunk is authenticated as it is read, so a corrupt input aborts the copy and
+    // the partial output is discarded
+    let bytes_in =
+        io::copy(&mut plain_reader, &mut age_writer).context("failed to re-encrypt archive")?;
+    bar.finish_and_clear();
 
-    // Finalize the encryption writer
     age_writer
         .finish()
+        .and_then(age::armor::ArmoredWriter::finish)
         .context("failed to finalize age writer")?;
+    w.flush().context("failed to flush output buffer")?;
+    let bytes_out = w.count();
+    w.into_inner().commit()?;
+
+    log::info!("Re-encrypted '{}' → '{}'", input.display(), out.display());
+    Ok(Report {
+        archive: Some(out.clone()),
+        bytes_in,
+        bytes_out,
+        ..Report::new("rekey")
+    })
+}
+
+/// What `rewrite_archive` changes
+enum Edit {
+    Append(Vec<PathBuf>),
+    Remove(PathFilter),
+    /// Store again what changed in `folder`, which the archive was made from
+    Update {
+        folder: PathBuf,
+        checksum: bool,
+        delete: bool,
+    },
+}
+
+impl Edit {
+    fn command(&self) -> &'static str {
+        match self {
+            Edit::Append(_) => "append",
+            Edit::Remove(_) => "remove",
+            Edit::Update { .. } => "update",
+        }
+    }
+}
+
+/// Apply `edit` to the archive `input`, replacing it atomically with the new archive
+fn rewrite_archive(
+    input: &PathBuf,
+    edit: Edit,
+    mut options: PackOptions,
+    args: &RewriteArgs,
+) -> Result<Report> {
+    let command = edit.command();
+    if streams::is_stdio(input) || streams::is_remote(input) {
+        anyhow::bail!("{} needs a local archive file, which it replaces", command);
+    }
+    if input.extension().is_some_and(|ext| ext == "001") {
+        anyhow::bail!("split archives can't be changed; join the volumes first");
+    }
+    let mut recipients = args.recipients.clone();
+    for file in &args.recipient_files {
+        recipients.extend(read_recipients_file(file)?);
+    }
+    // Settle what can fail without a key before asking for one
+    match &edit {
+        Edit::Append(paths) => {
+            Sources::named(paths.clone())?;
+        }
+        Edit::Update { folder, .. } => check_folder(folder)?,
+        Edit::Remove(_) => {}
+    }
+    let envelope = folder_lock::envelope::read(BufReader::new(streams::open_input(input)?))
+        .with_context(|| format!("failed to read {}", input.display()))?;
+    if envelope.key_kind() != KeyKind::Passphrase {
+        if recipients.is_empty() {
+            anyhow::bail!(
+                "'{}' is encrypted to recipients, which can't be read back; pass them with -r/-R",
+                input.display()
+            );
+        }
+        if matches!(edit, Edit::Update { .. }) && !args.keys.has_identities() {
+            // The archive is read twice, and a pasted identity can't be asked for again
+            anyhow::bail!("update needs -i/--identity for recipient-encrypted archives");
+        }
+    }
+    let new_recipients = if recipients.is_empty() {
+        None
+    } else {
+        Some(parse_recipients(&recipients)?)
+    };
+
+    let bar = progress::bar(0);
+    let (mut plain, pass) = open_decrypted_keeping(input, &args.keys, &bar)?;
+    let mut changes = Vec::new();
+    if let Edit::Update {
+        folder,
+        checksum,
+        delete,
+    } = &edit
+    {
+        let mut archive = folder_lock::open_archive(plain)?;
+        let (archived, manifest) = Snapshot::with_manifest(&mut archive)?;
+        if *checksum && manifest.is_none() {
+            log::warn!("archive has no checksum manifest; comparing by size and mtime");
+        }
+        changes = diff::diff(
+            &archived,
+            manifest.as_ref(),
+            folder,
+            &options.filters,
+            *checksum,
+        )?;
+        if !delete {
+            changes.retain(|c| c.change != diff::Change::Removed);
+        }
+        if changes.is_empty() {
+            log::info!("'{}' is up to date", input.display());
+            return Ok(Report {
+                archive: Some(input.clone()),
+                ..Report::new(command)
+            });
+        }
+        drop(archive);
+        // Second pass, with the secret that opened the first
+        let r = BufReader::new(streams::open_input(input)?);
+        plain = folder_lock::decrypt(r, |kind| match (kind, &pass) {
+            (KeyKind::Passphrase | KeyKind::Either, Some(pass)) => {
+                Ok(Key::Passphrase(pass.clone()))
+            }
+            _ => Ok(Key::Identities(args.keys.read_identities()?)),
+        })?;
+    }
+    // The rewritten archive keeps the label and metadata
+    let (layout, plain) = peek_header(plain)?;
+    if let Some(layout) = layout {
+        options.label = layout.label;
+        options.meta = layout.meta;
+    }
+    let mut plain = BufReader::new(plain);
+    let head = plain.fill_buf().context("failed to decrypt")?;
+    let detected = compression::detect(head);
+    let algorithm = args.compression.unwrap_or(detected);
+    let compression = compression::Settings::new(algorithm, args.level, None)?;
+    let archive = folder_lock::open_archive(Box::new(plain))?;
+
+    let paths = match &edit {
+        Edit::Append(paths) => paths.clone(),
+        Edit::Update { folder, .. } => vec![folder.clone()],
+        Edit::Remove(_) => Vec::new(),
+    };
+    let mut locker = Locker::with_paths(paths)
+        .options(options)
+        .compression_settings(compression);
+    locker = match new_recipients {
+        Some(recipients) if envelope.key_kind() == KeyKind::Either => match pass {
+            Some(pass) => locker
+                .passphrase_and_recipients(pass, recipients)
+                .kdf_cost(envelope.scrypt_log_n.unwrap_or(kdf::DEFAULT_COST)),
+            None => {
+                log::warn!(
+                    "'{}' was opened with an identity, so its passphrase can't be kept",
+                    input.display()
+                );
+                locker.recipients(recipients)
+            }
+        },
+        Some(recipients) => locker.recipients(recipients),
+        None => {
+            let pass = pass.expect("a passphrase archive was opened with a passphrase");
+            let locker = locker.passphrase(pass);
+            // Keep the work factor of the passphrase too, if it is one we can write
+            match envelope.scrypt_log_n {
+                Some(log_n) if (kdf::MIN_COST..=kdf::MAX_COST).contains(&log_n) => {
+                    locker.kdf_cost(log_n)
+                }
+                _ => locker,
+            }
+        }
+    };
 
-    // Flush buffered output
+    let mut w = CountingWriter::new(streams::create_output(input, true, None)?);
+    progress::start(&bar);
+    let locked = match &edit {
+        Edit::Append(_) => locker.append_to(archive, &mut w)?,
+        Edit::Remove(filter) => locker.remove(filter.clone()).append_to(archive, &mut w)?,
+        Edit::Update { .. } => locker.update_to(archive, &changes, &mut w)?,
+    };
+    bar.finish_and_clear();
     w.flush().context("failed to flush output buffer")?;
+    let bytes_out = w.count();
+    w.into_inner().commit()?;
 
-    println!("Encrypted '{}' → '{}'", folder.display(), out.display());
-    Ok(())
+    match &edit {
+        Edit::Append(paths) => {
+            let paths = paths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            log::info!("Appended '{}' to '{}'", paths.join("', '"), input.display());
+        }
+        Edit::Remove(_) => log::info!("Removed entries from '{}'", input.display()),
+        Edit::Update { folder, .. } => {
+            let count = |kind| changes.iter().filter(|c| c.change == kind).count();
+            log::info!(
+                "Updated '{}' from '{}': {} added, {} removed, {} modified",
+                input.display(),
+                folder.display(),
+                count(diff::Change::Added),
+                count(diff::Change::Removed),
+                count(diff::Change::Modified)
+            );
+        }
+    }
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: locked.stats.files,
+        bytes_in: locked.stats.bytes,
+        bytes_out,
+        compression_ratio: Report::ratio(locked.stats.bytes, bytes_out),
+        changes,
+        ..Report::new(command)
+    })
+}
+
+/// How `merge_archives` writes its output
+struct Merged {
+    on_conflict: merge::Conflict,
+    new_key: NewKey,
+    compression: Option<Algorithm>,
+    level: Option<i32>,
+    armor: bool,
+    force: bool,
+}
+
+/// Combine `inputs` into a new archive at `out`
+fn merge_archives(
+    inputs: &[PathBuf],
+    out: &PathBuf,
+    merged: &Merged,
+    keys: &KeyArgs,
+) -> Result<Report> {
+    let two_pass = merged.on_conflict.needs_snapshots();
+    for input in inputs {
+        if streams::is_stdio(input) {
+            anyhow::bail!("merge reads archive files, not stdin");
+        }
+        let envelope = folder_lock::envelope::read(BufReader::new(streams::open_input(input)?))
+            .with_context(|| format!("failed to read {}", input.display()))?;
+        if two_pass && envelope.key_kind() != KeyKind::Passphrase && !keys.has_identities() {
+            // Read twice, and a pasted identity can't be asked for again
+            anyhow::bail!("merge needs -i/--identity for recipient-encrypted archives");
+        }
+    }
+    let names = inputs
+        .iter()
+        .map(|input| {
+            let name = input.file_name().unwrap_or_default().to_string_lossy();
+            let name = name.strip_suffix(".001").unwrap_or(&name);
+            name.strip_suffix(".age").unwrap_or(name).to_string()
+        })
+        .collect::<Vec<_>>();
+    if !two_pass {
+        // Fail on clashing folder names before any secret is asked for
+        merge::Plan::new(merged.on_conflict, names.clone(), &[])?;
+    }
+
+    // First pass: each archive's secret, and its snapshot if overlaps must be settled
+    let mut opened = Vec::new();
+    let mut snapshots = Vec::new();
+    for input in inputs {
+        let (plain, pass) = open_decrypted_keeping(input, keys, &ProgressBar::hidden())?;
+        if two_pass {
+            let mut archive = folder_lock::open_archive(plain)?;
+            snapshots.push(
+                Snapshot::from_archive(&mut archive)
+                    .with_context(|| format!("failed to read {}", input.display()))?,
+            );
+            opened.push((None, pass));
+        } else {
+            opened.push((Some(plain), pass));
+        }
+    }
+    let plan = merge::Plan::new(merged.on_conflict, names, &snapshots)?;
+
+    let locker = Locker::with_paths(Vec::new()).armor(merged.armor);
+    let locker = match &merged.new_key {
+        NewKey::Passphrase { file, confirm } => {
+            locker.passphrase(passphrase::read_replacement(file.as_deref(), *confirm)?)
+        }
+        NewKey::Generated => locker.passphrase(generated_passphrase()),
+        NewKey::Recipients(recipients) => locker.recipients(parse_recipients(recipients)?),
+    };
+
+    // Second pass: open every archive again, with the secret given the first time
+    let bar = progress::bar(0);
+    let mut archives = Vec::new();
+    let mut detected = None;
+    for (input, (plain, pass)) in inputs.iter().zip(opened) {
+        let plain = match plain {
+            Some(plain) => plain,
+            None => {
+                let r = BufReader::new(bar.wrap_read(streams::open_input(input)?));
+                folder_lock::decrypt(r, |kind| match (kind, pass) {
+                    (KeyKind::Passphrase | KeyKind::Either, Some(pass)) => {
+                        Ok(Key::Passphrase(pass))
+                    }
+                    _ => Ok(Key::Identities(keys.read_identities()?)),
+                })?
+            }
+        };
+        let mut plain = BufReader::new(plain);
+        if detected.is_none() {
+            detected = Some(compression::detect(
+                plain.fill_buf().context("failed to decrypt")?,
+            ));
+        }
+        archives.push(folder_lock::open_archive(Box::new(plain))?);
+    }
+    let algorithm = merged.compression.or(detected).unwrap_or_default();
+    let compression = compression::Settings::new(algorithm, merged.level, None)?;
+
+    let mut w = CountingWriter::new(streams::create_output(out, merged.force, None)?);
+    progress::start(&bar);
+    let locked = locker
+        .compression_settings(compression)
+        .merge_to(archives, &plan, &mut w)?;
+    bar.finish_and_clear();
+    w.flush().context("failed to flush output buffer")?;
+    let bytes_out = w.count();
+    w.into_inner().commit()?;
+
+    log::info!("Merged {} archives into '{}'", inputs.len(), out.display());
+    Ok(Report {
+        archive: Some(out.clone()),
+        files: locked.stats.files,
+        bytes_in: locked.stats.bytes,
+        bytes_out,
+        compression_ratio: Report::ratio(locked.stats.bytes, bytes_out),
+        ..Report::new("merge")
+    })
+}
+
+/// Snapshot of the backup an increment is taken against
+///
+/// `base` is either an age archive (opened with `identities`, or `passphrase` if it is
+/// passphrase-encrypted) or a snapshot file saved with `--write-snapshot`.
+fn load_base(base: &Path, identities: &[PathBuf], passphrase: &PassphraseArgs) -> Result<Snapshot> {
+    let mut magic = [0u8; 14];
+    let n = streams::open_input(base)?
+        .read(&mut magic)
+        .with_context(|| format!("failed to read {}", base.display()))?;
+    let magic = &magic[..n];
+    if !magic.starts_with(b"age-encryption") && !magic.starts_with(b"-----BEGIN AGE") {
+        return Snapshot::load(base);
+    }
+    let keys = KeyArgs {
+        identities: identities.to_vec(),
+        passphrase: passphrase.clone(),
+        passphrase_retries: PASSPHRASE_RETRIES,
+        tpm: false,
+    };
+    let mut archive = open_archive(&base.to_path_buf(), &keys, &ProgressBar::hidden())?;
+    Snapshot::from_archive(&mut archive)
+        .with_context(|| format!("failed to read base archive {}", base.display()))
+}
+
+/// Encrypt `folder` to `<folder>.age` beside it, then delete the folder
+fn lock_folder(
+    folder: &Path,
+    recipients: &[String],
+    passphrase: &PassphraseArgs,
+    confirm: bool,
+    compression: &compression::Settings,
+    shred: bool,
+) -> Result<Report> {
+    check_folder(folder)?;
+    let root = folder
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", folder.display()))?;
+    if root.parent().is_none() {
+        anyhow::bail!("refusing to lock the root directory");
+    }
+    // Extraction skips it as folder_lock's own, so deleting the folder would lose it
+    if root.join(snapshot::META_DIR).symlink_metadata().is_ok() {
+        anyhow::bail!(
+            "'{}' holds a '{}' entry, which can't be restored; rename it first",
+            folder.display(),
+            snapshot::META_DIR
+        );
+    }
+    let mut out = root.clone().into_os_string();
+    out.push(".age");
+    let out = PathBuf::from(out);
+    streams::check_output(&out, false)?;
+
+    // Kept to decrypt the result before anything is deleted
+    let mut secret = None;
+    let locker = if recipients.is_empty() {
+        let pass = passphrase::read_new(passphrase, &out, confirm, None)?;
+        secret = Some(pass.clone());
+        Locker::new(&root).passphrase(pass)
+    } else {
+        Locker::new(&root).recipients(parse_recipients(recipients)?)
+    };
+    // Everything must come back on unlock, permissions included; the snapshot proves it
+    let options = PackOptions {
+        preserve_permissions: true,
+        snapshot: true,
+        ..PackOptions::default()
+    };
+    let summary = Sources::Folder(root.clone()).scan(&options.filters)?;
+    let bar = progress::bar(summary.tar_bytes);
+    let output = streams::create_output(&out, false, None)?;
+    let mut w = HashingWriter::new(CountingWriter::new(output));
+    progress::start(&bar);
+    let locked = locker
+        .options(options)
+        .compression_settings(*compression)
+        .progress(bar.clone())
+        .encrypt_to(&mut w)?;
+    bar.finish_and_clear();
+    w.flush().context("failed to flush output buffer")?;
+    let (w, written) = w.finish();
+    let bytes_out = w.count();
+    w.into_inner().commit()?;
+
+    // Nothing is deleted unless the archive reads back as written and covers everything
+    let on_disk = checksum::hash_file(&out)
+        .with_context(|| format!("failed to read back {}", out.display()))?;
+    if on_disk != written {
+        anyhow::bail!(
+            "'{}' does not read back as written; '{}' was left in place",
+            out.display(),
+            folder.display()
+        );
+    }
+    if let Some(missing) = first_unarchived(&root, &locked.snapshot)? {
+        anyhow::bail!(
+            "'{}' was not archived (listed in an ignore file?); '{}' was left in place",
+            missing.display(),
+            folder.display()
+        );
+    }
+    if let Some(pass) = secret {
+        let r = BufReader::new(streams::open_input(&out)?);
+        folder_lock::decrypt(r, |_| Ok(Key::Passphrase(pass)))
+            .and_then(folder_lock::open_archive)
+            .and_then(|archive| checksum::check_archive(archive, &ProgressBar::hidden()))
+            .with_context(|| format!("'{}' was left in place", folder.display()))?;
+        log::debug!("'{}' decrypts and matches its manifest", out.display());
+    }
+    if shred {
+        let shredded = folder_lock::shred::shred_tree(&root)?;
+        log::info!("Shredded {} files", shredded);
+    } else {
+        std::fs::remove_dir_all(&root)
+            .with_context(|| format!("failed to remove {}", folder.display()))?;
+    }
+
+    log::info!("Locked '{}' → '{}'", folder.display(), out.display());
+    Ok(Report {
+        archive: Some(out),
+        files: locked.stats.files,
+        bytes_in: locked.stats.bytes,
+        bytes_out,
+        compression_ratio: Report::ratio(locked.stats.bytes, bytes_out),
+        ..Report::new("lock")
+    })
+}
+
+/// A path under `root` that `snapshot` doesn't cover, or that extraction would skip, if any
+fn first_unarchived(root: &Path, snapshot: &Snapshot) -> Result<Option<PathBuf>> {
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to read {}", dir.display()))?;
+            let path = entry.path();
+            let rel = path.strip_prefix(root).expect("read_dir stays under root");
+            let key = snapshot::key(rel);
+            if snapshot::is_metadata(&key) || !snapshot.files.contains_key(&key) {
+                return Ok(Some(path));
+            }
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Restore `<name>.age` as the folder `<name>` beside it, then delete the archive
+///
+/// The folder is unpacked under a hidden staging name and renamed into place, so a failed
+/// unlock leaves neither a partial folder nor a missing archive.
+fn unlock_archive(input: &PathBuf, keys: &KeyArgs) -> Result<Report> {
+    if streams::is_stdio(input) || streams::is_remote(input) {
+        anyhow::bail!("unlock needs a local .age file");
+    }
+    let name = input
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_suffix(".age"))
+        .filter(|n| !n.is_empty())
+        .with_context(|| format!("'{}' is not named <folder>.age", input.display()))?;
+    let target = input.with_file_name(name);
+    if target.symlink_metadata().is_ok() {
+        return Err(Failure::OutputExists.error(format!(
+            "'{}' already exists; not unlocking over it",
+            target.display()
+        )));
+    }
+    let staging = input.with_file_name(format!(".{}.unlocking", name));
+    std::fs::create_dir(&staging).with_context(|| {
+        format!(
+            "failed to create {} (left over from an interrupted unlock?)",
+            staging.display()
+        )
+    })?;
+
+    let options = ExtractOptions {
+        preserve_permissions: true,
+        ..ExtractOptions::default()
+    };
+    let bar = progress::bar(0);
+    let restored = open_archive(input, keys, &bar).and_then(|mut archive| {
+        progress::start(&bar);
+        extract::extract(&mut archive, &staging, &options, &bar)
+    });
+    bar.finish_and_clear();
+    let stats = match restored {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&staging, &target).with_context(|| {
+        format!(
+            "failed to move {} into place as {}",
+            staging.display(),
+            target.display()
+        )
+    })?;
+    std::fs::remove_file(input).with_context(|| format!("failed to remove {}", input.display()))?;
+
+    log::info!("Unlocked '{}' → '{}'", input.display(), target.display());
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: stats.files,
+        bytes_in: bar.position(),
+        bytes_out: stats.bytes,
+        ..Report::new("unlock")
+    })
 }
 
-fn decrypt_file(input: &PathBuf, out_folder: &PathBuf) -> Result<()> {
+/// Write the single file of an `encrypt --raw` archive to `out`
+fn decrypt_raw(input: &PathBuf, out: &PathBuf, force: bool, keys: &KeyArgs) -> Result<Report> {
+    // Fail before asking for any secret
+    streams::check_output(out, force)?;
+    let bar = progress::bar(0);
+    let mut plain = open_decrypted(input, keys, &bar)?;
+    progress::start(&bar);
+    let bytes = if streams::is_stdio(out) {
+        let mut stdout = io::stdout().lock();
+        let n = io::copy(&mut plain, &mut stdout).context("failed to decrypt")?;
+        stdout.flush().context("failed to flush stdout")?;
+        n
+    } else {
+        let mut w = streams::create_output(out, force, None)?;
+        let n = io::copy(&mut plain, &mut w).context("failed to decrypt")?;
+        w.commit()?;
+        n
+    };
+    bar.finish_and_clear();
+    log::info!("Decrypted '{}' → '{}'", input.display(), out.display());
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: 1,
+        bytes_in: bar.position(),
+        bytes_out: bytes,
+        ..Report::new("decrypt")
+    })
+}
+
+/// Restore `input`, then apply each of `increments` on top of it in order
+fn decrypt_file(
+    input: &PathBuf,
+    increments: &[PathBuf],
+    out_folder: &PathBuf,
+    options: &ExtractOptions,
+    keys: &KeyArgs,
+) -> Result<Report> {
     if !out_folder.is_dir() {
         anyhow::bail!(
             "'{}' is not a directory (please create it first)",
@@ -107,40 +3162,795 @@ fn decrypt_file(input: &PathBuf, out_folder: &PathBuf) -> Result<()> {
         );
     }
 
-    println!("Enter passphrase (input hidden):");
-    let pass = read_password().context("failed to read passphrase")?;
-    if pass.is_empty() {
-        anyhow::bail!("empty passphrase is not allowed");
+    let bar = progress::bar(0);
+    let mut archive = open_archive(input, keys, &bar)?;
+    progress::start(&bar);
+    let mut stats = extract::extract(&mut archive, out_folder, options, &bar)?;
+    bar.finish_and_clear();
+    let mut bytes_in = bar.position();
+
+    // Each increment replaces what changed since the layer below it
+    let layer_options = ExtractOptions {
+        existing: Existing::Overwrite,
+        ..options.clone()
+    };
+    for increment in increments {
+        let bar = progress::bar(0);
+        let mut archive = open_archive(increment, keys, &bar)?;
+        progress::start(&bar);
+        let layer = extract::extract(&mut archive, out_folder, &layer_options, &bar)
+            .with_context(|| format!("failed to apply increment {}", increment.display()))?;
+        bar.finish_and_clear();
+        log::info!("Applied increment '{}'", increment.display());
+        stats.files += layer.files;
+        stats.bytes += layer.bytes;
+        bytes_in += bar.position();
     }
 
-    // Open input file
-    let fin = File::open(input)
-        .with_context(|| format!("failed to open input file {}", input.display()))?;
-    let mut r = BufReader::new(fin);
+    log::info!(
+        "Decrypted '{}' → '{}'",
+        input.display(),
+        out_folder.display()
+    );
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: stats.files,
+        bytes_in,
+        bytes_out: stats.bytes,
+        compression_ratio: Report::ratio(stats.bytes, bytes_in),
+        ..Report::new("decrypt")
+    })
+}
 
-    // Create age decryptor
-    let decryptor = age::Decryptor::new(&mut r)?;
-    let mut plain_reader = match decryptor {
-        age::Decryptor::Recipients(_) => {
-            anyhow::bail!("file was encrypted for recipients (public key). This tool only supports passphrase-protected files.");
+fn list_archive(input: &PathBuf, keys: &KeyArgs, format: OutputFormat) -> Result<Report> {
+    let mut archive = open_archive(input, keys, &ProgressBar::hidden())?;
+    let mut report = Report {
+        archive: Some(input.clone()),
+        ..Report::new("list")
+    };
+    for entry in archive
+        .entries()
+        .context("failed to read archive entries")?
+    {
+        let mut entry = entry.context("failed to read archive entry")?;
+        let path = entry
+            .path()
+            .context("invalid path in archive")?
+            .into_owned();
+        if snapshot::key(&path) == header::HEADER_PATH {
+            // The label and metadata come before the entries they describe
+            let layout = Header::read(&mut entry)?;
+            if format == OutputFormat::Text {
+                for line in layout.label_lines() {
+                    println!("{}", line);
+                }
+            }
+            report.header = Some(layout);
         }
-        age::Decryptor::Passphrase(dec) => {
-            let reader = dec.decrypt(&|_: &age::Recipient| Ok(pass.clone().into_bytes()))?;
-            reader
+        let header = entry.header();
+        let mtime = header.mtime().unwrap_or(0);
+        let size = header.size().unwrap_or(0);
+        if header.entry_type() != tar::EntryType::Directory {
+            report.files += 1;
+            report.bytes_out += size;
         }
+        if format == OutputFormat::Json {
+            report.entries.push(EntryInfo {
+                archive: None,
+                path: path.display().to_string(),
+                kind: entry_kind(header.entry_type()),
+                mode: header.mode().unwrap_or(0),
+                size,
+                mtime,
+            });
+            continue;
+        }
+        println!(
+            "{} {:>12} {} {}",
+            mode_string(header),
+            size,
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(mtime)),
+            path.display()
+        );
+    }
+    Ok(report)
+}
+
+/// What `find` looks for in entry names
+enum NameMatcher {
+    /// Matched against the last path component only
+    Name(globset::GlobMatcher),
+    Path(globset::GlobMatcher),
+    Regex(regex::Regex),
+}
+
+impl NameMatcher {
+    fn new(pattern: &str, regex: bool, ignore_case: bool) -> Result<Self> {
+        if regex {
+            let regex = regex::RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .with_context(|| format!("invalid regex '{}'", pattern))?;
+            return Ok(NameMatcher::Regex(regex));
+        }
+        let glob = globset::GlobBuilder::new(pattern.trim_start_matches("./"))
+            .case_insensitive(ignore_case)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid pattern '{}'", pattern))?
+            .compile_matcher();
+        Ok(if pattern.contains('/') {
+            NameMatcher::Path(glob)
+        } else {
+            NameMatcher::Name(glob)
+        })
+    }
+
+    /// `path` is an archive path (`docs/a.txt`)
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            NameMatcher::Name(glob) => glob.is_match(path.rsplit('/').next().unwrap_or(path)),
+            NameMatcher::Path(glob) => glob.is_match(path),
+            NameMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// Print the entries of every archive in `inputs` whose name `matcher` accepts
+fn find_entries(
+    inputs: &[PathBuf],
+    matcher: &NameMatcher,
+    keys: &KeyArgs,
+    format: OutputFormat,
+) -> Result<Report> {
+    let mut report = Report::new("find");
+    let several = inputs.len() > 1;
+    for input in inputs {
+        let mut archive = open_archive(input, keys, &ProgressBar::hidden())?;
+        for entry in archive
+            .entries()
+            .context("failed to read archive entries")?
+        {
+            let entry = entry.context("failed to read archive entry")?;
+            let path = snapshot::key(&entry.path().context("invalid path in archive")?);
+            if snapshot::is_metadata(&path) || !matcher.matches(&path) {
+                continue;
+            }
+            let header = entry.header();
+            let mtime = header.mtime().unwrap_or(0);
+            let size = header.size().unwrap_or(0);
+            report.files += 1;
+            if format == OutputFormat::Json {
+                report.entries.push(EntryInfo {
+                    archive: several.then(|| input.clone()),
+                    path,
+                    kind: entry_kind(header.entry_type()),
+                    mode: header.mode().unwrap_or(0),
+                    size,
+                    mtime,
+                });
+                continue;
+            }
+            let prefix = if several {
+                format!("{}: ", input.display())
+            } else {
+                String::new()
+            };
+            println!(
+                "{}{} {:>12} {} {}",
+                prefix,
+                mode_string(header),
+                size,
+                humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(mtime)),
+                path
+            );
+        }
+    }
+    if report.files == 0 {
+        return Err(Failure::SourceMissing.error("no entry matches"));
+    }
+    Ok(report)
+}
+
+/// Copy the contents of the file stored at `path` to stdout
+fn cat_file(input: &PathBuf, path: &str, keys: &KeyArgs) -> Result<Report> {
+    let wanted = snapshot::key(Path::new(path));
+    let mut archive = open_archive(input, keys, &ProgressBar::hidden())?;
+    for entry in archive
+        .entries()
+        .classify(Failure::Corrupted, "failed to read archive entries")?
+    {
+        let mut entry = entry.classify(Failure::Corrupted, "failed to read archive entry")?;
+        let entry_path = entry
+            .path()
+            .context("invalid path in archive")?
+            .into_owned();
+        if snapshot::key(&entry_path) != wanted {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        if kind.is_hard_link() {
+            let target = entry.link_name()?.unwrap_or_default().display().to_string();
+            anyhow::bail!(
+                "'{}' is a hard link to '{}'; cat that path instead",
+                path,
+                target
+            );
+        } else if !checksum::has_contents(kind) {
+            anyhow::bail!("'{}' is a {}, not a file", path, entry_kind(kind));
+        }
+        let mut out = io::stdout().lock();
+        let bytes = io::copy(&mut entry, &mut out)
+            .and_then(|bytes| out.flush().map(|()| bytes))
+            .with_context(|| format!("failed to write '{}' to stdout", path))?;
+        return Ok(Report {
+            archive: Some(input.clone()),
+            files: 1,
+            bytes_out: bytes,
+            ..Report::new("cat")
+        });
+    }
+    Err(Failure::SourceMissing.error(format!("no entry '{}' in {}", path, input.display())))
+}
+
+/// Serve `input` at `mountpoint`, keeping the secret in memory to reopen it for reads
+#[cfg(all(feature = "fuse", unix))]
+fn mount_archive(input: PathBuf, mountpoint: &Path, keys: KeyArgs) -> Result<Report> {
+    folder_lock::mount::mount(Box::new(reopener(input, keys, "mount")?), mountpoint)?;
+    Ok(Report::new("mount"))
+}
+
+/// Let the user pick entries of `input` in a terminal UI, then extract them to `out_folder`
+#[cfg(feature = "tui")]
+fn browse_archive(input: PathBuf, out_folder: &Path, keys: KeyArgs) -> Result<Report> {
+    check_folder(out_folder)?;
+    let archive = input.clone();
+    let open = reopener(input, keys, "browse")?;
+    let tree = folder_lock::browse::Tree::read(&mut open()?)?;
+    if tree.is_empty() {
+        log::info!("'{}' is empty", archive.display());
+        return Ok(Report::new("browse"));
+    }
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    let Some(patterns) = folder_lock::browse::run(&tree, &name)? else {
+        return Ok(Report::new("browse"));
+    };
+
+    let options = ExtractOptions {
+        filter: PathFilter::new(&patterns)?,
+        existing: Existing::Ask,
+        ..ExtractOptions::default()
+    };
+    let bar = progress::bar(0);
+    progress::start(&bar);
+    let stats = extract::extract(&mut open()?, out_folder, &options, &bar)?;
+    bar.finish_and_clear();
+    log::info!(
+        "Extracted {} marked entries of '{}' → '{}'",
+        patterns.len(),
+        archive.display(),
+        out_folder.display()
+    );
+    Ok(Report {
+        archive: Some(archive),
+        files: stats.files,
+        bytes_out: stats.bytes,
+        ..Report::new("browse")
+    })
+}
+
+/// Opens `input` again on every call, asking for its secret only the first time
+#[cfg(any(all(feature = "fuse", unix), feature = "tui"))]
+fn reopener(
+    input: PathBuf,
+    keys: KeyArgs,
+    command: &'static str,
+) -> Result<impl Fn() -> Result<tar::Archive<Box<dyn Read>>>> {
+    if streams::is_stdio(&input) {
+        anyhow::bail!("{} needs an archive file, not stdin", command);
+    }
+    // Ask once, on the first open, then reuse the answer for every later one
+    let passphrase = std::cell::RefCell::new(None::<age::secrecy::SecretString>);
+    Ok(move || {
+        let r = BufReader::new(streams::open_input(&input)?);
+        let plain = folder_lock::decrypt(r, |kind| match kind {
+            KeyKind::Identities | KeyKind::Either if keys.has_identities() => {
+                Ok(Key::Identities(keys.read_identities()?))
+            }
+            KeyKind::Identities => anyhow::bail!(
+                "{} needs -i/--identity for recipient-encrypted archives",
+                command
+            ),
+            KeyKind::Passphrase | KeyKind::Either => {
+                let mut cached = passphrase.borrow_mut();
+                if cached.is_none() {
+                    *cached = Some(passphrase::read(&keys.passphrase, &input)?);
+                }
+                Ok(Key::Passphrase(cached.clone().expect("just set")))
+            }
+        })?;
+        folder_lock::open_archive(plain)
+    })
+}
+
+fn diff_archive(
+    input: &PathBuf,
+    folder: &Path,
+    filters: &Filters,
+    checksum: bool,
+    keys: &KeyArgs,
+    format: OutputFormat,
+) -> Result<Report> {
+    check_folder(folder)?;
+    let mut archive = open_archive(input, keys, &ProgressBar::hidden())?;
+    let (archived, manifest) = Snapshot::with_manifest(&mut archive)?;
+    if checksum && manifest.is_none() {
+        log::warn!("archive has no checksum manifest; comparing by size and mtime");
+    }
+    let changes = diff::diff(&archived, manifest.as_ref(), folder, filters, checksum)?;
+
+    if format == OutputFormat::Text {
+        for change in &changes {
+            println!("{} {}", change.change.marker(), change.path);
+        }
+    }
+    let count = |kind| changes.iter().filter(|c| c.change == kind).count();
+    log::info!(
+        "{} added, {} removed, {} modified",
+        count(diff::Change::Added),
+        count(diff::Change::Removed),
+        count(diff::Change::Modified)
+    );
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: changes.len() as u64,
+        changes,
+        ..Report::new("diff")
+    })
+}
+
+fn diff_archives(
+    old: &PathBuf,
+    new: &PathBuf,
+    keys: &KeyArgs,
+    format: OutputFormat,
+) -> Result<Report> {
+    if streams::is_stdio(old) && streams::is_stdio(new) {
+        anyhow::bail!("only one of the archives can be read from stdin");
+    }
+    let read = |input: &PathBuf| -> Result<_> {
+        let mut archive = open_archive(input, keys, &ProgressBar::hidden())?;
+        let (snapshot, manifest) = Snapshot::with_manifest(&mut archive)
+            .with_context(|| format!("failed to read {}", input.display()))?;
+        if manifest.is_none() {
+            log::warn!(
+                "'{}' has no checksum manifest; comparing by size and mtime",
+                input.display()
+            );
+        }
+        Ok((snapshot, manifest))
     };
+    let (old_files, old_manifest) = read(old)?;
+    let (new_files, new_manifest) = read(new)?;
+    let changes = diff::diff_archives(
+        &old_files,
+        old_manifest.as_ref(),
+        &new_files,
+        new_manifest.as_ref(),
+    );
+
+    if format == OutputFormat::Text {
+        for change in &changes {
+            println!("{} {}", change.change.marker(), change.path);
+        }
+    }
+    let count = |kind| changes.iter().filter(|c| c.change == kind).count();
+    log::info!(
+        "{} added, {} removed, {} modified",
+        count(diff::Change::Added),
+        count(diff::Change::Removed),
+        count(diff::Change::Modified)
+    );
+    Ok(Report {
+        archive: Some(new.clone()),
+        files: changes.len() as u64,
+        changes,
+        ..Report::new("diff-archives")
+    })
+}
 
-    // The decrypted stream is a gzipped tar archive
-    let mut gz = flate2::read::GzDecoder::new(&mut plain_reader);
-    let mut archive = tar::Archive::new(&mut gz);
-    archive
-        .unpack(out_folder)
-        .context("failed to unpack archive")?;
+fn verify_archive(input: &PathBuf, keys: &KeyArgs) -> Result<Report> {
+    let bar = progress::bar(0);
+    let archive = open_archive(input, keys, &bar)?;
+    progress::start(&bar);
+    let checked = checksum::check_archive(archive, &bar)?;
+    bar.finish_and_clear();
 
-    println!(
-        "Decrypted '{}' → '{}'",
+    log::info!(
+        "OK '{}': {} entries, {} bytes",
         input.display(),
-        out_folder.display()
+        checked.entries,
+        checked.bytes
     );
-    Ok(())
+    Ok(Report {
+        archive: Some(input.clone()),
+        files: checked.files,
+        bytes_in: bar.position(),
+        bytes_out: checked.bytes,
+        ..Report::new("verify")
+    })
+}
+
+/// Decrypt `input` to the end and throw the plaintext away
+///
+/// age authenticates every chunk and marks the last one, so reaching EOF proves both the
+/// key and that the file is complete.
+fn test_archive(input: &PathBuf, keys: &KeyArgs) -> Result<Report> {
+    let bar = progress::bar(0);
+    let mut plain = open_decrypted(input, keys, &bar)?;
+    progress::start(&bar);
+    let bytes = io::copy(&mut plain, &mut io::sink())
+        .classify(Failure::Corrupted, "archive is corrupted or truncated")?;
+    bar.finish_and_clear();
+
+    log::info!("OK '{}': decrypts to {} bytes", input.display(), bytes);
+    Ok(Report {
+        archive: Some(input.clone()),
+        bytes_in: bar.position(),
+        bytes_out: bytes,
+        ..Report::new("test")
+    })
+}
+
+/// Print what the cleartext header of `input` says, plus its compression with `decrypt`
+fn archive_info(
+    input: &PathBuf,
+    decrypt: bool,
+    keys: &KeyArgs,
+    format: OutputFormat,
+) -> Result<Report> {
+    let envelope = folder_lock::envelope::read(BufReader::new(streams::open_input(input)?))
+        .with_context(|| format!("failed to read {}", input.display()))?;
+    let (compression, header) = if decrypt {
+        if streams::is_stdio(input) {
+            anyhow::bail!("--decrypt needs an archive file; stdin can't be read twice");
+        }
+        let mut plain = BufReader::new(open_decrypted(input, keys, &ProgressBar::hidden())?);
+        let head = plain.fill_buf().context("failed to decrypt")?;
+        let algorithm = compression::detect(head);
+        (Some(algorithm), read_header(Box::new(plain))?)
+    } else {
+        (None, None)
+    };
+    let size = streams::input_len(input);
+
+    if format == OutputFormat::Text {
+        let layout = if envelope.armored {
+            "ASCII-armored"
+        } else {
+            "binary"
+        };
+        println!("format:      {} ({})", envelope.version, layout);
+        if let Some(size) = size {
+            println!("size:        {} bytes", size);
+        }
+        match envelope.key_kind() {
+            KeyKind::Passphrase => match envelope.scrypt_log_n {
+                Some(log_n) => {
+                    println!("encryption:  passphrase (scrypt, work factor 2^{})", log_n)
+                }
+                None => println!("encryption:  passphrase (scrypt)"),
+            },
+            KeyKind::Identities | KeyKind::Either => {
+                let mut kinds = BTreeMap::<&str, usize>::new();
+                for stanza in &envelope.stanzas {
+                    *kinds.entry(stanza).or_default() += 1;
+                }
+                let kinds = kinds
+                    .iter()
+                    .map(|(kind, n)| format!("{}× {}", n, kind))
+                    .collect::<Vec<_>>();
+                println!(
+                    "encryption:  {} recipient stanza(s): {}",
+                    envelope.stanzas.len(),
+                    kinds.join(", ")
+                );
+                if let (KeyKind::Either, Some(log_n)) = (envelope.key_kind(), envelope.scrypt_log_n)
+                {
+                    println!(
+                        "             or a passphrase (scrypt, work factor 2^{})",
+                        log_n
+                    );
+                }
+            }
+        }
+        match compression {
+            Some(Algorithm::Store) => println!("compression: none (plain tar, or a --raw file)"),
+            Some(algorithm) => println!("compression: {}", algorithm.name()),
+            None => println!("compression: unknown until decrypted (see --decrypt)"),
+        }
+        match &header {
+            Some(header) => println!(
+                "layout:      version {} ({} container, written by folder_lock {})",
+                header.format_version, header.container, header.tool_version
+            ),
+            None if decrypt => println!("layout:      no header (older archive, or a --raw file)"),
+            None => {}
+        }
+        for line in header.iter().flat_map(Header::label_lines) {
+            println!("{}", line);
+        }
+    }
+    Ok(Report {
+        archive: Some(input.clone()),
+        bytes_in: size.unwrap_or(0),
+        envelope: Some(envelope),
+        compression: compression.map(|algorithm| algorithm.name().to_string()),
+        header,
+        ..Report::new("info")
+    })
+}
+
+/// The layout header at the start of a decrypted archive, if it has one
+///
+/// Archives from before headers existed, and `--raw` files, have none.
+fn read_header(plain: Box<dyn Read>) -> Result<Option<Header>> {
+    let Ok(mut archive) = folder_lock::open_archive(plain) else {
+        return Ok(None);
+    };
+    let Some(Ok(entry)) = archive.entries()?.next() else {
+        return Ok(None);
+    };
+    let is_header = entry
+        .path()
+        .is_ok_and(|path| snapshot::key(&path) == header::HEADER_PATH);
+    if !is_header {
+        return Ok(None);
+    }
+    Header::read(entry).map(Some)
+}
+
+/// Decrypted bytes `peek_header` decompresses, far more than any header's entry takes
+const HEADER_PEEK: u64 = 64 * 1024;
+
+/// The layout header of a decrypted archive, and `plain` with nothing consumed
+fn peek_header(mut plain: Box<dyn Read>) -> Result<(Option<Header>, Box<dyn Read>)> {
+    let mut head = Vec::new();
+    (&mut plain)
+        .take(HEADER_PEEK)
+        .read_to_end(&mut head)
+        .context("failed to decrypt")?;
+    // Anything wrong with the header is reported when the archive itself is read
+    let layout = read_header(Box::new(io::Cursor::new(head.clone()))).unwrap_or(None);
+    Ok((layout, Box::new(io::Cursor::new(head).chain(plain))))
+}
+
+/// Render an entry's type and permission bits like `ls -l` (e.g. `drwxr-xr-x`)
+fn mode_string(header: &tar::Header) -> String {
+    let kind = match header.entry_type() {
+        tar::EntryType::Directory => 'd',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Link => 'h',
+        tar::EntryType::Char => 'c',
+        tar::EntryType::Block => 'b',
+        tar::EntryType::Fifo => 'p',
+        _ => '-',
+    };
+    let mode = header.mode().unwrap_or(0);
+    let mut s = String::with_capacity(10);
+    s.push(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+/// Open an encrypted archive and return a tar reader over its decrypted contents
+///
+/// `bar` tracks how much of the encrypted input has been read.
+fn open_archive(
+    input: &PathBuf,
+    keys: &KeyArgs,
+    bar: &ProgressBar,
+) -> Result<tar::Archive<Box<dyn Read>>> {
+    folder_lock::open_archive(open_decrypted(input, keys, bar)?)
+}
+
+/// Open an `.age` file, asking for whichever secret its header requires
+///
+/// A wrong passphrase typed at the prompt is asked for again, up to `--passphrase-retries`
+/// times; the input is reopened for each attempt, since age consumes it.
+fn open_decrypted(input: &PathBuf, keys: &KeyArgs, bar: &ProgressBar) -> Result<Box<dyn Read>> {
+    Ok(open_decrypted_keeping(input, keys, bar)?.0)
+}
+
+/// Like `open_decrypted`, also returning the passphrase that opened the archive, if any
+fn open_decrypted_keeping(
+    input: &PathBuf,
+    keys: &KeyArgs,
+    bar: &ProgressBar,
+) -> Result<(Box<dyn Read>, Option<age::secrecy::SecretString>)> {
+    let mut retries = if streams::is_stdio(input)
+        || keys.passphrase.is_non_interactive()
+        || keys.passphrase.use_keyring
+    {
+        0
+    } else {
+        keys.passphrase_retries
+    };
+    loop {
+        // Open input file (or stdin)
+        let fin = streams::open_input(input)?;
+        match streams::input_len(input) {
+            Some(len) => bar.set_length(len),
+            None => bar.unset_length(),
+        }
+        bar.set_position(0);
+        let r = BufReader::new(bar.wrap_read(fin));
+
+        let mut used = None;
+        let result = folder_lock::decrypt(r, |kind| match kind {
+            // Either key opens it: a given identity, else the passphrase
+            KeyKind::Identities | KeyKind::Either if keys.has_identities() => {
+                Ok(Key::Identities(keys.read_identities()?))
+            }
+            KeyKind::Identities => Ok(Key::Identities(vec![prompt_identity()?])),
+            KeyKind::Passphrase | KeyKind::Either => {
+                let pass = passphrase::read(&keys.passphrase, input)?;
+                used = Some(pass.clone());
+                Ok(Key::Passphrase(pass))
+            }
+        });
+        match result {
+            Err(e) if used.is_some() && retries > 0 && wrong_passphrase(&e) => {
+                log::warn!("Wrong passphrase, please try again");
+                agent::forget(agent::PASSPHRASE);
+                retries -= 1;
+            }
+            result => return result.map(|plain| (plain, used)),
+        }
+    }
+}
+
+/// Whether `e` is a passphrase that didn't open the header, which another try may fix
+///
+/// Other `Failure::WrongKey` errors (no identity matched, the archive needs another kind
+/// of key) and damaged headers wouldn't be helped by typing it again.
+fn wrong_passphrase(e: &anyhow::Error) -> bool {
+    matches!(decrypt_error(e), Some(age::DecryptError::DecryptionFailed))
+}
+
+/// The age error behind `e`, if decryption is what failed
+fn decrypt_error(e: &anyhow::Error) -> Option<&age::DecryptError> {
+    e.chain().find_map(|cause| cause.downcast_ref())
+}
+
+fn keygen(out: &PathBuf, format: OutputFormat) -> Result<Report> {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public();
+    write_identity(out, &identity)?;
+
+    log::info!("Identity written to '{}'", out.display());
+    if format == OutputFormat::Text {
+        println!("Public key: {}", recipient);
+    }
+    Ok(Report {
+        recipient: Some(recipient.to_string()),
+        ..Report::new("keygen")
+    })
+}
+
+/// Print a secret for a paper backup: its words and, with `qr`, a QR code (or an SVG file)
+fn export_key(
+    identity: Option<&Path>,
+    passphrase_file: Option<&Path>,
+    qr: bool,
+    svg: Option<&Path>,
+    format: OutputFormat,
+) -> Result<Report> {
+    let (secret, words, recipient) = match identity {
+        Some(file) => {
+            let contents = std::fs::read_to_string(file)
+                .map(Zeroizing::new)
+                .with_context(|| format!("failed to read identity file {}", file.display()))?;
+            let line = contents
+                .lines()
+                .map(str::trim)
+                .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+                .with_context(|| {
+                    format!("{} holds no age X25519 identity to export", file.display())
+                })?;
+            let identity = line
+                .parse::<age::x25519::Identity>()
+                .map_err(|e| anyhow::anyhow!("invalid age identity: {}", e))?;
+            let words = paper::identity_words(&identity)?;
+            let recipient = identity.to_public().to_string();
+            (Zeroizing::new(line.to_owned()), words, Some(recipient))
+        }
+        None => {
+            let pass = passphrase::read_replacement(passphrase_file, true)?;
+            let secret = Zeroizing::new(pass.expose_secret().clone());
+            (secret.clone(), secret, None)
+        }
+    };
+    if let Some(path) = svg {
+        let image = Zeroizing::new(paper::qr_svg(&secret)?);
+        // The QR code holds the secret itself, so it gets an identity file's permissions
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut f = match options.open(path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(Failure::OutputExists
+                    .error(format!("output '{}' already exists", path.display())));
+            }
+            result => result.with_context(|| format!("failed to create {}", path.display()))?,
+        };
+        f.write_all(image.as_bytes())
+            .and_then(|()| f.flush())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        log::info!("QR code written to '{}'", path.display());
+    }
+    if format == OutputFormat::Text {
+        if let Some(recipient) = &recipient {
+            println!("Public key: {}", recipient);
+        }
+        println!("{}", words.as_str());
+        if qr && svg.is_none() {
+            println!("{}", paper::qr_text(&secret)?);
+        }
+    }
+    Ok(Report {
+        recipient,
+        ..Report::new("export-key")
+    })
+}
+
+/// Write the identity whose words or QR text `export-key` printed to a new identity file
+fn import_key(out: &PathBuf, from: Option<&Path>, format: OutputFormat) -> Result<Report> {
+    let text = match from {
+        Some(path) => std::fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .with_context(|| format!("failed to read {}", path.display()))?,
+        None => rpassword::prompt_password("Enter the backup words or QR text (input hidden): ")
+            .map(Zeroizing::new)
+            .context("failed to read backup")?,
+    };
+    let identity = paper::identity_from_text(&text)?;
+    let recipient = identity.to_public();
+    write_identity(out, &identity)?;
+
+    log::info!("Identity written to '{}'", out.display());
+    if format == OutputFormat::Text {
+        println!("Public key: {}", recipient);
+    }
+    Ok(Report {
+        recipient: Some(recipient.to_string()),
+        ..Report::new("import-key")
+    })
+}
+
+/// Create the identity file `out` (0600, never overwritten) holding `identity`
+fn write_identity(out: &Path, identity: &age::x25519::Identity) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options
+        .open(out)
+        .with_context(|| format!("failed to create identity file {}", out.display()))?;
+
+    writeln!(f, "# public key: {}", identity.to_public())?;
+    writeln!(f, "{}", identity.to_string().expose_secret())?;
+    f.flush().context("failed to flush identity file")
 }
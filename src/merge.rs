@@ -0,0 +1,228 @@
+//! Combining the entries of several archives into one, for `merge`
+//!
+//! Which archive an overlapping path is taken from is settled up front from each
+//! archive's snapshot (`Snapshot::from_archive`), so every archive is read twice: once for
+//! its headers, then again while its chosen entries are copied (see `rewrite`).
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::snapshot::Snapshot;
+
+/// What to do when several archives hold the same path
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Conflict {
+    /// Keep the entry with the latest mtime; on a tie, the later archive's
+    #[default]
+    NewestWins,
+    /// Refuse to merge (folders may still appear in several archives)
+    Error,
+    /// Store each archive's entries under a folder named after it, so nothing overlaps
+    PrefixBySource,
+}
+
+impl Conflict {
+    /// Whether `Plan::new` needs the archives' snapshots
+    pub fn needs_snapshots(self) -> bool {
+        self != Conflict::PrefixBySource
+    }
+}
+
+/// Which archive each entry of the merged archive comes from
+pub struct Plan {
+    pub(crate) names: Vec<String>,
+    /// Archive index per entry key, `None` for entries dropped under a file that won;
+    /// keys missing here are taken from whichever archive has them
+    pub(crate) winners: HashMap<String, Option<usize>>,
+    pub(crate) prefixed: bool,
+}
+
+impl Plan {
+    /// Settle `conflict` for archives called `names` (used in errors, and as folder names
+    /// with `PrefixBySource`), given their snapshots in the same order
+    ///
+    /// `snapshots` may be empty when `conflict` doesn't `needs_snapshots`.
+    pub fn new(conflict: Conflict, names: Vec<String>, snapshots: &[Snapshot]) -> Result<Self> {
+        if conflict == Conflict::PrefixBySource {
+            let mut seen = HashMap::new();
+            for (i, name) in names.iter().enumerate() {
+                if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                    anyhow::bail!("'{}' can't be used as a folder name", name);
+                }
+                if let Some(first) = seen.insert(name.as_str(), i) {
+                    anyhow::bail!(
+                        "archives {} and {} would both go under '{}/'",
+                        first + 1,
+                        i + 1,
+                        name
+                    );
+                }
+            }
+            return Ok(Self {
+                names,
+                winners: HashMap::new(),
+                prefixed: true,
+            });
+        }
+        assert_eq!(names.len(), snapshots.len(), "one snapshot per archive");
+
+        // (archive, mtime, is_dir) of the entry chosen so far for each key
+        let mut chosen: HashMap<&str, (usize, u64, bool)> = HashMap::new();
+        let mut conflicts = Vec::new();
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            for (key, state) in &snapshot.files {
+                let Some(&(j, mtime, is_dir)) = chosen.get(key.as_str()) else {
+                    chosen.insert(key, (i, state.mtime, state.is_dir));
+                    continue;
+                };
+                if conflict == Conflict::Error && !(is_dir && state.is_dir) {
+                    conflicts.push(format!(
+                        "'{}' is in both {} and {}",
+                        key, names[j], names[i]
+                    ));
+                } else if state.mtime >= mtime {
+                    chosen.insert(key, (i, state.mtime, state.is_dir));
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            anyhow::bail!(
+                "{} path(s) are in more than one archive:\n  {}",
+                conflicts.len(),
+                conflicts.join("\n  ")
+            );
+        }
+
+        // A file that won over a folder takes the folder's contents with it
+        let mut winners = HashMap::new();
+        for (key, &(i, _, _)) in &chosen {
+            let under_file = key.match_indices('/').any(|(at, _)| {
+                chosen
+                    .get(&key[..at])
+                    .is_some_and(|&(_, _, is_dir)| !is_dir)
+            });
+            winners.insert(key.to_string(), (!under_file).then_some(i));
+        }
+        Ok(Self {
+            names,
+            winners,
+            prefixed: false,
+        })
+    }
+
+    /// Folder the entries of archive `i` go under, if they are prefixed
+    pub(crate) fn prefix(&self, i: usize) -> Option<PathBuf> {
+        self.prefixed.then(|| PathBuf::from(&self.names[i]))
+    }
+
+    /// Whether archive `i` loses its entry `key` to another archive
+    pub(crate) fn skips(&self, i: usize, key: &str) -> bool {
+        match self.winners.get(key) {
+            Some(Some(winner)) => *winner != i,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::FileState;
+
+    /// A snapshot of `(key, mtime, is_dir)` entries
+    fn snapshot(entries: &[(&str, u64, bool)]) -> Snapshot {
+        let files = entries
+            .iter()
+            .map(|&(key, mtime, is_dir)| {
+                let state = FileState {
+                    size: 0,
+                    mtime,
+                    is_dir,
+                };
+                (key.to_string(), state)
+            })
+            .collect();
+        Snapshot {
+            files,
+            deleted: Vec::new(),
+        }
+    }
+
+    fn names(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("archive{}", i)).collect()
+    }
+
+    #[test]
+    fn newest_entries_win() {
+        let snapshots = [
+            snapshot(&[("newer", 5, false), ("tie", 1, false), ("first", 0, false)]),
+            snapshot(&[("newer", 3, false), ("tie", 1, false), ("second", 0, false)]),
+        ];
+        let plan = Plan::new(Conflict::NewestWins, names(2), &snapshots).unwrap();
+        assert!(!plan.skips(0, "newer") && plan.skips(1, "newer"));
+        assert!(plan.skips(0, "tie") && !plan.skips(1, "tie"));
+        assert!(!plan.skips(0, "first") && !plan.skips(1, "second"));
+        assert_eq!(plan.prefix(0), None);
+    }
+
+    #[test]
+    fn files_that_win_over_folders_drop_their_contents() {
+        let snapshots = [
+            snapshot(&[("docs", 1, true), ("docs/a.txt", 1, false)]),
+            snapshot(&[("docs", 2, false)]),
+        ];
+        let plan = Plan::new(Conflict::NewestWins, names(2), &snapshots).unwrap();
+        assert!(plan.skips(0, "docs") && !plan.skips(1, "docs"));
+        assert!(plan.skips(0, "docs/a.txt"));
+    }
+
+    #[test]
+    fn conflicts_can_be_refused() {
+        // Folders may be in several archives, with different contents
+        let snapshots = [
+            snapshot(&[("docs", 1, true), ("docs/a.txt", 1, false)]),
+            snapshot(&[("docs", 2, true), ("docs/b.txt", 1, false)]),
+        ];
+        assert!(Plan::new(Conflict::Error, names(2), &snapshots).is_ok());
+
+        let snapshots = [
+            snapshot(&[("a.txt", 1, false)]),
+            snapshot(&[("a.txt", 2, false)]),
+        ];
+        let err = Plan::new(Conflict::Error, names(2), &snapshots).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'a.txt' is in both archive1 and archive2"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn prefixes_are_distinct_folder_names() {
+        let plan = Plan::new(Conflict::PrefixBySource, names(2), &[]).unwrap();
+        assert_eq!(plan.prefix(1), Some(PathBuf::from("archive2")));
+        assert!(!plan.skips(0, "a.txt") && !plan.skips(1, "a.txt"));
+
+        for bad in [&["same", "same"][..], &[".."], &["a/b"], &[""]] {
+            let names = bad.iter().map(|name| name.to_string()).collect();
+            assert!(
+                Plan::new(Conflict::PrefixBySource, names, &[]).is_err(),
+                "{:?}",
+                bad
+            );
+        }
+    }
+}
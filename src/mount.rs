@@ -0,0 +1,354 @@
+//! Read-only FUSE view of an archive (feature `fuse`, Linux and macOS)
+//!
+//! The compressed tar stream can't be seeked, so mounting reads every header once to build
+//! an index. File contents are fetched on first read by streaming the archive up to the
+//! entry; files up to `CACHE_FILE_LIMIT` are then kept in memory so further reads are
+//! instant, while larger ones are streamed again for each read.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+
+use crate::snapshot;
+
+/// Reopens the archive from the start; called once per uncached read
+pub type Opener = Box<dyn Fn() -> Result<tar::Archive<Box<dyn Read>>>>;
+
+/// Nothing changes under a read-only mount, so the kernel may cache attributes for long
+const TTL: Duration = Duration::from_secs(3600);
+
+const ROOT: u64 = 1;
+
+/// Files up to this size are cached whole after their first read
+const CACHE_FILE_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Total size of cached file contents before the oldest are evicted
+const CACHE_LIMIT: u64 = 256 * 1024 * 1024;
+
+struct Node {
+    attr: FileAttr,
+    parent: u64,
+    children: BTreeMap<OsString, u64>,
+    /// Symlink target
+    target: Option<PathBuf>,
+    /// Position of the entry holding this file's data among all archive entries
+    data: Option<usize>,
+}
+
+pub struct ArchiveFs {
+    open: Opener,
+    nodes: HashMap<u64, Node>,
+    cache: HashMap<u64, Arc<Vec<u8>>>,
+    /// Cached inodes, oldest first
+    cache_order: VecDeque<u64>,
+    cache_bytes: u64,
+}
+
+impl ArchiveFs {
+    /// Index the archive returned by `open`
+    pub fn new(open: Opener) -> Result<Self> {
+        let mut fs = Self {
+            open,
+            nodes: HashMap::new(),
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_bytes: 0,
+        };
+        fs.nodes
+            .insert(ROOT, new_node(ROOT, ROOT, FileType::Directory, 0o755, 0, 0));
+        fs.index()?;
+        Ok(fs)
+    }
+
+    fn index(&mut self) -> Result<()> {
+        let mut archive = (self.open)()?;
+        let mut by_key: HashMap<String, u64> = HashMap::new();
+        for (position, entry) in archive
+            .entries()
+            .context("failed to read archive entries")?
+            .enumerate()
+        {
+            let entry = entry.context("failed to read archive entry")?;
+            let key = snapshot::key(&entry.path().context("invalid path in archive")?);
+            if snapshot::is_metadata(&key) {
+                continue;
+            }
+            let header = entry.header();
+            let perm = (header.mode().unwrap_or(0o644) & 0o7777) as u16;
+            let mtime = header.mtime().unwrap_or(0);
+            let kind = header.entry_type();
+            if key.is_empty() {
+                let root = self.nodes.get_mut(&ROOT).expect("root exists");
+                root.attr.perm = perm;
+                root.attr.mtime = UNIX_EPOCH + Duration::from_secs(mtime);
+                continue;
+            }
+
+            let (file_type, size, data, target) = if kind.is_dir() {
+                (FileType::Directory, 0, None, None)
+            } else if kind.is_symlink() {
+                let target = entry.link_name()?.map(|t| t.into_owned());
+                let len = target.as_ref().map_or(0, |t| t.as_os_str().len() as u64);
+                (FileType::Symlink, len, None, target)
+            } else if kind.is_hard_link() {
+                // Share the data of the entry linked to
+                let first = entry
+                    .link_name()?
+                    .and_then(|t| by_key.get(&snapshot::key(&t)).copied())
+                    .and_then(|ino| self.nodes.get(&ino));
+                match first {
+                    Some(first) => (FileType::RegularFile, first.attr.size, first.data, None),
+                    None => {
+                        log::warn!("hard link '{}' points to a missing entry", key);
+                        continue;
+                    }
+                }
+            } else if kind.is_file() || kind.is_gnu_sparse() {
+                (FileType::RegularFile, entry.size(), Some(position), None)
+            } else {
+                // Devices and fifos can't be served meaningfully from an archive
+                continue;
+            };
+
+            let parent = self.make_parents(&key, &mut by_key);
+            let name = OsString::from(key.rsplit('/').next().unwrap_or(&key));
+            let ino = match by_key.get(&key) {
+                // A directory created implicitly by an earlier child; keep its inode
+                Some(&ino) => ino,
+                None => self.nodes.len() as u64 + 1,
+            };
+            let mut node = new_node(ino, parent, file_type, perm, size, mtime);
+            node.target = target;
+            node.data = data;
+            if let Some(existing) = self.nodes.remove(&ino) {
+                node.children = existing.children;
+            }
+            self.nodes.insert(ino, node);
+            self.nodes
+                .get_mut(&parent)
+                .expect("parent exists")
+                .children
+                .insert(name, ino);
+            by_key.insert(key, ino);
+        }
+        Ok(())
+    }
+
+    /// Inode of the directory holding `key`, creating any directories missing from the archive
+    fn make_parents(&mut self, key: &str, by_key: &mut HashMap<String, u64>) -> u64 {
+        let mut parent = ROOT;
+        let mut prefix = String::new();
+        let components: Vec<&str> = key.split('/').collect();
+        for part in &components[..components.len() - 1] {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(part);
+            parent = match by_key.get(&prefix) {
+                Some(&ino) => ino,
+                None => {
+                    let ino = self.nodes.len() as u64 + 1;
+                    let node = new_node(ino, parent, FileType::Directory, 0o755, 0, 0);
+                    self.nodes.insert(ino, node);
+                    self.nodes
+                        .get_mut(&parent)
+                        .expect("parent exists")
+                        .children
+                        .insert(OsString::from(*part), ino);
+                    by_key.insert(prefix.clone(), ino);
+                    ino
+                }
+            };
+        }
+        parent
+    }
+
+    /// Read `size` bytes at `offset` from the data entry at `position`
+    fn read_data(
+        &mut self,
+        ino: u64,
+        position: usize,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.cache.get(&ino) {
+            return Ok(slice(data, offset, size));
+        }
+        let file_size = self.nodes[&ino].attr.size;
+        let whole = file_size <= CACHE_FILE_LIMIT;
+        let mut archive = (self.open)().map_err(io::Error::other)?;
+        let mut entry = archive
+            .entries()?
+            .nth(position)
+            .ok_or_else(|| io::Error::other("archive entry vanished"))??;
+        if whole {
+            let mut data = Vec::with_capacity(file_size as usize);
+            entry.read_to_end(&mut data)?;
+            let data = Arc::new(data);
+            self.insert_cache(ino, data.clone());
+            return Ok(slice(&data, offset, size));
+        }
+        io::copy(&mut (&mut entry).take(offset), &mut io::sink())?;
+        let mut data = Vec::with_capacity(size as usize);
+        entry.take(size).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn insert_cache(&mut self, ino: u64, data: Arc<Vec<u8>>) {
+        self.cache_bytes += data.len() as u64;
+        self.cache.insert(ino, data);
+        self.cache_order.push_back(ino);
+        while self.cache_bytes > CACHE_LIMIT {
+            let Some(oldest) = self.cache_order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.cache.remove(&oldest) {
+                self.cache_bytes -= evicted.len() as u64;
+            }
+        }
+    }
+}
+
+fn new_node(ino: u64, parent: u64, kind: FileType, perm: u16, size: u64, mtime: u64) -> Node {
+    let time = UNIX_EPOCH + Duration::from_secs(mtime);
+    // Archives usually store owner 0:0, so show everything as the mounting user's
+    // SAFETY: geteuid/getegid have no preconditions and cannot fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    Node {
+        attr: FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        },
+        parent,
+        children: BTreeMap::new(),
+        target: None,
+        data: None,
+    }
+}
+
+fn slice(data: &[u8], offset: u64, size: u64) -> Vec<u8> {
+    let start = (offset as usize).min(data.len());
+    let end = (start + size as usize).min(data.len());
+    data[start..end].to_vec()
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = self
+            .nodes
+            .get(&parent)
+            .and_then(|p| p.children.get(name))
+            .and_then(|ino| self.nodes.get(ino));
+        match found {
+            Some(node) => reply.entry(&TTL, &node.attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.nodes.get(&ino) {
+            Some(node) => reply.attr(&TTL, &node.attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.nodes.get(&ino).and_then(|n| n.target.as_ref()) {
+            Some(target) => reply.data(target.as_os_str().as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(position) = self.nodes.get(&ino).and_then(|n| n.data) else {
+            reply.error(libc::EISDIR);
+            return;
+        };
+        match self.read_data(ino, position, offset.max(0) as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                log::error!("failed to read inode {}: {}", ino, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(dir) = self.nodes.get(&ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (dir.parent, FileType::Directory, OsString::from("..")),
+        ];
+        for (name, child) in &dir.children {
+            entries.push((*child, self.nodes[child].attr.kind, name.clone()));
+        }
+        for (i, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount the archive at `mountpoint` and serve it until unmounted (`umount`/`fusermount -u`)
+pub fn mount(open: Opener, mountpoint: &Path) -> Result<()> {
+    let started = SystemTime::now();
+    let fs = ArchiveFs::new(open)?;
+    log::info!(
+        "Indexed {} entries in {:.1}s; mounted read-only at '{}'",
+        fs.nodes.len(),
+        started.elapsed().unwrap_or_default().as_secs_f64(),
+        mountpoint.display()
+    );
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("folder_lock".to_string()),
+        MountOption::Subtype("folder_lock".to_string()),
+    ];
+    fuser::mount2(fs, mountpoint, &options)
+        .with_context(|| format!("failed to mount at '{}'", mountpoint.display()))
+}
@@ -0,0 +1,169 @@
+//! File names that aren't valid UTF-8, as Linux archives may contain
+//!
+//! Unix file systems store raw bytes, so such names restore fine there; elsewhere they
+//! can't be created at all. Escaping writes every byte of an invalid sequence as `%XX`
+//! (`caf%E9.txt`), and each `%` of such a name as `%25`, one path component at a time,
+//! which `unescape` turns back into
+//! the original bytes when the restored folder is encrypted again. Valid names are never
+//! escaped, and `unescape` only decodes a name that `escape` gives back unchanged from
+//! the decoded bytes; text that merely looks like an escape (`%C3%A9`, a valid `é`, or a
+//! lone `100%`) is left alone.
+
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+/// What extraction does with an entry whose name isn't valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InvalidNames {
+    /// Restore the name's bytes as they are (the default on Unix; fails elsewhere)
+    Keep,
+    /// Restore under an escaped name (`%XX` per invalid byte; the default elsewhere)
+    Escape,
+    /// Leave the entry out, with a warning
+    Skip,
+    /// Stop with an error
+    Fail,
+}
+
+impl Default for InvalidNames {
+    fn default() -> Self {
+        if cfg!(unix) {
+            InvalidNames::Keep
+        } else {
+            InvalidNames::Escape
+        }
+    }
+}
+
+/// `bytes` as UTF-8, with every byte of each invalid sequence written as `%XX` and, in
+/// the components that have any, each `%` as `%25`
+pub fn escape(bytes: &[u8]) -> String {
+    let components: Vec<String> = bytes.split(|&b| b == b'/').map(escape_component).collect();
+    components.join("/")
+}
+
+fn escape_component(bytes: &[u8]) -> String {
+    if let Ok(valid) = std::str::from_utf8(bytes) {
+        return valid.to_owned();
+    }
+    let mut out = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                out.push_str(&valid.replace('%', "%25"));
+                return out;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let valid = std::str::from_utf8(valid).expect("checked by from_utf8");
+                out.push_str(&valid.replace('%', "%25"));
+                let bad = e.error_len().unwrap_or(after.len());
+                for byte in &after[..bad] {
+                    out.push_str(&format!("%{:02X}", byte));
+                }
+                rest = &after[bad..];
+            }
+        }
+    }
+}
+
+/// Undo `escape` on `path`; names `escape` didn't produce are returned as is
+#[cfg(unix)]
+pub fn unescape(path: &Path) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let components: Vec<Vec<u8>> = path
+        .as_os_str()
+        .as_bytes()
+        .split(|&b| b == b'/')
+        .map(|component| decode(component).unwrap_or_else(|| component.to_vec()))
+        .collect();
+    PathBuf::from(OsStr::from_bytes(&components.join(&b'/')))
+}
+
+/// The bytes `escape` turned the component `bytes` into, if it did
+///
+/// Every `%` must start an `%XX`, and the result must be invalid UTF-8 that `escape` writes
+/// exactly as `bytes`, which rules out `%C3%A9` for `é` and `%41` for `A`.
+#[cfg(unix)]
+fn decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    let canonical = escape_component(&out).as_bytes() == bytes;
+    (std::str::from_utf8(&out).is_err() && canonical).then_some(out)
+}
+
+/// Non-UTF-8 names can't be stored outside Unix, so there is nothing to undo
+#[cfg(not(unix))]
+pub fn unescape(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_names_are_kept() {
+        for name in ["café.txt", "100%", "%C3%A9", "a%25b", "%41", ""] {
+            assert_eq!(escape(name.as_bytes()), name);
+            assert_eq!(unescape(Path::new(name)), Path::new(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_bytes_and_their_names_percent_signs_are_escaped() {
+        assert_eq!(escape(b"caf\xE9.txt"), "caf%E9.txt");
+        assert_eq!(escape(b"50%\xFF"), "50%25%FF");
+        assert_eq!(escape(b"\xC3\xA9\xFF"), "é%FF");
+        assert_eq!(escape(b"caf\xE9/100%"), "caf%E9/100%");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unescape_reverses_escape() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let names: [&[u8]; 8] = [
+            b"caf\xE9.txt",
+            b"%E9\xE9",
+            b"50%\xFF",
+            b"%25\xC3",
+            b"\xC3\xA9\xFF",
+            b"%\xFF%",
+            b"\xFF%41",
+            b"caf\xE9/100%/\xFF%",
+        ];
+        for name in names {
+            let escaped = escape(name);
+            assert_eq!(
+                unescape(Path::new(&escaped)).as_os_str().as_bytes(),
+                name,
+                "{}",
+                escaped
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_canonical_escapes_are_decoded() {
+        // `escape` writes a valid `é` as itself, and a lone `%` in such a name as `%25`
+        for name in ["%C3%A9%FF", "50%%FF", "%FF%4"] {
+            assert_eq!(unescape(Path::new(name)), Path::new(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,620 @@
+//! Building the tar stream from a walk of the source folder
+//!
+//! Memory stays flat however many entries there are: the walk streams (see `walk::walk`),
+//! file data is copied through fixed buffers, and manifest lines are spooled to a temporary
+//! file. What does grow is the hard-link table (one path per multiply-linked file) and,
+//! when asked for or for increments, the snapshot (one small record per entry).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use tar::{Builder, Header, HeaderMode};
+
+use crate::checksum::{self, Hash, HashingReader};
+use crate::container::Container;
+use crate::failure::Failure;
+use crate::names;
+use crate::progress;
+use crate::report::Stats;
+use crate::snapshot::{self, FileState, Snapshot};
+use crate::sparse;
+use crate::walk::{self, Filters};
+
+/// What goes into the archive and how much file metadata is recorded
+#[derive(Default)]
+pub struct PackOptions {
+    pub filters: Filters,
+    /// Record uid/gid; otherwise entries are stored as owned by 0:0
+    pub preserve_owner: bool,
+    /// Record setuid/setgid/sticky bits; otherwise only rwx permission bits are kept
+    pub preserve_permissions: bool,
+    /// Record extended attributes as PAX `SCHILY.xattr.*` records (Unix only)
+    pub xattrs: bool,
+    /// Store holes in sparse files as GNU sparse entries instead of runs of zeros
+    pub sparse: bool,
+    /// Reproducible mode: clamp every mtime to this Unix time and store no owners or
+    /// access/change times, so identical trees produce identical tar streams
+    pub source_date_epoch: Option<u64>,
+    /// Incremental mode: only store files that changed since this snapshot (directories
+    /// are always stored) and embed the new snapshot, including what was deleted
+    pub base: Option<Snapshot>,
+    /// Return the snapshot of every selected entry even without `base`, e.g. to save it
+    /// as the base of a later increment; left empty otherwise to save memory
+    pub snapshot: bool,
+    /// Store names escaped by `--invalid-names escape` under their original bytes again
+    /// (see `names`; Unix only)
+    pub unescape_names: bool,
+    /// Tar, or zip for opening with built-in tools once decrypted (see `container`)
+    pub container: Container,
+    /// Name stored in the archive's header, to tell it apart from others once decrypted
+    pub label: Option<String>,
+    /// Free-form pairs stored in the archive's header
+    pub meta: BTreeMap<String, String>,
+}
+
+/// What an archive is made of
+pub enum Sources {
+    /// A folder, whose contents become the top level of the archive
+    Folder(PathBuf),
+    /// Files and folders, each stored at the top level under its own name (`ssh/…`,
+    /// `gnupg/…`)
+    Named(Vec<PathBuf>),
+}
+
+impl Sources {
+    /// A single folder stands for its contents; anything else is `Named`
+    pub fn new(paths: Vec<PathBuf>) -> Result<Self> {
+        if let [path] = paths.as_slice() {
+            if path.is_dir() {
+                return Ok(Sources::Folder(path.clone()));
+            }
+        }
+        Self::named(paths)
+    }
+
+    /// Every path under its own name, even a single folder
+    pub fn named(paths: Vec<PathBuf>) -> Result<Self> {
+        let mut names = HashMap::new();
+        for path in &paths {
+            if path.symlink_metadata().is_err() {
+                return Err(
+                    Failure::SourceMissing.error(format!("'{}' does not exist", path.display()))
+                );
+            }
+            if !path.is_file() && !path.is_dir() {
+                anyhow::bail!("'{}' is not a file or directory", path.display());
+            }
+            let name = path
+                .file_name()
+                .with_context(|| format!("'{}' has no file name", path.display()))?;
+            if let Some(other) = names.insert(name.to_os_string(), path) {
+                anyhow::bail!(
+                    "'{}' and '{}' would both be stored as '{}'",
+                    other.display(),
+                    path.display(),
+                    name.to_string_lossy()
+                );
+            }
+        }
+        if paths.is_empty() {
+            anyhow::bail!("nothing to encrypt");
+        }
+        Ok(Sources::Named(paths))
+    }
+
+    /// Call `f` for every entry the archive would hold, parents before children
+    ///
+    /// Paths named explicitly are taken as they are: filters only apply inside folders,
+    /// and symlinks among the sources themselves are followed.
+    pub(crate) fn visit(
+        &self,
+        filters: &Filters,
+        mut f: impl FnMut(walk::Entry) -> Result<()>,
+    ) -> Result<()> {
+        // Its entries would be taken for folder_lock's own and never restored
+        let mut f = |entry: walk::Entry| {
+            if snapshot::is_metadata(&snapshot::key(&entry.rel)) {
+                anyhow::bail!(
+                    "can't archive '{}': '{}' at the top level is reserved for folder_lock",
+                    entry.path.display(),
+                    snapshot::META_DIR
+                );
+            }
+            f(entry)
+        };
+        match self {
+            Sources::Folder(folder) => walk::walk(folder, filters, f),
+            Sources::Named(paths) => paths.iter().try_for_each(|path| {
+                let name = PathBuf::from(path.file_name().expect("checked by Sources::new"));
+                let is_dir = path.is_dir();
+                f(walk::Entry {
+                    path: path.clone(),
+                    rel: name.clone(),
+                    is_dir,
+                    is_symlink: false,
+                })?;
+                if !is_dir {
+                    return Ok(());
+                }
+                walk::walk(path, filters, |entry| {
+                    f(walk::Entry {
+                        rel: name.join(&entry.rel),
+                        ..entry
+                    })
+                })
+            }),
+        }
+    }
+
+    /// File count and size for progress reporting, as `walk::scan` gives for a folder
+    pub fn scan(&self, filters: &Filters) -> Result<walk::Summary> {
+        match self {
+            Sources::Folder(folder) => walk::scan(folder, filters),
+            Sources::Named(paths) => {
+                let mut summary = walk::Summary {
+                    tar_bytes: 2 * 512,
+                    ..walk::Summary::default()
+                };
+                for path in paths {
+                    if path.is_dir() {
+                        // Its root header stands for the folder's own entry; drop the end blocks
+                        let folder = walk::scan(path, filters)?;
+                        summary.files += folder.files;
+                        summary.bytes += folder.bytes;
+                        summary.tar_bytes += folder.tar_bytes - 2 * 512;
+                        continue;
+                    }
+                    let len = std::fs::metadata(path)
+                        .with_context(|| format!("failed to stat '{}'", path.display()))?
+                        .len();
+                    summary.files += 1;
+                    summary.bytes += len;
+                    summary.tar_bytes += 512 + len.div_ceil(512) * 512;
+                }
+                Ok(summary)
+            }
+        }
+    }
+}
+
+/// Append `folder` to `tar` under `.`, honoring `options`
+///
+/// A SHA-256 manifest of the stored file contents is appended after the last file.
+/// Returns the snapshot of everything the walk selected, whether or not it was stored, if
+/// `options.snapshot` or `options.base` is set.
+pub fn append_folder<W: Write>(
+    tar: &mut Builder<W>,
+    folder: &Path,
+    options: &PackOptions,
+    bar: &ProgressBar,
+) -> Result<(Stats, Snapshot)> {
+    append_sources(tar, &Sources::Folder(folder.to_path_buf()), options, bar)
+}
+
+/// Like `append_folder`, for any `Sources`
+pub fn append_sources<W: Write>(
+    tar: &mut Builder<W>,
+    sources: &Sources,
+    options: &PackOptions,
+    bar: &ProgressBar,
+) -> Result<(Stats, Snapshot)> {
+    let mut manifest = ManifestSpool::new().context("failed to create the manifest spool")?;
+    let (stats, mut current) = append_entries(tar, sources, options, bar, &mut manifest, None)?;
+    manifest
+        .append_to(tar, options)
+        .context("failed to add checksum manifest to tar archive")?;
+    if let Some(base) = &options.base {
+        current.deleted = base
+            .files
+            .keys()
+            .filter(|key| !current.files.contains_key(*key))
+            .cloned()
+            .collect();
+        // Last, so the increment can serve as the base of the next one
+        let json = serde_json::to_vec_pretty(&current)?;
+        append_metadata_file(tar, snapshot::SNAPSHOT_PATH, &json, options)
+            .context("failed to add snapshot to tar archive")?;
+    }
+    Ok((stats, current))
+}
+
+/// The entries of `append_sources`, without the trailing metadata; content hashes go to
+/// `manifest`
+///
+/// With `only`, just the entries with those snapshot keys are stored (`""` for the folder
+/// itself); folders are still walked to find them.
+pub(crate) fn append_entries<W: Write>(
+    tar: &mut Builder<W>,
+    sources: &Sources,
+    options: &PackOptions,
+    bar: &ProgressBar,
+    manifest: &mut ManifestSpool,
+    only: Option<&HashSet<String>>,
+) -> Result<(Stats, Snapshot)> {
+    let mut links = HardLinks::default();
+    let selected = |key: &str| only.map_or(true, |only| only.contains(key));
+    if let (Sources::Folder(folder), true) = (sources, selected("")) {
+        append_entry(
+            tar,
+            folder,
+            Path::new("."),
+            false,
+            options,
+            &mut links,
+            manifest,
+        )
+        .with_context(|| format!("failed to add '{}' to tar archive", folder.display()))?;
+    }
+
+    let mut stats = Stats::default();
+    let mut current = Snapshot::default();
+    let record = options.snapshot || options.base.is_some();
+    sources.visit(&options.filters, |mut entry| {
+        if options.unescape_names {
+            entry.rel = names::unescape(&entry.rel);
+        }
+        let mut changed = true;
+        if record {
+            let state = entry_state(&entry, options)
+                .with_context(|| format!("failed to stat '{}'", entry.path.display()))?;
+            let key = snapshot::key(&entry.rel);
+            changed = options
+                .base
+                .as_ref()
+                .map_or(true, |base| entry.is_dir || base.has_changed(&key, &state));
+            current.files.insert(key, state);
+        }
+        if !changed || !selected(&snapshot::key(&entry.rel)) {
+            log::trace!("unchanged {}", entry.rel.display());
+            return Ok(());
+        }
+        log::debug!("adding {}", entry.rel.display());
+        let bytes = append_entry(
+            tar,
+            &entry.path,
+            &entry.rel,
+            entry.is_symlink,
+            options,
+            &mut links,
+            manifest,
+        )
+        .with_context(|| format!("failed to add '{}' to tar archive", entry.path.display()))?;
+        if !entry.is_dir {
+            stats.files += 1;
+            stats.bytes += bytes;
+            progress::set_files(bar, stats.files, "added", &entry.rel);
+        }
+        Ok(())
+    })?;
+    Ok((stats, current))
+}
+
+/// An entry `append_sources` would store, as listed by `encrypt --dry-run`
+pub struct Planned {
+    pub entry: walk::Entry,
+    /// File size; zero for directories and symlinks
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+/// Select what `append_sources` would store with `options`, without reading any contents
+pub fn plan(sources: &Sources, options: &PackOptions) -> Result<Vec<Planned>> {
+    let mut planned = Vec::new();
+    sources.visit(&options.filters, |entry| {
+        let meta = if entry.is_symlink {
+            std::fs::symlink_metadata(&entry.path)
+        } else {
+            std::fs::metadata(&entry.path)
+        }
+        .with_context(|| format!("failed to stat '{}'", entry.path.display()))?;
+        let header = header_for(&meta, options);
+        let mtime = header.mtime().unwrap_or(0);
+        let changed = options.base.as_ref().map_or(true, |base| {
+            let state = FileState::new(&meta, mtime);
+            entry.is_dir || base.has_changed(&snapshot::key(&entry.rel), &state)
+        });
+        if changed {
+            planned.push(Planned {
+                size: if meta.is_file() { meta.len() } else { 0 },
+                mode: header.mode().unwrap_or(0),
+                mtime,
+                entry,
+            });
+        }
+        Ok(())
+    })?;
+    Ok(planned)
+}
+
+/// What the snapshot records for `entry`, using the metadata its header would carry
+fn entry_state(entry: &walk::Entry, options: &PackOptions) -> Result<FileState> {
+    let meta = if entry.is_symlink {
+        std::fs::symlink_metadata(&entry.path)?
+    } else {
+        std::fs::metadata(&entry.path)?
+    };
+    let mtime = header_for(&meta, options).mtime().unwrap_or(0);
+    Ok(FileState::new(&meta, mtime))
+}
+
+/// Store one of folder_lock's own files (under `snapshot::META_DIR`) as a regular entry
+pub(crate) fn append_metadata_file<W: Write>(
+    tar: &mut Builder<W>,
+    path: &str,
+    data: &[u8],
+    options: &PackOptions,
+) -> Result<()> {
+    append_metadata(tar, path, data.len() as u64, data, options)
+}
+
+/// Like `append_metadata_file`, for `len` bytes read from `data`
+fn append_metadata<W: Write>(
+    tar: &mut Builder<W>,
+    path: &str,
+    len: u64,
+    data: impl Read,
+    options: &PackOptions,
+) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(len);
+    header.set_mode(0o644);
+    header.set_mtime(metadata_mtime(options));
+    tar.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+/// mtime of folder_lock's own entries: the reproducible clamp, or now
+pub(crate) fn metadata_mtime(options: &PackOptions) -> u64 {
+    options.source_date_epoch.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    })
+}
+
+/// Manifest lines in walk order, kept in a temporary file until the manifest entry is written
+pub(crate) struct ManifestSpool {
+    file: BufWriter<File>,
+    len: u64,
+    /// Where the open file couldn't be unlinked right away (Windows), removed on drop
+    _path: Option<TempPath>,
+}
+
+pub(crate) struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A new read-write file in the temp directory, named after `what`
+///
+/// Keep the `TempPath` for as long as the file is used.
+pub(crate) fn spool_file(what: &str) -> io::Result<(File, Option<TempPath>)> {
+    let name = format!(".folder_lock-{}-{:016x}", what, rand::random::<u64>());
+    let path = std::env::temp_dir().join(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // Unlinked while open, nothing is left behind even if the process is killed
+    let path = std::fs::remove_file(&path).is_err().then(|| TempPath(path));
+    Ok((file, path))
+}
+
+impl ManifestSpool {
+    pub(crate) fn new() -> io::Result<Self> {
+        let (file, path) = spool_file("manifest")?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            len: 0,
+            _path: path,
+        })
+    }
+
+    pub(crate) fn insert(&mut self, key: &str, hash: &Hash) -> io::Result<()> {
+        let line = checksum::line(key, hash);
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes of lines inserted so far
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Spooled lines from byte `offset` on, as `insert_lines` takes them back
+    pub(crate) fn lines_from(&mut self, offset: u64) -> io::Result<Vec<u8>> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut lines = Vec::new();
+        file.read_to_end(&mut lines)?;
+        Ok(lines)
+    }
+
+    /// Add lines returned by `lines_from`
+    pub(crate) fn insert_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        self.file.write_all(lines)?;
+        self.len += lines.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn append_to<W: Write>(
+        mut self,
+        tar: &mut Builder<W>,
+        options: &PackOptions,
+    ) -> Result<()> {
+        let len = self.len;
+        append_metadata(tar, checksum::MANIFEST_PATH, len, self.contents()?, options)
+    }
+
+    /// The spooled lines, read from the start
+    pub(crate) fn contents(&mut self) -> io::Result<&mut File> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.rewind()?;
+        Ok(file)
+    }
+}
+
+/// Archive paths of files with several hard links, keyed by (device, inode)
+///
+/// The first path seen for an inode carries the data; later ones become tar link entries.
+#[derive(Default)]
+pub(crate) struct HardLinks {
+    seen: HashMap<(u64, u64), PathBuf>,
+}
+
+impl HardLinks {
+    /// The archive path already holding this file's data, or `None` after recording `rel`
+    fn check(&mut self, meta: &Metadata, rel: &Path) -> Option<PathBuf> {
+        let key = inode(meta)?;
+        if let Some(first) = self.seen.get(&key) {
+            return Some(first.clone());
+        }
+        self.seen.insert(key, rel.to_path_buf());
+        None
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Append one file system entry as `rel`, returning the number of content bytes stored
+///
+/// `is_symlink` entries are stored as links; anything else is read through any links.
+pub(crate) fn append_entry<W: Write>(
+    tar: &mut Builder<W>,
+    path: &Path,
+    rel: &Path,
+    is_symlink: bool,
+    options: &PackOptions,
+    links: &mut HardLinks,
+    manifest: &mut ManifestSpool,
+) -> Result<u64> {
+    if is_symlink {
+        let meta = std::fs::symlink_metadata(path)?;
+        let target = std::fs::read_link(path)?;
+        let mut header = header_for(&meta, options);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, rel, &target)?;
+        return Ok(0);
+    }
+
+    let meta = std::fs::metadata(path)?;
+    if options.xattrs {
+        append_xattrs(tar, path)?;
+    }
+
+    let mut header = header_for(&meta, options);
+    if let Some(first) = links.check(&meta, rel) {
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        tar.append_link(&mut header, rel, &first)?;
+        return Ok(0);
+    }
+    if meta.is_file() {
+        let mut f = File::open(path)?;
+        if options.sparse {
+            if let Some(regions) = sparse::data_regions(&f, &meta)? {
+                if sparse::append(tar, header.clone(), rel, &mut f, meta.len(), &regions)? {
+                    f.rewind()?;
+                    manifest.insert(&snapshot::key(rel), &checksum::hash_reader(&mut f)?)?;
+                    return Ok(meta.len());
+                }
+                f.rewind()?;
+            }
+        }
+        let mut reader = HashingReader::new(f);
+        tar.append_data(&mut header, rel, &mut reader)?;
+        manifest.insert(&snapshot::key(rel), &reader.finish())?;
+        Ok(meta.len())
+    } else {
+        header.set_size(0);
+        tar.append_data(&mut header, rel, io::empty())?;
+        Ok(0)
+    }
+}
+
+/// Record in `links` what `append_entry` would for `path`, without storing anything
+pub(crate) fn skip_entry(
+    path: &Path,
+    rel: &Path,
+    is_symlink: bool,
+    links: &mut HardLinks,
+) -> io::Result<()> {
+    if !is_symlink {
+        links.check(&std::fs::metadata(path)?, rel);
+    }
+    Ok(())
+}
+
+pub(crate) fn header_for(meta: &Metadata, options: &PackOptions) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(meta, HeaderMode::Complete);
+    if !options.preserve_owner || options.source_date_epoch.is_some() {
+        header.set_uid(0);
+        header.set_gid(0);
+    }
+    if !options.preserve_permissions {
+        let mode = header.mode().unwrap_or(0o644);
+        header.set_mode(mode & 0o777);
+    }
+    if let Some(epoch) = options.source_date_epoch {
+        let mtime = header.mtime().unwrap_or(0).min(epoch);
+        header.set_mtime(mtime);
+        if let Some(gnu) = header.as_gnu_mut() {
+            gnu.set_atime(0);
+            gnu.set_ctime(0);
+        }
+    }
+    header
+}
+
+/// Emit a PAX extension header carrying the xattrs of `path`; it applies to the next entry
+fn append_xattrs<W: Write>(tar: &mut Builder<W>, path: &Path) -> Result<()> {
+    let records = xattr_records(path)?;
+    if !records.is_empty() {
+        tar.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
+    Ok(())
+}
+
+/// The xattrs of `path` as sorted PAX `SCHILY.xattr.*` records
+#[cfg(unix)]
+pub(crate) fn xattr_records(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    for name in xattr::list_deref(path).context("failed to list extended attributes")? {
+        if let Some(value) = xattr::get_deref(path, &name)? {
+            records.push((format!("SCHILY.xattr.{}", name.to_string_lossy()), value));
+        }
+    }
+    // Listing order depends on the file system; sort so archives are reproducible
+    records.sort();
+    Ok(records)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn xattr_records(_path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    anyhow::bail!("--xattrs is only supported on Unix")
+}
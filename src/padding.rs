@@ -0,0 +1,233 @@
+//! Padding the ciphertext up to a multiple of a bucket size, for `encrypt --pad-to`
+//!
+//! An archive's exact length says a lot about what it holds (two backups of the same tree
+//! differ by exactly the size of what changed). With padding, an observer only learns
+//! which bucket the length falls in.
+//!
+//! The padding goes inside the encryption, right after the compressed stream, in a form
+//! the decompressor skips: zero bytes for gzip, xz (as stream padding) and uncompressed
+//! tar (after its end marker), skippable frames for zstd. It is authenticated like the
+//! rest of the payload, and `verify` reads it to the end. Each age chunk carries a 16-byte
+//! tag, so some lengths can't be reached exactly; the file then ends a few bytes short of
+//! the bucket boundary, the same few bytes for every archive made with that key.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::compression::Algorithm;
+use crate::header::AGE_CHUNK_SIZE;
+
+/// Authentication tag added to every age STREAM chunk
+const TAG_SIZE: u64 = 16;
+
+/// zstd skippable frame magic (the first of 16 the format reserves) and header size
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const ZSTD_SKIPPABLE_HEADER: u64 = 8;
+/// Largest skippable frame written; keeps every frame well below zstd's 4 GiB limit
+const ZSTD_SKIPPABLE_MAX: u64 = 1 << 30;
+
+/// Ciphertext length of `plain` payload bytes, after `overhead` bytes of age header and
+/// nonce; an empty payload still has one (empty) chunk
+fn ciphertext_len(overhead: u64, plain: u64) -> u64 {
+    let chunks = plain.div_ceil(AGE_CHUNK_SIZE as u64).max(1);
+    overhead + plain + chunks * TAG_SIZE
+}
+
+/// Padding bytes to add to a `plain`-byte payload so the binary age file, whose header
+/// and nonce took `overhead` bytes, ends on a multiple of `granularity` (or just short of it)
+pub fn padding_len(overhead: u64, plain: u64, granularity: u64, algorithm: Algorithm) -> u64 {
+    let target = ciphertext_len(overhead, plain).next_multiple_of(granularity);
+    // Largest payload whose ciphertext still fits; lengths only grow with the payload
+    let (mut lo, mut hi) = (plain, target);
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if ciphertext_len(overhead, mid) <= target {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    let padding = lo - plain;
+    match algorithm {
+        // A skippable frame needs room for its header
+        Algorithm::Zstd if padding < ZSTD_SKIPPABLE_HEADER => 0,
+        // xz stream padding comes in multiples of four bytes
+        Algorithm::Xz => padding - padding % 4,
+        _ => padding,
+    }
+}
+
+/// Write `len` bytes of padding the decompressor for `algorithm` will skip
+pub(crate) fn write_padding(w: &mut impl Write, algorithm: Algorithm, len: u64) -> io::Result<()> {
+    if algorithm != Algorithm::Zstd {
+        io::copy(&mut io::repeat(0).take(len), w)?;
+        return Ok(());
+    }
+    let mut left = len;
+    while left > 0 {
+        let mut data = (left - ZSTD_SKIPPABLE_HEADER).min(ZSTD_SKIPPABLE_MAX);
+        // Never leave a remainder too small for another frame header
+        let rest = left - ZSTD_SKIPPABLE_HEADER - data;
+        if rest > 0 && rest < ZSTD_SKIPPABLE_HEADER {
+            data -= ZSTD_SKIPPABLE_HEADER;
+        }
+        w.write_all(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes())?;
+        w.write_all(&(data as u32).to_le_bytes())?;
+        io::copy(&mut io::repeat(0).take(data), w)?;
+        left -= ZSTD_SKIPPABLE_HEADER + data;
+    }
+    Ok(())
+}
+
+/// A decoder that reads whatever follows the compressed stream once it ends
+///
+/// Decoders stop at the end of their stream and never touch the bytes after it, so the
+/// age chunks holding the padding would otherwise never be decrypted and checked.
+pub(crate) struct DrainAfter {
+    decoder: Box<dyn Read>,
+    plain: Rc<RefCell<Box<dyn Read>>>,
+}
+
+impl DrainAfter {
+    /// Split `plain` so `decode` can wrap one handle while the other is drained later
+    pub(crate) fn new(
+        plain: Box<dyn Read>,
+        decode: impl FnOnce(Box<dyn Read>) -> io::Result<Box<dyn Read>>,
+    ) -> io::Result<Self> {
+        let plain = Rc::new(RefCell::new(plain));
+        let decoder = decode(Box::new(Shared(plain.clone())))?;
+        Ok(Self { decoder, plain })
+    }
+}
+
+impl Read for DrainAfter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decoder.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            io::copy(&mut *self.plain.borrow_mut(), &mut io::sink())?;
+        }
+        Ok(n)
+    }
+}
+
+struct Shared(Rc<RefCell<Box<dyn Read>>>);
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+/// Counts bytes written through it, readable while the writer is owned by another layer
+pub(crate) struct Tally<W> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W: Write> Tally<W> {
+    pub(crate) fn new(inner: W, count: Rc<Cell<u64>>) -> Self {
+        Self { inner, count }
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Tally<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: u64 = AGE_CHUNK_SIZE as u64;
+
+    /// Payload sizes around the edges of age chunks
+    fn sizes() -> impl Iterator<Item = u64> {
+        [
+            0,
+            1,
+            100,
+            CHUNK - 1,
+            CHUNK,
+            CHUNK + 1,
+            3 * CHUNK - 17,
+            10 * CHUNK + 5,
+        ]
+        .into_iter()
+    }
+
+    #[test]
+    fn padding_reaches_the_bucket() {
+        for (overhead, granularity) in [(200, 1024), (200, CHUNK), (1000, 1 << 20), (3, 17)] {
+            for plain in sizes() {
+                let padded = plain + padding_len(overhead, plain, granularity, Algorithm::Store);
+                let len = ciphertext_len(overhead, padded);
+                let target = ciphertext_len(overhead, plain).next_multiple_of(granularity);
+                assert!(len <= target, "{} > {}", len, target);
+                // One more byte would overshoot: short only when a tag is in the way
+                assert!(ciphertext_len(overhead, padded + 1) > target);
+                assert!(
+                    target - len <= TAG_SIZE,
+                    "{} short of {}",
+                    target - len,
+                    target
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_payloads_get_no_padding() {
+        let overhead = 200;
+        let plain = 1000;
+        let granularity = ciphertext_len(overhead, plain);
+        assert_eq!(
+            padding_len(overhead, plain, granularity, Algorithm::Gzip),
+            0
+        );
+        assert_eq!(padding_len(overhead, plain, 1, Algorithm::Gzip), 0);
+    }
+
+    #[test]
+    fn padding_fits_the_algorithm() {
+        for granularity in [64, 1000, 4096, CHUNK] {
+            for plain in sizes() {
+                let store = padding_len(200, plain, granularity, Algorithm::Store);
+                let xz = padding_len(200, plain, granularity, Algorithm::Xz);
+                assert_eq!(xz % 4, 0);
+                assert!(store - xz < 4);
+                let zstd = padding_len(200, plain, granularity, Algorithm::Zstd);
+                assert!(zstd == 0 || zstd >= ZSTD_SKIPPABLE_HEADER);
+                assert!(zstd == store || store < ZSTD_SKIPPABLE_HEADER);
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_padding_is_skippable_frames() {
+        for len in [8, 9, 15, 16, 100] {
+            let mut out = Vec::new();
+            write_padding(&mut out, Algorithm::Zstd, len).unwrap();
+            assert_eq!(out.len() as u64, len);
+            let mut rest = out.as_slice();
+            while !rest.is_empty() {
+                let magic = u32::from_le_bytes(rest[..4].try_into().unwrap());
+                let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+                assert_eq!(magic, ZSTD_SKIPPABLE_MAGIC);
+                rest = &rest[8 + size..];
+            }
+        }
+    }
+}
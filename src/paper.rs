@@ -0,0 +1,79 @@
+//! Paper backups of identities and passphrases, for `export-key` and `import-key`
+//!
+//! An X25519 identity is its 32-byte secret, which BIP-39 turns into 24 words with a
+//! checksum, so a mistyped word is caught when they are typed back in. The QR code holds the
+//! `AGE-SECRET-KEY-1…` string itself: any scanner app reads it back as text, and that text
+//! is accepted in place of the words. A passphrase is printed (and encoded) as it is.
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::ExposeSecret;
+use anyhow::{Context, Result};
+use bech32::{FromBase32, ToBase32, Variant};
+use bip39::{Language, Mnemonic};
+use qrcode::render::{svg, unicode};
+use qrcode::{EcLevel, QrCode};
+
+/// Human-readable part of an identity's bech32 encoding
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+/// The 24 BIP-39 words of `identity`'s secret
+pub fn identity_words(identity: &age::x25519::Identity) -> Result<Zeroizing<String>> {
+    let encoded = Zeroizing::new(identity.to_string().expose_secret().to_lowercase());
+    let (_, data, _) = bech32::decode(&encoded).context("malformed identity")?;
+    let secret = Zeroizing::new(Vec::<u8>::from_base32(&data).context("malformed identity")?);
+    let words = Mnemonic::from_entropy(&secret).context("malformed identity")?;
+    Ok(Zeroizing::new(words.to_string()))
+}
+
+/// Rebuild an identity from its words, or from the `AGE-SECRET-KEY-1…` text of its QR code
+pub fn identity_from_text(text: &str) -> Result<age::x25519::Identity> {
+    let text = text.trim();
+    let encoded = if text.to_ascii_uppercase().starts_with("AGE-SECRET-KEY-") {
+        Zeroizing::new(text.to_ascii_uppercase())
+    } else {
+        let words = Zeroizing::new(
+            text.split_whitespace()
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        let mnemonic = Mnemonic::parse_in_normalized(Language::English, &words)
+            .map_err(|e| anyhow::anyhow!("invalid backup words: {}", e))?;
+        let (entropy, len) = mnemonic.to_entropy_array();
+        let entropy = Zeroizing::new(entropy);
+        if len != 32 {
+            anyhow::bail!(
+                "expected the 24 words of an identity, got {}",
+                mnemonic.word_count()
+            );
+        }
+        let encoded = bech32::encode(IDENTITY_HRP, entropy[..len].to_base32(), Variant::Bech32)
+            .context("failed to encode identity")?;
+        Zeroizing::new(encoded.to_ascii_uppercase())
+    };
+    encoded
+        .parse::<age::x25519::Identity>()
+        .map_err(|e| anyhow::anyhow!("invalid age identity: {}", e))
+}
+
+/// `data` as a QR code drawn with block characters, for a terminal or a monospace printout
+pub fn qr_text(data: &str) -> Result<String> {
+    let code = qr_code(data)?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// `data` as a QR code in an SVG image, for printing at any size
+pub fn qr_svg(data: &str) -> Result<String> {
+    let code = qr_code(data)?;
+    Ok(code.render::<svg::Color>().min_dimensions(512, 512).build())
+}
+
+/// High error correction: a printout survives a crease or a stain
+fn qr_code(data: &str) -> Result<QrCode> {
+    QrCode::with_error_correction_level(data, EcLevel::H)
+        .context("secret is too long for a QR code")
+}
@@ -0,0 +1,414 @@
+//! Passphrase sources: interactive prompt, file, file descriptor, systemd credential,
+//! external command, environment, OS keyring, the agent, or generated
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::{ExposeSecret, Secret, SecretString};
+use anyhow::{Context, Result};
+use clap::Args;
+use rand::rngs::OsRng;
+use rand::Rng;
+use rpassword::prompt_password;
+
+use crate::{agent, pinentry, streams};
+
+/// Environment variable read when no other passphrase source is given
+pub const PASSPHRASE_ENV: &str = "FOLDER_LOCK_PASSPHRASE";
+
+/// Directory of a systemd service's credentials (`LoadCredential=`, `SetCredential=`)
+const CREDENTIALS_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Service name of folder_lock's entries in the OS credential store
+const KEYRING_SERVICE: &str = "folder_lock";
+
+/// Where to read the passphrase from (defaults to an interactive prompt)
+#[derive(Args, Debug, Default, Clone)]
+pub struct PassphraseArgs {
+    /// Read the passphrase from the first line of a file
+    #[arg(long, value_name = "FILE", conflicts_with = "passphrase_fd")]
+    pub passphrase_file: Option<PathBuf>,
+    /// Read the passphrase from an already-open file descriptor (Unix only)
+    #[arg(long, value_name = "N")]
+    pub passphrase_fd: Option<i32>,
+    /// Read the passphrase from the systemd credential NAME, given to the service with
+    /// `LoadCredential=NAME:PATH` (or `LoadCredentialEncrypted=`, see systemd-creds)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["passphrase_file", "passphrase_fd"])]
+    pub passphrase_credential: Option<String>,
+    /// Run a shell command and use the first line of its output as the passphrase (e.g.
+    /// `pass show backups/nas`, `op read op://vault/item/password`)
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["passphrase_file", "passphrase_fd", "passphrase_credential"]
+    )]
+    pub passphrase_command: Option<String>,
+    /// Look the passphrase up in the OS credential store (Keychain, Credential Manager,
+    /// Secret Service), saving it there when an archive is created with it
+    #[arg(long)]
+    pub use_keyring: bool,
+    /// Name of the keyring entry, shared by every archive that uses it (default: the
+    /// archive's absolute path)
+    #[arg(long, value_name = "LABEL", requires = "use_keyring")]
+    pub keyring_label: Option<String>,
+}
+
+impl PassphraseArgs {
+    /// Whether the passphrase comes from somewhere other than the terminal
+    pub fn is_non_interactive(&self) -> bool {
+        self.passphrase_file.is_some()
+            || self.passphrase_fd.is_some()
+            || self.passphrase_credential.is_some()
+            || self.passphrase_command.is_some()
+            || std::env::var_os(PASSPHRASE_ENV).is_some()
+    }
+
+    /// Keyring entry for `archive`, if `--use-keyring` is set
+    fn keyring_account(&self, archive: &Path) -> Result<Option<String>> {
+        if !self.use_keyring {
+            return Ok(None);
+        }
+        if let Some(label) = &self.keyring_label {
+            return Ok(Some(label.clone()));
+        }
+        if streams::is_stdio(archive) {
+            anyhow::bail!("--use-keyring needs --keyring-label when the archive is stdin/stdout");
+        }
+        if streams::is_remote(archive) {
+            return Ok(Some(archive.to_string_lossy().into_owned()));
+        }
+        let path = std::path::absolute(archive)
+            .with_context(|| format!("failed to resolve {}", archive.display()))?;
+        Ok(Some(path.to_string_lossy().into_owned()))
+    }
+}
+
+/// Read the passphrase for `archive`, falling back to a hidden prompt
+///
+/// With `--use-keyring` a stored passphrase is used as is; one typed in its place is not
+/// saved, since it may be wrong. A running agent is asked before prompting, and keeps what
+/// was typed.
+pub fn read(args: &PassphraseArgs, archive: &Path) -> Result<SecretString> {
+    if let Some(account) = args.keyring_account(archive)? {
+        if let Some(pass) = keyring_get(&account)? {
+            log::info!(
+                "Using the passphrase stored in the OS keyring as '{}'",
+                account
+            );
+            return Ok(pass);
+        }
+    }
+    if !args.is_non_interactive() {
+        if let Some(pass) = agent::get(agent::PASSPHRASE) {
+            log::info!("Using the passphrase cached by the agent");
+            return Ok(pass);
+        }
+    }
+    let pass = read_source(args)?;
+    if !args.is_non_interactive() {
+        agent::put(agent::PASSPHRASE, &pass);
+    }
+    Ok(pass)
+}
+
+/// Read the passphrase from the configured source, falling back to a hidden prompt
+fn read_source(args: &PassphraseArgs) -> Result<SecretString> {
+    let pass = if let Some(path) = &args.passphrase_file {
+        let mut f = File::open(path)
+            .with_context(|| format!("failed to open passphrase file {}", path.display()))?;
+        read_from(&mut f)
+            .with_context(|| format!("failed to read passphrase file {}", path.display()))?
+    } else if let Some(fd) = args.passphrase_fd {
+        read_from_fd(fd)?
+    } else if let Some(name) = &args.passphrase_credential {
+        let path = credential_path(name)?;
+        let mut f = File::open(&path)
+            .with_context(|| format!("failed to open credential {}", path.display()))?;
+        read_from(&mut f)
+            .with_context(|| format!("failed to read credential {}", path.display()))?
+    } else if let Some(command) = &args.passphrase_command {
+        read_from_command(command)?
+    } else if let Ok(pass) = std::env::var(PASSPHRASE_ENV) {
+        Secret::new(pass)
+    } else {
+        prompt("Enter passphrase (input hidden):")?
+    };
+
+    if pass.expose_secret().is_empty() {
+        anyhow::bail!("empty passphrase is not allowed");
+    }
+    Ok(pass)
+}
+
+/// Read a passphrase for the new archive `archive`; interactive input is asked twice when
+/// `confirm` is set
+///
+/// A weak passphrase is warned about, or refused when its estimated strength is below
+/// `min_bits` (see `check_strength`). With `--use-keyring` a stored passphrase is reused
+/// unchecked, and a new one is saved for next time. A passphrase cached by the agent is
+/// reused too, and a confirmed one handed to it.
+pub fn read_new(
+    args: &PassphraseArgs,
+    archive: &Path,
+    confirm: bool,
+    min_bits: Option<u32>,
+) -> Result<SecretString> {
+    let account = args.keyring_account(archive)?;
+    if let Some(account) = &account {
+        if let Some(pass) = keyring_get(account)? {
+            log::info!(
+                "Using the passphrase stored in the OS keyring as '{}'",
+                account
+            );
+            return Ok(pass);
+        }
+    }
+    if !args.is_non_interactive() {
+        if let Some(pass) = agent::get(agent::PASSPHRASE) {
+            log::info!("Using the passphrase cached by the agent");
+            return Ok(pass);
+        }
+    }
+    let pass = read_source(args)?;
+    // Checked before the confirmation prompt, so a rejected passphrase isn't typed twice
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    check_strength(&pass, &context_words(&name), min_bits)?;
+    if confirm && !args.is_non_interactive() {
+        let again = prompt("Confirm passphrase:")?;
+        if again.expose_secret() != pass.expose_secret() {
+            anyhow::bail!("passphrases do not match");
+        }
+    }
+    if !args.is_non_interactive() {
+        agent::put(agent::PASSPHRASE, &pass);
+    }
+    if let Some(account) = &account {
+        keyring_set(account, &pass)?;
+        log::info!("Saved the passphrase in the OS keyring as '{}'", account);
+    }
+    Ok(pass)
+}
+
+/// Read the replacement passphrase for `rekey` from `file`, or prompt for it
+///
+/// The passphrase environment variable is deliberately not consulted: it most likely holds
+/// the old passphrase.
+pub fn read_replacement(file: Option<&Path>, confirm: bool) -> Result<SecretString> {
+    let pass = match file {
+        Some(path) => {
+            let mut f = File::open(path)
+                .with_context(|| format!("failed to open passphrase file {}", path.display()))?;
+            read_from(&mut f)
+                .with_context(|| format!("failed to read passphrase file {}", path.display()))?
+        }
+        None => {
+            let pass = prompt("Enter new passphrase (input hidden):")?;
+            if confirm && prompt("Confirm new passphrase:")?.expose_secret() != pass.expose_secret()
+            {
+                anyhow::bail!("passphrases do not match");
+            }
+            pass
+        }
+    };
+    if pass.expose_secret().is_empty() {
+        anyhow::bail!("empty passphrase is not allowed");
+    }
+    check_strength(&pass, &[], None)?;
+    Ok(pass)
+}
+
+/// New passphrases estimated below this many bits get a warning
+const WEAK_BITS: f64 = 50.0;
+
+/// Estimated strength of `pass` in bits, and zxcvbn's advice on improving it
+///
+/// The estimate is log2 of the guesses an attacker trying common passwords, words, names,
+/// dates and keyboard patterns first would need. `context` lists words an attacker can be
+/// assumed to know, such as the archive's name.
+pub fn strength(pass: &SecretString, context: &[&str]) -> (f64, String) {
+    // Only a blank passphrase fails to parse
+    let Ok(estimate) = zxcvbn::zxcvbn(pass.expose_secret(), context) else {
+        return (0.0, String::new());
+    };
+    let mut advice = Vec::new();
+    if let Some(feedback) = estimate.feedback() {
+        advice.extend(feedback.warning().map(|w| w.to_string()));
+        advice.extend(feedback.suggestions().iter().map(|s| s.to_string()));
+    }
+    (
+        estimate.guesses_log10() * std::f64::consts::LOG2_10,
+        advice.join(" "),
+    )
+}
+
+/// Refuse `pass` if it is estimated below `min_bits`; warn if it is merely weak
+pub fn check_strength(pass: &SecretString, context: &[&str], min_bits: Option<u32>) -> Result<()> {
+    let (bits, advice) = strength(pass, context);
+    let advice = if advice.is_empty() {
+        advice
+    } else {
+        format!(" {}", advice)
+    };
+    if let Some(min) = min_bits.filter(|&min| bits < min as f64) {
+        anyhow::bail!(
+            "passphrase is too weak: estimated {:.0} bits, below --min-entropy {}.{}",
+            bits,
+            min,
+            advice
+        );
+    }
+    if bits < WEAK_BITS {
+        log::warn!(
+            "passphrase is weak (estimated {:.0} bits); an offline attacker may guess it.{}",
+            bits,
+            advice
+        );
+    }
+    Ok(())
+}
+
+/// Words of an archive name like `taxes-2024.age`, which an attacker is bound to try
+fn context_words(name: &str) -> Vec<&str> {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Number of words in a generated passphrase (~110 bits from the 2048-word BIP-39 list)
+const GENERATED_WORDS: usize = 10;
+
+/// Generate a random word-list passphrase, the same shape as `age -p` produces
+pub fn generate() -> SecretString {
+    let words = bip39::Language::English.word_list();
+    let mut rng = OsRng;
+    let pass = (0..GENERATED_WORDS)
+        .map(|_| words[rng.gen_range(0..words.len())])
+        .collect::<Vec<_>>()
+        .join("-");
+    Secret::new(pass)
+}
+
+/// Show `message` and read a line from the terminal without echo
+///
+/// Both go through the controlling tty, never stdin/stdout, which may carry archive data.
+/// The question is put by a pinentry dialog instead when one is in use (see `pinentry`).
+pub fn prompt(message: &str) -> Result<SecretString> {
+    if let Some(program) = pinentry::program() {
+        let description = message
+            .trim_end_matches(':')
+            .trim_end_matches(" (input hidden)");
+        return pinentry::get_pin(&program, description);
+    }
+    let pass = prompt_password(format!("{} ", message)).context("failed to read passphrase")?;
+    Ok(Secret::new(pass))
+}
+
+/// Read everything from `r`, keeping only the first line
+///
+/// The rest of the buffer is wiped before it is freed, like the `SecretString` itself.
+fn read_from(r: &mut impl Read) -> Result<SecretString> {
+    let mut buf = Zeroizing::new(String::new());
+    r.read_to_string(&mut buf)?;
+    let line = buf.lines().next().unwrap_or_default().to_string();
+    Ok(Secret::new(line))
+}
+
+/// Where systemd put the credential `name` for this service
+fn credential_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        anyhow::bail!("invalid credential name '{}'", name);
+    }
+    let dir = std::env::var_os(CREDENTIALS_ENV)
+        .filter(|dir| !dir.is_empty())
+        .with_context(|| {
+            format!(
+                "--passphrase-credential needs ${} (set for services with LoadCredential=)",
+                CREDENTIALS_ENV
+            )
+        })?;
+    Ok(PathBuf::from(dir).join(name))
+}
+
+/// Run `command` in the shell (`sh -c`, or `cmd /C` on Windows) and keep the first line
+/// of its standard output
+///
+/// The command shares our terminal and standard error, so a secret store can ask to be
+/// unlocked (gpg's pinentry for `pass`, say); standard input is left out of it, since it
+/// may carry archive data.
+fn read_from_command(command: &str) -> Result<SecretString> {
+    use std::process::{Command, Stdio};
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    log::debug!("running passphrase command: {}", command);
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run passphrase command '{}'", command))?;
+    let pass = child
+        .stdout
+        .take()
+        .map(|mut out| read_from(&mut out))
+        .transpose();
+    let status = child
+        .wait()
+        .with_context(|| format!("failed to run passphrase command '{}'", command))?;
+    if !status.success() {
+        anyhow::bail!("passphrase command '{}' exited with {}", command, status);
+    }
+    let pass = pass.with_context(|| format!("failed to read from '{}'", command))?;
+    Ok(pass.unwrap_or_else(|| Secret::new(String::new())))
+}
+
+#[cfg(unix)]
+fn read_from_fd(fd: i32) -> Result<SecretString> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the caller hands us ownership of the descriptor; it is closed when `f` drops
+    let mut f = unsafe { File::from_raw_fd(fd) };
+    read_from(&mut f).with_context(|| format!("failed to read passphrase from fd {}", fd))
+}
+
+#[cfg(not(unix))]
+fn read_from_fd(_fd: i32) -> Result<SecretString> {
+    anyhow::bail!("--passphrase-fd is only supported on Unix")
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(account: &str) -> Result<Option<SecretString>> {
+    let entry =
+        keyring::Entry::new(KEYRING_SERVICE, account).context("failed to open the OS keyring")?;
+    match entry.get_password() {
+        Ok(pass) => Ok(Some(Secret::new(pass))),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e).context("failed to read the passphrase from the OS keyring"),
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_set(account: &str, pass: &SecretString) -> Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, account)
+        .and_then(|entry| entry.set_password(pass.expose_secret()))
+        .context("failed to save the passphrase in the OS keyring")
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(_account: &str) -> Result<Option<SecretString>> {
+    anyhow::bail!("--use-keyring needs folder_lock built with the `keyring` feature")
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_set(_account: &str, _pass: &SecretString) -> Result<()> {
+    anyhow::bail!("--use-keyring needs folder_lock built with the `keyring` feature")
+}
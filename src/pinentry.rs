@@ -0,0 +1,249 @@
+//! Passphrase prompts through pinentry, the dialog program GnuPG asks for secrets with
+//!
+//! Used for every hidden prompt after `enable` (`--pinentry`), and on its own when there
+//! is no terminal but a graphical session (`$DISPLAY` or `$WAYLAND_DISPLAY`). The program
+//! is the one given to `enable`, else gpg-agent's `pinentry-program` from
+//! `$GNUPGHOME/gpg-agent.conf`, else `pinentry` from `PATH`. It is driven with the Assuan
+//! commands of its own protocol over its standard input and output.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::OnceLock;
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::{Secret, SecretString};
+use anyhow::{Context, Result};
+
+/// The program chosen by `enable`; `None` inside means "look it up"
+static ENABLED: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Prompt through pinentry from now on, with `program` or the one GnuPG is configured with
+pub fn enable(program: Option<PathBuf>) {
+    let _ = ENABLED.set(program);
+}
+
+/// The pinentry program to prompt with, if prompts should go through one
+pub fn program() -> Option<PathBuf> {
+    match ENABLED.get() {
+        Some(program) => Some(program.clone().unwrap_or_else(configured)),
+        None if !has_terminal() && has_display() => Some(configured()),
+        None => None,
+    }
+}
+
+/// gpg-agent's `pinentry-program`, else plain `pinentry`
+fn configured() -> PathBuf {
+    let home = match std::env::var_os("GNUPGHOME") {
+        Some(home) => PathBuf::from(home),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".gnupg"),
+            None => return PathBuf::from("pinentry"),
+        },
+    };
+    let conf = std::fs::read_to_string(home.join("gpg-agent.conf")).unwrap_or_default();
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("pinentry-program"))
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(|rest| PathBuf::from(rest.trim()))
+        .last()
+        .unwrap_or_else(|| PathBuf::from("pinentry"))
+}
+
+/// Whether a hidden prompt can be read from a controlling terminal
+fn has_terminal() -> bool {
+    if cfg!(unix) {
+        std::fs::File::open("/dev/tty").is_ok()
+    } else {
+        use std::io::IsTerminal;
+        std::io::stdin().is_terminal()
+    }
+}
+
+fn has_display() -> bool {
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+}
+
+/// Ask for a secret with `program`, showing `description`; cancelling is an error
+pub fn get_pin(program: &Path, description: &str) -> Result<SecretString> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {}", program.display()))?;
+    let mut session = Session {
+        stdin: child.stdin.take().context("pinentry has no stdin")?,
+        stdout: BufReader::new(child.stdout.take().context("pinentry has no stdout")?),
+    };
+    let result = session.get_pin(description);
+    drop(session);
+    let _ = child.wait();
+    result.with_context(|| {
+        format!(
+            "failed to ask for the passphrase with {}",
+            program.display()
+        )
+    })
+}
+
+struct Session {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Session {
+    fn get_pin(&mut self, description: &str) -> Result<SecretString> {
+        // Greeting
+        self.response()?;
+        // Curses and tty pinentries need to know which terminal to draw on
+        if let Some(tty) = std::env::var_os("GPG_TTY") {
+            self.command(&format!("OPTION ttyname={}", tty.to_string_lossy()))?;
+        }
+        if let Some(term) = std::env::var_os("TERM") {
+            self.command(&format!("OPTION ttytype={}", term.to_string_lossy()))?;
+        }
+        self.command("SETTITLE folder_lock")?;
+        self.command(&format!("SETDESC {}", escape(description)))?;
+        self.command("SETPROMPT Passphrase:")?;
+        let pin = self.command("GETPIN")?;
+        let _ = writeln!(self.stdin, "BYE");
+        Ok(Secret::new(pin.to_string()))
+    }
+
+    /// Send one command and return the data lines of its `OK` response
+    fn command(&mut self, command: &str) -> Result<Zeroizing<String>> {
+        writeln!(self.stdin, "{}", command).context("pinentry closed its input")?;
+        self.stdin.flush().context("pinentry closed its input")?;
+        self.response()
+    }
+
+    fn response(&mut self) -> Result<Zeroizing<String>> {
+        let mut data = Zeroizing::new(String::new());
+        loop {
+            let mut line = Zeroizing::new(String::new());
+            if self.stdout.read_line(&mut line)? == 0 {
+                anyhow::bail!("pinentry exited unexpectedly");
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line == "OK" || line.starts_with("OK ") {
+                return Ok(data);
+            }
+            if let Some(error) = line.strip_prefix("ERR ") {
+                // Code 83886179 is the user pressing Cancel
+                let message = error.split_once(' ').map_or(error, |(_, message)| message);
+                anyhow::bail!("{}", message);
+            }
+            if let Some(chunk) = line.strip_prefix("D ") {
+                data.push_str(&unescape(chunk));
+            }
+            // `S` status and `#` comment lines carry nothing we need
+        }
+    }
+}
+
+/// Percent-encode what can't appear in an Assuan line
+fn escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn unescape(text: &str) -> Zeroizing<String> {
+    let bytes = text.as_bytes();
+    let mut out = Zeroizing::new(Vec::with_capacity(bytes.len()));
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Zeroizing::new(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn escapes_round_trip() {
+        let text = "100% sure\r\nsecond line %0A";
+        let escaped = escape(text);
+        assert_eq!(escaped, "100%25 sure%0D%0Asecond line %250A");
+        assert_eq!(*unescape(&escaped), text);
+    }
+
+    #[test]
+    fn unescaping_keeps_what_is_not_an_escape() {
+        assert_eq!(*unescape("%41%62c"), "Abc");
+        assert_eq!(*unescape("%7e%7E"), "~~");
+        assert_eq!(*unescape("50%"), "50%");
+        assert_eq!(*unescape("%4"), "%4");
+        assert_eq!(*unescape("%zz"), "%zz");
+        assert_eq!(*unescape("%+1"), "%+1");
+        assert_eq!(*unescape("%é"), "%é");
+        assert_eq!(*unescape("%C3%A9"), "é");
+    }
+
+    /// A pinentry that answers GETPIN with `answer` and logs the commands it gets to `dir/log`
+    #[cfg(unix)]
+    fn fake_pinentry(dir: &Path, answer: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let program = dir.join("pinentry");
+        let log = dir.join("log");
+        let script = [
+            "#!/bin/sh".to_string(),
+            "echo 'OK Pleased to meet you'".to_string(),
+            "while read -r line; do".to_string(),
+            format!("  echo \"$line\" >> '{}'", log.display()),
+            "  case \"$line\" in".to_string(),
+            format!("    GETPIN) {} ;;", answer),
+            "    BYE) echo OK; exit 0 ;;".to_string(),
+            "    *) echo OK ;;".to_string(),
+            "  esac".to_string(),
+            "done\n".to_string(),
+        ]
+        .join("\n");
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        program
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pins_are_read_from_data_lines() {
+        let dir = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let answer = "echo 'S PASSPHRASE_QUALITY 0'; echo 'D p%25ss'; echo 'D %0Aword'; echo OK";
+        let program = fake_pinentry(&dir, answer);
+
+        let pin = get_pin(&program, "Passphrase for\n100% of it").unwrap();
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let cancelled = fake_pinentry(&dir, "echo 'ERR 83886179 Operation cancelled'");
+        let err = get_pin(&cancelled, "again").unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(pin.expose_secret(), "p%ss\nword");
+        assert!(log
+            .lines()
+            .any(|l| l == "SETDESC Passphrase for%0A100%25 of it"));
+        assert!(log.lines().any(|l| l == "GETPIN"));
+        assert!(format!("{:#}", err).contains("Operation cancelled"));
+    }
+}
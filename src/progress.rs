@@ -0,0 +1,187 @@
+//! Terminal progress reporting on stderr, or JSON events for front-ends
+//!
+//! With `--progress json`, bars are never drawn. Instead one JSON object per line goes to
+//! stderr (or `--progress-fd`), each with an `event` field: `scan-started`, `file-added`
+//! (`file-extracted`, `file-backed-up`) with the running count and the entry's path,
+//! `bytes-written` a few times a second while a bar runs, and `finished` (with the JSON
+//! report's fields) or `error` at the end. While `batch --jobs` runs jobs at once, their
+//! bar events also carry a `job` field naming the archive.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde_json::{json, Value};
+
+const TEMPLATE: &str =
+    "{spinner} {prefix}[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta}) {msg}";
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Where events go once `json_events` is called
+static EVENTS: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// How often `bytes-written` is sent for a running bar
+const TICK: Duration = Duration::from_millis(200);
+
+thread_local! {
+    /// Where `set_files` also keeps its count on this thread (see `count_files`)
+    static FILES: RefCell<Option<Arc<AtomicU64>>> = const { RefCell::new(None) };
+}
+
+/// How progress is shown (`--progress`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Progress bars on stderr, when it is a terminal
+    #[default]
+    Bar,
+    /// Newline-delimited JSON events, for GUIs and scripts
+    Json,
+}
+
+/// Every visible bar is drawn through this, so log lines can be printed above them
+fn multi() -> &'static MultiProgress {
+    static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+    MULTI.get_or_init(MultiProgress::new)
+}
+
+/// Never draw progress bars (`--quiet`)
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Send JSON events to `w` instead of drawing bars
+pub fn json_events(w: Box<dyn Write + Send>) {
+    disable();
+    let _ = EVENTS.set(Mutex::new(w));
+}
+
+/// Send one event, if events are on; `fields` is an object merged after `event`
+pub fn emit(event: &str, fields: Value) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    let mut line = json!({ "event": event });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    let mut w = events.lock().unwrap_or_else(|e| e.into_inner());
+    // A front-end that went away shouldn't stop the work it started
+    let _ = writeln!(w, "{}", line).and_then(|_| w.flush());
+}
+
+/// Run `f` with any visible progress bars temporarily cleared
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    multi().suspend(f)
+}
+
+/// A byte-based progress bar that stays hidden until `start` is called
+///
+/// Keeping it hidden lets passphrase prompts finish before the bar starts redrawing.
+pub fn bar(total_bytes: u64) -> ProgressBar {
+    let bar = ProgressBar::with_draw_target(Some(total_bytes), ProgressDrawTarget::hidden());
+    bar.set_style(
+        ProgressStyle::with_template(TEMPLATE)
+            .expect("progress template is valid")
+            .progress_chars("=> "),
+    );
+    bar
+}
+
+/// Begin drawing `bar` on stderr (indicatif skips drawing when stderr isn't a terminal),
+/// or sending its `bytes-written` events until it finishes
+pub fn start(bar: &ProgressBar) {
+    if EVENTS.get().is_some() {
+        let bar = bar.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TICK);
+            emit(
+                "bytes-written",
+                labelled(
+                    &bar,
+                    json!({ "bytes": bar.position(), "total_bytes": bar.length() }),
+                ),
+            );
+            if bar.is_finished() {
+                break;
+            }
+        });
+    } else if ENABLED.load(Ordering::Relaxed) {
+        multi().add(bar.clone());
+    }
+}
+
+/// Also keep the count of every later `set_files` on this thread in `files`, for callers
+/// that report it themselves (the daemon's job status)
+pub fn count_files(files: Arc<AtomicU64>) {
+    FILES.with(|f| *f.borrow_mut() = Some(files));
+}
+
+/// Show the running file count next to the byte counters, `path` being the latest
+pub fn set_files(bar: &ProgressBar, files: u64, verb: &str, path: &Path) {
+    FILES.with(|f| {
+        if let Some(count) = &*f.borrow() {
+            count.store(files, Ordering::Relaxed);
+        }
+    });
+    bar.set_message(format!("{} files {}", files, verb));
+    emit(
+        &format!("file-{}", verb.replace(' ', "-")),
+        labelled(
+            bar,
+            json!({ "files": files, "path": path.to_string_lossy() }),
+        ),
+    );
+}
+
+/// Name `bar` in front of its counters and in its events' `job` field, to tell apart the
+/// bars of jobs running at once
+pub fn label(bar: &ProgressBar, label: String) {
+    bar.set_prefix(label + " ");
+}
+
+/// `fields` with the `job` of a labelled bar added
+fn labelled(bar: &ProgressBar, mut fields: Value) -> Value {
+    let label = bar.prefix();
+    if let (false, Value::Object(fields)) = (label.is_empty(), &mut fields) {
+        fields.insert("job".to_string(), label.trim_end().into());
+    }
+    fields
+}
+
+/// Counts bytes written through it into a progress bar
+pub struct ProgressWriter<W> {
+    inner: W,
+    bar: ProgressBar,
+}
+
+impl<W: Write> ProgressWriter<W> {
+    pub fn new(inner: W, bar: ProgressBar) -> Self {
+        Self { inner, bar }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bar.inc(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,301 @@
+//! Retention for dated backups: which archives in a folder a keep policy lets go
+//!
+//! Archives are recognised by the file name part of the `output` template (`{folder}`,
+//! `{date}`, `{timestamp}`), and each `{folder}` forms its own series. Within a series the
+//! newest archive of each of the last N days, weeks, months or years is kept, the same
+//! scheme as `restic forget` and `borg prune`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// How many archives of each period to keep; `None` keeps nothing on that account
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Policy {
+    pub last: Option<u32>,
+    pub daily: Option<u32>,
+    pub weekly: Option<u32>,
+    pub monthly: Option<u32>,
+    pub yearly: Option<u32>,
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        [
+            self.last,
+            self.daily,
+            self.weekly,
+            self.monthly,
+            self.yearly,
+        ]
+        .iter()
+        .all(Option::is_none)
+    }
+}
+
+/// One archive found in the folder
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// The `.age` file, or the first volume of a split archive
+    pub path: PathBuf,
+    /// Every file making up the archive
+    pub files: Vec<PathBuf>,
+    /// The `{folder}` part of the name, empty if the template has none
+    pub series: String,
+    /// When the archive was made, from its name
+    pub time: SystemTime,
+}
+
+/// What a policy decides for a folder
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub keep: Vec<Backup>,
+    pub remove: Vec<Backup>,
+}
+
+enum Part {
+    Literal(String),
+    Folder,
+    Date,
+    Timestamp,
+}
+
+/// File-name matcher compiled from an `output` template
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Compile the file name part of `template`; it must contain `{date}` or `{timestamp}`
+    pub fn new(template: &str) -> Result<Self> {
+        let name = Path::new(template)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("template '{}' has no file name", template))?;
+        let mut parts = Vec::new();
+        let mut rest = name;
+        while !rest.is_empty() {
+            let next = ["{folder}", "{date}", "{timestamp}"]
+                .iter()
+                .filter_map(|p| rest.find(p).map(|i| (i, *p)))
+                .min();
+            let Some((i, placeholder)) = next else {
+                parts.push(Part::Literal(rest.to_string()));
+                break;
+            };
+            if i > 0 {
+                parts.push(Part::Literal(rest[..i].to_string()));
+            }
+            parts.push(match placeholder {
+                "{folder}" => Part::Folder,
+                "{date}" => Part::Date,
+                _ => Part::Timestamp,
+            });
+            rest = &rest[i + placeholder.len()..];
+        }
+        if !parts
+            .iter()
+            .any(|p| matches!(p, Part::Date | Part::Timestamp))
+        {
+            anyhow::bail!(
+                "template '{}' has no {{date}} or {{timestamp}}, so its archives can't be dated",
+                template
+            );
+        }
+        Ok(Self { parts })
+    }
+
+    /// The series and time of a file named `name`, if the template produced it
+    pub fn parse(&self, name: &str) -> Option<(String, SystemTime)> {
+        let mut found = Found::default();
+        match_parts(&self.parts, name, &mut found).then_some(())?;
+        let time = match (found.timestamp, found.date) {
+            (Some(timestamp), _) => timestamp,
+            (None, Some(date)) => date,
+            (None, None) => return None,
+        };
+        Some((found.folder.unwrap_or_default(), time))
+    }
+}
+
+#[derive(Default, Clone)]
+struct Found {
+    folder: Option<String>,
+    date: Option<SystemTime>,
+    timestamp: Option<SystemTime>,
+}
+
+fn match_parts(parts: &[Part], name: &str, found: &mut Found) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return name.is_empty();
+    };
+    match part {
+        Part::Literal(literal) => name
+            .strip_prefix(literal.as_str())
+            .is_some_and(|name| match_parts(rest, name, found)),
+        Part::Date => {
+            let Some(date) = name.get(..10).and_then(parse_date) else {
+                return false;
+            };
+            found.date = Some(date);
+            match_parts(rest, &name[10..], found)
+        }
+        Part::Timestamp => {
+            let Some(time) = name.get(..16).and_then(parse_timestamp) else {
+                return false;
+            };
+            found.timestamp = Some(time);
+            match_parts(rest, &name[16..], found)
+        }
+        // Shortest folder name that lets the rest match
+        Part::Folder => (1..=name.len())
+            .filter(|&i| name.is_char_boundary(i))
+            .any(|i| {
+                let mut attempt = found.clone();
+                attempt.folder = Some(name[..i].to_string());
+                let matched = match_parts(rest, &name[i..], &mut attempt);
+                if matched {
+                    *found = attempt;
+                }
+                matched
+            }),
+    }
+}
+
+/// `2024-05-31`, as `{date}` writes it
+fn parse_date(date: &str) -> Option<SystemTime> {
+    humantime::parse_rfc3339(&format!("{}T00:00:00Z", date)).ok()
+}
+
+/// `20240531T142501Z`, as `{timestamp}` writes it
+fn parse_timestamp(t: &str) -> Option<SystemTime> {
+    if !t.is_ascii() || t.as_bytes()[8] != b'T' {
+        return None;
+    }
+    let rfc3339 = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &t[..4],
+        &t[4..6],
+        &t[6..8],
+        &t[9..11],
+        &t[11..13],
+        &t[13..]
+    );
+    humantime::parse_rfc3339(&rfc3339).ok()
+}
+
+/// Every archive in `dir` named by `template`, split archives counted once
+pub fn scan(dir: &Path, template: &Template) -> Result<Vec<Backup>> {
+    let mut backups: BTreeMap<PathBuf, Backup> = BTreeMap::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read {}", dir.display()))?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        // `OUT.001`, `OUT.002`, … are volumes of the split archive OUT
+        let split = name
+            .rsplit_once('.')
+            .filter(|(_, n)| n.len() == 3 && n.bytes().all(|b| b.is_ascii_digit()))
+            .map(|(base, _)| base.to_string());
+        let (archive, (series, time), first) = match (template.parse(&name), split) {
+            (Some(parsed), _) => (name.clone(), parsed, entry.path()),
+            (None, Some(base)) => match template.parse(&base) {
+                Some(parsed) => (base.clone(), parsed, dir.join(format!("{}.001", base))),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+        backups
+            .entry(dir.join(archive))
+            .or_insert_with(|| Backup {
+                path: first,
+                files: Vec::new(),
+                series,
+                time,
+            })
+            .files
+            .push(entry.path());
+    }
+    let mut backups: Vec<Backup> = backups.into_values().collect();
+    for backup in &mut backups {
+        backup.files.sort();
+    }
+    Ok(backups)
+}
+
+/// Split `backups` into those `policy` keeps and those it lets go, newest first
+pub fn plan(mut backups: Vec<Backup>, policy: &Policy) -> Plan {
+    backups.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.path.cmp(&a.path)));
+    let mut series: BTreeMap<String, Vec<Backup>> = BTreeMap::new();
+    for backup in backups {
+        series
+            .entry(backup.series.clone())
+            .or_default()
+            .push(backup);
+    }
+    let mut plan = Plan::default();
+    for backups in series.into_values() {
+        let mut keep = vec![false; backups.len()];
+        keep_by(&backups, &mut keep, policy.last, |b| {
+            b.path.display().to_string()
+        });
+        keep_by(&backups, &mut keep, policy.daily, |b| period(b.time, 10));
+        keep_by(&backups, &mut keep, policy.weekly, |b| {
+            week(b.time).to_string()
+        });
+        keep_by(&backups, &mut keep, policy.monthly, |b| period(b.time, 7));
+        keep_by(&backups, &mut keep, policy.yearly, |b| period(b.time, 4));
+        for (backup, keep) in backups.into_iter().zip(keep) {
+            if keep {
+                plan.keep.push(backup);
+            } else {
+                plan.remove.push(backup);
+            }
+        }
+    }
+    plan
+}
+
+/// Keep the newest backup of each of the first `count` distinct periods
+fn keep_by(
+    backups: &[Backup],
+    keep: &mut [bool],
+    count: Option<u32>,
+    period: impl Fn(&Backup) -> String,
+) {
+    let Some(count) = count else {
+        return;
+    };
+    let mut seen = HashSet::new();
+    for (i, backup) in backups.iter().enumerate() {
+        if seen.len() == count as usize {
+            break;
+        }
+        if seen.insert(period(backup)) {
+            keep[i] = true;
+        }
+    }
+}
+
+/// Leading `len` characters of the UTC RFC 3339 time: 10 for the day, 7 the month, 4 the year
+fn period(time: SystemTime, len: usize) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()[..len].to_string()
+}
+
+/// Weeks since the Monday before the epoch, so weeks run Monday to Sunday
+fn week(time: SystemTime) -> u64 {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+        / 86_400;
+    // 1970-01-01 was a Thursday
+    (days + 3) / 7
+}
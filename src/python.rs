@@ -0,0 +1,272 @@
+//! Python bindings (feature `python`), built into the `folder_lock` extension module by
+//! `maturin build --release` (see pyproject.toml)
+//!
+//! ```python
+//! import folder_lock
+//!
+//! folder_lock.encrypt("results", "results.age", passphrase=pw, progress=print)
+//! folder_lock.verify("results.age", passphrase=pw)
+//! ```
+//!
+//! The work runs with the GIL released. A `progress` callable gets the bytes written (for
+//! `encrypt`) or read from the archive so far, and the archive's size or `None` when
+//! unknown; an exception it raises aborts the operation and propagates. Other failures
+//! raise `folder_lock.Error`, or its subclass `WrongKeyError` for a key that doesn't open
+//! the archive.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use age::secrecy::SecretString;
+use anyhow::Result;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::checksum::{self, Checked};
+use crate::failure::Failure;
+use crate::keys::{parse_recipients, read_identities};
+use crate::report::{entry_kind, Stats};
+use crate::{streams, Locker, Unlocker};
+
+pyo3::create_exception!(folder_lock, Error, pyo3::exceptions::PyException);
+pyo3::create_exception!(folder_lock, WrongKeyError, Error);
+
+/// `progress` is called at most once per this many bytes, and once at the end
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// An exception raised by a `progress` callable, to re-raise once the work has stopped
+type Raised = Arc<Mutex<Option<PyErr>>>;
+
+#[pymodule]
+fn folder_lock(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(encrypt, m)?)?;
+    m.add_function(wrap_pyfunction!(decrypt, m)?)?;
+    m.add_function(wrap_pyfunction!(list_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(verify, m)?)?;
+    m.add("Error", m.py().get_type_bound::<Error>())?;
+    m.add("WrongKeyError", m.py().get_type_bound::<WrongKeyError>())?;
+    Ok(())
+}
+
+/// Encrypt `folder` to `out` with a passphrase, age recipients, or both; returns the
+/// file and byte counts
+#[pyfunction]
+#[pyo3(signature = (folder, out, *, passphrase=None, recipients=None, force=false, progress=None))]
+fn encrypt<'py>(
+    py: Python<'py>,
+    folder: PathBuf,
+    out: PathBuf,
+    passphrase: Option<String>,
+    recipients: Option<Vec<String>>,
+    force: bool,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let raised = Raised::default();
+    let result = py.allow_threads(|| -> Result<Stats> {
+        let recipients = recipients.unwrap_or_default();
+        let locker = Locker::new(folder);
+        let locker = match (passphrase.map(SecretString::new), recipients.is_empty()) {
+            (Some(pass), true) => locker.passphrase(pass),
+            (None, false) => locker.recipients(parse_recipients(&recipients)?),
+            (Some(pass), false) => {
+                locker.passphrase_and_recipients(pass, parse_recipients(&recipients)?)
+            }
+            (None, true) => anyhow::bail!("a passphrase or at least one recipient is needed"),
+        };
+        let output = streams::create_output(&out, force, None)?;
+        let mut w = Reporting::new(output, None, progress, raised.clone());
+        let locked = locker.encrypt_to(&mut w)?;
+        w.report(true)?;
+        w.inner.commit()?;
+        Ok(locked.stats)
+    });
+    let stats = raise(result, &raised)?;
+    stats_dict(py, stats.files, stats.bytes)
+}
+
+/// Restore `archive` into the existing folder `out_folder`, never replacing files already
+/// there; returns the file and byte counts
+#[pyfunction]
+#[pyo3(signature = (archive, out_folder, *, passphrase=None, identity_file=None, progress=None))]
+fn decrypt<'py>(
+    py: Python<'py>,
+    archive: PathBuf,
+    out_folder: PathBuf,
+    passphrase: Option<String>,
+    identity_file: Option<PathBuf>,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let raised = Raised::default();
+    let result = py.allow_threads(|| {
+        unlocker(&archive, passphrase, identity_file, progress, &raised)?.extract_to(out_folder)
+    });
+    let stats = raise(result, &raised)?;
+    stats_dict(py, stats.files, stats.bytes)
+}
+
+/// The entries of `archive`, as dicts with `path`, `kind`, `mode`, `size` and `mtime`
+#[pyfunction(name = "list")]
+#[pyo3(signature = (archive, *, passphrase=None, identity_file=None, progress=None))]
+fn list_entries<'py>(
+    py: Python<'py>,
+    archive: PathBuf,
+    passphrase: Option<String>,
+    identity_file: Option<PathBuf>,
+    progress: Option<PyObject>,
+) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let raised = Raised::default();
+    let result = py.allow_threads(|| -> Result<Vec<_>> {
+        let mut archive =
+            unlocker(&archive, passphrase, identity_file, progress, &raised)?.archive()?;
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            entries.push((
+                entry.path()?.display().to_string(),
+                entry_kind(header.entry_type()),
+                header.mode().unwrap_or(0),
+                header.size().unwrap_or(0),
+                header.mtime().unwrap_or(0),
+            ));
+        }
+        Ok(entries)
+    });
+    raise(result, &raised)?
+        .into_iter()
+        .map(|(path, kind, mode, size, mtime)| {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("path", path)?;
+            dict.set_item("kind", kind)?;
+            dict.set_item("mode", mode)?;
+            dict.set_item("size", size)?;
+            dict.set_item("mtime", mtime)?;
+            Ok(dict)
+        })
+        .collect()
+}
+
+/// Decrypt `archive` to the end and check every file against its checksum manifest;
+/// returns the entry, file and byte counts
+#[pyfunction]
+#[pyo3(signature = (archive, *, passphrase=None, identity_file=None, progress=None))]
+fn verify<'py>(
+    py: Python<'py>,
+    archive: PathBuf,
+    passphrase: Option<String>,
+    identity_file: Option<PathBuf>,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let raised = Raised::default();
+    let result = py.allow_threads(|| -> Result<Checked> {
+        let archive =
+            unlocker(&archive, passphrase, identity_file, progress, &raised)?.archive()?;
+        checksum::check_archive(archive, &indicatif::ProgressBar::hidden())
+    });
+    let checked = raise(result, &raised)?;
+    let dict = stats_dict(py, checked.files, checked.bytes)?;
+    dict.set_item("entries", checked.entries)?;
+    Ok(dict)
+}
+
+/// An `Unlocker` over `archive`, reporting its bytes read to `progress`
+fn unlocker(
+    archive: &Path,
+    passphrase: Option<String>,
+    identity_file: Option<PathBuf>,
+    progress: Option<PyObject>,
+    raised: &Raised,
+) -> Result<Unlocker<Reporting<Box<dyn Read>>>> {
+    let input = streams::open_input(archive)?;
+    let total = streams::input_len(archive);
+    let unlocker = Unlocker::new(Reporting::new(input, total, progress, raised.clone()));
+    Ok(match (passphrase, identity_file) {
+        (Some(pass), None) => unlocker.passphrase(SecretString::new(pass)),
+        (None, Some(file)) => unlocker.identities(read_identities(&[file])?),
+        (Some(_), Some(_)) => anyhow::bail!("pass either a passphrase or an identity file"),
+        (None, None) => anyhow::bail!("a passphrase or an identity file is needed"),
+    })
+}
+
+/// The Python exception for `result`'s error; one raised by `progress` takes precedence
+fn raise<T>(result: Result<T>, raised: &Raised) -> PyResult<T> {
+    if let Some(e) = raised.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        return Err(e);
+    }
+    result.map_err(|e| {
+        let message = format!("{:#}", e);
+        match Failure::of(&e) {
+            Some(Failure::WrongKey) => WrongKeyError::new_err(message),
+            _ => Error::new_err(message),
+        }
+    })
+}
+
+fn stats_dict(py: Python<'_>, files: u64, bytes: u64) -> PyResult<Bound<'_, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("files", files)?;
+    dict.set_item("bytes", bytes)?;
+    Ok(dict)
+}
+
+/// Counts the bytes passing through and hands the count to a Python `progress` callable
+struct Reporting<T> {
+    inner: T,
+    done: u64,
+    reported: u64,
+    total: Option<u64>,
+    callback: Option<PyObject>,
+    raised: Raised,
+}
+
+impl<T> Reporting<T> {
+    fn new(inner: T, total: Option<u64>, callback: Option<PyObject>, raised: Raised) -> Self {
+        Self {
+            inner,
+            done: 0,
+            reported: 0,
+            total,
+            callback,
+            raised,
+        }
+    }
+
+    /// Call `progress` if `PROGRESS_STEP` bytes passed since the last call, or if `last`
+    fn report(&mut self, last: bool) -> io::Result<()> {
+        let Some(callback) = &self.callback else {
+            return Ok(());
+        };
+        if !last && self.done - self.reported < PROGRESS_STEP {
+            return Ok(());
+        }
+        self.reported = self.done;
+        let (done, total) = (self.done, self.total);
+        Python::with_gil(|py| callback.call1(py, (done, total)).map(drop)).map_err(|e| {
+            *self.raised.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+            io::Error::other("progress callback raised an exception")
+        })
+    }
+}
+
+impl<R: Read> Read for Reporting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        self.report(n == 0 && !buf.is_empty())?;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Reporting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.done += n as u64;
+        self.report(false)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
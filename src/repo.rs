@@ -0,0 +1,698 @@
+//! Deduplicating backup repository: content-defined chunks, each encrypted on its own
+//!
+//! A repository is a folder:
+//!
+//! ```text
+//! key.age                   the repository key, encrypted to the user's passphrase or recipients
+//! chunks/3f/3fa0…           one zstd-compressed chunk, encrypted to the repository key
+//! snapshots/<time>.age      one backup: every entry's metadata and chunk list, encrypted the same way
+//! ```
+//!
+//! Files are cut where a rolling (gear) hash of the last bytes hits a pattern, so an edit
+//! only changes the chunks around it; chunks already in the repository aren't stored again.
+//! The repository key is a random age x25519 identity: scrypt is far too slow to run once
+//! per chunk, so the passphrase (or the user's recipients) only protect `key.age`. Chunks
+//! are named by an HMAC-SHA256 keyed with that identity, so the names say nothing about
+//! contents to someone without the key, and backing up needs the key too.
+//!
+//! Restoring streams a snapshot as a tar archive, so `extract` applies its path checks and
+//! options exactly as for a regular archive.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use age::secrecy::ExposeSecret;
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::checksum::{self, Hash};
+use crate::failure::{Classify, Failure};
+use crate::locker::{self, Key};
+use crate::pack::{self, PackOptions, Sources};
+use crate::progress;
+use crate::snapshot;
+use crate::streams;
+
+/// The repository key, relative to the repository folder
+pub const KEY_FILE: &str = "key.age";
+
+/// Snapshot layout version written by this build
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Chunk size bounds; cut points land on average every `1 << AVG_BITS` bytes in between
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+const AVG_BITS: u32 = 20;
+
+/// An opened repository, holding its key
+pub struct Repo {
+    root: PathBuf,
+    identity: age::x25519::Identity,
+    /// Keys the chunk names, derived from `identity`
+    id_key: Hash,
+}
+
+/// One backed-up entry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Item {
+    /// Archive-style path (`docs/a.txt`)
+    pub path: String,
+    pub kind: ItemKind,
+    pub mode: u32,
+    pub mtime: u64,
+    pub size: u64,
+    /// Chunk names, in order, for files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// Link target, for symlinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Owner uid and gid, recorded with `--preserve-owner`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<(u64, u64)>,
+    /// Extended attributes as PAX `SCHILY.xattr.*` records, recorded with `--xattrs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub version: u32,
+    /// Unix time the backup started
+    pub time: u64,
+    /// The source paths as given
+    pub sources: Vec<String>,
+    pub items: Vec<Item>,
+}
+
+/// What `Repo::backup` did
+#[derive(Debug, Default)]
+pub struct BackupStats {
+    /// Name of the new snapshot
+    pub snapshot: String,
+    pub files: u64,
+    /// Size of all files backed up
+    pub bytes: u64,
+    pub chunks: u64,
+    /// Chunks that weren't in the repository yet, and their encrypted size
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+}
+
+impl Repo {
+    /// Create a repository in the empty (or missing) folder `root`, with `encryptor`
+    /// protecting its key
+    pub fn init(root: &Path, encryptor: age::Encryptor) -> Result<Self> {
+        if root.exists() && std::fs::read_dir(root)?.next().is_some() {
+            return Err(Failure::OutputExists
+                .error(format!("'{}' exists and is not empty", root.display())));
+        }
+        for dir in ["chunks", "snapshots"] {
+            std::fs::create_dir_all(root.join(dir))
+                .with_context(|| format!("failed to create {}", root.join(dir).display()))?;
+        }
+        let identity = age::x25519::Identity::generate();
+        let path = Self::key_path(root);
+        let mut w = encryptor
+            .wrap_output(File::create(&path)?)
+            .context("failed to create age encrypting writer")?;
+        w.write_all(identity.to_string().expose_secret().as_bytes())?;
+        w.finish()?.sync_all()?;
+        Ok(Self::with_identity(root, identity))
+    }
+
+    /// Open the repository at `root`; `key` is `KEY_FILE`, already decrypted
+    pub fn open(root: &Path, mut key: impl Read) -> Result<Self> {
+        let mut text = String::new();
+        key.read_to_string(&mut text)
+            .classify(Failure::Corrupted, "failed to read the repository key")?;
+        let identity = text
+            .trim()
+            .parse::<age::x25519::Identity>()
+            .map_err(|e| Failure::Corrupted.error(format!("invalid repository key: {}", e)))?;
+        Ok(Self::with_identity(root, identity))
+    }
+
+    fn with_identity(root: &Path, identity: age::x25519::Identity) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"folder_lock repository chunk names\0");
+        hasher.update(identity.to_string().expose_secret().as_bytes());
+        Self {
+            root: root.to_path_buf(),
+            identity,
+            id_key: hasher.finalize().into(),
+        }
+    }
+
+    /// Where the key of the repository at `root` is stored
+    pub fn key_path(root: &Path) -> PathBuf {
+        root.join(KEY_FILE)
+    }
+
+    /// Snapshot names, oldest first; they sort by the time they were taken
+    pub fn snapshots(root: &Path) -> Result<Vec<String>> {
+        let dir = root.join("snapshots");
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&dir).classify(
+            Failure::SourceMissing,
+            format!("'{}' is not a repository", root.display()),
+        )? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(name) = name.strip_suffix(".age") {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Store a new snapshot of `sources`, filtered by `options.filters`, with owners and
+    /// xattrs as `options` asks
+    ///
+    /// `bar` advances by the file bytes read. Chunks are written before the snapshot that
+    /// refers to them, so an interrupted backup leaves only unreferenced chunks behind.
+    pub fn backup(
+        &self,
+        sources: &Sources,
+        names: &[PathBuf],
+        options: &PackOptions,
+        bar: &ProgressBar,
+    ) -> Result<BackupStats> {
+        let started = SystemTime::now();
+        let mut stats = BackupStats::default();
+        let mut items = Vec::new();
+        sources.visit(&options.filters, |entry| {
+            let meta = if entry.is_symlink {
+                std::fs::symlink_metadata(&entry.path)
+            } else {
+                std::fs::metadata(&entry.path)
+            }
+            .with_context(|| format!("failed to stat '{}'", entry.path.display()))?;
+            let header = pack::header_for(&meta, options);
+            let owner = match (header.uid(), header.gid()) {
+                (Ok(uid), Ok(gid)) if options.preserve_owner => Some((uid, gid)),
+                _ => None,
+            };
+            let mut item = Item {
+                path: snapshot::key(&entry.rel),
+                kind: ItemKind::Dir,
+                mode: header.mode().unwrap_or(0o644),
+                mtime: header.mtime().unwrap_or(0),
+                size: 0,
+                chunks: Vec::new(),
+                target: None,
+                owner,
+                xattrs: Vec::new(),
+            };
+            // Like `pack`, which reads them through links and stores none for symlinks
+            if options.xattrs && !entry.is_symlink {
+                item.xattrs = pack::xattr_records(&entry.path).with_context(|| {
+                    format!("failed to read xattrs of '{}'", entry.path.display())
+                })?;
+            }
+            if entry.is_symlink {
+                let target = std::fs::read_link(&entry.path)
+                    .with_context(|| format!("failed to read link '{}'", entry.path.display()))?;
+                item.kind = ItemKind::Symlink;
+                item.target = Some(target.to_string_lossy().into_owned());
+            } else if meta.is_file() {
+                item.kind = ItemKind::File;
+                let f = File::open(&entry.path)
+                    .with_context(|| format!("failed to open '{}'", entry.path.display()))?;
+                // The size is what was read, which stays consistent with the chunks even
+                // if the file changes underneath
+                (item.chunks, item.size) = self
+                    .store_file(bar.wrap_read(f), &mut stats)
+                    .with_context(|| format!("failed to back up '{}'", entry.path.display()))?;
+                stats.files += 1;
+                stats.bytes += item.size;
+                progress::set_files(bar, stats.files, "backed up", &entry.rel);
+            } else if !meta.is_dir() {
+                log::warn!(
+                    "skipping '{}': not a file, folder or symlink",
+                    entry.path.display()
+                );
+                return Ok(());
+            }
+            log::debug!("backed up {}", entry.rel.display());
+            items.push(item);
+            Ok(())
+        })?;
+
+        let snapshot = SnapshotFile {
+            version: SNAPSHOT_VERSION,
+            time: started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            sources: names.iter().map(|p| p.display().to_string()).collect(),
+            items,
+        };
+        stats.snapshot = snapshot_name(started);
+        let path = self
+            .root
+            .join("snapshots")
+            .join(format!("{}.age", stats.snapshot));
+        if path.exists() {
+            anyhow::bail!("snapshot '{}' already exists", stats.snapshot);
+        }
+        self.write_encrypted(&path, &serde_json::to_vec(&snapshot)?)
+            .context("failed to write the snapshot")?;
+        Ok(stats)
+    }
+
+    /// Cut `r` into chunks, storing the ones the repository lacks; returns their names and
+    /// the total size
+    fn store_file(&self, r: impl Read, stats: &mut BackupStats) -> Result<(Vec<String>, u64)> {
+        let mut chunker = Chunker::new(r);
+        let mut names = Vec::new();
+        let mut size = 0;
+        while let Some(chunk) = chunker.next_chunk()? {
+            size += chunk.len() as u64;
+            let name = checksum::hex(&self.chunk_id(&chunk));
+            let path = self.chunk_path(&name);
+            stats.chunks += 1;
+            if !path.exists() {
+                let dir = path.parent().expect("chunks live in a subfolder");
+                std::fs::create_dir_all(dir)?;
+                let compressed = zstd::encode_all(chunk.as_slice(), 3)?;
+                // Written under a temporary name, so a chunk file is always complete
+                let partial = path.with_extension("partial");
+                self.write_encrypted(&partial, &compressed)?;
+                std::fs::rename(&partial, &path)?;
+                stats.new_chunks += 1;
+                stats.new_bytes += std::fs::metadata(&path)?.len();
+            }
+            names.push(name);
+        }
+        Ok((names, size))
+    }
+
+    /// Read snapshot `name` (or the newest, for `latest`)
+    pub fn snapshot(&self, name: &str) -> Result<SnapshotFile> {
+        let name = match name {
+            "latest" => Self::snapshots(&self.root)?
+                .pop()
+                .ok_or_else(|| Failure::SourceMissing.error("the repository has no snapshots"))?,
+            name => name.to_string(),
+        };
+        let path = self.root.join("snapshots").join(format!("{}.age", name));
+        let r = streams::open_file(&path, "snapshot")?;
+        let snapshot: SnapshotFile = serde_json::from_reader(self.decrypt(r)?).classify(
+            Failure::Corrupted,
+            format!("snapshot '{}' is unreadable", name),
+        )?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            anyhow::bail!(
+                "snapshot '{}' uses version {}; this build reads up to {}",
+                name,
+                snapshot.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Snapshot `name` as a tar stream, for `extract` or any other tar reader
+    ///
+    /// Every chunk is checked against its name while it's read; a mismatch is a read error.
+    pub fn restore(self, name: &str) -> Result<tar::Archive<Box<dyn Read>>> {
+        let snapshot = self.snapshot(name)?;
+        let repo = Arc::new(self);
+        Ok(tar::Archive::new(streams::tar_from(move |tar| {
+            for item in snapshot.items {
+                let mut header = tar::Header::new_gnu();
+                header.set_mode(item.mode);
+                header.set_mtime(item.mtime);
+                // Without a recorded owner the entry is 0:0, as in an archive made without
+                // --preserve-owner
+                let (uid, gid) = item.owner.unwrap_or((0, 0));
+                header.set_uid(uid);
+                header.set_gid(gid);
+                if !item.xattrs.is_empty() {
+                    let records = item.xattrs.iter().map(|(k, v)| (k.as_str(), v.as_slice()));
+                    tar.append_pax_extensions(records)?;
+                }
+                match item.kind {
+                    ItemKind::Dir => {
+                        header.set_entry_type(tar::EntryType::Directory);
+                        header.set_size(0);
+                        tar.append_data(&mut header, &item.path, io::empty())?;
+                    }
+                    ItemKind::Symlink => {
+                        header.set_entry_type(tar::EntryType::Symlink);
+                        header.set_size(0);
+                        let target = item.target.unwrap_or_default();
+                        tar.append_link(&mut header, &item.path, &target)?;
+                    }
+                    ItemKind::File => {
+                        header.set_entry_type(tar::EntryType::Regular);
+                        header.set_size(item.size);
+                        let reader = ChunkReader {
+                            repo: repo.clone(),
+                            chunks: item.chunks.into_iter(),
+                            current: io::Cursor::new(Vec::new()),
+                        };
+                        tar.append_data(&mut header, &item.path, reader)
+                            .with_context(|| format!("failed to restore '{}'", item.path))?;
+                    }
+                }
+            }
+            Ok(())
+        })))
+    }
+
+    /// Decrypt and check chunk `name`
+    fn load_chunk(&self, name: &str) -> Result<Vec<u8>> {
+        let r = streams::open_file(&self.chunk_path(name), "chunk")?;
+        let data = zstd::decode_all(BufReader::new(self.decrypt(r)?))
+            .classify(Failure::Corrupted, format!("chunk {} is corrupted", name))?;
+        if checksum::hex(&self.chunk_id(&data)) != name {
+            return Err(Failure::Corrupted.error(format!("chunk {} doesn't match its name", name)));
+        }
+        Ok(data)
+    }
+
+    fn chunk_id(&self, data: &[u8]) -> Hash {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.id_key)
+            .expect("HMAC takes keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn chunk_path(&self, name: &str) -> PathBuf {
+        self.root.join("chunks").join(&name[..2]).join(name)
+    }
+
+    fn write_encrypted(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let recipient: Box<dyn age::Recipient + Send> = Box::new(self.identity.to_public());
+        let encryptor =
+            age::Encryptor::with_recipients(vec![recipient]).context("no recipients given")?;
+        let f =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut w = encryptor.wrap_output(f)?;
+        w.write_all(data)?;
+        w.finish()?.sync_all()?;
+        Ok(())
+    }
+
+    fn decrypt(&self, r: File) -> Result<Box<dyn Read>> {
+        let identity: Box<dyn age::Identity + Send> = Box::new(self.identity.clone());
+        locker::decrypt(BufReader::new(r), |_| Ok(Key::Identities(vec![identity])))
+    }
+}
+
+/// Snapshot names are their UTC start time, with `-` for `:` so they work on Windows too
+fn snapshot_name(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time)
+        .to_string()
+        .replace(':', "-")
+}
+
+/// A file's contents, read back chunk by chunk
+struct ChunkReader {
+    repo: Arc<Repo>,
+    chunks: std::vec::IntoIter<String>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let Some(name) = self.chunks.next() else {
+                return Ok(0);
+            };
+            let data = self
+                .repo
+                .load_chunk(&name)
+                .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+            self.current = io::Cursor::new(data);
+        }
+    }
+}
+
+/// Splits a stream where a gear hash of the preceding bytes has its high `AVG_BITS` clear
+///
+/// Each step shifts the hash left, so its high bits mix in the last 64 bytes while its low
+/// bits only see the last few, as in FastCDC.
+struct Chunker<R> {
+    r: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    fn new(r: R) -> Self {
+        Self {
+            r,
+            buf: Vec::with_capacity(MAX_CHUNK),
+            eof: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // Top the buffer up to a full maximum-size chunk, unless the input has run out
+        while !self.eof && self.buf.len() < MAX_CHUNK {
+            let start = self.buf.len();
+            self.buf.resize(MAX_CHUNK, 0);
+            let n = self.r.read(&mut self.buf[start..])?;
+            self.buf.truncate(start + n);
+            self.eof = n == 0;
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let gear = gear_table();
+        let mask = !0u64 << (64 - AVG_BITS);
+        let mut cut = self.buf.len();
+        let mut hash = 0u64;
+        // Each step shifts older bytes out, so 64 bytes before MIN_CHUNK suffice to warm up
+        for (i, &byte) in self
+            .buf
+            .iter()
+            .enumerate()
+            .skip(MIN_CHUNK.saturating_sub(64))
+        {
+            hash = (hash << 1).wrapping_add(gear[byte as usize]);
+            if i + 1 >= MIN_CHUNK && hash & mask == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+        let rest = self.buf.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Random-looking values for the gear hash, fixed forever: changing them would move every
+/// cut point and defeat deduplication against existing repositories
+fn gear_table() -> &'static [u64; 256] {
+    static TABLE: OnceLock<[u64; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        // splitmix64, from a fixed seed
+        let mut state = 0x666f_6c64_6572_6c6bu64;
+        std::array::from_fn(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes that look random, the same every run
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data);
+        std::iter::from_fn(|| chunker.next_chunk().unwrap()).collect()
+    }
+
+    #[test]
+    fn chunks_cover_the_input_within_bounds() {
+        let data = noise(16 << 20, 1);
+        let cut = chunks(&data);
+        assert_eq!(cut.concat(), data);
+        assert!(cut.len() > 4, "{} chunks", cut.len());
+        let (last, full) = cut.split_last().unwrap();
+        assert!(full
+            .iter()
+            .all(|c| (MIN_CHUNK..=MAX_CHUNK).contains(&c.len())));
+        assert!(last.len() <= MAX_CHUNK);
+        // The same input is always cut in the same places
+        assert_eq!(chunks(&data), cut);
+    }
+
+    #[test]
+    fn edits_only_change_nearby_chunks() {
+        let data = noise(16 << 20, 2);
+        let before = chunks(&data);
+        let mut edited = data.clone();
+        let middle = edited.len() / 2;
+        edited.splice(
+            middle..middle,
+            *b"an insertion that shifts everything after it",
+        );
+        edited[3 << 20] ^= 0xff;
+        let after = chunks(&edited);
+
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(
+            shared + 4 >= after.len(),
+            "only {} of {} chunks survived two edits",
+            shared,
+            after.len()
+        );
+    }
+
+    #[test]
+    fn uniform_input_is_cut_at_the_maximum() {
+        let lens: Vec<usize> = chunks(&vec![0; 9 << 20]).iter().map(Vec::len).collect();
+        assert_eq!(lens, [MAX_CHUNK, MAX_CHUNK, 1 << 20]);
+    }
+
+    #[test]
+    fn chunk_names_are_keyed() {
+        let a = Repo::with_identity(Path::new("a"), age::x25519::Identity::generate());
+        let b = Repo::with_identity(Path::new("b"), age::x25519::Identity::generate());
+        assert_eq!(a.chunk_id(b"data"), a.chunk_id(b"data"));
+        assert_ne!(a.chunk_id(b"data"), b.chunk_id(b"data"));
+        assert_ne!(a.chunk_id(b"data"), a.chunk_id(b"date"));
+    }
+
+    #[test]
+    fn backups_share_unchanged_chunks() {
+        let base = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        let (root, source) = (base.join("repo"), base.join("source"));
+        std::fs::create_dir_all(&source).unwrap();
+        let mut data = noise(8 << 20, 3);
+        std::fs::write(source.join("big.bin"), &data).unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let recipient: Box<dyn age::Recipient + Send> = Box::new(identity.to_public());
+        let repo = Repo::init(
+            &root,
+            age::Encryptor::with_recipients(vec![recipient]).unwrap(),
+        )
+        .unwrap();
+        let options = PackOptions {
+            preserve_owner: true,
+            ..PackOptions::default()
+        };
+        let sources = Sources::new(vec![source.clone()]).unwrap();
+        let bar = ProgressBar::hidden();
+        let first = repo
+            .backup(&sources, &[source.clone()], &options, &bar)
+            .unwrap();
+        assert_eq!(first.new_chunks, first.chunks);
+
+        data[5 << 20] ^= 0xff;
+        std::fs::write(source.join("big.bin"), &data).unwrap();
+        // Snapshots are named by the millisecond they were taken
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = repo
+            .backup(&sources, &[source.clone()], &options, &bar)
+            .unwrap();
+        assert!(second.new_chunks <= 2, "{} new chunks", second.new_chunks);
+        assert_eq!(Repo::snapshots(&root).unwrap().len(), 2);
+
+        let snapshot = repo.snapshot("latest").unwrap();
+        let item = snapshot.items.iter().find(|i| i.path == "big.bin").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let meta = std::fs::metadata(source.join("big.bin")).unwrap();
+            assert_eq!(item.owner, Some((meta.uid() as u64, meta.gid() as u64)));
+        }
+
+        let mut archive = repo.restore("latest").unwrap();
+        let mut restored = None;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new("big.bin") {
+                let owner = (entry.header().uid().unwrap(), entry.header().gid().unwrap());
+                assert_eq!(Some(owner), item.owner);
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                restored = Some(contents);
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+        assert!(
+            restored.unwrap() == data,
+            "the latest snapshot restores the edited file"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xattrs_are_recorded_and_restored() {
+        let base = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        let (root, source) = (base.join("repo"), base.join("source"));
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("tagged"), b"contents").unwrap();
+        if xattr::set(source.join("tagged"), "user.folder_lock", b"value").is_err() {
+            // The temporary folder's filesystem has no user xattrs
+            std::fs::remove_dir_all(&base).unwrap();
+            return;
+        }
+
+        let identity = age::x25519::Identity::generate();
+        let recipient: Box<dyn age::Recipient + Send> = Box::new(identity.to_public());
+        let encryptor = age::Encryptor::with_recipients(vec![recipient]).unwrap();
+        let repo = Repo::init(&root, encryptor).unwrap();
+        let options = PackOptions {
+            xattrs: true,
+            ..PackOptions::default()
+        };
+        let sources = Sources::new(vec![source.clone()]).unwrap();
+        repo.backup(&sources, &[source], &options, &ProgressBar::hidden())
+            .unwrap();
+
+        let mut archive = repo.restore("latest").unwrap();
+        let mut records = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new("tagged") {
+                for ext in entry.pax_extensions().unwrap().unwrap() {
+                    let ext = ext.unwrap();
+                    records.push((ext.key().unwrap().to_string(), ext.value_bytes().to_vec()));
+                }
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+        assert!(records.contains(&("SCHILY.xattr.user.folder_lock".into(), b"value".to_vec())));
+    }
+}
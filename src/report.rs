@@ -0,0 +1,199 @@
+//! Results of a command, printed as JSON for wrapping scripts with `--output json`
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use crate::diff::Difference;
+use crate::envelope::Envelope;
+use crate::failure::Failure;
+use crate::header::Header;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable messages on stderr
+    #[default]
+    Text,
+    /// A single JSON object on stdout when the command finishes
+    Json,
+}
+
+/// Counts gathered while packing or unpacking
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Non-directory entries
+    pub files: u64,
+    /// File content bytes
+    pub bytes: u64,
+}
+
+/// One archive entry, as shown by `list` and `find`
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    /// The archive holding the entry, when `find` searched several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<PathBuf>,
+    pub path: String,
+    pub kind: &'static str,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// Entry type name used in JSON output
+pub fn entry_kind(kind: tar::EntryType) -> &'static str {
+    match kind {
+        tar::EntryType::Directory => "dir",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Regular | tar::EntryType::Continuous => "file",
+        _ => "other",
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub command: &'static str,
+    /// `ok` or `error`
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<PathBuf>,
+    pub files: u64,
+    /// Bytes read: source file data on encrypt, ciphertext on decrypt
+    pub bytes_in: u64,
+    /// Bytes written: ciphertext on encrypt, restored file data on decrypt
+    pub bytes_out: u64,
+    pub duration_secs: f64,
+    /// `bytes_in` per second of wall time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throughput: Option<f64>,
+    /// Largest resident set size of the process (Unix only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_memory_bytes: Option<u64>,
+    /// Uncompressed size divided by encrypted size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Cleartext header, from `info`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<Envelope>,
+    /// Inner compression (`gzip`, `zstd`, `xz`, `none`), from `info --decrypt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// Layout header from inside the archive, with its label, from `info --decrypt` and `list`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<Header>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<EntryInfo>,
+    /// Paths that differ between an archive and a folder, from `diff`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<Difference>,
+    /// Archives deleted by `prune`, or that would be with `--dry-run`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<PathBuf>,
+    /// Snapshot written or restored by `repo backup` / `repo restore`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+    /// Snapshot names, from `repo list`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<String>,
+    /// One report per archive written, from `batch`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<Report>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Process exit status of a failed command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+impl Report {
+    pub fn new(command: &'static str) -> Self {
+        Self {
+            command,
+            status: "ok",
+            ..Self::default()
+        }
+    }
+
+    pub fn failed(command: &'static str, error: &anyhow::Error) -> Self {
+        Self {
+            command,
+            status: "error",
+            error: Some(format!("{:#}", error)),
+            exit_code: Some(Failure::of(error).map_or(1, Failure::exit_code)),
+            ..Self::default()
+        }
+    }
+
+    /// Ratio of `uncompressed` to `compressed`, if both are non-zero
+    pub fn ratio(uncompressed: u64, compressed: u64) -> Option<f64> {
+        (uncompressed > 0 && compressed > 0).then(|| uncompressed as f64 / compressed as f64)
+    }
+
+    /// Fill in the timing and resource figures of a command that took `duration_secs`
+    pub fn finish(&mut self, duration_secs: f64) {
+        self.duration_secs = duration_secs;
+        self.throughput = (duration_secs > 0.0).then(|| self.bytes_in as f64 / duration_secs);
+        self.peak_memory_bytes = peak_memory();
+    }
+
+    /// Print the figures as an aligned table on stderr, for `--stats`
+    pub fn print_stats(&self) {
+        eprintln!("files:        {}", self.files);
+        eprintln!(
+            "bytes in:     {} ({})",
+            self.bytes_in,
+            HumanBytes(self.bytes_in)
+        );
+        eprintln!(
+            "bytes out:    {} ({})",
+            self.bytes_out,
+            HumanBytes(self.bytes_out)
+        );
+        if let Some(ratio) = self.compression_ratio {
+            eprintln!("ratio:        {:.2}", ratio);
+        }
+        if let Some(throughput) = self.throughput {
+            eprintln!("throughput:   {}/s", HumanBytes(throughput as u64));
+        }
+        eprintln!("wall time:    {:.2}s", self.duration_secs);
+        if let Some(peak) = self.peak_memory_bytes {
+            eprintln!("peak memory:  {}", HumanBytes(peak));
+        }
+    }
+
+    /// Print as one line of JSON on stdout, or stderr when stdout carries archive data
+    pub fn print_json(&self, to_stderr: bool) {
+        match serde_json::to_string(self) {
+            Ok(json) if to_stderr => eprintln!("{}", json),
+            Ok(json) => println!("{}", json),
+            Err(e) => log::error!("failed to serialize report: {}", e),
+        }
+    }
+}
+
+/// Peak resident set size so far, from `getrusage`
+#[cfg(unix)]
+fn peak_memory() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is a valid out-pointer
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let max_rss = usage.ru_maxrss as u64;
+    // Linux and the BSDs count kilobytes, macOS bytes
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_memory() -> Option<u64> {
+    None
+}
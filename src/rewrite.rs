@@ -0,0 +1,574 @@
+//! Writing a new archive from the entries of existing ones, for `append`, `remove`,
+//! `update` and `merge`
+//!
+//! The old archive is decrypted and read once, front to back, while the new one is
+//! written: entries are copied across with their headers, PAX records and contents, and
+//! nothing is unpacked to disk. The checksum manifest is rebuilt from the copied contents
+//! and checked against the old one at the end, so damage in the old archive fails the
+//! rewrite instead of being carried over. Incremental archives and zip containers can't
+//! be rewritten.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use tar::{Builder, EntryType};
+
+use crate::checksum::{self, HashingReader, Manifest};
+use crate::container::Container;
+use crate::extract::PathFilter;
+use crate::failure::{Classify, Failure};
+use crate::header::{Header, HEADER_PATH};
+use crate::merge::Plan;
+use crate::pack::{self, ManifestSpool, PackOptions, Sources};
+use crate::report::Stats;
+use crate::snapshot::{self, Snapshot, SNAPSHOT_PATH};
+
+const INCREMENTAL: &str = "incremental archives can't be rewritten; decrypt and re-encrypt instead";
+
+/// Which old entries a rewrite leaves out, and which source entries it stores
+#[derive(Default)]
+pub(crate) struct Selection<'a> {
+    /// Old entries this matches (`Locker::remove`); at least one must
+    pub remove: Option<&'a PathFilter>,
+    /// Keys of old entries to leave out, such as files deleted from the source folder
+    pub deleted: HashSet<String>,
+    /// Keys of the only source entries to store; every entry when `None`
+    pub only: Option<HashSet<String>>,
+}
+
+/// Copy the entries of `old` to `tar`, then add `sources` after them
+///
+/// An old entry is also left out when `sources` stores the same path, or a file where the
+/// old entry has a parent folder. Returns the totals of both parts together.
+pub(crate) fn append<W: Write>(
+    tar: &mut Builder<W>,
+    old: &mut tar::Archive<Box<dyn Read>>,
+    selection: &Selection,
+    sources: &Sources,
+    options: &PackOptions,
+    bar: &ProgressBar,
+) -> Result<(Stats, Snapshot)> {
+    let mut paths = HashSet::new();
+    let mut files = HashSet::new();
+    for planned in pack::plan(sources, options)? {
+        let key = snapshot::key(&planned.entry.rel);
+        if selection
+            .only
+            .as_ref()
+            .is_some_and(|only| !only.contains(&key))
+        {
+            continue;
+        }
+        if !planned.entry.is_dir {
+            files.insert(key.clone());
+        }
+        paths.insert(key);
+    }
+    let replaced = |key: &str| {
+        paths.contains(key)
+            || key
+                .match_indices('/')
+                .any(|(i, _)| files.contains(&key[..i]))
+    };
+
+    let removed = Cell::new(0u64);
+    let skip = |key: &str| {
+        if selection
+            .remove
+            .is_some_and(|filter| filter.matches(Path::new(key)))
+            || selection.deleted.contains(key)
+        {
+            log::debug!("Removing '{}'", key);
+            removed.set(removed.get() + 1);
+            return true;
+        }
+        replaced(key)
+    };
+
+    let mut manifest = ManifestSpool::new().context("failed to create the manifest spool")?;
+    let copied = copy_entries(tar, old, &skip, None, &mut manifest)?;
+    if selection.remove.is_some() && removed.get() == 0 {
+        return Err(Failure::SourceMissing.error("no entry matches the paths to remove"));
+    }
+    let only = selection.only.as_ref();
+    let (added, snapshot) = pack::append_entries(tar, sources, options, bar, &mut manifest, only)?;
+    manifest
+        .append_to(tar, options)
+        .context("failed to add checksum manifest to tar archive")?;
+    let stats = Stats {
+        files: copied.files + added.files,
+        bytes: copied.bytes + added.bytes,
+    };
+    Ok((stats, snapshot))
+}
+
+/// Copy the entries of each archive in `archives` that `plan` picks, in order
+pub(crate) fn merge<W: Write>(
+    tar: &mut Builder<W>,
+    archives: Vec<tar::Archive<Box<dyn Read>>>,
+    plan: &Plan,
+    options: &PackOptions,
+) -> Result<Stats> {
+    let mut manifest = ManifestSpool::new().context("failed to create the manifest spool")?;
+    let mut stats = Stats::default();
+    for (i, mut archive) in archives.into_iter().enumerate() {
+        let skip = |key: &str| plan.skips(i, key);
+        let prefix = plan.prefix(i);
+        let copied = copy_entries(tar, &mut archive, &skip, prefix.as_deref(), &mut manifest)
+            .with_context(|| format!("failed to copy the entries of {}", plan.names[i]))?;
+        stats.files += copied.files;
+        stats.bytes += copied.bytes;
+    }
+    manifest
+        .append_to(tar, options)
+        .context("failed to add checksum manifest to tar archive")?;
+    Ok(stats)
+}
+
+/// Copy every entry of `old` but folder_lock's own and those `skip` picks, under `prefix`
+/// if given
+fn copy_entries<W: Write>(
+    tar: &mut Builder<W>,
+    old: &mut tar::Archive<Box<dyn Read>>,
+    skip: &dyn Fn(&str) -> bool,
+    prefix: Option<&Path>,
+    manifest: &mut ManifestSpool,
+) -> Result<Stats> {
+    let moved = |path: &Path| match prefix {
+        Some(prefix) => prefix.join(path),
+        None => path.to_path_buf(),
+    };
+    let mut stats = Stats::default();
+    let mut kept = HashSet::new();
+    let mut hashes = BTreeMap::new();
+    let mut old_manifest = None;
+    let entries = old
+        .entries()
+        .classify(Failure::Corrupted, "failed to read archive entries")?;
+    for entry in entries {
+        let mut entry = entry.classify(Failure::Corrupted, "failed to read archive entry")?;
+        let path = entry
+            .path()
+            .context("invalid path in archive")?
+            .into_owned();
+        let key = snapshot::key(&path);
+        if key == HEADER_PATH {
+            check_header(Header::read(&mut entry)?)?;
+            continue;
+        }
+        if key == checksum::MANIFEST_PATH {
+            old_manifest =
+                Some(Manifest::parse(&mut entry).context("failed to parse the checksum manifest")?);
+            continue;
+        }
+        if key == SNAPSHOT_PATH {
+            anyhow::bail!(INCREMENTAL);
+        }
+        if snapshot::is_metadata(&key) || (!key.is_empty() && skip(&key)) {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        if kind.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .context("hard link without a target")?
+                .into_owned();
+            if !kept.contains(&snapshot::key(&target)) {
+                log::warn!(
+                    "Dropping hard link '{}': its target is no longer stored",
+                    key
+                );
+                continue;
+            }
+        }
+
+        copy_pax_extensions(tar, &mut entry)?;
+        let new_path = moved(&path);
+        let mut header = entry.header().clone();
+        if kind.is_gnu_sparse() {
+            // Stored dense; the holes come back as zeros, which the manifest hashes anyway
+            header = dense_header(entry.header(), entry.size());
+        }
+        if checksum::has_contents(kind) {
+            // The header field may be a placeholder for a PAX `size` record
+            header.set_size(entry.size());
+            let mut reader = HashingReader::new(&mut entry);
+            tar.append_data(&mut header, &new_path, &mut reader)
+                .with_context(|| format!("failed to copy '{}'", path.display()))?;
+            let hash = reader.finish();
+            manifest.insert(&snapshot::key(&new_path), &hash)?;
+            hashes.insert(key.clone(), hash);
+            stats.files += 1;
+            stats.bytes += entry.size();
+        } else if kind.is_symlink() || kind.is_hard_link() {
+            let mut target = entry
+                .link_name()?
+                .context("link without a target")?
+                .into_owned();
+            // Hard links name another entry, which moved too; symlinks resolve on disk
+            if kind.is_hard_link() {
+                target = moved(&target);
+            }
+            tar.append_link(&mut header, &new_path, target)
+                .with_context(|| format!("failed to copy '{}'", path.display()))?;
+        } else {
+            header.set_size(0);
+            tar.append_data(&mut header, &new_path, io::empty())
+                .with_context(|| format!("failed to copy '{}'", path.display()))?;
+        }
+        kept.insert(key);
+    }
+
+    match old_manifest {
+        Some(old_manifest) => checksum::compare(&old_manifest, &hashes, false)
+            .classify(Failure::Corrupted, "the archive being rewritten is damaged")?,
+        None => log::warn!("The archive has no checksum manifest; copied contents are unchecked"),
+    }
+    Ok(stats)
+}
+
+fn check_header(header: Header) -> Result<()> {
+    if header.container != Container::Tar.name() {
+        anyhow::bail!(
+            "only tar archives can be rewritten, not {}",
+            header.container
+        );
+    }
+    if header.trailer.iter().any(|path| path == SNAPSHOT_PATH) {
+        anyhow::bail!(INCREMENTAL);
+    }
+    Ok(())
+}
+
+/// Re-emit the entry's PAX records that the builder doesn't write itself
+fn copy_pax_extensions<W: Write, R: Read>(
+    tar: &mut Builder<W>,
+    entry: &mut tar::Entry<R>,
+) -> Result<()> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+    let mut records = Vec::new();
+    for extension in extensions {
+        let extension = extension.context("invalid PAX record in archive")?;
+        let key = extension.key().context("invalid PAX record in archive")?;
+        // Names and sizes are written again by the builder, the sparse map not at all
+        if matches!(key, "path" | "linkpath" | "size") || key.starts_with("GNU.sparse.") {
+            continue;
+        }
+        records.push((key.to_string(), extension.value_bytes().to_vec()));
+    }
+    if !records.is_empty() {
+        tar.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
+    Ok(())
+}
+
+/// A regular-file header with the metadata of the sparse entry `old`, for `size` bytes
+fn dense_header(old: &tar::Header, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(old.mode().unwrap_or(0o644));
+    header.set_uid(old.uid().unwrap_or(0));
+    header.set_gid(old.gid().unwrap_or(0));
+    header.set_mtime(old.mtime().unwrap_or(0));
+    if let Ok(Some(name)) = old.username() {
+        let _ = header.set_username(name);
+    }
+    if let Ok(Some(name)) = old.groupname() {
+        let _ = header.set_groupname(name);
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::merge::Conflict;
+    use crate::snapshot::FileState;
+
+    /// A tar archive of `files`, with the manifest `pack` would give them
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Builder::new(Vec::new());
+        let mut manifest = Manifest::default();
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *data).unwrap();
+            manifest.insert(path.to_string(), Sha256::digest(data).into());
+        }
+        let options = PackOptions::default();
+        pack::append_metadata_file(
+            &mut tar,
+            checksum::MANIFEST_PATH,
+            &manifest.to_bytes(),
+            &options,
+        )
+        .unwrap();
+        tar.into_inner().unwrap()
+    }
+
+    fn open(bytes: Vec<u8>) -> tar::Archive<Box<dyn Read>> {
+        tar::Archive::new(Box::new(io::Cursor::new(bytes)))
+    }
+
+    /// The files in `bytes`, in order, after checking them against its manifest
+    fn contents(bytes: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut manifest = None;
+        for entry in open(bytes).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let key = snapshot::key(&entry.path().unwrap());
+            if key == checksum::MANIFEST_PATH {
+                manifest = Some(Manifest::parse(&mut entry).unwrap());
+            } else if entry.header().entry_type().is_file() {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                files.push((key, data));
+            }
+        }
+        let hashes = files
+            .iter()
+            .map(|(key, data)| (key.clone(), Sha256::digest(data).into()))
+            .collect();
+        checksum::compare(&manifest.expect("a manifest"), &hashes, true).unwrap();
+        files
+    }
+
+    fn rewrite(
+        old: Vec<u8>,
+        selection: &Selection,
+        sources: &Sources,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut tar = Builder::new(Vec::new());
+        let options = PackOptions::default();
+        append(
+            &mut tar,
+            &mut open(old),
+            selection,
+            sources,
+            &options,
+            &ProgressBar::hidden(),
+        )?;
+        Ok(contents(tar.into_inner()?))
+    }
+
+    fn files(list: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+        list.iter()
+            .map(|(key, data)| (key.to_string(), data.to_vec()))
+            .collect()
+    }
+
+    /// A new directory under the temp directory holding `files`; the caller removes it
+    fn scratch(files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        for (name, data) in files {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn appended_paths_replace_old_entries() {
+        let old = archive(&[("a.txt", b"old"), ("docs/b.txt", b"kept")]);
+        let dir = scratch(&[("a.txt", b"new"), ("new.txt", b"added")]);
+        let sources = Sources::named(vec![dir.join("a.txt"), dir.join("new.txt")]).unwrap();
+        let result = rewrite(old, &Selection::default(), &sources);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.unwrap(),
+            files(&[
+                ("docs/b.txt", b"kept"),
+                ("a.txt", b"new"),
+                ("new.txt", b"added")
+            ])
+        );
+    }
+
+    #[test]
+    fn appended_files_replace_old_folders() {
+        let old = archive(&[("docs/b.txt", b"under docs"), ("other", b"kept")]);
+        let dir = scratch(&[("docs", b"a file now")]);
+        let sources = Sources::named(vec![dir.join("docs")]).unwrap();
+        let result = rewrite(old, &Selection::default(), &sources);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.unwrap(),
+            files(&[("other", b"kept"), ("docs", b"a file now")])
+        );
+    }
+
+    #[test]
+    fn damaged_archives_fail_the_rewrite() {
+        let mut old = archive(&[("a.txt", b"intact contents")]);
+        let at = old.windows(6).position(|w| w == b"intact").unwrap();
+        old[at..at + 6].copy_from_slice(b"broken");
+        let err = rewrite(old, &Selection::default(), &Sources::Named(Vec::new())).unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::Corrupted));
+    }
+
+    #[test]
+    fn incremental_archives_are_refused() {
+        let old = archive(&[(SNAPSHOT_PATH, b"{}"), ("a.txt", b"changed")]);
+        let err = rewrite(old, &Selection::default(), &Sources::Named(Vec::new())).unwrap_err();
+        assert_eq!(err.to_string(), INCREMENTAL);
+    }
+
+    fn removing(patterns: &[&str], old: Vec<u8>) -> Result<Vec<u8>> {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        let filter = PathFilter::new(&patterns)?;
+        let selection = Selection {
+            remove: Some(&filter),
+            ..Selection::default()
+        };
+        let mut tar = Builder::new(Vec::new());
+        let (sources, options) = (Sources::Named(Vec::new()), PackOptions::default());
+        append(
+            &mut tar,
+            &mut open(old),
+            &selection,
+            &sources,
+            &options,
+            &ProgressBar::hidden(),
+        )?;
+        Ok(tar.into_inner()?)
+    }
+
+    #[test]
+    fn removed_folders_take_their_contents() {
+        let old = archive(&[
+            ("a.txt", b"a"),
+            ("docs/b.txt", b"b"),
+            ("docs/sub/c.txt", b"c"),
+            ("docs.txt", b"d"),
+        ]);
+        let new = removing(&["docs"], old.clone()).unwrap();
+        assert_eq!(contents(new), files(&[("a.txt", b"a"), ("docs.txt", b"d")]));
+        let new = removing(&["docs/sub"], old).unwrap();
+        assert_eq!(
+            contents(new),
+            files(&[("a.txt", b"a"), ("docs/b.txt", b"b"), ("docs.txt", b"d")])
+        );
+    }
+
+    #[test]
+    fn removing_nothing_fails() {
+        let old = archive(&[("a.txt", b"a")]);
+        let err = removing(&["b.txt"], old).unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::SourceMissing));
+    }
+
+    #[test]
+    fn hard_links_go_with_their_target() {
+        let mut tar = Builder::new(Vec::new());
+        for path in ["a.txt", "b.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            tar.append_data(&mut header, path, &b"data"[..]).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        tar.append_link(&mut header, "link", "a.txt").unwrap();
+        let old = tar.into_inner().unwrap();
+
+        let keys = |removed: &str| -> Vec<String> {
+            let new = removing(&[removed], old.clone()).unwrap();
+            let mut archive = open(new);
+            let entries = archive.entries().unwrap();
+            entries
+                .map(|entry| snapshot::key(&entry.unwrap().path().unwrap()))
+                .filter(|key| !snapshot::is_metadata(key))
+                .collect()
+        };
+        assert_eq!(keys("b.txt"), ["a.txt", "link"]);
+        assert_eq!(keys("a.txt"), ["b.txt"]);
+    }
+
+    #[test]
+    fn updates_store_only_changed_files() {
+        let old = archive(&[
+            ("changed.txt", b"before"),
+            ("same.txt", b"as archived"),
+            ("gone.txt", b"deleted since"),
+        ]);
+        // `same.txt` differs on disk, but isn't listed as changed, so the old copy stays
+        let dir = scratch(&[
+            ("changed.txt", b"after"),
+            ("same.txt", b"as on disk"),
+            ("added.txt", b"new"),
+        ]);
+        let selection = Selection {
+            deleted: HashSet::from(["gone.txt".to_string()]),
+            only: Some(HashSet::from([
+                "changed.txt".to_string(),
+                "added.txt".to_string(),
+            ])),
+            ..Selection::default()
+        };
+        let result = rewrite(old, &selection, &Sources::new(vec![dir.clone()]).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut result = result.unwrap();
+        // Stored in the walk's order, which the file system picks
+        result[1..].sort();
+        assert_eq!(
+            result,
+            files(&[
+                ("same.txt", b"as archived"),
+                ("added.txt", b"new"),
+                ("changed.txt", b"after"),
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_copy_what_the_plan_picks() {
+        let merged = |plan: &Plan| {
+            let archives = vec![
+                open(archive(&[("a.txt", b"first"), ("b.txt", b"only first")])),
+                open(archive(&[("a.txt", b"second")])),
+            ];
+            let mut tar = Builder::new(Vec::new());
+            merge(&mut tar, archives, plan, &PackOptions::default()).unwrap();
+            contents(tar.into_inner().unwrap())
+        };
+        let names = vec!["one".to_string(), "two".to_string()];
+
+        let plan = Plan::new(Conflict::PrefixBySource, names.clone(), &[]).unwrap();
+        assert_eq!(
+            merged(&plan),
+            files(&[
+                ("one/a.txt", b"first"),
+                ("one/b.txt", b"only first"),
+                ("two/a.txt", b"second"),
+            ])
+        );
+
+        // Both `a.txt` have mtime 0, and ties go to the later archive
+        let state = FileState {
+            size: 0,
+            mtime: 0,
+            is_dir: false,
+        };
+        let snapshot = |keys: &[&str]| Snapshot {
+            files: keys.iter().map(|key| (key.to_string(), state)).collect(),
+            deleted: Vec::new(),
+        };
+        let snapshots = [snapshot(&["a.txt", "b.txt"]), snapshot(&["a.txt"])];
+        let plan = Plan::new(Conflict::NewestWins, names, &snapshots).unwrap();
+        assert_eq!(
+            merged(&plan),
+            files(&[("b.txt", b"only first"), ("a.txt", b"second")])
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! `s3://bucket/key` as an archive location (feature `s3`)
+//!
+//! Uploads go through a multipart upload, so the ciphertext never touches local disk and
+//! the object only appears once the last part is in; an aborted run leaves nothing behind.
+//! Credentials, region and endpoint come from the standard AWS chain (`AWS_*` variables,
+//! `~/.aws/config` profiles, instance metadata). Set `AWS_ENDPOINT_URL` for S3-compatible
+//! services.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use tokio::runtime::Runtime;
+use tokio_util::io::SyncIoBridge;
+
+use crate::failure::Failure;
+
+/// Size of each uploaded part; S3 requires at least 5 MiB for all but the last
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// S3 allows at most this many parts per upload; parts grow past it to keep going
+const PART_LIMIT: usize = 10_000;
+
+/// Bucket and key of an `s3://` location
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl Location {
+    /// `Some` if `path` is an `s3://bucket/key` URL; an `s3://` URL without a key is an error
+    pub fn parse(path: &Path) -> Result<Option<Self>> {
+        let Some(rest) = path.to_str().and_then(|s| s.strip_prefix("s3://")) else {
+            return Ok(None);
+        };
+        match rest.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Some(Self {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            })),
+            _ => anyhow::bail!("'{}' is not of the form s3://bucket/key", path.display()),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// The runtime driving the SDK, shared by every upload and download
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("failed to start the S3 runtime")
+    })
+}
+
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let config = runtime().block_on(aws_config::load_defaults(
+            aws_config::BehaviorVersion::latest(),
+        ));
+        Client::new(&config)
+    })
+}
+
+/// Size of the object, or `None` if it doesn't exist
+pub fn object_len(location: &Location) -> Result<Option<u64>> {
+    let head = runtime().block_on(
+        client()
+            .head_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send(),
+    );
+    match head {
+        Ok(head) => Ok(Some(head.content_length().unwrap_or(0).max(0) as u64)),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to look up {}", location)),
+    }
+}
+
+/// Stream the object's contents
+pub fn open(location: &Location) -> Result<Box<dyn Read>> {
+    let object = runtime()
+        .block_on(
+            client()
+                .get_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .send(),
+        )
+        .with_context(|| format!("failed to download {}", location))?;
+    let body = object.body.into_async_read();
+    Ok(Box::new(SyncIoBridge::new_with_handle(
+        body,
+        runtime().handle().clone(),
+    )))
+}
+
+/// Multipart upload to S3, completed on `commit` and aborted if dropped before
+pub struct Upload {
+    location: Location,
+    force: bool,
+    upload_id: Option<String>,
+    buffer: Vec<u8>,
+    parts: Vec<CompletedPart>,
+    committed: bool,
+}
+
+impl Upload {
+    /// Nothing is sent until the first part fills, so bad credentials show up there
+    pub fn create(location: Location, force: bool) -> Self {
+        Self {
+            location,
+            force,
+            upload_id: None,
+            buffer: Vec::with_capacity(PART_SIZE),
+            parts: Vec::new(),
+            committed: false,
+        }
+    }
+
+    /// Bytes per part, growing once the part limit comes near so any size fits
+    fn part_size(&self) -> usize {
+        PART_SIZE * (1 + self.parts.len() / (PART_LIMIT / 10))
+    }
+
+    fn send_part(&mut self) -> Result<()> {
+        let client = client();
+        let upload_id = match &self.upload_id {
+            Some(id) => id.clone(),
+            None => {
+                let started = runtime()
+                    .block_on(
+                        client
+                            .create_multipart_upload()
+                            .bucket(&self.location.bucket)
+                            .key(&self.location.key)
+                            .send(),
+                    )
+                    .with_context(|| format!("failed to start upload to {}", self.location))?;
+                let id = started
+                    .upload_id()
+                    .context("S3 returned no upload id")?
+                    .to_string();
+                self.upload_id = Some(id.clone());
+                id
+            }
+        };
+        if self.parts.len() == PART_LIMIT {
+            anyhow::bail!("upload to {} exceeds {} parts", self.location, PART_LIMIT);
+        }
+        let number = self.parts.len() as i32 + 1;
+        let body = std::mem::take(&mut self.buffer);
+        let part = runtime()
+            .block_on(
+                client
+                    .upload_part()
+                    .bucket(&self.location.bucket)
+                    .key(&self.location.key)
+                    .upload_id(upload_id)
+                    .part_number(number)
+                    .body(ByteStream::from(body))
+                    .send(),
+            )
+            .with_context(|| format!("failed to upload part {} to {}", number, self.location))?;
+        log::debug!("uploaded part {} to {}", number, self.location);
+        self.parts.push(
+            CompletedPart::builder()
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .part_number(number)
+                .build(),
+        );
+        self.buffer = Vec::with_capacity(self.part_size());
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        // Always send a last part, even an empty one: an upload needs at least one
+        self.send_part()?;
+        // Re-check: the object may have appeared while we were uploading
+        if !self.force && object_len(&self.location)?.is_some() {
+            return Err(Failure::OutputExists.error(format!(
+                "output '{}' already exists (use --force to overwrite)",
+                self.location
+            )));
+        }
+        let upload_id = self.upload_id.clone().expect("started by send_part");
+        runtime()
+            .block_on(
+                client()
+                    .complete_multipart_upload()
+                    .bucket(&self.location.bucket)
+                    .key(&self.location.key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(std::mem::take(&mut self.parts)))
+                            .build(),
+                    )
+                    .send(),
+            )
+            .with_context(|| format!("failed to complete upload to {}", self.location))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = self.part_size() - self.buffer.len();
+        let n = room.min(buf.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.part_size() {
+            self.send_part().map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    /// Parts are only sent once full; `commit` sends the rest
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if let Some(upload_id) = self.upload_id.take() {
+            let aborted = runtime().block_on(
+                client()
+                    .abort_multipart_upload()
+                    .bucket(&self.location.bucket)
+                    .key(&self.location.key)
+                    .upload_id(upload_id)
+                    .send(),
+            );
+            if let Err(e) = aborted {
+                log::warn!("failed to abort upload to {}: {}", self.location, e);
+            }
+        }
+    }
+}
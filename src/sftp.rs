@@ -0,0 +1,290 @@
+//! `sftp://[user@]host[:port]/path` as an archive location (feature `sftp`)
+//!
+//! The archive streams over a single SSH connection, so nothing is staged on local disk.
+//! Uploads go to `<path>.tmp` on the server and are renamed into place on commit, like local
+//! outputs. The path is absolute on the server; start it with `/~/` for one relative to the
+//! remote home directory. The host key must already be in `~/.ssh/known_hosts`, and
+//! authentication tries the SSH agent, then the unencrypted default keys in `~/.ssh`.
+
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+
+use crate::failure::Failure;
+
+/// Keys tried, in order, when the agent has none that work
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// SFTP packets are small, so writes are batched before being sent
+const WRITE_BUFFER: usize = 256 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub path: PathBuf,
+}
+
+impl Location {
+    /// `Some` if `path` is an `sftp://` URL; one without a file path is an error
+    pub fn parse(path: &Path) -> Result<Option<Self>> {
+        let Some(rest) = path.to_str().and_then(|s| s.strip_prefix("sftp://")) else {
+            return Ok(None);
+        };
+        let invalid = || {
+            anyhow::anyhow!(
+                "'{}' is not of the form sftp://[user@]host/path",
+                path.display()
+            )
+        };
+        let (authority, remote) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (host, 22),
+        };
+        if host.is_empty() || remote.is_empty() || remote.ends_with('/') {
+            return Err(invalid());
+        }
+        // libssh2 resolves relative paths against the login directory
+        let remote = match remote.strip_prefix("~/") {
+            Some(relative) => PathBuf::from(relative),
+            None => Path::new("/").join(remote),
+        };
+        Ok(Some(Self {
+            user,
+            host: host.to_string(),
+            port,
+            path: remote,
+        }))
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        PathBuf::from(tmp)
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sftp://")?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}", self.host)?;
+        if self.port != 22 {
+            write!(f, ":{}", self.port)?;
+        }
+        match self.path.strip_prefix("/") {
+            Ok(absolute) => write!(f, "/{}", absolute.display()),
+            Err(_) => write!(f, "/~/{}", self.path.display()),
+        }
+    }
+}
+
+/// An authenticated SFTP channel; the session must outlive it
+struct Connection {
+    sftp: Sftp,
+    _session: Session,
+}
+
+fn connect(location: &Location) -> Result<Connection> {
+    let tcp = TcpStream::connect((location.host.as_str(), location.port))
+        .with_context(|| format!("failed to connect to {}:{}", location.host, location.port))?;
+    let mut session = Session::new().context("failed to start SSH session")?;
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {} failed", location.host))?;
+    check_host_key(&session, location)?;
+
+    let user = match &location.user {
+        Some(user) => user.clone(),
+        None => std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .context("no user in the sftp:// URL and $USER is not set")?,
+    };
+    if session.userauth_agent(&user).is_err() {
+        let ssh_dir = ssh_dir()?;
+        for name in DEFAULT_KEYS {
+            let key = ssh_dir.join(name);
+            if key.is_file()
+                && session
+                    .userauth_pubkey_file(&user, None, &key, None)
+                    .is_ok()
+            {
+                log::debug!("authenticated with {}", key.display());
+                break;
+            }
+        }
+    }
+    if !session.authenticated() {
+        anyhow::bail!(
+            "SSH authentication as '{}' on {} failed (load a key into ssh-agent)",
+            user,
+            location.host
+        );
+    }
+    let sftp = session.sftp().context("failed to start SFTP")?;
+    Ok(Connection {
+        sftp,
+        _session: session,
+    })
+}
+
+fn ssh_dir() -> Result<PathBuf> {
+    let home = std::env::var_os("HOME").context("$HOME is not set")?;
+    Ok(Path::new(&home).join(".ssh"))
+}
+
+/// Refuse hosts whose key isn't already trusted, as `ssh` does with `StrictHostKeyChecking`
+fn check_host_key(session: &Session, location: &Location) -> Result<()> {
+    let mut known = session
+        .known_hosts()
+        .context("failed to read known hosts")?;
+    let file = ssh_dir()?.join("known_hosts");
+    if file.is_file() {
+        known
+            .read_file(&file, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("failed to read {}", file.display()))?;
+    }
+    let (key, _) = session.host_key().context("server sent no host key")?;
+    match known.check_port(&location.host, location.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => anyhow::bail!(
+            "host key of {} is not in {} (connect once with ssh to add it)",
+            location.host,
+            file.display()
+        ),
+        CheckResult::Mismatch => anyhow::bail!(
+            "host key of {} does not match {}; refusing to connect",
+            location.host,
+            file.display()
+        ),
+        CheckResult::Failure => anyhow::bail!("failed to check the host key of {}", location.host),
+    }
+}
+
+/// Size of the remote file, or `None` if it doesn't exist
+pub fn file_len(location: &Location) -> Result<Option<u64>> {
+    let conn = connect(location)?;
+    Ok(stat_len(&conn.sftp, &location.path))
+}
+
+fn stat_len(sftp: &Sftp, path: &Path) -> Option<u64> {
+    sftp.stat(path).ok().map(|stat| stat.size.unwrap_or(0))
+}
+
+struct Download {
+    file: ssh2::File,
+    _conn: Connection,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+/// Stream the remote file's contents
+pub fn open(location: &Location) -> Result<Box<dyn Read>> {
+    let conn = connect(location)?;
+    let file = conn
+        .sftp
+        .open(&location.path)
+        .with_context(|| format!("failed to open {}", location))?;
+    Ok(Box::new(Download { file, _conn: conn }))
+}
+
+/// Writes `<path>.tmp` on the server and renames it over `<path>` on `commit`
+///
+/// Dropped without committing, the temp file is removed.
+pub struct Upload {
+    location: Location,
+    force: bool,
+    writer: Option<BufWriter<ssh2::File>>,
+    conn: Connection,
+    committed: bool,
+}
+
+impl Upload {
+    pub fn create(location: Location, force: bool) -> Result<Self> {
+        let conn = connect(&location)?;
+        let tmp = location.tmp_path();
+        let file = conn
+            .sftp
+            .open_mode(
+                &tmp,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o600,
+                OpenType::File,
+            )
+            .with_context(|| format!("failed to create {}.tmp", location))?;
+        Ok(Self {
+            location,
+            force,
+            writer: Some(BufWriter::with_capacity(WRITE_BUFFER, file)),
+            conn,
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        let writer = self.writer.take().expect("writer is only taken here");
+        let mut file = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("failed to flush upload")?;
+        // Not every server supports fsync@openssh.com
+        let _ = file.fsync();
+        drop(file);
+        let sftp = &self.conn.sftp;
+        // Re-check: the output may have appeared while we were writing
+        if stat_len(sftp, &self.location.path).is_some() {
+            if !self.force {
+                return Err(Failure::OutputExists.error(format!(
+                    "output '{}' already exists (use --force to overwrite)",
+                    self.location
+                )));
+            }
+            // Plain SFTP rename fails on an existing target
+            sftp.unlink(&self.location.path)
+                .with_context(|| format!("failed to replace {}", self.location))?;
+        }
+        sftp.rename(
+            &self.location.tmp_path(),
+            &self.location.path,
+            Some(RenameFlags::ATOMIC | RenameFlags::NATIVE),
+        )
+        .with_context(|| format!("failed to move {}.tmp into place", self.location))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().expect("open until commit").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().expect("open until commit").flush()
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.writer = None;
+            let _ = self.conn.sftp.unlink(&self.location.tmp_path());
+        }
+    }
+}
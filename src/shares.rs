@@ -0,0 +1,309 @@
+//! Shamir secret sharing of an archive's file key, for `encrypt --split-key K-of-N`
+//!
+//! `Recipient` splits the file key into N shares, any K of which rebuild it, and leaves a
+//! `folder-lock-shares` stanza in the header naming the share set and K. Each share goes
+//! to its own small text file for one custodian; `decrypt -i` takes share files like
+//! identity files and combines them (see `keys::read_identities`). Fewer than K shares say
+//! nothing about the key. age itself can't open such archives.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use age::secrecy::zeroize::Zeroizing;
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
+use blahaj::{Share, Sharks};
+
+/// Stanza type of a split file key
+pub const TAG: &str = "folder-lock-shares";
+
+/// Prefix of the line holding a share: `FOLDER-LOCK-SHARE-<set id>-<base64>`
+const SHARE_PREFIX: &str = "FOLDER-LOCK-SHARE-";
+
+const FILE_KEY_BYTES: usize = 16;
+
+/// How many shares to write, and how many of them open the archive
+#[derive(Clone, Copy, Debug)]
+pub struct SplitKey {
+    pub threshold: u8,
+    pub shares: u8,
+}
+
+/// Parse `K-of-N`, e.g. `3-of-5`
+pub fn parse_split_key(s: &str) -> std::result::Result<SplitKey, String> {
+    let (k, n) = s
+        .split_once("-of-")
+        .ok_or_else(|| format!("expected K-of-N (e.g. 3-of-5), got '{}'", s))?;
+    let threshold = k
+        .parse::<u8>()
+        .map_err(|_| format!("invalid share count '{}'", k))?;
+    let shares = n
+        .parse::<u8>()
+        .map_err(|_| format!("invalid share count '{}'", n))?;
+    if threshold < 2 || threshold > shares {
+        return Err(format!(
+            "need 2 <= K <= N (at most 255 shares), got {}-of-{}",
+            threshold, shares
+        ));
+    }
+    Ok(SplitKey { threshold, shares })
+}
+
+/// Where share `i` (counting from 1) of `archive` is written: `<archive>.share<i>`
+pub fn share_path(archive: &Path, i: u8) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(format!(".share{}", i));
+    PathBuf::from(path)
+}
+
+/// Splits the file key when the archive is encrypted; the shares are kept for `Dealt`
+pub struct Recipient {
+    split: SplitKey,
+    set: u64,
+    dealt: Arc<Mutex<Vec<Zeroizing<Vec<u8>>>>>,
+}
+
+impl Recipient {
+    /// The recipient, and the handle its shares can be written through once the archive
+    /// has been encrypted
+    pub fn new(split: SplitKey) -> (Self, Dealt) {
+        let set = rand::random();
+        let dealt = Arc::new(Mutex::new(Vec::new()));
+        let recipient = Self {
+            split,
+            set,
+            dealt: dealt.clone(),
+        };
+        (recipient, Dealt { split, set, dealt })
+    }
+}
+
+impl age::Recipient for Recipient {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, age::EncryptError> {
+        let dealer = Sharks(self.split.threshold).dealer(file_key.expose_secret());
+        let shares = dealer
+            .take(self.split.shares as usize)
+            .map(|share| Zeroizing::new(Vec::from(&share)))
+            .collect();
+        *self.dealt.lock().expect("no other holder panics") = shares;
+        Ok(vec![Stanza {
+            tag: TAG.to_owned(),
+            args: vec![
+                format!("{:016x}", self.set),
+                self.split.threshold.to_string(),
+            ],
+            body: Vec::new(),
+        }])
+    }
+}
+
+/// Shares dealt by a `Recipient`
+pub struct Dealt {
+    split: SplitKey,
+    set: u64,
+    dealt: Arc<Mutex<Vec<Zeroizing<Vec<u8>>>>>,
+}
+
+impl Dealt {
+    /// Write every share to its `share_path`, readable by the owner only; returns the
+    /// paths in share order
+    pub fn write(&self, archive: &Path, force: bool) -> Result<Vec<PathBuf>> {
+        let dealt = self.dealt.lock().expect("no other holder panics");
+        if dealt.is_empty() {
+            anyhow::bail!("the archive key was never split");
+        }
+        let name = archive.file_name().unwrap_or_default().to_string_lossy();
+        let mut paths = Vec::new();
+        for (i, share) in (1..).zip(dealt.iter()) {
+            let path = share_path(archive, i);
+            let mut options = OpenOptions::new();
+            options.write(true);
+            if force {
+                options.create(true).truncate(true);
+            } else {
+                options.create_new(true);
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut f = options
+                .open(&path)
+                .with_context(|| format!("failed to create share file {}", path.display()))?;
+            writeln!(
+                f,
+                "# folder-lock key share {} of {} for {}; any {} of them open it",
+                i, self.split.shares, name, self.split.threshold
+            )?;
+            writeln!(
+                f,
+                "{}{:016x}-{}",
+                SHARE_PREFIX,
+                self.set,
+                BASE64_STANDARD_NO_PAD.encode(share.as_slice())
+            )?;
+            f.sync_all()
+                .with_context(|| format!("failed to write share file {}", path.display()))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// Whether `contents` (an `-i` file) is a share file rather than an identity file
+pub fn is_share_file(contents: &str) -> bool {
+    contents
+        .lines()
+        .any(|line| line.trim().starts_with(SHARE_PREFIX))
+}
+
+/// Rebuilds a split file key from the shares given with `-i`
+#[derive(Default)]
+pub struct Identity {
+    shares: Vec<(String, Zeroizing<Vec<u8>>)>,
+}
+
+impl Identity {
+    /// Add the share in a share file's `contents`
+    pub fn add(&mut self, contents: &str) -> Result<()> {
+        let line = contents
+            .lines()
+            .map(str::trim)
+            .find_map(|line| line.strip_prefix(SHARE_PREFIX))
+            .context("no share in file")?;
+        let (set, share) = line.split_once('-').context("malformed share")?;
+        let share = BASE64_STANDARD_NO_PAD
+            .decode(share)
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow::anyhow!("malformed share"))?;
+        Share::try_from(share.as_slice()).map_err(|e| anyhow::anyhow!("malformed share: {}", e))?;
+        self.shares.push((set.to_owned(), share));
+        Ok(())
+    }
+}
+
+impl age::Identity for Identity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        if stanza.tag != TAG {
+            return None;
+        }
+        let [set, threshold] = &stanza.args[..] else {
+            return Some(Err(age::DecryptError::InvalidHeader));
+        };
+        let Ok(threshold) = threshold.parse::<u8>() else {
+            return Some(Err(age::DecryptError::InvalidHeader));
+        };
+        let shares = self
+            .shares
+            .iter()
+            .filter(|(s, _)| s == set)
+            .filter_map(|(_, share)| Share::try_from(share.as_slice()).ok())
+            .collect::<Vec<_>>();
+        if shares.len() < threshold as usize {
+            log::warn!(
+                "the archive needs {} key shares, {} of the given ones belong to it",
+                threshold,
+                shares.len()
+            );
+            return None;
+        }
+        let key = match Sharks(threshold).recover(&shares) {
+            Ok(key) => Zeroizing::new(key),
+            Err(_) => return Some(Err(age::DecryptError::DecryptionFailed)),
+        };
+        let Ok(file_key) = <[u8; FILE_KEY_BYTES]>::try_from(key.as_slice()) else {
+            return Some(Err(age::DecryptError::DecryptionFailed));
+        };
+        Some(Ok(file_key.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split a known key `k`-of-`n`, returning it, the stanza and each share file's contents
+    fn deal(k: u8, n: u8) -> ([u8; FILE_KEY_BYTES], Stanza, Vec<String>) {
+        let key = [7u8; FILE_KEY_BYTES];
+        let split = parse_split_key(&format!("{}-of-{}", k, n)).unwrap();
+        let (recipient, dealt) = Recipient::new(split);
+        let mut stanzas = age::Recipient::wrap_file_key(&recipient, &key.into()).unwrap();
+        let files = dealt
+            .dealt
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|share| {
+                let share = BASE64_STANDARD_NO_PAD.encode(share.as_slice());
+                format!("# a share\n{}{:016x}-{}\n", SHARE_PREFIX, dealt.set, share)
+            })
+            .collect();
+        (key, stanzas.remove(0), files)
+    }
+
+    fn open(stanza: &Stanza, files: &[&String]) -> Option<Result<FileKey, age::DecryptError>> {
+        let mut identity = Identity::default();
+        for contents in files {
+            assert!(is_share_file(contents));
+            identity.add(contents).unwrap();
+        }
+        age::Identity::unwrap_stanza(&identity, stanza)
+    }
+
+    /// The shares picked by the set bits of `mask`
+    fn pick(files: &[String], mask: u32) -> Vec<&String> {
+        (0..files.len())
+            .filter(|i| mask & (1 << i) != 0)
+            .map(|i| &files[i])
+            .collect()
+    }
+
+    #[test]
+    fn any_k_shares_open() {
+        for (k, n) in [(2, 2), (2, 3), (3, 5), (5, 5)] {
+            let (key, stanza, files) = deal(k, n);
+            for mask in (0u32..1 << n).filter(|mask| mask.count_ones() >= k as u32) {
+                let file_key = open(&stanza, &pick(&files, mask)).unwrap().unwrap();
+                assert_eq!(
+                    file_key.expose_secret(),
+                    &key,
+                    "{}-of-{}, shares {:b}",
+                    k,
+                    n,
+                    mask
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_than_k_shares_fail() {
+        for (k, n) in [(2, 3), (3, 5), (5, 5)] {
+            let (_, stanza, files) = deal(k, n);
+            for mask in (0u32..1 << n).filter(|mask| mask.count_ones() < k as u32) {
+                assert!(open(&stanza, &pick(&files, mask)).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn shares_of_another_set_dont_count() {
+        let (_, stanza, files) = deal(2, 3);
+        let (_, _, others) = deal(2, 3);
+        assert!(open(&stanza, &[&files[0], &others[1]]).is_none());
+    }
+
+    #[test]
+    fn split_key_bounds() {
+        let split = parse_split_key("3-of-5").unwrap();
+        assert_eq!((split.threshold, split.shares), (3, 5));
+        for bad in ["1-of-3", "4-of-3", "3of5", "2-of-256"] {
+            assert!(parse_split_key(bad).is_err(), "{}", bad);
+        }
+    }
+}
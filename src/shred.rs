@@ -0,0 +1,124 @@
+//! Overwriting file contents before they are unlinked, for `lock --shred`
+//!
+//! This is best effort. Copy-on-write file systems (btrfs, ZFS, APFS) write the new bytes
+//! elsewhere and snapshots keep old blocks, and SSDs remap writes internally, so the
+//! original data may survive on those; a warning is logged when the file system is
+//! known to be copy-on-write. Full-disk encryption is the reliable protection.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+
+/// Random data is written in chunks of this size
+const CHUNK: usize = 1024 * 1024;
+
+/// Overwrite every regular file under `root` with random data, then remove the tree
+///
+/// Files with other hard links are only unlinked: their data is still reachable through
+/// the other names, which may lie outside `root`. Returns the number of files shredded.
+pub fn shred_tree(root: &Path) -> Result<u64> {
+    if is_copy_on_write(root) {
+        log::warn!(
+            "'{}' is on a copy-on-write file system; overwritten data may survive elsewhere",
+            root.display()
+        );
+    }
+    let mut shredded = 0;
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to read {}", dir.display()))?;
+            let path = entry.path();
+            let meta = std::fs::symlink_metadata(&path)
+                .with_context(|| format!("failed to stat {}", path.display()))?;
+            if meta.is_dir() {
+                pending.push(path);
+            } else if meta.is_file() && link_count(&meta) == 1 {
+                overwrite(&path, meta.len())
+                    .with_context(|| format!("failed to overwrite {}", path.display()))?;
+                shredded += 1;
+            } else if meta.is_file() {
+                log::warn!(
+                    "'{}' has other hard links; unlinking without overwriting",
+                    path.display()
+                );
+            }
+        }
+    }
+    std::fs::remove_dir_all(root)
+        .with_context(|| format!("failed to remove {}", root.display()))?;
+    Ok(shredded)
+}
+
+/// One pass of random data over the whole file, synced to disk, then truncated
+fn overwrite(path: &Path, len: u64) -> std::io::Result<()> {
+    let mut f = OpenOptions::new().write(true).open(path)?;
+    let mut buf = vec![0u8; CHUNK];
+    let mut rng = rand::thread_rng();
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        rng.fill_bytes(&mut buf[..n]);
+        f.write_all(&buf[..n])?;
+        left -= n as u64;
+    }
+    f.sync_all()?;
+    // Drop the size too, so the file's length no longer hints at what it held
+    f.set_len(0)?;
+    f.sync_all()
+}
+
+#[cfg(unix)]
+fn link_count(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_meta: &std::fs::Metadata) -> u64 {
+    1
+}
+
+/// Whether `path` lives on btrfs or ZFS (Linux) or APFS (macOS)
+#[cfg(target_os = "linux")]
+fn is_copy_on_write(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+    const ZFS_SUPER_MAGIC: u32 = 0x2fc1_2fc1;
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `fs` is a valid out-pointer
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut fs) } != 0 {
+        return false;
+    }
+    // `f_type`'s width differs between libcs; the magic numbers fit in 32 bits
+    matches!(fs.f_type as u32, BTRFS_SUPER_MAGIC | ZFS_SUPER_MAGIC)
+}
+
+#[cfg(target_os = "macos")]
+fn is_copy_on_write(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `fs` is a valid out-pointer
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut fs) } != 0 {
+        return false;
+    }
+    // SAFETY: the kernel fills `f_fstypename` with a NUL-terminated name
+    let name = unsafe { std::ffi::CStr::from_ptr(fs.f_fstypename.as_ptr()) };
+    name.to_bytes() == b"apfs"
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_copy_on_write(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,81 @@
+//! Detached minisign (Ed25519) signatures of encrypted archives
+//!
+//! The signature covers the ciphertext as stored, so it can be checked without the
+//! passphrase or identity, before anything is decrypted: it shows who made the archive,
+//! where age alone only shows that whoever encrypted it knew the recipients. Signatures
+//! are written next to the archive as `<archive>.minisig`, the layout `minisign -V`
+//! expects, and keys are minisign's own (`minisign -G`).
+
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use minisign::{PublicKey, SecretKey, SignatureBox};
+
+use crate::failure::Failure;
+use crate::streams::{self, AtomicFile};
+
+/// Where the signature of `archive` is kept
+pub fn signature_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".minisig");
+    PathBuf::from(path)
+}
+
+/// Sign the file `archive` with the minisign secret key in `key_file`, asking for the key's
+/// password if it has one; returns the signature's path
+///
+/// An existing signature is only replaced with `force`, and never left half written.
+pub fn sign(archive: &Path, key_file: &Path, force: bool) -> Result<PathBuf> {
+    let sk = SecretKey::from_file(key_file, None)
+        .with_context(|| format!("failed to read signing key {}", key_file.display()))?;
+    let data =
+        File::open(archive).with_context(|| format!("failed to open {}", archive.display()))?;
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    let trusted = format!("file:{}", name);
+    let signature = minisign::sign(None, &sk, BufReader::new(data), Some(&trusted), None)
+        .with_context(|| format!("failed to sign {}", archive.display()))?;
+    let path = signature_path(archive);
+    streams::check_output(&path, force)?;
+    let mut f = AtomicFile::create(&path, force)?;
+    f.write_all(signature.into_string().as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    f.commit()?;
+    Ok(path)
+}
+
+/// A minisign public key, given inline (`RWQ…`) or as a `minisign.pub` file
+pub fn public_key(key: &str) -> Result<PublicKey> {
+    if Path::new(key).is_file() {
+        return PublicKey::from_file(key)
+            .with_context(|| format!("failed to read public key {}", key));
+    }
+    PublicKey::from_base64(key).context("invalid minisign public key (expected RWQ… or a file)")
+}
+
+/// Check `archive` against its `.minisig` file and `public_key`
+///
+/// A missing or non-matching signature is a `Failure::Corrupted`: the archive can't be
+/// trusted to be the one that was signed.
+pub fn verify(archive: &Path, public_key: &PublicKey) -> Result<()> {
+    let path = signature_path(archive);
+    if !path.is_file() {
+        return Err(Failure::Corrupted.error(format!("no signature found at {}", path.display())));
+    }
+    let signature = SignatureBox::from_file(&path)
+        .with_context(|| format!("failed to read signature {}", path.display()))?;
+    let data =
+        File::open(archive).with_context(|| format!("failed to open {}", archive.display()))?;
+    minisign::verify(
+        public_key,
+        &signature,
+        BufReader::new(data),
+        true,
+        false,
+        false,
+    )
+    .map_err(|e| {
+        Failure::Corrupted.error(format!("bad signature for {}: {}", archive.display(), e))
+    })
+}
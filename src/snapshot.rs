@@ -0,0 +1,174 @@
+//! Snapshots of the archived tree, used to build and restore incremental archives
+//!
+//! An incremental archive only holds entries that changed since its base, plus a
+//! snapshot of the whole tree at `SNAPSHOT_PATH` listing what exists now and what was
+//! deleted. Restoring the base and then each increment in order reproduces the tree.
+
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{self, Manifest};
+use crate::streams::{self, AtomicFile};
+
+/// Directory inside the archive reserved for folder_lock's own metadata
+pub const META_DIR: &str = ".folder-lock";
+
+/// Archive path of the snapshot embedded in incremental archives
+pub const SNAPSHOT_PATH: &str = ".folder-lock/snapshot.json";
+
+/// What is compared to decide whether an entry changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// Content size for regular files, 0 for everything else
+    pub size: u64,
+    /// Modification time in whole seconds since the Unix epoch
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_dir: bool,
+}
+
+impl FileState {
+    /// State of a file about to be archived with a header carrying `mtime`
+    ///
+    /// The header's mtime is used rather than the file's, so `--reproducible` clamping
+    /// compares equal to what a base archive recorded.
+    pub fn new(meta: &Metadata, mtime: u64) -> Self {
+        Self {
+            size: if meta.is_file() { meta.len() } else { 0 },
+            mtime,
+            is_dir: meta.is_dir(),
+        }
+    }
+
+    /// State of a file as it is on disk now
+    pub fn on_disk(meta: &Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        Self::new(meta, mtime)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Every archived entry keyed by its archive path (`docs/a.txt`)
+    pub files: BTreeMap<String, FileState>,
+    /// Paths present in the base but gone since; only set in increments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
+}
+
+impl Snapshot {
+    /// Read a snapshot file written by `encrypt --write-snapshot`
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = File::open(path)
+            .with_context(|| format!("failed to open snapshot {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("failed to parse snapshot {}", path.display()))
+    }
+
+    /// Write the snapshot to `path`, replacing an existing file only with `force`
+    ///
+    /// The file is written beside `path` and renamed into place, so a failed run leaves any
+    /// earlier snapshot intact.
+    pub fn save(&self, path: &Path, force: bool) -> Result<()> {
+        streams::check_output(path, force)?;
+        let mut f = AtomicFile::create(path, force)?;
+        serde_json::to_writer_pretty(&mut f, self)
+            .with_context(|| format!("failed to write snapshot {}", path.display()))?;
+        f.flush()
+            .with_context(|| format!("failed to write snapshot {}", path.display()))?;
+        f.commit()
+    }
+
+    /// The state recorded by an existing archive
+    ///
+    /// Increments carry their snapshot; for other archives it is rebuilt from the headers.
+    pub fn from_archive<R: Read>(archive: &mut tar::Archive<R>) -> Result<Self> {
+        Ok(Self::with_manifest(archive)?.0)
+    }
+
+    /// Like `from_archive`, also returning the archive's checksum manifest if it has one
+    pub fn with_manifest<R: Read>(
+        archive: &mut tar::Archive<R>,
+    ) -> Result<(Self, Option<Manifest>)> {
+        let mut snapshot = Snapshot::default();
+        let mut manifest = None;
+        for entry in archive
+            .entries()
+            .context("failed to read archive entries")?
+        {
+            let mut entry = entry.context("failed to read archive entry")?;
+            let name = key(&entry.path().context("invalid path in archive")?);
+            if name == SNAPSHOT_PATH {
+                // Written after the manifest, so nothing of interest follows it
+                let mut embedded: Snapshot = serde_json::from_reader(&mut entry)
+                    .context("failed to parse the archive's snapshot")?;
+                embedded.deleted.clear();
+                return Ok((embedded, manifest));
+            }
+            if name == checksum::MANIFEST_PATH {
+                manifest = Some(
+                    Manifest::parse(&mut entry).context("failed to parse the checksum manifest")?,
+                );
+                continue;
+            }
+            if name.is_empty() || is_metadata(&name) {
+                continue;
+            }
+            let header = entry.header();
+            let kind = header.entry_type();
+            let mut state = FileState {
+                size: if kind.is_file() || kind.is_gnu_sparse() {
+                    entry.size()
+                } else {
+                    0
+                },
+                mtime: header.mtime().unwrap_or(0),
+                is_dir: kind.is_dir(),
+            };
+            if kind.is_hard_link() {
+                // The link shares its data with an earlier entry, so record that one's size
+                if let Some(target) = entry.link_name()? {
+                    if let Some(first) = snapshot.files.get(&key(&target)) {
+                        state.size = first.size;
+                    }
+                }
+            }
+            snapshot.files.insert(name, state);
+        }
+        Ok((snapshot, manifest))
+    }
+
+    /// Whether `rel` must go into an increment on top of this snapshot
+    pub fn has_changed(&self, rel: &str, state: &FileState) -> bool {
+        self.files.get(rel) != Some(state)
+    }
+}
+
+/// Snapshot key for an archive path: `./` components dropped, `/` separators
+pub fn key(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether the archive path `key` is folder_lock's own: `META_DIR` or anything in it
+///
+/// Only the first component counts, so `.folder-lock-old/` and `docs/.folder-lock/` are
+/// ordinary entries; packing refuses a source whose own top level has a `META_DIR`.
+pub fn is_metadata(key: &str) -> bool {
+    key.split('/').next() == Some(META_DIR)
+}
@@ -0,0 +1,106 @@
+//! Unix sockets that only the user who created them can talk to, for `agent` and `daemon`
+//!
+//! A socket is bound under a 0177 umask, so it is never reachable by others even for a
+//! moment, and both ends check the other's uid (`SO_PEERCRED`, or `getpeereid` on macOS
+//! and the BSDs) against their own effective uid. Without `$XDG_RUNTIME_DIR`, sockets go
+//! in a per-user 0700 directory under the temp directory rather than in it directly.
+
+use std::fs::DirBuilder;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// The directory for this user's sockets: `$XDG_RUNTIME_DIR`, else
+/// `$TMPDIR/folder_lock-<uid>`, created with 0700 permissions
+pub fn runtime_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    let uid = euid();
+    let dir = std::env::temp_dir().join(format!("folder_lock-{}", uid));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to create {}", dir.display())),
+    }
+    // Someone else may have made it first, to watch or replace what goes in it
+    let meta = std::fs::symlink_metadata(&dir)
+        .with_context(|| format!("failed to inspect {}", dir.display()))?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        anyhow::bail!(
+            "{} must be a directory of yours that only you can access",
+            dir.display()
+        );
+    }
+    Ok(dir)
+}
+
+/// Listen on `socket`, replacing a stale one but not one something still answers on
+///
+/// `what` names the server in the error for a live socket (`an agent`).
+pub fn listen(socket: &Path, what: &str) -> Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            anyhow::bail!("{} is already listening on {}", what, socket.display());
+        }
+        // Left behind by a server that didn't exit cleanly
+        std::fs::remove_file(socket)
+            .with_context(|| format!("failed to remove stale socket {}", socket.display()))?;
+    }
+    // SAFETY: umask only swaps the process's file mode mask
+    let old = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    // SAFETY: as above
+    unsafe { libc::umask(old) };
+    listener.with_context(|| format!("failed to listen on {}", socket.display()))
+}
+
+/// Whether the process at the other end of `stream` runs as this process's user
+pub fn peer_is_self(stream: &UnixStream) -> bool {
+    match peer_uid(stream) {
+        Ok(uid) => uid == euid(),
+        Err(e) => {
+            log::debug!("failed to get the socket peer's uid: {}", e);
+            false
+        }
+    }
+}
+
+fn euid() -> u32 {
+    // SAFETY: geteuid has no preconditions
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid out-pointers of the sizes given
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `uid` and `gid` are valid out-pointers
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
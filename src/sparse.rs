@@ -0,0 +1,254 @@
+//! Detecting holes in sparse files and storing them as GNU sparse tar entries
+//!
+//! Restoring needs nothing extra: `tar` seeks over the holes of GNU sparse entries when
+//! unpacking, so extracted files stay sparse.
+
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use tar::{Builder, EntryType, GnuExtSparseHeader, GnuSparseHeader, Header};
+
+const BLOCK: u64 = 512;
+/// Largest value an 11-digit octal header field can hold; bigger files are stored dense
+const MAX_OCTAL: u64 = 0o77777777777;
+/// Sparse map slots in the main GNU header and in each extension header
+const HEADER_SLOTS: usize = 4;
+const EXT_SLOTS: usize = 21;
+
+/// Data regions `(offset, length)` of `file`, or `None` if it has no holes worth recording
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+))]
+pub fn data_regions(file: &File, meta: &Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let len = meta.len();
+    // Fully allocated files can't have holes, which skips the lseek calls for most files
+    if len == 0 || len > MAX_OCTAL || meta.blocks() * BLOCK >= len {
+        return Ok(None);
+    }
+
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut pos = 0i64;
+    while (pos as u64) < len {
+        // SAFETY: plain lseek on a descriptor we own for the duration of the call
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // ENXIO: no data after `pos`, the rest of the file is a hole
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        // SAFETY: as above
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        regions.push((data as u64, (hole - data) as u64));
+        pos = hole;
+    }
+    Ok(Some(regions))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+)))]
+pub fn data_regions(_file: &File, _meta: &Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Append `file` as a GNU sparse entry holding only `regions`
+///
+/// Returns `Ok(false)` without writing anything when the entry can't be represented this
+/// way (long path, unaligned regions), so the caller can fall back to a regular entry.
+pub fn append<W: Write>(
+    tar: &mut Builder<W>,
+    mut header: Header,
+    rel: &Path,
+    file: &mut File,
+    len: u64,
+    regions: &[(u64, u64)],
+) -> io::Result<bool> {
+    // Every region but the last must fill whole blocks for readers to locate the next one
+    let aligned = regions.iter().rev().skip(1).all(|(_, n)| n % BLOCK == 0);
+    if !aligned || header.set_path(rel).is_err() {
+        return Ok(false);
+    }
+
+    // A trailing zero-length region marks the logical end when the file ends in a hole
+    let mut map = regions.to_vec();
+    if map.last().map_or(true, |(off, n)| off + n < len) {
+        map.push((len, 0));
+    }
+    let stored: u64 = regions.iter().map(|(_, n)| n).sum();
+
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(stored);
+    {
+        let gnu = header
+            .as_gnu_mut()
+            .ok_or_else(|| io::Error::other("not a GNU header"))?;
+        octal(&mut gnu.realsize, len);
+        for (slot, region) in gnu.sparse.iter_mut().zip(&map) {
+            set_region(slot, *region);
+        }
+        gnu.isextended[0] = (map.len() > HEADER_SLOTS) as u8;
+    }
+    header.set_cksum();
+
+    let w = tar.get_mut();
+    w.write_all(header.as_bytes())?;
+    let extra: Vec<_> = map.iter().skip(HEADER_SLOTS).collect();
+    let chunks: Vec<_> = extra.chunks(EXT_SLOTS).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut ext = GnuExtSparseHeader::new();
+        for (slot, region) in ext.sparse.iter_mut().zip(chunk.iter()) {
+            set_region(slot, **region);
+        }
+        ext.isextended[0] = (i + 1 < chunks.len()) as u8;
+        w.write_all(ext.as_bytes())?;
+    }
+
+    for &(off, n) in regions {
+        file.seek(SeekFrom::Start(off))?;
+        let copied = io::copy(&mut file.by_ref().take(n), w)?;
+        if copied != n {
+            return Err(io::Error::other("file shrank while it was being archived"));
+        }
+    }
+    let pad = (BLOCK - stored % BLOCK) % BLOCK;
+    w.write_all(&vec![0; pad as usize])?;
+    Ok(true)
+}
+
+fn set_region(slot: &mut GnuSparseHeader, (offset, len): (u64, u64)) {
+    octal(&mut slot.offset, offset);
+    octal(&mut slot.numbytes, len);
+}
+
+/// Zero-padded octal with a trailing NUL, the encoding tar uses for numeric fields
+fn octal(dst: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = dst.len() - 1);
+    dst[..digits.len()].copy_from_slice(digits.as_bytes());
+    dst[digits.len()] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    /// A `len`-byte file in the temp directory holding only `data`, and its path
+    fn sparse_file(len: u64, data: &[(u64, &[u8])]) -> (File, std::path::PathBuf) {
+        let name = format!("folder_lock-sparse-{:016x}", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        let mut f = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        f.set_len(len).unwrap();
+        for (offset, bytes) in data {
+            f.seek(SeekFrom::Start(*offset)).unwrap();
+            f.write_all(bytes).unwrap();
+        }
+        f.sync_all().unwrap();
+        (f, path)
+    }
+
+    /// Archive `file` as a sparse entry, then read it back the way `tar` unpacks it
+    fn round_trip(file: &mut File, len: u64, regions: &[(u64, u64)]) -> Option<Vec<u8>> {
+        let mut tar = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        if !append(
+            &mut tar,
+            header,
+            Path::new("sparse.bin"),
+            file,
+            len,
+            regions,
+        )
+        .unwrap()
+        {
+            assert!(
+                tar.get_ref().is_empty(),
+                "nothing is written for a fallback"
+            );
+            return None;
+        }
+        let bytes = tar.into_inner().unwrap();
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.header().entry_type(), EntryType::GNUSparse);
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        Some(contents)
+    }
+
+    #[test]
+    fn holes_at_both_ends_restore_as_zeros() {
+        let data = vec![0xa5; 1000];
+        let (mut f, path) = sparse_file(3 * MIB, &[(MIB, &data)]);
+        let contents = round_trip(&mut f, 3 * MIB, &[(MIB, 1000)]);
+        let expected = std::fs::read(&path).unwrap();
+        drop(f);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.unwrap(), expected);
+    }
+
+    #[test]
+    fn regions_beyond_the_header_go_in_extension_headers() {
+        let data = [7u8; 512];
+        let regions: Vec<(u64, u64)> = (1..30).map(|i| (i * 8192, 512)).collect();
+        let writes: Vec<(u64, &[u8])> = regions.iter().map(|&(off, _)| (off, &data[..])).collect();
+        let (mut f, path) = sparse_file(MIB, &writes);
+        let contents = round_trip(&mut f, MIB, &regions);
+        let expected = std::fs::read(&path).unwrap();
+        drop(f);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.unwrap(), expected);
+    }
+
+    #[test]
+    fn unaligned_middle_regions_fall_back() {
+        let (mut f, path) = sparse_file(MIB, &[(0, b"head"), (MIB / 2, b"middle")]);
+        let contents = round_trip(&mut f, MIB, &[(0, 4), (MIB / 2, 6)]);
+        drop(f);
+        std::fs::remove_file(&path).unwrap();
+        assert!(contents.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn data_regions_find_the_data() {
+        let data = vec![0x5a; 1000];
+        let (mut f, path) = sparse_file(3 * MIB, &[(MIB + 100, &data)]);
+        let meta = f.metadata().unwrap();
+        let regions = data_regions(&f, &meta);
+        let expected = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // File systems without holes store the file densely, which is fine
+        let Some(regions) = regions.unwrap() else {
+            return;
+        };
+        let covered = |pos: u64| regions.iter().any(|&(off, n)| off <= pos && pos < off + n);
+        assert!(covered(MIB + 100) && covered(MIB + 1099));
+        assert!(!covered(0) && !covered(3 * MIB - 1));
+        assert_eq!(round_trip(&mut f, 3 * MIB, &regions).unwrap(), expected);
+    }
+}
@@ -0,0 +1,776 @@
+//! `-` as a path means stdin/stdout, so the tool composes with pipes
+//!
+//! Archives can also be split into numbered volumes (`backup.age.001`, `.002`, …); naming
+//! the `.001` volume as input reads all of them back as one stream. With the `s3` and `sftp`
+//! features, `s3://bucket/key` and `sftp://[user@]host/path` work as input and output too.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+use anyhow::{Context, Result};
+
+use crate::failure::{Classify, Failure};
+#[cfg(feature = "s3")]
+use crate::s3;
+#[cfg(feature = "sftp")]
+use crate::sftp;
+
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// URL schemes for remote archives, with the feature each needs
+const SCHEMES: [(&str, &str); 2] = [("s3://", "s3"), ("sftp://", "sftp")];
+
+/// Whether `path` is a remote URL (`s3://`, `sftp://`) rather than a local path
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| SCHEMES.iter().any(|(scheme, _)| s.starts_with(scheme)))
+}
+
+/// An archive location on another machine
+enum Remote {
+    #[cfg(feature = "s3")]
+    S3(s3::Location),
+    #[cfg(feature = "sftp")]
+    Sftp(sftp::Location),
+}
+
+/// The remote location `path` names, if it is a URL
+///
+/// A URL whose scheme this build lacks the feature for is an error rather than a strange
+/// local path.
+fn remote(path: &Path) -> Result<Option<Remote>> {
+    #[cfg(feature = "s3")]
+    if let Some(location) = s3::Location::parse(path)? {
+        return Ok(Some(Remote::S3(location)));
+    }
+    #[cfg(feature = "sftp")]
+    if let Some(location) = sftp::Location::parse(path)? {
+        return Ok(Some(Remote::Sftp(location)));
+    }
+    if let Some(s) = path.to_str() {
+        for (scheme, feature) in SCHEMES {
+            if s.starts_with(scheme) {
+                anyhow::bail!(
+                    "'{}' needs {} support, which this build lacks (feature `{}`)",
+                    path.display(),
+                    scheme.trim_end_matches("://"),
+                    feature
+                );
+            }
+        }
+    }
+    Ok(None)
+}
+
+impl Remote {
+    // Matching on `*self` keeps the match exhaustive when no remote feature is enabled
+    fn open(&self) -> Result<Box<dyn Read>> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Remote::S3(ref location) => s3::open(location),
+            #[cfg(feature = "sftp")]
+            Remote::Sftp(ref location) => sftp::open(location),
+        }
+    }
+
+    /// Size of the archive there, or `None` if there is none
+    fn len(&self) -> Result<Option<u64>> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Remote::S3(ref location) => s3::object_len(location),
+            #[cfg(feature = "sftp")]
+            Remote::Sftp(ref location) => sftp::file_len(location),
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "s3", feature = "sftp")), allow(unused_variables))]
+    fn create(self, force: bool) -> Result<Output> {
+        match self {
+            #[cfg(feature = "s3")]
+            Remote::S3(location) => Ok(Output::S3(s3::Upload::create(location, force))),
+            #[cfg(feature = "sftp")]
+            Remote::Sftp(location) => Ok(Output::Sftp(sftp::Upload::create(location, force)?)),
+        }
+    }
+}
+
+/// Parse a size like `2G`, `500M`, `64k` or `1048576` (binary units) for `--split-size`
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}' (expected e.g. 2G or 500M)", s))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let shift = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown size unit '{}' (use K, M, G or T)", unit)),
+    };
+    let size = number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", s))?;
+    if size == 0 {
+        return Err("size must be greater than zero".to_string());
+    }
+    Ok(size)
+}
+
+/// Path of volume `n` (1-based) of a split archive: `backup.age` → `backup.age.001`
+pub fn volume_path(base: &Path, n: usize) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{:03}", n));
+    PathBuf::from(path)
+}
+
+/// All volumes of a split archive if `path` names its first one (`*.001`), in order
+fn volumes(path: &Path) -> Option<Vec<PathBuf>> {
+    let base = path.to_str()?.strip_suffix(".001")?;
+    let base = Path::new(base);
+    let parts = (1..)
+        .map(|n| volume_path(base, n))
+        .take_while(|part| part.is_file())
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then_some(parts)
+}
+
+/// Open `path` for reading, or stdin for `-`
+///
+/// A path ending in `.001` is read together with the `.002`, `.003`, … volumes after it.
+pub fn open_input(path: &Path) -> Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdin()));
+    }
+    if let Some(remote) = remote(path)? {
+        return remote.open();
+    }
+    if let Some(parts) = volumes(path) {
+        log::debug!(
+            "reading {} volumes starting at {}",
+            parts.len(),
+            path.display()
+        );
+        let files = parts
+            .iter()
+            .map(|part| open_file(part, "volume"))
+            .collect::<Result<_>>()?;
+        return Ok(Box::new(Volumes { files }));
+    }
+    Ok(Box::new(open_file(path, "input file")?))
+}
+
+/// Open a local input, marking a missing one as `Failure::SourceMissing`
+pub(crate) fn open_file(path: &Path, what: &str) -> Result<File> {
+    match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e).classify(
+            Failure::SourceMissing,
+            format!("{} {} does not exist", what, path.display()),
+        ),
+        result => result.with_context(|| format!("failed to open {} {}", what, path.display())),
+    }
+}
+
+/// Size of the input if it is a regular file (unknown for stdin and pipes)
+///
+/// For a split archive this is the total of all volumes.
+pub fn input_len(path: &Path) -> Option<u64> {
+    if is_stdio(path) {
+        return None;
+    }
+    if let Ok(Some(remote)) = remote(path) {
+        return remote.len().ok().flatten();
+    }
+    if let Some(parts) = volumes(path) {
+        return parts
+            .iter()
+            .map(|part| std::fs::metadata(part).ok().map(|m| m.len()))
+            .sum();
+    }
+    std::fs::metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+}
+
+/// Reads the volumes of a split archive back to back
+struct Volumes {
+    files: VecDeque<File>,
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(file) = self.files.front_mut() {
+            let n = file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.files.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+/// Fail early if `path` exists and may not be replaced, before any prompt or work
+pub fn check_output(path: &Path, force: bool) -> Result<()> {
+    if let Some(remote) = remote(path)? {
+        if !force && remote.len()?.is_some() {
+            return Err(Failure::OutputExists.error(format!(
+                "output '{}' already exists (use --force to overwrite)",
+                path.display()
+            )));
+        }
+        return Ok(());
+    }
+    if !force && !is_stdio(path) && path.symlink_metadata().is_ok() {
+        return Err(Failure::OutputExists.error(format!(
+            "output '{}' already exists (use --force to overwrite)",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Like `check_output`, for the volumes a split archive at `base` would occupy
+pub fn check_split_output(base: &Path, force: bool) -> Result<()> {
+    if is_stdio(base) {
+        anyhow::bail!("--split-size needs an output file, not stdout");
+    }
+    if is_remote(base) {
+        anyhow::bail!("--split-size needs a local output file, not a URL");
+    }
+    check_output(&volume_path(base, 1), force)
+}
+
+/// Destination of an encrypted archive: stdout, temp files renamed into place, or a remote
+pub enum Output {
+    Stdout(BufWriter<io::StdoutLock<'static>>),
+    File(AtomicFile),
+    Split(SplitFile),
+    #[cfg(feature = "s3")]
+    S3(s3::Upload),
+    #[cfg(feature = "sftp")]
+    Sftp(sftp::Upload),
+}
+
+impl Output {
+    /// Flush everything and, for files, move the finished archive into place
+    pub fn commit(self) -> Result<()> {
+        match self {
+            Output::Stdout(mut w) => w.flush().context("failed to flush stdout"),
+            Output::File(f) => f.commit(),
+            Output::Split(f) => f.commit(),
+            #[cfg(feature = "s3")]
+            Output::S3(u) => u.commit(),
+            #[cfg(feature = "sftp")]
+            Output::Sftp(u) => u.commit(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(w) => w.write(buf),
+            Output::File(f) => f.writer.write(buf),
+            Output::Split(f) => f.write(buf),
+            #[cfg(feature = "s3")]
+            Output::S3(u) => u.write(buf),
+            #[cfg(feature = "sftp")]
+            Output::Sftp(u) => u.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(w) => w.flush(),
+            Output::File(f) => f.writer.flush(),
+            Output::Split(f) => f.flush(),
+            #[cfg(feature = "s3")]
+            Output::S3(u) => u.flush(),
+            #[cfg(feature = "sftp")]
+            Output::Sftp(u) => u.flush(),
+        }
+    }
+}
+
+/// Writes to a new temporary file next to the target and renames over it on `commit`
+///
+/// If dropped without committing (an error or panic mid-write) the temp file is removed,
+/// so a truncated archive never appears under the real name.
+pub struct AtomicFile {
+    writer: BufWriter<File>,
+    tmp: PathBuf,
+    path: PathBuf,
+    force: bool,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path, force: bool) -> Result<Self> {
+        let (f, tmp) = create_temp(path).with_context(|| {
+            format!(
+                "failed to create a temporary file beside {}",
+                path.display()
+            )
+        })?;
+        Ok(Self {
+            writer: BufWriter::new(f),
+            tmp,
+            path: path.to_path_buf(),
+            force,
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.writer.flush().context("failed to flush output file")?;
+        self.writer
+            .get_ref()
+            .sync_all()
+            .context("failed to sync output file")?;
+        // Re-check: the output may have appeared while we were writing
+        check_output(&self.path, self.force)?;
+        std::fs::rename(&self.tmp, &self.path).with_context(|| {
+            format!(
+                "failed to move {} into place as {}",
+                self.tmp.display(),
+                self.path.display()
+            )
+        })?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Create `<path>.<random>.tmp` for writing, never opening a file that already exists
+///
+/// A fixed name could be a link planted by someone else or the file of a concurrent run.
+pub(crate) fn create_temp(path: &Path) -> io::Result<(File, PathBuf)> {
+    loop {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{:08x}.tmp", rand::random::<u32>()));
+        let tmp = PathBuf::from(tmp);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(f) => return Ok((f, tmp)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes `<base>.001`, `<base>.002`, … of at most `size` bytes each
+///
+/// Every volume is written under a temporary name and only renamed into place on `commit`,
+/// so an interrupted run leaves no partial set behind.
+pub struct SplitFile {
+    base: PathBuf,
+    size: u64,
+    force: bool,
+    current: Option<BufWriter<File>>,
+    /// Bytes written to the current volume
+    written: u64,
+    /// Temp paths of the volumes created so far, in order
+    parts: Vec<PathBuf>,
+    committed: bool,
+}
+
+impl SplitFile {
+    pub fn create(base: &Path, size: u64, force: bool) -> Self {
+        Self {
+            base: base.to_path_buf(),
+            size,
+            force,
+            current: None,
+            written: 0,
+            parts: Vec::new(),
+            committed: false,
+        }
+    }
+
+    /// Finish the current volume (if any) and open the next one
+    fn next_volume(&mut self) -> io::Result<()> {
+        self.finish_volume()?;
+        let (f, tmp) = create_temp(&volume_path(&self.base, self.parts.len() + 1))?;
+        self.parts.push(tmp);
+        self.current = Some(BufWriter::new(f));
+        self.written = 0;
+        Ok(())
+    }
+
+    fn finish_volume(&mut self) -> io::Result<()> {
+        if let Some(mut w) = self.current.take() {
+            w.flush()?;
+            w.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        if self.parts.is_empty() {
+            self.next_volume()
+                .context("failed to create output volume")?;
+        }
+        self.finish_volume()
+            .context("failed to flush output volume")?;
+        let count = self.parts.len();
+        for n in 1..=count {
+            check_output(&volume_path(&self.base, n), self.force)?;
+        }
+        let stale = stale_volumes(&self.base, count, self.force)?;
+        for (n, tmp) in self.parts.iter().enumerate() {
+            let path = volume_path(&self.base, n + 1);
+            std::fs::rename(tmp, &path).with_context(|| {
+                format!(
+                    "failed to move {} into place as {}",
+                    tmp.display(),
+                    path.display()
+                )
+            })?;
+        }
+        self.committed = true;
+        remove_volumes(stale)?;
+        log::debug!("wrote {} volumes of up to {} bytes", count, self.size);
+        Ok(())
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.current.is_none() || self.written == self.size {
+            self.next_volume()?;
+        }
+        let room = (self.size - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.as_mut().unwrap().write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SplitFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.current = None;
+            for tmp in &self.parts {
+                let _ = std::fs::remove_file(tmp);
+            }
+        }
+    }
+}
+
+/// The `.NNN` volumes found after the last of `count` at `base`, refused without `force`
+///
+/// A stale volume after the last one would be read back as part of the new archive.
+pub(crate) fn stale_volumes(base: &Path, count: usize, force: bool) -> Result<Vec<PathBuf>> {
+    let stale = (count + 1..)
+        .map(|n| volume_path(base, n))
+        .take_while(|path| path.symlink_metadata().is_ok())
+        .collect::<Vec<_>>();
+    if !stale.is_empty() && !force {
+        return Err(Failure::OutputExists.error(format!(
+            "volume '{}' from an older archive already exists (use --force to remove it)",
+            stale[0].display()
+        )));
+    }
+    Ok(stale)
+}
+
+/// Remove what `stale_volumes` found, once the new volumes are in place
+pub(crate) fn remove_volumes(stale: Vec<PathBuf>) -> Result<()> {
+    for path in stale {
+        log::debug!("removing stale volume {}", path.display());
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Create `path` for writing, or use stdout for `-`
+///
+/// Binary output is refused when stdout is a terminal, like `age` does. Without `force`,
+/// an existing file is never replaced. With `split_size`, the archive is written as
+/// numbered volumes next to `path` instead. A remote URL streams the archive there.
+pub fn create_output(path: &Path, force: bool, split_size: Option<u64>) -> Result<Output> {
+    if let Some(size) = split_size {
+        check_split_output(path, force)?;
+        return Ok(Output::Split(SplitFile::create(path, size, force)));
+    }
+    if let Some(remote) = remote(path)? {
+        check_output(path, force)?;
+        return remote.create(force);
+    }
+    if is_stdio(path) {
+        if io::stdout().is_terminal() {
+            anyhow::bail!("refusing to write an encrypted archive to a terminal; redirect stdout");
+        }
+        return Ok(Output::Stdout(BufWriter::new(io::stdout().lock())));
+    }
+    check_output(path, force)?;
+    Ok(Output::File(AtomicFile::create(path, force)?))
+}
+
+/// Space an unprivileged user may still fill on the file system `path` would be written to
+///
+/// `None` for stdout and remote outputs, or when the file system can't be asked.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    if is_stdio(path) || is_remote(path) {
+        return None;
+    }
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut fs: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `fs` is a valid out-pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut fs) } != 0 {
+        return None;
+    }
+    Some(fs.f_bavail as u64 * fs.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Counts bytes written through it
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Run `f` on a background thread to write a tar stream, and read that stream as it's written
+///
+/// An error from `f` reaches the reader as a read error, ahead of the end-of-archive blocks
+/// the builder writes when dropped, so a failed stream never passes for a complete one.
+pub(crate) fn tar_from(
+    f: impl FnOnce(&mut tar::Builder<PipeWriter>) -> Result<()> + Send + 'static,
+) -> Box<dyn Read> {
+    // A few chunks in flight keep both sides busy without holding the whole stream
+    let (tx, rx) = mpsc::sync_channel(16);
+    std::thread::spawn(move || {
+        let mut tar = tar::Builder::new(PipeWriter(tx.clone()));
+        if let Err(e) = f(&mut tar).and_then(|()| Ok(tar.finish()?)) {
+            let _ = tx.send(Err(io::Error::other(format!("{:#}", e))));
+        }
+    });
+    Box::new(PipeReader {
+        rx,
+        chunk: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// Sends everything written to it as chunks to a `PipeReader`
+pub(crate) struct PipeWriter(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tar reader went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the chunks a `PipeWriter` sent; EOF once every writer is dropped
+struct PipeReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new, empty directory under the temp directory; the caller removes it
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("folder_lock-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Write `data` as volumes of `size` bytes at `base`
+    fn split(base: &Path, size: u64, data: &[u8], force: bool) -> Result<()> {
+        let mut w = SplitFile::create(base, size, force);
+        w.write_all(data)?;
+        w.commit()
+    }
+
+    fn volume_lens(base: &Path) -> Vec<u64> {
+        (1..)
+            .map(|n| volume_path(base, n))
+            .take_while(|path| path.exists())
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .collect()
+    }
+
+    /// Only the finished volumes, no temp files
+    fn entries(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn volumes_end_exactly_at_the_size() {
+        let dir = scratch();
+        let base = dir.join("a.age");
+        split(&base, 10, &[1; 20], false).unwrap();
+        assert_eq!(volume_lens(&base), [10, 10]);
+        assert_eq!(entries(&dir), 2);
+
+        let other = dir.join("b.age");
+        split(&other, 10, &[1; 21], false).unwrap();
+        assert_eq!(volume_lens(&other), [10, 10, 1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_empty_archive_is_one_empty_volume() {
+        let dir = scratch();
+        let base = dir.join("a.age");
+        split(&base, 10, &[], false).unwrap();
+        assert_eq!(volume_lens(&base), [0]);
+        assert_eq!(entries(&dir), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_volumes_need_force() {
+        let dir = scratch();
+        let base = dir.join("a.age");
+        split(&base, 4, &[1; 12], false).unwrap();
+
+        // A shorter archive would leave `.003` behind, to be read back as part of it
+        let err = split(&base, 4, &[2; 8], false).unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::OutputExists));
+        assert_eq!(entries(&dir), 3, "nothing is left of the refused run");
+
+        split(&base, 4, &[2; 8], true).unwrap();
+        assert_eq!(volume_lens(&base), [4, 4]);
+        assert_eq!(std::fs::read(volume_path(&base, 1)).unwrap(), [2; 4]);
+        assert_eq!(entries(&dir), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_volumes_are_refused_even_without_earlier_ones() {
+        let dir = scratch();
+        let base = dir.join("a.age");
+        std::fs::write(volume_path(&base, 3), "old").unwrap();
+        std::fs::write(volume_path(&base, 4), "old").unwrap();
+        let stale = stale_volumes(&base, 2, true).unwrap();
+        assert_eq!(stale, [volume_path(&base, 3), volume_path(&base, 4)]);
+        assert!(stale_volumes(&base, 4, false).unwrap().is_empty());
+
+        let err = split(&base, 4, &[1; 8], false).unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::OutputExists));
+        assert!(format!("{:#}", err).contains("a.age.003"), "{:#}", err);
+        assert_eq!(entries(&dir), 2, "nothing is left of the refused run");
+
+        split(&base, 4, &[1; 8], true).unwrap();
+        assert_eq!(volume_lens(&base), [4, 4]);
+        assert_eq!(entries(&dir), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn volumes_read_back_in_order() {
+        let dir = scratch();
+        let base = dir.join("a.age");
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        split(&base, 64, &data, false).unwrap();
+        assert_eq!(volume_lens(&base).len(), 16);
+
+        let first = volume_path(&base, 1);
+        assert_eq!(input_len(&first), Some(1000));
+        let mut read = Vec::new();
+        open_input(&first).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
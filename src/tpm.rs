@@ -0,0 +1,75 @@
+//! Archives sealed to this machine's TPM, for `encrypt --tpm` and `--tpm` when opening
+//!
+//! The key is made by `age-plugin-tpm` inside the TPM and never leaves it; the identity
+//! file only refers to it. Archives encrypted to its recipient therefore open on this
+//! machine without any passphrase, and on no other, which suits unattended local backups.
+//! The identity is kept at `$XDG_DATA_HOME/folder_lock/tpm-identity.txt` and created on
+//! first use. Losing the machine (or clearing its TPM) loses the archives, so keep a second
+//! recipient for anything that must survive it.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// The plugin binary that talks to the TPM
+const PLUGIN: &str = "age-plugin-tpm";
+
+/// Where this machine's TPM identity file is kept
+pub fn identity_path() -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").context("$HOME is not set")?)
+            .join(".local")
+            .join("share"),
+    };
+    Ok(dir.join("folder_lock").join("tpm-identity.txt"))
+}
+
+/// The TPM identity file, which must already exist
+pub fn identity() -> Result<PathBuf> {
+    let path = identity_path()?;
+    if !path.is_file() {
+        anyhow::bail!(
+            "no TPM identity at {}; archives sealed with `encrypt --tpm` create it",
+            path.display()
+        );
+    }
+    Ok(path)
+}
+
+/// The `age1tpm1…` recipient of this machine's TPM, creating its identity if needed
+pub fn recipient() -> Result<String> {
+    let path = identity_path()?;
+    if !path.is_file() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        log::info!("Creating a TPM identity at '{}'", path.display());
+        run(Command::new(PLUGIN).arg("--generate").arg("-o").arg(&path))?;
+    }
+    let out = run(Command::new(PLUGIN).arg("-y").arg(&path))?;
+    let recipient = String::from_utf8_lossy(&out).trim().to_owned();
+    if !recipient.starts_with("age1tpm1") {
+        anyhow::bail!("{} printed no TPM recipient for {}", PLUGIN, path.display());
+    }
+    Ok(recipient)
+}
+
+fn run(command: &mut Command) -> Result<Vec<u8>> {
+    let output = command.output().with_context(|| {
+        format!(
+            "failed to run {} (install it from https://github.com/Foxboron/age-plugin-tpm)",
+            PLUGIN
+        )
+    })?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed: {}",
+            PLUGIN,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,226 @@
+//! Walking the source folder and deciding which entries go into the archive
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+
+/// Tool-specific ignore file, always honored in every directory of the source folder
+pub const IGNORE_FILE: &str = ".folderlockignore";
+
+/// Include/exclude glob patterns applied per entry during the walk
+///
+/// Patterns without a `/` match the entry's file name at any depth (`node_modules`,
+/// `*.log`); patterns with a `/` match the path relative to the source folder.
+#[derive(Clone, Default)]
+pub struct Filters {
+    include: Option<Patterns>,
+    exclude: Option<Patterns>,
+    /// Also honor `.gitignore`, `.ignore`, and `.git/info/exclude`
+    pub use_gitignore: bool,
+    pub symlinks: Symlinks,
+    /// Leave out entries whose name starts with `.`; hidden directories are not descended into
+    pub skip_hidden: bool,
+    /// Deepest level archived, counting the folder's own entries as 1
+    pub max_depth: Option<usize>,
+    /// Visit directory entries in file name order instead of file system order
+    pub sorted: bool,
+}
+
+/// How symbolic links in the source folder are archived
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Store the link itself, pointing wherever it pointed
+    #[default]
+    Preserve,
+    /// Store what the link points to (directories are descended into)
+    Follow,
+    /// Leave links out of the archive
+    Skip,
+}
+
+#[derive(Clone)]
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Result<Option<Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns {
+            let trimmed = pattern.trim_start_matches("./").trim_end_matches('/');
+            let glob =
+                Glob::new(trimmed).with_context(|| format!("invalid pattern '{}'", pattern))?;
+            if trimmed.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+        Ok(Some(Self {
+            names: names.build().context("failed to build glob patterns")?,
+            paths: paths.build().context("failed to build glob patterns")?,
+        }))
+    }
+
+    fn is_match(&self, rel: &Path) -> bool {
+        let name_match = rel.file_name().is_some_and(|n| self.names.is_match(n));
+        name_match || self.paths.is_match(rel)
+    }
+}
+
+impl Filters {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        use_gitignore: bool,
+        symlinks: Symlinks,
+    ) -> Result<Self> {
+        Ok(Self {
+            include: Patterns::new(include)?,
+            exclude: Patterns::new(exclude)?,
+            use_gitignore,
+            symlinks,
+            skip_hidden: false,
+            max_depth: None,
+            sorted: false,
+        })
+    }
+
+    /// Excluded entries are skipped; excluded directories are not descended into
+    fn is_excluded(&self, rel: &Path) -> bool {
+        self.exclude.as_ref().is_some_and(|p| p.is_match(rel))
+    }
+
+    /// Includes only restrict files; directories are always walked so nested matches are found
+    fn is_included(&self, rel: &Path, is_dir: bool) -> bool {
+        is_dir || self.include.as_ref().map_or(true, |p| p.is_match(rel))
+    }
+}
+
+/// An entry selected for archiving
+pub struct Entry {
+    /// Path on disk
+    pub path: PathBuf,
+    /// Path relative to the source folder, used as the name inside the archive
+    pub rel: PathBuf,
+    pub is_dir: bool,
+    /// Only set when links are preserved; followed links report their target's type
+    pub is_symlink: bool,
+}
+
+/// Walk `folder` and call `f` for every entry that passes `filters`, parents before children
+///
+/// Entries are handed over one at a time as directories are read, never collected, so
+/// memory depends on the tree's depth rather than its size. With `filters.sorted` each
+/// directory is listed whole to sort it, which costs memory for the widest directory.
+pub fn walk(
+    folder: &Path,
+    filters: &Filters,
+    mut f: impl FnMut(Entry) -> Result<()>,
+) -> Result<()> {
+    let base = long_path(folder);
+    let mut builder = WalkBuilder::new(&base);
+    builder
+        // Start from "archive everything" and opt into each ignore source explicitly
+        .standard_filters(false)
+        .hidden(filters.skip_hidden)
+        .git_ignore(filters.use_gitignore)
+        .git_exclude(filters.use_gitignore)
+        .ignore(filters.use_gitignore)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .max_depth(filters.max_depth)
+        .follow_links(filters.symlinks == Symlinks::Follow);
+    if filters.sorted {
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+    }
+
+    let root = base.clone();
+    let exclude_filters = filters.clone();
+    builder.filter_entry(move |e| {
+        if exclude_filters.symlinks == Symlinks::Skip && e.path_is_symlink() {
+            return false;
+        }
+        let rel = e.path().strip_prefix(&root).unwrap_or(e.path());
+        !exclude_filters.is_excluded(rel)
+    });
+
+    for entry in builder.build() {
+        let entry = entry.with_context(|| format!("failed to walk '{}'", folder.display()))?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let rel = entry
+            .path()
+            .strip_prefix(&base)
+            .context("walked outside the source folder")?
+            .to_path_buf();
+        let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+        let is_symlink = entry.file_type().is_some_and(|t| t.is_symlink());
+        if !filters.is_included(&rel, is_dir) {
+            continue;
+        }
+        f(Entry {
+            path: entry.into_path(),
+            rel,
+            is_dir,
+            is_symlink,
+        })?;
+    }
+    Ok(())
+}
+
+/// `folder` in the `\\?\` form Windows needs for paths longer than `MAX_PATH`
+#[cfg(windows)]
+fn long_path(folder: &Path) -> PathBuf {
+    // `canonicalize` returns verbatim paths there, so everything joined onto it stays long
+    folder
+        .canonicalize()
+        .unwrap_or_else(|_| folder.to_path_buf())
+}
+
+#[cfg(not(windows))]
+fn long_path(folder: &Path) -> PathBuf {
+    folder.to_path_buf()
+}
+
+/// File count and total size of what a walk would archive
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub files: u64,
+    /// Sum of file sizes
+    pub bytes: u64,
+    /// Expected size of the uncompressed tar stream, including headers and padding
+    pub tar_bytes: u64,
+}
+
+/// tar works in 512-byte blocks: one header per entry, data padded to a block
+const TAR_BLOCK: u64 = 512;
+
+/// Walk `folder` without archiving anything and total up the selected files
+pub fn scan(folder: &Path, filters: &Filters) -> Result<Summary> {
+    // Root entry plus the two zero blocks that end the archive
+    let mut summary = Summary {
+        tar_bytes: 3 * TAR_BLOCK,
+        ..Summary::default()
+    };
+    walk(folder, filters, |entry| {
+        summary.tar_bytes += TAR_BLOCK;
+        if !entry.is_dir && !entry.is_symlink {
+            let meta = std::fs::metadata(&entry.path)
+                .with_context(|| format!("failed to stat '{}'", entry.path.display()))?;
+            summary.files += 1;
+            summary.bytes += meta.len();
+            summary.tar_bytes += meta.len().div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        }
+        Ok(())
+    })?;
+    Ok(summary)
+}
@@ -0,0 +1,190 @@
+//! JavaScript API of the WebAssembly build, to list and decrypt archives in a browser
+//!
+//! Built with `wasm-pack build --target web`. Everything works on in-memory bytes, the
+//! archive as a `Uint8Array`; nothing touches a file system. Passphrase archives take a
+//! while to open, since scrypt is meant to be slow and is slower still in WebAssembly.
+//! xz archives and age plugin identities aren't supported.
+//!
+//! ```js
+//! import init, { list, decrypt } from "./pkg/folder_lock.js";
+//!
+//! await init();
+//! const entries = JSON.parse(list(bytes, passphrase, undefined));
+//! for (const file of decrypt(bytes, undefined, identityText)) {
+//!     download(file.path, file.contents);
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+
+use age::secrecy::SecretString;
+use anyhow::{Context, Result};
+use wasm_bindgen::prelude::*;
+
+use crate::checksum::{self, HashingReader, Manifest};
+use crate::report::{entry_kind, EntryInfo};
+use crate::{header, snapshot, Key};
+
+/// A regular file restored by `decrypt`
+#[wasm_bindgen]
+pub struct File {
+    path: String,
+    contents: Vec<u8>,
+    mode: u32,
+    mtime: u64,
+}
+
+#[wasm_bindgen]
+impl File {
+    /// Path inside the archive, `/`-separated
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn contents(&self) -> Vec<u8> {
+        self.contents.clone()
+    }
+
+    /// Unix permission bits
+    #[wasm_bindgen(getter)]
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Modification time in seconds since the Unix epoch
+    #[wasm_bindgen(getter)]
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+}
+
+/// The entries of `archive` as JSON, in the shape of `list --output json`
+///
+/// Give either the passphrase or the text of an age identity file.
+#[wasm_bindgen]
+pub fn list(
+    archive: &[u8],
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<String, JsError> {
+    let entries = (|| -> Result<Vec<EntryInfo>> {
+        let mut archive = open(archive, passphrase, identity)?;
+        let mut entries = Vec::new();
+        for entry in archive
+            .entries()
+            .context("failed to read archive entries")?
+        {
+            let entry = entry.context("failed to read archive entry")?;
+            let header = entry.header();
+            entries.push(EntryInfo {
+                archive: None,
+                path: entry
+                    .path()
+                    .context("invalid path in archive")?
+                    .display()
+                    .to_string(),
+                kind: entry_kind(header.entry_type()),
+                mode: header.mode().unwrap_or(0),
+                size: header.size().unwrap_or(0),
+                mtime: header.mtime().unwrap_or(0),
+            });
+        }
+        Ok(entries)
+    })()
+    .map_err(js_error)?;
+    Ok(serde_json::to_string(&entries)?)
+}
+
+/// Every regular file in `archive`, checked against the archive's checksum manifest if it
+/// has one
+///
+/// Directories, links and special files are left out. Give either the passphrase or the
+/// text of an age identity file.
+#[wasm_bindgen]
+pub fn decrypt(
+    archive: &[u8],
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<Vec<File>, JsError> {
+    (|| -> Result<Vec<File>> {
+        let mut archive = open(archive, passphrase, identity)?;
+        let mut files = Vec::new();
+        let mut manifest = None;
+        let mut hashes = BTreeMap::new();
+        for entry in archive
+            .entries()
+            .context("failed to read archive entries")?
+        {
+            let mut entry = entry.context("failed to read archive entry")?;
+            let path = entry.path().context("invalid path in archive")?;
+            let key = snapshot::key(&path);
+            if key == checksum::MANIFEST_PATH {
+                manifest = Some(Manifest::parse(&mut entry)?);
+                continue;
+            }
+            if key == header::HEADER_PATH || !checksum::has_contents(entry.header().entry_type()) {
+                continue;
+            }
+            let mode = entry.header().mode().unwrap_or(0);
+            let mtime = entry.header().mtime().unwrap_or(0);
+            let mut contents = Vec::new();
+            let mut reader = HashingReader::new(&mut entry);
+            reader
+                .read_to_end(&mut contents)
+                .with_context(|| format!("archive is corrupted at '{}'", key))?;
+            hashes.insert(key.clone(), reader.finish());
+            files.push(File {
+                path: key,
+                contents,
+                mode,
+                mtime,
+            });
+        }
+        // Archives from before the manifest existed are only checked by age
+        if let Some(manifest) = &manifest {
+            checksum::compare(manifest, &hashes, true)?;
+        }
+        Ok(files)
+    })()
+    .map_err(js_error)
+}
+
+/// Decrypt `archive` with whichever key was given
+fn open(
+    archive: &[u8],
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<tar::Archive<Box<dyn Read>>> {
+    let key = match (passphrase, identity) {
+        (Some(pass), None) => Key::Passphrase(SecretString::new(pass)),
+        (None, Some(text)) => {
+            let entries = age::IdentityFile::from_buffer(text.as_bytes())
+                .context("failed to read the identity")?
+                .into_identities();
+            if entries.is_empty() {
+                anyhow::bail!("the identity text contains no identities");
+            }
+            Key::Identities(
+                entries
+                    .into_iter()
+                    .map(|entry| match entry {
+                        age::IdentityFileEntry::Native(identity) => {
+                            Box::new(identity) as Box<dyn age::Identity + Send>
+                        }
+                    })
+                    .collect(),
+            )
+        }
+        (Some(_), Some(_)) => anyhow::bail!("give either a passphrase or an identity, not both"),
+        (None, None) => anyhow::bail!("a passphrase or an identity is needed"),
+    };
+    let plain = crate::decrypt(Cursor::new(archive.to_vec()), move |_| Ok(key))?;
+    crate::open_archive(plain)
+}
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", e))
+}
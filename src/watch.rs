@@ -0,0 +1,46 @@
+//! Re-running an action whenever a folder changes, with debouncing
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use notify_debouncer_mini::new_debouncer;
+use notify_debouncer_mini::notify::RecursiveMode;
+
+/// Call `on_change` once now and again after every burst of changes under `folder`
+///
+/// Changes are collected until `debounce` passes without a new one, so saving a dozen
+/// files triggers one run. Errors from `on_change` are logged and watching continues;
+/// this only returns if the watcher itself fails.
+pub fn watch(
+    folder: &Path,
+    debounce: Duration,
+    mut on_change: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut debouncer = new_debouncer(debounce, tx).context("failed to start file watcher")?;
+    debouncer
+        .watcher()
+        .watch(folder, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch '{}'", folder.display()))?;
+
+    if let Err(e) = on_change() {
+        log::error!("{:#}", e);
+    }
+    for result in rx {
+        match result {
+            Ok(events) => {
+                log::debug!("{} paths changed", events.len());
+                for event in &events {
+                    log::trace!("changed {}", event.path.display());
+                }
+                if let Err(e) = on_change() {
+                    log::error!("{:#}", e);
+                }
+            }
+            Err(e) => log::warn!("file watcher error: {}", e),
+        }
+    }
+    anyhow::bail!("file watcher stopped unexpectedly")
+}